@@ -1,29 +1,61 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::time::Duration;
 
+use aws_sdk_s3::model::ObjectOwnership;
 use aws_sdk_s3::Client as S3Client;
-use common::checkpointer::Checkpointer;
+use common::checkpointer::{Checkpointer, ExpirePolicy, KeyNormalization};
+use common::object_url::ObjectUrl;
+use futures::FutureExt;
 use serde::{Deserialize, Serialize};
 use vector::aws::{AwsAuthentication, RegionOrEndpoint};
 use vector::config::{AcknowledgementsConfig, GenerateConfig, SinkConfig, SinkContext};
-use vector::sinks::s3_common::config::S3Options;
+use vector::sinks::s3_common::config::{S3Options, S3ServerSideEncryption};
 use vector::sinks::s3_common::service::S3Service;
 use vector::sinks::{s3_common, Healthcheck};
+use vector::template::Template;
 use vector::tls::TlsConfig;
 use vector_core::config::proxy::ProxyConfig;
 use vector_core::config::{DataType, Input};
 use vector_core::sink::VectorSink;
 
-use crate::processor::S3UploadFileSink;
+use crate::compress::Compress;
+use crate::key_prefix::KeyPrefix;
+use crate::manifest::ManifestWriter;
+use crate::probe::{probe_auth, probe_endpoint};
+use crate::processor::{S3UploadFileSink, SinkOptions, UploadDelay};
+use crate::uploader::{validate_metadata, Dedup, S3Uploader, UploaderOptions};
+
+// Resolves to the bucket's region, for sinks whose region is only known to
+// the SDK.
+const GLOBAL_ENDPOINT: &str = "https://s3.amazonaws.com";
+
+// bounds of the upload delays, keeping uploads from being put off for good or
+// their deadlines from overflowing the clock
+const MAX_UPLOAD_DELAY_SECS: u64 = 30 * 24 * 60 * 60;
+const MAX_DELAY_UPLOAD_SECS_PER_MIB: f64 = 3600.0;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct S3UploadFileConfig {
     pub bucket: String,
+    /// A field of upload events holding the bucket to upload the file to instead of `bucket`, e.g. to route the files of each tenant to a bucket of its own. Events without it are uploaded to `bucket`, while events with an invalid bucket name are rejected. Only `bucket` is checked by the healthcheck, and `acl` or the manifest must work with every bucket routed to.
+    pub bucket_field: Option<String>,
+    /// The directory filenames in `message` are relative to. Events with an absolute filename, or one escaping it through `..`, are rejected. Filenames are taken as they are if unset.
+    pub base_dir: Option<PathBuf>,
+    /// How the `key` of events is normalized before uploading, by default collapsing runs of `/` and rejecting events with control characters in their key. Set `control_chars` to `percent_encode` or `keep` to accept them, `lowercase` to lowercase keys, or `enabled` to `false` to take keys as they are.
+    #[serde(default)]
+    pub key_normalization: KeyNormalization,
     #[serde(flatten)]
     pub options: S3Options,
     #[serde(flatten)]
     pub region: RegionOrEndpoint,
+    /// Send requests to the FIPS endpoint of `region`, e.g. for GovCloud. Can't be combined with `endpoint`.
+    #[serde(default)]
+    pub use_fips_endpoint: bool,
+    /// Send requests to the dual-stack (IPv4 and IPv6) endpoint of `region`. Can't be combined with `endpoint`.
+    #[serde(default)]
+    pub use_dual_stack_endpoint: bool,
     pub tls: Option<TlsConfig>,
     #[serde(default)]
     pub auth: AwsAuthentication,
@@ -37,38 +69,182 @@ pub struct S3UploadFileConfig {
     /// The directory used to persist file checkpoint.
     ///
     /// By default, the global `data_dir` option is used. Please make sure the user Vector is running as has write permissions to this directory.
+    ///
+    /// The checkpoints are kept in a subdirectory named after the sink type, locked while the sink runs, so sinks of the same type need a `data_dir` each.
     pub data_dir: Option<PathBuf>,
 
-    /// Delay between receiving upload event and beginning to upload file.
+    /// Delay between receiving upload event and beginning to upload file, at most 30 days.
     #[serde(alias = "delay_upload", default = "default_delay_upload_secs")]
     pub delay_upload_secs: u64,
 
+    /// Extra delay for every MiB of the file, as larger files take longer to finish being written, e.g. `0.1` delays a 100 MiB file by 10 more seconds. At most `3600`.
+    #[serde(default)]
+    pub delay_upload_secs_per_mib: f64,
+
+    /// The maximum delay with `delay_upload_secs_per_mib`, a day by default and at most 30 days.
+    #[serde(default = "default_max_delay_upload_secs")]
+    pub max_delay_upload_secs: u64,
+
     /// The expire time of uploaded file records which used to prevent duplicate uploads.
     #[serde(alias = "expire_after", default = "default_expire_after_secs")]
     pub expire_after_secs: u64,
+
+    /// The point in time that `expire_after_secs` is counted from, either `upload_time` or `file_mtime`.
+    #[serde(default)]
+    pub expire_policy: ExpirePolicy,
+
+    /// The maximum number of files waiting to be uploaded. Once reached, no more upload events are accepted until the pending uploads drain to half of it.
+    #[serde(default = "default_max_pending_uploads")]
+    pub max_pending_uploads: usize,
+
+    /// How often checkpoints are written out even while no upload finishes, e.g. to persist expired checkpoints dropped during quiet periods. Checkpoints are written after every upload either way, and only when they changed. `0` disables it.
+    #[serde(default = "default_checkpoint_flush_interval_secs")]
+    pub checkpoint_flush_interval_secs: u64,
+
+    /// Whether to overwrite existing objects. When disabled, files whose object already exists are skipped, for write-once archives.
+    #[serde(default = "default_overwrite")]
+    pub overwrite: bool,
+
+    /// How to avoid uploading a file again, one of:
+    /// - `etag` (default): skip files checkpointed with the same mtime, and files identical to the existing object by etag. Correct even when checkpoints expire or are lost, at the cost of a `HEAD` and re-reading the whole file whenever the object exists.
+    /// - `checkpoint_only`: skip files checkpointed with the same mtime only. Identical files are uploaded again once their checkpoint expires or is lost, so only use it for files written once.
+    /// - `always`: upload on every event, ignoring checkpoints. Identical content is re-uploaded as often as it's seen.
+    ///
+    /// With `overwrite` disabled, existing objects are never replaced regardless. Files of 8 MiB or more, uploaded in parts, are then still checked with a `HEAD` first, so their parts aren't uploaded for nothing.
+    #[serde(default)]
+    pub dedup: Dedup,
+
+    /// Compress files on the way to S3, one of `none` (default), `gzip` or `zstd`. The suffix `.gz` or `.zst` is appended to object keys and `content_encoding` is set accordingly, so it can't be configured together. Files are compressed into the system temp directory before uploading, which needs room for the compressed copy. Without `overwrite`, files whose object exists are skipped before being compressed.
+    #[serde(default)]
+    pub compress: Compress,
+
+    /// User metadata (`x-amz-meta-*`) set on every object. Upload events may add to or override it with a `metadata` object of strings, and events taking the metadata over S3's 2 KiB limit (the UTF-8 bytes of every key and value) are rejected.
+    pub metadata: Option<HashMap<String, String>>,
+
+    /// A prefix prepended to the `key` of every upload event, supporting templates.
+    ///
+    /// strftime specifiers are rendered against the event's timestamp rather than the wall clock, e.g. `logs/year=%Y/month=%m/day=%d/` lays objects out in Hive-style partitions, which Athena or BigQuery external tables can prune by date. Events with a missing or invalid timestamp are rendered with the ingest time instead, with a warning.
+    pub key_prefix: Option<String>,
+
+    /// Check at startup that the endpoint resolves, accepts TLS connections and the credentials, failing fast with an error telling which went wrong. Credentials are checked by listing no objects of the bucket, and denied access (`AccessDenied`) is only warned about, as they may be allowed to upload without `s3:ListBucket`. Each step times out after 5 seconds. Disable it where the endpoint can't be reached during startup, e.g. air-gapped test environments.
+    #[serde(default = "default_probe_endpoint")]
+    pub probe_endpoint: bool,
+
+    /// Append an entry (key, size, etag and timestamp) for each uploaded object to a manifest object in the bucket, written with the same ACL, encryption, storage class and tags as the objects.
+    pub manifest: Option<ManifestConfig>,
+
+    /// Abort multipart uploads this agent started but never finished, e.g. as it crashed mid-upload, on startup once they're older than `orphan_multipart_age_secs`. Their parts are billed until aborted. Multipart uploads are tracked in `data_dir` while in progress, so uploads of other writers to the bucket are left alone.
+    #[serde(default)]
+    pub cleanup_orphan_multiparts: bool,
+
+    /// The age multipart uploads are aborted at with `cleanup_orphan_multiparts`.
+    #[serde(default = "default_orphan_multipart_age_secs")]
+    pub orphan_multipart_age_secs: u64,
+
+    /// Grow the part size of multipart uploads with the file size, keeping within the 10000 parts S3 allows for files of up to 5 TiB. Otherwise files are uploaded in 8 MiB parts, which fails for files over 78.125 GiB (10000 parts of 8 MiB). Smaller files keep 8 MiB parts either way, so their etags are unaffected.
+    #[serde(default)]
+    pub auto_part_size: bool,
+
+    /// Remember what the `HEAD` of an object found for this long, so files seen again meanwhile aren't checked against S3 again, saving requests with `dedup = "etag"`. Objects created or replaced by other writers within it go unnoticed, a file may then be uploaded over an object that already holds it, or skipped by the etag the object had before. Objects uploaded by this sink are checked again on their next upload. `0` disables it.
+    #[serde(default)]
+    pub head_cache_ttl_secs: u64,
+
+    /// Have S3 encrypt objects with a bucket-level key derived from the KMS key, which cuts the requests S3 makes to KMS, and so their cost, for buckets of many objects. Only takes effect with `server_side_encryption = "aws:kms"`.
+    #[serde(default)]
+    pub bucket_key_enabled: bool,
+
+    /// Check the size and the number of parts of every object completed by a multipart upload with `GetObjectAttributes`, failing the upload and so uploading the file again on a mismatch, guarding archives against rare completion anomalies. Takes an extra request per multipart upload, and the `s3:GetObjectAttributes` permission.
+    #[serde(default)]
+    pub verify_object: bool,
+
+    /// Write a zero-byte object for events with a `key` but an empty or absent `message`, e.g. to mark an empty directory. The key, after `key_prefix`, must end with `/`, and no compression extension is appended. Without it such events are rejected.
+    #[serde(default)]
+    pub allow_empty_marker: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestConfig {
+    /// The key of the manifest object, supporting templates. Defaults to a manifest per hour, `manifests/%Y-%m-%d/%H.ndjson`.
+    ///
+    /// Every upload rewrites the whole manifest, so keep the key rolling over with time, e.g. by date, to keep each manifest small. The manifest is updated by read-modify-write, so other writers appending to the same manifest concurrently may lose entries. Give each Vector instance its own manifest.
+    #[serde(default = "default_manifest_key")]
+    pub key: String,
+}
+
+pub fn default_manifest_key() -> String {
+    "manifests/%Y-%m-%d/%H.ndjson".to_owned()
 }
 
 pub fn default_delay_upload_secs() -> u64 {
     10
 }
 
+pub const fn default_max_delay_upload_secs() -> u64 {
+    24 * 60 * 60
+}
+
 pub fn default_expire_after_secs() -> u64 {
     1800
 }
 
+pub fn default_max_pending_uploads() -> usize {
+    10000
+}
+
+pub const fn default_checkpoint_flush_interval_secs() -> u64 {
+    60
+}
+
+pub const fn default_overwrite() -> bool {
+    true
+}
+
+pub const fn default_probe_endpoint() -> bool {
+    true
+}
+
+pub const fn default_orphan_multipart_age_secs() -> u64 {
+    24 * 60 * 60
+}
+
 impl GenerateConfig for S3UploadFileConfig {
     fn generate_config() -> toml::Value {
         toml::Value::try_from(Self {
             bucket: "".to_owned(),
+            bucket_field: None,
+            base_dir: None,
+            key_normalization: KeyNormalization::default(),
             options: S3Options::default(),
             region: RegionOrEndpoint::default(),
+            use_fips_endpoint: false,
+            use_dual_stack_endpoint: false,
             tls: None,
             auth: AwsAuthentication::default(),
             acknowledgements: Default::default(),
 
             data_dir: None,
             delay_upload_secs: default_delay_upload_secs(),
+            delay_upload_secs_per_mib: 0.0,
+            max_delay_upload_secs: default_max_delay_upload_secs(),
             expire_after_secs: default_expire_after_secs(),
+            expire_policy: ExpirePolicy::default(),
+            max_pending_uploads: default_max_pending_uploads(),
+            checkpoint_flush_interval_secs: default_checkpoint_flush_interval_secs(),
+            overwrite: default_overwrite(),
+            dedup: Dedup::default(),
+            compress: Compress::default(),
+            metadata: None,
+            key_prefix: None,
+            probe_endpoint: default_probe_endpoint(),
+            manifest: None,
+            cleanup_orphan_multiparts: false,
+            orphan_multipart_age_secs: default_orphan_multipart_age_secs(),
+            auto_part_size: false,
+            head_cache_ttl_secs: 0,
+            bucket_key_enabled: false,
+            verify_object: false,
+            allow_empty_marker: false,
         })
         .unwrap()
     }
@@ -79,6 +255,12 @@ impl GenerateConfig for S3UploadFileConfig {
 impl SinkConfig for S3UploadFileConfig {
     async fn build(&self, cx: SinkContext) -> vector::Result<(VectorSink, Healthcheck)> {
         let service = self.create_service(&cx.proxy).await?;
+        if self.probe_endpoint {
+            if let Some(endpoint) = self.probed_endpoint()? {
+                probe_endpoint(&endpoint, &self.tls, &cx.proxy).await?;
+            }
+            probe_auth(&service.client(), &self.bucket).await?;
+        }
         let healthcheck = self.build_healthcheck(service.client())?;
         let sink = self.build_processor(service, cx)?;
         Ok((sink, healthcheck))
@@ -106,36 +288,308 @@ impl S3UploadFileConfig {
         let data_dir = cx
             .globals
             .resolve_and_make_data_subdir(self.data_dir.as_ref(), self.sink_type())?;
-        let mut checkpointer = Checkpointer::new(data_dir);
-        checkpointer.read_checkpoints();
+        // locked and read once running
+        let checkpointer = Checkpointer::new(data_dir, self.expire_policy)?;
 
-        let sink = S3UploadFileSink::new(
-            self.bucket.clone(),
+        let uploader = S3Uploader::new(
+            service.client(),
             self.options.clone(),
-            Duration::from_secs(self.delay_upload_secs),
-            Duration::from_secs(self.expire_after_secs),
-            service,
-            checkpointer,
+            self.uploader_options()?,
         );
+        let sink =
+            S3UploadFileSink::new(uploader, checkpointer, self.sink_options(service.client())?);
 
         Ok(VectorSink::from_event_streamsink(sink))
     }
 
+    pub fn uploader_options(&self) -> vector::Result<UploaderOptions> {
+        if self.compress != Compress::None && self.options.content_encoding.is_some() {
+            return Err(
+                "`content_encoding` can't be set together with `compress`, which sets it".into(),
+            );
+        }
+        let metadata = self.metadata.clone().unwrap_or_default();
+        validate_metadata(&metadata)?;
+        let bucket_key_enabled = self.effective_bucket_key_enabled();
+        if self.bucket_key_enabled && !bucket_key_enabled {
+            warn!(
+                message = "`bucket_key_enabled` only takes effect with `server_side_encryption = \"aws:kms\"`, ignoring it."
+            );
+        }
+        Ok(UploaderOptions {
+            overwrite: self.overwrite,
+            dedup: self.dedup,
+            compress: self.compress,
+            metadata,
+            orphan_multipart_age: self
+                .cleanup_orphan_multiparts
+                .then(|| Duration::from_secs(self.orphan_multipart_age_secs)),
+            auto_part_size: self.auto_part_size,
+            head_cache_ttl: (self.head_cache_ttl_secs > 0)
+                .then(|| Duration::from_secs(self.head_cache_ttl_secs)),
+            bucket_key_enabled,
+            verify_object: self.verify_object,
+        })
+    }
+
+    // `bucket_key_enabled` only applies to SSE-KMS
+    fn effective_bucket_key_enabled(&self) -> bool {
+        self.bucket_key_enabled
+            && matches!(
+                self.options.server_side_encryption,
+                Some(S3ServerSideEncryption::AwsKms)
+            )
+    }
+
+    pub fn sink_options(&self, client: S3Client) -> vector::Result<SinkOptions> {
+        let key_prefix = self.key_prefix.as_deref().map(KeyPrefix::new).transpose()?;
+        let manifest = match &self.manifest {
+            Some(manifest) => {
+                let key = Template::try_from(manifest.key.as_str())?;
+                if !key.is_dynamic() {
+                    warn!(
+                        message = "The manifest key never changes, so the manifest grows without bound and is rewritten whole on every upload.",
+                        key = %manifest.key,
+                    );
+                }
+                Some(ManifestWriter::new(
+                    client,
+                    key,
+                    self.options.clone(),
+                    self.effective_bucket_key_enabled(),
+                ))
+            }
+            None => None,
+        };
+        if !(0.0..=MAX_DELAY_UPLOAD_SECS_PER_MIB).contains(&self.delay_upload_secs_per_mib) {
+            return Err(format!(
+                "`delay_upload_secs_per_mib` must be 0 to {}",
+                MAX_DELAY_UPLOAD_SECS_PER_MIB
+            )
+            .into());
+        }
+        for (name, secs) in [
+            ("delay_upload_secs", self.delay_upload_secs),
+            ("max_delay_upload_secs", self.max_delay_upload_secs),
+        ] {
+            if secs > MAX_UPLOAD_DELAY_SECS {
+                return Err(format!("`{}` can't be more than 30 days", name).into());
+            }
+        }
+        let delay_upload = UploadDelay::new(
+            Duration::from_secs(self.delay_upload_secs),
+            Duration::from_secs_f64(self.delay_upload_secs_per_mib),
+            Duration::from_secs(self.max_delay_upload_secs),
+        );
+        Ok(SinkOptions {
+            bucket: self.bucket.clone(),
+            bucket_field: self.bucket_field.clone(),
+            base_dir: self.base_dir.clone(),
+            key_normalization: self.key_normalization,
+            key_prefix,
+            manifest,
+            object_url: self.object_url()?,
+            allow_empty_marker: self.allow_empty_marker,
+            delay_upload,
+            expire_after: Duration::from_secs(self.expire_after_secs),
+            max_pending_uploads: self.max_pending_uploads,
+            checkpoint_flush_interval: (self.checkpoint_flush_interval_secs > 0)
+                .then(|| Duration::from_secs(self.checkpoint_flush_interval_secs)),
+        })
+    }
+
     pub fn build_healthcheck(&self, client: S3Client) -> vector::Result<Healthcheck> {
-        s3_common::config::build_healthcheck(self.bucket.clone(), client)
+        let healthcheck =
+            s3_common::config::build_healthcheck(self.bucket.clone(), client.clone())?;
+        if self.options.acl.is_none() {
+            return Ok(healthcheck);
+        }
+
+        let bucket = self.bucket.clone();
+        Ok(async move {
+            healthcheck.await?;
+            check_acl_enabled(&client, &bucket).await
+        }
+        .boxed())
+    }
+
+    /// The endpoint requests are sent to, if it's known without resolving the
+    /// region from the environment.
+    fn probed_endpoint(&self) -> vector::Result<Option<hyper::Uri>> {
+        let region = self.region_or_endpoint()?;
+        let endpoint = match (region.endpoint, region.region) {
+            (Some(endpoint), _) => endpoint,
+            (None, Some(region)) => region_endpoint(&region, false, false),
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(endpoint.parse()?))
+    }
+
+    /// Where uploaded objects are served, with the bucket in the host for AWS
+    /// endpoints, or in the path for custom ones, as the SDK addresses them.
+    fn object_url(&self) -> vector::Result<ObjectUrl> {
+        let endpoint = match self.probed_endpoint()? {
+            Some(endpoint) => endpoint.to_string(),
+            None => GLOBAL_ENDPOINT.to_owned(),
+        };
+        Ok(ObjectUrl::new(
+            endpoint.parse()?,
+            self.region.endpoint.is_some(),
+        ))
+    }
+
+    /// `region` with the FIPS or dual-stack endpoint of the region filled in
+    /// if enabled. The SDK this builds with has no such settings of its own, so
+    /// the endpoint is set explicitly.
+    fn region_or_endpoint(&self) -> vector::Result<RegionOrEndpoint> {
+        if !self.use_fips_endpoint && !self.use_dual_stack_endpoint {
+            return Ok(self.region.clone());
+        }
+        if self.region.endpoint.is_some() {
+            return Err("`endpoint` can't be combined with `use_fips_endpoint` or \
+                        `use_dual_stack_endpoint`, set it to the FIPS or dual-stack \
+                        endpoint instead"
+                .into());
+        }
+        let region = self.region.region.as_ref().ok_or(
+            "`use_fips_endpoint` and `use_dual_stack_endpoint` require `region` to be set",
+        )?;
+        if self.use_fips_endpoint && region.starts_with("cn-") {
+            return Err(format!("there are no FIPS endpoints in region {}", region).into());
+        }
+        Ok(RegionOrEndpoint {
+            region: Some(region.clone()),
+            endpoint: Some(region_endpoint(
+                region,
+                self.use_fips_endpoint,
+                self.use_dual_stack_endpoint,
+            )),
+        })
     }
 
     pub async fn create_service(&self, proxy: &ProxyConfig) -> vector::Result<S3Service> {
-        s3_common::config::create_service(&self.region, &self.auth, proxy, &self.tls).await
+        let region = self.region_or_endpoint()?;
+        s3_common::config::create_service(&region, &self.auth, proxy, &self.tls).await
+    }
+}
+
+fn region_endpoint(region: &str, fips: bool, dual_stack: bool) -> String {
+    let service = if fips { "s3-fips" } else { "s3" };
+    let dual_stack = if dual_stack { ".dualstack" } else { "" };
+    let domain = if region.starts_with("cn-") {
+        "amazonaws.com.cn"
+    } else {
+        "amazonaws.com"
+    };
+    format!("https://{}{}.{}.{}", service, dual_stack, region, domain)
+}
+
+// Buckets with Object Ownership set to `BucketOwnerEnforced` reject any request
+// carrying an ACL, so every upload would fail if `acl` is configured.
+async fn check_acl_enabled(client: &S3Client, bucket: &str) -> vector::Result<()> {
+    let response = match client
+        .get_bucket_ownership_controls()
+        .bucket(bucket)
+        .send()
+        .await
+    {
+        Ok(response) => response,
+        Err(error) => {
+            // Buckets without ownership controls keep ACLs enabled, and missing
+            // permission to read them shouldn't block startup either.
+            debug!(message = "Failed to get bucket ownership controls.", %error);
+            return Ok(());
+        }
+    };
+
+    let enforced = response
+        .ownership_controls
+        .and_then(|controls| controls.rules)
+        .unwrap_or_default()
+        .into_iter()
+        .any(|rule| rule.object_ownership == Some(ObjectOwnership::BucketOwnerEnforced));
+    if enforced {
+        return Err(format!(
+            "ACLs are disabled on bucket {} (Object Ownership is BucketOwnerEnforced), \
+             but `acl` is configured. Remove the `acl` option to upload to this bucket.",
+            bucket
+        )
+        .into());
     }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mock_s3;
 
     #[test]
     fn generate_config() {
         vector::test_util::test_generate_config::<S3UploadFileConfig>();
     }
+
+    #[test]
+    fn fips_and_dual_stack_endpoints() {
+        let endpoint = |options: &str| {
+            toml::from_str::<S3UploadFileConfig>(&format!("bucket = \"bucket\"\n{}", options))
+                .unwrap()
+                .region_or_endpoint()
+                .map(|region| region.endpoint)
+        };
+
+        assert_eq!(endpoint("region = \"us-east-1\"").unwrap(), None);
+        assert_eq!(
+            endpoint("region = \"us-gov-west-1\"\nuse_fips_endpoint = true").unwrap(),
+            Some("https://s3-fips.us-gov-west-1.amazonaws.com".to_owned())
+        );
+        assert_eq!(
+            endpoint("region = \"us-east-1\"\nuse_dual_stack_endpoint = true").unwrap(),
+            Some("https://s3.dualstack.us-east-1.amazonaws.com".to_owned())
+        );
+        assert_eq!(
+            endpoint(
+                "region = \"us-east-1\"\nuse_fips_endpoint = true\nuse_dual_stack_endpoint = true"
+            )
+            .unwrap(),
+            Some("https://s3-fips.dualstack.us-east-1.amazonaws.com".to_owned())
+        );
+        assert_eq!(
+            endpoint("region = \"cn-north-1\"\nuse_dual_stack_endpoint = true").unwrap(),
+            Some("https://s3.dualstack.cn-north-1.amazonaws.com.cn".to_owned())
+        );
+
+        // ambiguous or unresolvable
+        assert!(endpoint("region = \"cn-north-1\"\nuse_fips_endpoint = true").is_err());
+        assert!(endpoint("use_fips_endpoint = true").is_err());
+        assert!(endpoint(
+            "region = \"us-east-1\"\nendpoint = \"http://127.0.0.1:9000\"\nuse_fips_endpoint = true"
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn bound_upload_delays() {
+        async fn sink_options(options: &str) -> vector::Result<SinkOptions> {
+            let config = mock_s3::config("http://127.0.0.1:1", options);
+            let client = mock_s3::client(&config).await;
+            config.sink_options(client)
+        }
+
+        assert!(sink_options("delay_upload_secs_per_mib = 3600.0")
+            .await
+            .is_ok());
+
+        for options in [
+            "delay_upload_secs_per_mib = -1.0",
+            "delay_upload_secs_per_mib = 1e300",
+            "delay_upload_secs_per_mib = inf",
+            "delay_upload_secs_per_mib = nan",
+            "delay_upload_secs = 9223372036854775807",
+            "max_delay_upload_secs = 9223372036854775807",
+        ] {
+            assert!(sink_options(options).await.is_err(), "{}", options);
+        }
+    }
 }
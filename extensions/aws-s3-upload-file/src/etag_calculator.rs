@@ -5,45 +5,46 @@ use md5::Digest;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 
+// the buffer the parts are read through, a part is never held in memory whole
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
 pub struct EtagCalculator {
-    chunk: Vec<u8>,
+    buffer: Vec<u8>,
     concat_md5: Vec<u8>,
-    multipart_upload_chunk_size: usize,
     multipart_upload_max_chunks: usize,
 }
 
 impl EtagCalculator {
-    pub fn new(multipart_upload_chunk_size: usize, multipart_upload_max_chunks: usize) -> Self {
+    pub fn new(multipart_upload_max_chunks: usize) -> Self {
         Self {
-            chunk: vec![],
+            buffer: vec![],
             concat_md5: vec![],
-            multipart_upload_chunk_size,
             multipart_upload_max_chunks,
         }
     }
 
-    pub fn content_md5(chunk: &[u8]) -> String {
-        base64::encode(md5::Md5::digest(chunk))
-    }
-
-    pub async fn file(&mut self, filename: impl AsRef<Path>) -> io::Result<String> {
+    /// The etag of `filename` as uploaded in parts of
+    /// `multipart_upload_chunk_size`, which must be the part size the file is
+    /// uploaded with.
+    pub async fn file(
+        &mut self,
+        filename: impl AsRef<Path>,
+        multipart_upload_chunk_size: usize,
+    ) -> io::Result<String> {
         let mut chunk_count = 0;
         let mut file = File::open(filename).await?;
         let mut total_size = 0;
+        self.buffer.resize(READ_BUFFER_SIZE, 0);
         loop {
-            self.chunk.clear();
-            let read_size = (&mut file)
-                .take(self.multipart_upload_chunk_size as u64)
-                .read_to_end(&mut self.chunk)
+            let read_size = self
+                .part_md5(&mut file, multipart_upload_chunk_size)
                 .await?;
             total_size += read_size;
             if read_size == 0 {
                 break;
             }
             chunk_count += 1;
-            let digest: [u8; 16] = md5::Md5::digest(&self.chunk).into();
-            self.concat_md5.extend_from_slice(&digest);
-            if read_size < self.multipart_upload_chunk_size {
+            if read_size < multipart_upload_chunk_size {
                 break;
             }
             if chunk_count > self.multipart_upload_max_chunks {
@@ -56,7 +57,7 @@ impl EtagCalculator {
             self.concat_md5.extend_from_slice(&digest);
         }
 
-        let res = if total_size >= self.multipart_upload_chunk_size {
+        let res = if total_size >= multipart_upload_chunk_size {
             format!(
                 "\"{:x}-{}\"",
                 md5::Md5::digest(&self.concat_md5),
@@ -69,10 +70,61 @@ impl EtagCalculator {
         // limit the capacity to avoid occupying too much memory
         const MAX_CAPACITY: usize = 10 * 1024; // 10KiB
         self.concat_md5.clear();
-        self.chunk.clear();
         self.concat_md5.shrink_to(MAX_CAPACITY);
-        self.chunk.shrink_to(MAX_CAPACITY);
 
         Ok(res)
     }
+
+    /// Hashes the next part of at most `part_size` bytes through the fixed
+    /// size read buffer, appending its md5 unless the file is at its end.
+    async fn part_md5(&mut self, file: &mut File, part_size: usize) -> io::Result<usize> {
+        let mut hasher = md5::Md5::new();
+        let mut read_size = 0;
+        while read_size < part_size {
+            let len = (part_size - read_size).min(self.buffer.len());
+            let n = file.read(&mut self.buffer[..len]).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&self.buffer[..n]);
+            read_size += n;
+        }
+        if read_size > 0 {
+            let digest: [u8; 16] = hasher.finalize().into();
+            self.concat_md5.extend_from_slice(&digest);
+        }
+        Ok(read_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn etag_of_parts_larger_than_the_buffer() {
+        let path = std::env::temp_dir().join(format!("etag-{}", std::process::id()));
+        let part_size = 3 * READ_BUFFER_SIZE / 2;
+        let content = (0..=255u8)
+            .cycle()
+            .take(part_size * 2 + 10)
+            .collect::<Vec<_>>();
+        std::fs::write(&path, &content).unwrap();
+
+        let mut concat_md5 = vec![];
+        for part in content.chunks(part_size) {
+            concat_md5.extend_from_slice(&md5::Md5::digest(part));
+        }
+        let expected = format!("\"{:x}-3\"", md5::Md5::digest(&concat_md5));
+        let mut calculator = EtagCalculator::new(10);
+        assert_eq!(calculator.file(&path, part_size).await.unwrap(), expected);
+
+        let single = format!("\"{:x}\"", md5::Md5::digest(&content));
+        assert_eq!(
+            calculator.file(&path, content.len() + 1).await.unwrap(),
+            single
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
 }
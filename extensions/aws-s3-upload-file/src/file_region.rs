@@ -0,0 +1,108 @@
+use std::io::{self, SeekFrom};
+use std::path::PathBuf;
+
+use aws_sdk_s3::types::ByteStream;
+use aws_smithy_http::body::SdkBody;
+use futures::{stream, TryStreamExt};
+use md5::Digest;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, Take};
+use tokio_util::io::ReaderStream;
+
+// the buffer used to compute the md5 of a region
+const MD5_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A byte range of a file, sent as a request body by streaming it from disk
+/// rather than buffering it in memory.
+///
+/// `Content-MD5` has to be sent ahead of the body, so the region is read twice,
+/// once to compute the md5 and once to send it. This trades a second read of
+/// the file, usually served by the page cache, for not holding a whole chunk in
+/// memory.
+#[derive(Clone, Debug)]
+pub struct FileRegion {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl FileRegion {
+    async fn open(&self) -> io::Result<Take<File>> {
+        let mut file = File::open(&self.path).await?;
+        file.seek(SeekFrom::Start(self.offset)).await?;
+        Ok(file.take(self.length))
+    }
+
+    pub async fn content_md5(&self) -> io::Result<String> {
+        let mut reader = self.open().await?;
+        let mut hasher = md5::Md5::new();
+        let mut buffer = vec![0; MD5_BUFFER_SIZE];
+        let mut read = 0;
+        loop {
+            let n = reader.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+            read += n as u64;
+        }
+        if read != self.length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "{:?} is shorter than expected, read {} of {} bytes",
+                    self.path, read, self.length
+                ),
+            ));
+        }
+        Ok(base64::encode(hasher.finalize()))
+    }
+
+    /// The body streaming the region. It's retryable, every attempt reopens
+    /// the file.
+    pub fn byte_stream(&self) -> ByteStream {
+        let region = self.clone();
+        let body = SdkBody::retryable(move || {
+            let region = region.clone();
+            let body = stream::once(async move { region.open().await.map(ReaderStream::new) })
+                .try_flatten();
+            SdkBody::from(hyper::Body::wrap_stream(body))
+        });
+        ByteStream::new(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stream_file_region() {
+        let path = std::env::temp_dir().join(format!("file-region-{}", std::process::id()));
+        let content = (0..=255u8).cycle().take(200 * 1024).collect::<Vec<_>>();
+        std::fs::write(&path, &content).unwrap();
+
+        let region = FileRegion {
+            path: path.clone(),
+            offset: 1000,
+            length: 100 * 1024,
+        };
+        let expected = &content[1000..1000 + 100 * 1024];
+        assert_eq!(
+            region.content_md5().await.unwrap(),
+            base64::encode(md5::Md5::digest(expected))
+        );
+
+        let body = region.byte_stream().collect().await.unwrap().into_bytes();
+        assert_eq!(body.as_ref(), expected);
+
+        let past_end = FileRegion {
+            path: path.clone(),
+            offset: 150 * 1024,
+            length: 100 * 1024,
+        };
+        assert!(past_end.content_md5().await.is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
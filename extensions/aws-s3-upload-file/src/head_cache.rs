@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use common::checkpointer::UploadKey;
+
+/// The etags of recent `HEAD`s of objects, `None` for objects found missing,
+/// so files seen again within `ttl` aren't checked against S3 again.
+///
+/// Objects changed by other writers within `ttl` go unnoticed: an object
+/// created meanwhile is taken as missing, and one replaced meanwhile is
+/// compared by its old etag. Entries are dropped once the object is uploaded.
+pub struct HeadCache {
+    ttl: Duration,
+    entries: HashMap<UploadKey, (Instant, Option<String>)>,
+}
+
+impl HeadCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// The cached etag of `key`, `Some(None)` if the object was missing.
+    pub fn get(&self, key: &UploadKey) -> Option<Option<String>> {
+        self.entries
+            .get(key)
+            .filter(|(cached_at, _)| cached_at.elapsed() < self.ttl)
+            .map(|(_, etag)| etag.clone())
+    }
+
+    pub fn insert(&mut self, key: UploadKey, etag: Option<String>) {
+        let ttl = self.ttl;
+        self.entries
+            .retain(|_, (cached_at, _)| cached_at.elapsed() < ttl);
+        self.entries.insert(key, (Instant::now(), etag));
+    }
+
+    pub fn invalidate(&mut self, key: &UploadKey) {
+        self.entries.remove(key);
+    }
+}
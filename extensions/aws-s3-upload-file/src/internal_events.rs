@@ -0,0 +1,21 @@
+use metrics::counter;
+use vector_core::internal_event::InternalEvent;
+
+/// A multipart upload this agent left behind, e.g. by crashing mid-upload,
+/// aborted on startup.
+#[derive(Debug)]
+pub struct OrphanMultipartAborted<'a> {
+    pub key: &'a str,
+    pub upload_id: &'a str,
+}
+
+impl<'a> InternalEvent for OrphanMultipartAborted<'a> {
+    fn emit(self) {
+        info!(
+            message = "Aborted orphaned multipart upload.",
+            key = %self.key,
+            upload_id = %self.upload_id,
+        );
+        counter!("orphan_multiparts_aborted_total", 1);
+    }
+}
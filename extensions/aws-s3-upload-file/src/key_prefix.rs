@@ -0,0 +1,82 @@
+use vector::config::log_schema;
+use vector::template::{Template, TemplateParseError, TemplateRenderingError};
+use vector_core::event::{Event, Value};
+
+/// A prefix prepended to the object key of every upload, rendered against the
+/// upload event.
+///
+/// strftime specifiers are rendered against the event's timestamp rather than
+/// the wall clock, so `year=%Y/month=%m/day=%d/` partitions objects by when
+/// their file was seen, matching the Hive-style layout of Athena or BigQuery
+/// external tables. Events without a valid timestamp fall back to the ingest
+/// time.
+pub struct KeyPrefix {
+    template: Template,
+    uses_timestamp: bool,
+}
+
+impl KeyPrefix {
+    pub fn new(prefix: &str) -> Result<Self, TemplateParseError> {
+        Ok(Self {
+            template: Template::try_from(prefix)?,
+            uses_timestamp: prefix.contains('%'),
+        })
+    }
+
+    pub fn render(&self, event: &Event) -> Result<String, TemplateRenderingError> {
+        if self.uses_timestamp {
+            let timestamp = event
+                .maybe_as_log()
+                .and_then(|log| log.get(log_schema().timestamp_key()));
+            if !matches!(timestamp, Some(Value::Timestamp(_))) {
+                warn!(
+                    message = "Missing or invalid event timestamp, rendering key prefix with the ingest time.",
+                    timestamp_key = %log_schema().timestamp_key(),
+                    internal_log_rate_secs = 10,
+                );
+            }
+        }
+        self.template.render_string(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use vector_core::event::LogEvent;
+
+    use super::*;
+
+    #[test]
+    fn render_key_prefix() {
+        let prefix = KeyPrefix::new("{{ cluster }}/year=%Y/month=%m/day=%d/").unwrap();
+
+        let mut log = LogEvent::from("/tmp/file");
+        log.insert("cluster", "c1");
+        log.insert(
+            log_schema().timestamp_key(),
+            Utc.ymd(2022, 8, 25).and_hms(3, 4, 5),
+        );
+        assert_eq!(
+            prefix.render(&log.clone().into()).unwrap(),
+            "c1/year=2022/month=08/day=25/"
+        );
+
+        // falls back to the ingest time
+        log.insert(log_schema().timestamp_key(), "yesterday");
+        let now = Utc::now();
+        let rendered = prefix.render(&log.into()).unwrap();
+        let expected =
+            |time: chrono::DateTime<Utc>| time.format("c1/year=%Y/month=%m/day=%d/").to_string();
+        // the date may have just changed
+        assert!(rendered == expected(now) || rendered == expected(Utc::now()));
+
+        let static_prefix = KeyPrefix::new("archive/").unwrap();
+        assert_eq!(
+            static_prefix
+                .render(&LogEvent::from("/tmp/file").into())
+                .unwrap(),
+            "archive/"
+        );
+    }
+}
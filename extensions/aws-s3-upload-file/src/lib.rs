@@ -1,8 +1,18 @@
 #[macro_use]
 extern crate tracing;
 
+mod compress;
 mod config;
 mod etag_calculator;
+mod file_region;
+mod head_cache;
+mod internal_events;
+mod key_prefix;
+mod manifest;
+#[cfg(test)]
+mod mock_s3;
+mod orphan_multiparts;
+mod probe;
 mod processor;
 mod uploader;
 
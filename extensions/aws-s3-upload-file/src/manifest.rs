@@ -0,0 +1,203 @@
+use std::io;
+
+use aws_sdk_s3::types::{ByteStream, SdkError};
+use aws_sdk_s3::Client as S3Client;
+use chrono::{DateTime, Utc};
+use common::checkpointer::UploadKey;
+use serde::Serialize;
+use vector::sinks::s3_common::config::S3Options;
+use vector::template::{Template, TemplateRenderingError};
+use vector_core::event::Event;
+
+use crate::uploader::tagging;
+
+/// Appends an entry for every uploaded object to a manifest object in the
+/// bucket, one JSON object per line.
+///
+/// The manifest is updated by read-modify-write, which is not atomic. Uploads
+/// of a single sink are sequential, but other writers appending to the same
+/// manifest concurrently (e.g. another Vector instance) may lose each other's
+/// entries, so give each writer its own manifest, e.g. by including the host
+/// name in the key template. As every append rewrites the whole manifest, the
+/// key should also roll over with time to keep each manifest small.
+///
+/// The manifest is written with the same ACL, grants, encryption, storage
+/// class and tags as the uploaded objects.
+pub struct ManifestWriter {
+    client: S3Client,
+    key: Template,
+    options: S3Options,
+    bucket_key_enabled: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ManifestEntry<'a> {
+    key: &'a str,
+    size: i64,
+    etag: Option<String>,
+    timestamp: DateTime<Utc>,
+}
+
+impl ManifestWriter {
+    pub const fn new(
+        client: S3Client,
+        key: Template,
+        options: S3Options,
+        bucket_key_enabled: bool,
+    ) -> Self {
+        Self {
+            client,
+            key,
+            options,
+            bucket_key_enabled,
+        }
+    }
+
+    /// Renders the manifest key for the upload carried by `event`.
+    pub fn render_key(&self, event: &Event) -> Result<String, TemplateRenderingError> {
+        self.key.render_string(event)
+    }
+
+    pub async fn append(&self, upload_key: &UploadKey, manifest_key: &str) -> io::Result<()> {
+        let object = self
+            .client
+            .head_object()
+            .bucket(&upload_key.bucket)
+            .key(&upload_key.object_key)
+            .send()
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let entry = ManifestEntry {
+            key: &upload_key.object_key,
+            size: object.content_length,
+            etag: object.e_tag,
+            timestamp: Utc::now(),
+        };
+
+        let manifest = self.read(&upload_key.bucket, manifest_key).await?;
+        let manifest = append_entry(manifest, &entry)?;
+
+        self.client
+            .put_object()
+            .bucket(&upload_key.bucket)
+            .key(manifest_key)
+            .content_type("application/x-ndjson")
+            .set_acl(self.options.acl.map(Into::into))
+            .set_grant_full_control(self.options.grant_full_control.clone())
+            .set_grant_read(self.options.grant_read.clone())
+            .set_grant_read_acp(self.options.grant_read_acp.clone())
+            .set_grant_write_acp(self.options.grant_write_acp.clone())
+            .set_server_side_encryption(self.options.server_side_encryption.map(Into::into))
+            .set_ssekms_key_id(self.options.ssekms_key_id.clone())
+            .set_bucket_key_enabled(self.bucket_key_enabled.then(|| true))
+            .set_storage_class(self.options.storage_class.map(Into::into))
+            .set_tagging(tagging(&self.options))
+            .body(ByteStream::from(manifest))
+            .send()
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        Ok(())
+    }
+
+    async fn read(&self, bucket: &str, manifest_key: &str) -> io::Result<Vec<u8>> {
+        let response = self
+            .client
+            .get_object()
+            .bucket(bucket)
+            .key(manifest_key)
+            .send()
+            .await;
+        match response {
+            Ok(output) => {
+                let body = output
+                    .body
+                    .collect()
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                Ok(body.into_bytes().to_vec())
+            }
+            Err(SdkError::ServiceError { err, .. }) if err.is_no_such_key() => Ok(vec![]),
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        }
+    }
+}
+
+fn append_entry(mut manifest: Vec<u8>, entry: &ManifestEntry<'_>) -> io::Result<Vec<u8>> {
+    if !manifest.is_empty() && !manifest.ends_with(b"\n") {
+        manifest.push(b'\n');
+    }
+    serde_json::to_writer(&mut manifest, entry)?;
+    manifest.push(b'\n');
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::mock_s3::{self, Response};
+
+    #[test]
+    fn append_manifest_entries() {
+        let entry = |key| ManifestEntry {
+            key,
+            size: 42,
+            etag: Some("\"d41d8cd98f00b204e9800998ecf8427e\"".to_owned()),
+            timestamp: Utc.timestamp(1661396787, 0),
+        };
+
+        let manifest = append_entry(vec![], &entry("a.log")).unwrap();
+        let manifest = append_entry(manifest, &entry("b.log")).unwrap();
+
+        let line = |key| {
+            format!(
+                r#"{{"key":"{}","size":42,"etag":"\"d41d8cd98f00b204e9800998ecf8427e\"","timestamp":"2022-08-25T03:06:27Z"}}"#,
+                key
+            )
+        };
+        assert_eq!(
+            String::from_utf8(manifest).unwrap(),
+            format!("{}\n{}\n", line("a.log"), line("b.log"))
+        );
+    }
+
+    #[tokio::test]
+    async fn write_manifest_with_object_options() {
+        let (endpoint, requests) = mock_s3::serve(|request| match request.method() {
+            "HEAD" => mock_s3::existing_object(request),
+            _ => Response::ok(""),
+        })
+        .await;
+        let config = mock_s3::config(
+            &endpoint,
+            r#"
+            acl = "bucket-owner-full-control"
+            server_side_encryption = "aws:kms"
+            ssekms_key_id = "key-id"
+            tags.team = "storage"
+            manifest = {}
+            "#,
+        );
+        let client = mock_s3::client(&config).await;
+        let manifest = config.sink_options(client).unwrap().manifest.unwrap();
+
+        manifest
+            .append(&mock_s3::upload_key("", "a.log"), "manifest.ndjson")
+            .await
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+        let put = requests.last().unwrap();
+        assert_eq!(put.method(), "PUT");
+        assert!(put.headers.contains("x-amz-acl: bucket-owner-full-control"));
+        assert!(put
+            .headers
+            .contains("x-amz-server-side-encryption: aws:kms"));
+        assert!(put
+            .headers
+            .contains("x-amz-server-side-encryption-aws-kms-key-id: key-id"));
+        assert!(put.headers.contains("x-amz-tagging: team=storage"));
+    }
+}
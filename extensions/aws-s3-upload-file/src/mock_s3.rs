@@ -0,0 +1,71 @@
+use std::path::PathBuf;
+
+use aws_sdk_s3::Client as S3Client;
+use common::checkpointer::{Checkpointer, UploadKey};
+pub use common::mock_http::{serve, Request, Response};
+use vector_core::config::proxy::ProxyConfig;
+
+use crate::config::S3UploadFileConfig;
+use crate::uploader::S3Uploader;
+
+/// Answers every request with an existing, empty object.
+pub fn existing_object(_: &Request) -> Response {
+    Response::ok("").header("ETag", "\"d41d8cd98f00b204e9800998ecf8427e\"")
+}
+
+/// A config uploading to `bucket` at `endpoint`, along with the `options`
+/// given as TOML.
+pub fn config(endpoint: &str, options: &str) -> S3UploadFileConfig {
+    toml::from_str(&format!(
+        r#"
+        bucket = "bucket"
+        region = "us-east-1"
+        endpoint = "{}"
+        auth.access_key_id = "id"
+        auth.secret_access_key = "secret"
+        {}
+        "#,
+        endpoint, options
+    ))
+    .unwrap()
+}
+
+/// The client `config` builds.
+pub async fn client(config: &S3UploadFileConfig) -> S3Client {
+    config
+        .create_service(&ProxyConfig::default())
+        .await
+        .unwrap()
+        .client()
+}
+
+/// The uploader `config` builds.
+pub async fn uploader(config: &S3UploadFileConfig) -> S3Uploader {
+    S3Uploader::new(
+        client(config).await,
+        config.options.clone(),
+        config.uploader_options().unwrap(),
+    )
+}
+
+/// A data dir of its own for `name`, created empty.
+pub fn data_dir(name: &str) -> PathBuf {
+    let data_dir = std::env::temp_dir().join(format!("s3-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&data_dir);
+    std::fs::create_dir_all(&data_dir).unwrap();
+    data_dir
+}
+
+/// A checkpointer in a data dir of its own, as it locks the dir.
+pub fn checkpointer(name: &str) -> Checkpointer {
+    Checkpointer::new(data_dir(name), Default::default()).unwrap()
+}
+
+/// The upload of `filename` to `object_key` of `bucket`.
+pub fn upload_key(filename: &str, object_key: &str) -> UploadKey {
+    UploadKey {
+        filename: filename.to_owned(),
+        bucket: "bucket".to_owned(),
+        object_key: object_key.to_owned(),
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+use aws_sdk_s3::Client as S3Client;
+use chrono::Utc;
+use common::checkpointer::{Checkpointer, UploadKey, UploadSession};
+use vector::emit;
+
+use crate::internal_events::OrphanMultipartAborted;
+
+// how long a multipart upload stays tracked past the age it's aborted at, so
+// it's still known on the startup after next if aborting it failed
+pub const TRACK_GRACE_PERIOD: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Aborts the multipart uploads of `bucket` started by this agent and never
+/// completed nor aborted, e.g. as it crashed mid-upload, once they're older
+/// than `max_age`. Their parts are billed until then.
+///
+/// Only uploads tracked in `checkpointer` are considered, uploads of other
+/// writers to the bucket are left alone. Tracked uploads S3 no longer lists
+/// are forgotten. Failures are logged, never failing the startup.
+pub async fn abort_orphan_multiparts(
+    client: &S3Client,
+    bucket: &str,
+    checkpointer: &mut Checkpointer,
+    max_age: Duration,
+) {
+    let tracked = checkpointer
+        .sessions()
+        .filter(|(upload_key, _)| upload_key.bucket == bucket)
+        .map(|(upload_key, session)| (upload_key.clone(), session.clone()))
+        .collect::<Vec<_>>();
+    if tracked.is_empty() {
+        return;
+    }
+
+    let prefix = common_prefix(tracked.iter().map(|(key, _)| key.object_key.as_str()));
+    let initiated = match list_multipart_uploads(client, bucket, prefix).await {
+        Ok(initiated) => initiated,
+        Err(error) => {
+            warn!(message = "Failed to list multipart uploads.", %error, %bucket);
+            return;
+        }
+    };
+
+    let now = Utc::now().timestamp();
+    for (upload_key, session) in tracked {
+        let key = (upload_key.object_key.clone(), session.session_uri.clone());
+        match initiated.get(&key) {
+            None => checkpointer.remove_session(&upload_key),
+            Some(&started) if now - started >= max_age.as_secs() as i64 => {
+                match abort(client, &upload_key, &session).await {
+                    Ok(()) => {
+                        emit!(OrphanMultipartAborted {
+                            key: &upload_key.object_key,
+                            upload_id: &session.session_uri,
+                        });
+                        checkpointer.remove_session(&upload_key);
+                    }
+                    Err(error) => warn!(
+                        message = "Failed to abort orphaned multipart upload.",
+                        %error,
+                        key = %upload_key.object_key,
+                        upload_id = %session.session_uri,
+                    ),
+                }
+            }
+            Some(_) => {}
+        }
+    }
+
+    if let Err(error) = checkpointer.write_checkpoints() {
+        error!(message = "Failed to write checkpoints.", %error);
+    }
+}
+
+// the initiation time (seconds since epoch) of every multipart upload under
+// `prefix`, by key and upload id
+async fn list_multipart_uploads(
+    client: &S3Client,
+    bucket: &str,
+    prefix: &str,
+) -> io::Result<HashMap<(String, String), i64>> {
+    let mut initiated = HashMap::new();
+    let mut key_marker = None;
+    let mut upload_id_marker = None;
+    loop {
+        let response = client
+            .list_multipart_uploads()
+            .bucket(bucket)
+            .prefix(prefix)
+            .set_key_marker(key_marker)
+            .set_upload_id_marker(upload_id_marker)
+            .send()
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        for upload in response.uploads.unwrap_or_default() {
+            if let (Some(key), Some(upload_id), Some(time)) =
+                (upload.key, upload.upload_id, upload.initiated)
+            {
+                initiated.insert((key, upload_id), time.secs());
+            }
+        }
+        if !response.is_truncated {
+            return Ok(initiated);
+        }
+        key_marker = response.next_key_marker;
+        upload_id_marker = response.next_upload_id_marker;
+    }
+}
+
+async fn abort(
+    client: &S3Client,
+    upload_key: &UploadKey,
+    session: &UploadSession,
+) -> io::Result<()> {
+    client
+        .abort_multipart_upload()
+        .bucket(&upload_key.bucket)
+        .key(&upload_key.object_key)
+        .upload_id(&session.session_uri)
+        .send()
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    Ok(())
+}
+
+// the longest prefix shared by all `keys`, so a single listing covers them
+fn common_prefix<'a>(mut keys: impl Iterator<Item = &'a str>) -> &'a str {
+    let mut prefix = keys.next().unwrap_or_default();
+    for key in keys {
+        let len = prefix
+            .char_indices()
+            .zip(key.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or_else(|| prefix.len().min(key.len()), |((i, _), _)| i);
+        prefix = &prefix[..len];
+    }
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_s3::{self, config, upload_key, Request, Response};
+
+    // Lists `uploads` as `(key, upload id, initiated)`, and aborts any.
+    fn list_uploads(uploads: Vec<(&str, &str, String)>) -> impl Fn(&Request) -> Response {
+        let uploads = uploads
+            .iter()
+            .map(|(key, upload_id, initiated)| {
+                format!(
+                    "<Upload><Key>{}</Key><UploadId>{}</UploadId><Initiated>{}</Initiated></Upload>",
+                    key, upload_id, initiated
+                )
+            })
+            .collect::<String>();
+        let listing = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+             <ListMultipartUploadsResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+             <Bucket>bucket</Bucket><IsTruncated>false</IsTruncated>{}\
+             </ListMultipartUploadsResult>",
+            uploads
+        );
+        move |request| match request.method() {
+            "GET" => Response::ok(listing.clone()),
+            _ => Response::status(204),
+        }
+    }
+
+    fn track(checkpointer: &mut Checkpointer, object_key: &str, upload_id: &str) -> UploadKey {
+        let upload_key = upload_key(&format!("/var/log/{}", object_key), object_key);
+        checkpointer.update_session(
+            upload_key.clone(),
+            UploadSession {
+                session_uri: upload_id.to_owned(),
+                committed_bytes: 0,
+                modified_at: Utc::now(),
+                expire_at: Utc::now() + chrono::Duration::days(7),
+            },
+        );
+        upload_key
+    }
+
+    #[tokio::test]
+    async fn abort_old_tracked_uploads() {
+        let old = "2020-01-01T00:00:00.000Z".to_owned();
+        let young = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+        let (endpoint, requests) = mock_s3::serve(list_uploads(vec![
+            ("logs/tidb.log", "orphan", old.clone()),
+            ("logs/tikv.log", "young", young),
+            ("logs/pd.log", "someone-elses", old),
+        ]))
+        .await;
+        let client = mock_s3::client(&config(&endpoint, "")).await;
+
+        let data_dir = mock_s3::data_dir("orphan-multiparts");
+        let mut checkpointer = Checkpointer::new(data_dir.clone(), Default::default()).unwrap();
+        let orphan = track(&mut checkpointer, "logs/tidb.log", "orphan");
+        let young = track(&mut checkpointer, "logs/tikv.log", "young");
+        let completed = track(&mut checkpointer, "logs/tiflash.log", "completed");
+
+        abort_orphan_multiparts(
+            &client,
+            "bucket",
+            &mut checkpointer,
+            Duration::from_secs(24 * 60 * 60),
+        )
+        .await;
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].method(), "GET");
+        assert!(requests[0].line.contains("uploads"));
+        assert!(requests[0].line.contains("prefix=logs"));
+        assert_eq!(requests[1].method(), "DELETE");
+        assert!(requests[1].line.contains("logs/tidb.log"));
+        assert!(requests[1].line.contains("uploadId=orphan"));
+
+        assert!(checkpointer.session(&orphan).is_none());
+        assert!(checkpointer.session(&young).is_some());
+        assert!(checkpointer.session(&completed).is_none());
+
+        drop(checkpointer);
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn shared_prefix() {
+        assert_eq!(common_prefix(["logs/a.log"].into_iter()), "logs/a.log");
+        assert_eq!(
+            common_prefix(["logs/tidb.log", "logs/tikv.log"].into_iter()),
+            "logs/ti"
+        );
+        assert_eq!(common_prefix(["logs/a.log", "logs"].into_iter()), "logs");
+        assert_eq!(common_prefix(["a", "b"].into_iter()), "");
+        assert_eq!(common_prefix(["日志/a", "日本/b"].into_iter()), "日");
+    }
+}
@@ -0,0 +1,184 @@
+use std::future::Future;
+use std::time::Duration;
+
+use aws_sdk_s3::types::SdkError;
+use aws_sdk_s3::Client as S3Client;
+use hyper::{Body, Request, Uri};
+use tokio::net::{lookup_host, TcpStream};
+use vector::http::HttpClient;
+use vector::tls::{TlsConfig, TlsSettings};
+use vector_core::config::proxy::ProxyConfig;
+
+// short enough not to hold up a healthy startup noticeably
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Checks that `endpoint` resolves and accepts connections and TLS handshakes,
+/// failing with an error telling which of them went wrong.
+///
+/// Only direct connections are probed, as a proxy may be the only one able to
+/// resolve and reach the endpoint.
+pub async fn probe_endpoint(
+    endpoint: &Uri,
+    tls: &Option<TlsConfig>,
+    proxy: &ProxyConfig,
+) -> vector::Result<()> {
+    if proxy.enabled && (proxy.http.is_some() || proxy.https.is_some()) {
+        return Ok(());
+    }
+
+    let host = endpoint
+        .host()
+        .ok_or_else(|| format!("Endpoint {} has no host.", endpoint))?;
+    let https = endpoint.scheme_str() != Some("http");
+    let port = endpoint.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+    let addrs = timeout(lookup_host((host, port)))
+        .await
+        .map_err(|error| format!("DNS resolution of {} failed: {}", host, error))?;
+    let addr = addrs
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("DNS resolution of {} failed: no addresses", host))?;
+
+    timeout(TcpStream::connect(addr))
+        .await
+        .map_err(|error| format!("Failed to connect to {} ({}): {}", host, addr, error))?;
+    if !https {
+        return Ok(());
+    }
+
+    // The connection went through, so a failing request is down to the TLS
+    // handshake. Any response, even an error status, means it succeeded.
+    let client = HttpClient::new(TlsSettings::from_options(tls)?, proxy)?;
+    let request = Request::head(endpoint).body(Body::empty())?;
+    timeout(client.send(request))
+        .await
+        .map_err(|error| format!("TLS handshake with {} failed: {}", host, error))?;
+
+    Ok(())
+}
+
+/// Checks that the credentials are accepted for `bucket`. Other failures are
+/// left to the healthcheck.
+///
+/// Lists no objects of the bucket, as unlike `HeadBucket` its errors carry a
+/// code telling bad credentials, which S3 answers with a 403 as well, apart
+/// from denied access. Only the former fail the probe. Denied access is merely
+/// warned about, as listing needs `s3:ListBucket`, which credentials allowed
+/// to upload may well lack.
+pub async fn probe_auth(client: &S3Client, bucket: &str) -> vector::Result<()> {
+    let list = client.list_objects_v2().bucket(bucket).max_keys(0).send();
+    match tokio::time::timeout(PROBE_TIMEOUT, list).await {
+        Ok(Err(SdkError::ServiceError { err, raw })) => match err.code() {
+            Some(
+                code @ ("InvalidAccessKeyId"
+                | "SignatureDoesNotMatch"
+                | "InvalidToken"
+                | "ExpiredToken"),
+            ) => Err(format!(
+                "Authentication to bucket {} failed with {} ({}), check the credentials.",
+                bucket,
+                code,
+                raw.http().status()
+            )
+            .into()),
+            Some("AccessDenied") => {
+                warn!(
+                    message = "Access to bucket denied, uploads fail unless the credentials are allowed to put objects into it.",
+                    %bucket,
+                    status = %raw.http().status()
+                );
+                Ok(())
+            }
+            _ => Ok(()),
+        },
+        Ok(Err(SdkError::ConstructionFailure(error))) => {
+            Err(format!("Failed to sign requests to bucket {}: {}", bucket, error).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn timeout<T, E>(future: impl Future<Output = Result<T, E>>) -> Result<T, String>
+where
+    E: std::fmt::Display,
+{
+    match tokio::time::timeout(PROBE_TIMEOUT, future).await {
+        Ok(result) => result.map_err(|error| error.to_string()),
+        Err(_) => Err(format!("timed out after {:?}", PROBE_TIMEOUT)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::mock_s3::{self, config, Request, Response};
+
+    async fn probe(endpoint: &str) -> String {
+        let endpoint = endpoint.parse::<Uri>().unwrap();
+        probe_endpoint(&endpoint, &None, &ProxyConfig::default())
+            .await
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn probe_endpoint_failures() {
+        let error = probe("https://bucket.nonexistent.invalid").await;
+        assert!(error.starts_with("DNS resolution"), "{}", error);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let error = probe(&format!("https://{}", address)).await;
+        assert!(error.starts_with("Failed to connect"), "{}", error);
+
+        // accepts connections, but closes them right away
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let _ = listener.accept().await;
+            }
+        });
+        let error = probe(&format!("https://{}", address)).await;
+        assert!(error.starts_with("TLS handshake"), "{}", error);
+        // plain http stops at the connection
+        let endpoint = format!("http://{}", address).parse::<Uri>().unwrap();
+        assert!(probe_endpoint(&endpoint, &None, &ProxyConfig::default())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn probe_auth_errors() {
+        let error = |status, code: &str| {
+            let body = format!("<Error><Code>{}</Code><Message></Message></Error>", code);
+            move |_: &Request| Response::status(status).body(body.clone())
+        };
+        for (respond, accepted) in [
+            (error(403, "InvalidAccessKeyId"), false),
+            (error(403, "SignatureDoesNotMatch"), false),
+            (error(400, "ExpiredToken"), false),
+            (error(403, "AccessDenied"), true),
+            (error(404, "NoSuchBucket"), true),
+        ] {
+            let (endpoint, requests) = mock_s3::serve(respond).await;
+            let client = mock_s3::client(&config(&endpoint, "")).await;
+            let result = probe_auth(&client, "bucket").await;
+            assert_eq!(result.is_ok(), accepted, "{:?}", result);
+
+            let request = requests.lock().unwrap()[0].clone();
+            assert_eq!(request.method(), "GET");
+            assert!(request.line.contains("list-type=2"), "{}", request.line);
+            assert!(request.line.contains("max-keys=0"), "{}", request.line);
+        }
+
+        let (endpoint, _) =
+            mock_s3::serve(|_| Response::ok("<ListBucketResult></ListBucketResult>")).await;
+        let client = mock_s3::client(&config(&endpoint, "")).await;
+        assert!(probe_auth(&client, "bucket").await.is_ok());
+    }
+}
@@ -1,51 +1,63 @@
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::io;
+use std::path::PathBuf;
 use std::time::{Duration, SystemTime};
 
-use common::checkpointer::{Checkpointer, UploadKey};
+use aws_sdk_s3::model::StorageClass;
+use common::checkpointer::{CheckpointMetadata, Checkpointer, KeyNormalization, UploadKey};
+use common::internal_events::{FileUploaded, UploadCheckpointExpired, UploadSkipped};
+use common::object_url::ObjectUrl;
 use futures::stream::BoxStream;
 use futures_util::StreamExt;
+use tokio::time::Interval;
 use tokio_util::time::DelayQueue;
 use vector::emit;
-use vector::event::Finalizable;
-use vector::sinks::s3_common::config::S3Options;
-use vector::sinks::s3_common::service::S3Service;
+use vector::event::{EventFinalizers, Finalizable};
 use vector_core::event::{Event, EventStatus};
 use vector_core::internal_event::EventsSent;
 use vector_core::sink::StreamSink;
 
-use crate::uploader::S3Uploader;
+use crate::key_prefix::KeyPrefix;
+use crate::manifest::ManifestWriter;
+use crate::uploader::{validate_bucket_name, S3Uploader};
 
 pub struct S3UploadFileSink {
-    pub service: S3Service,
+    uploader: S3Uploader,
+    checkpointer: Checkpointer,
+    options: SinkOptions,
+}
+
+/// How `S3UploadFileSink` turns events into uploads, see the options of the
+/// same names on `S3UploadFileConfig`.
+pub struct SinkOptions {
     pub bucket: String,
-    pub options: S3Options,
-    pub delay_upload: Duration,
+    pub bucket_field: Option<String>,
+    pub base_dir: Option<PathBuf>,
+    pub key_normalization: KeyNormalization,
+    pub key_prefix: Option<KeyPrefix>,
+    pub manifest: Option<ManifestWriter>,
+    pub object_url: ObjectUrl,
+    pub allow_empty_marker: bool,
+    pub delay_upload: UploadDelay,
     pub expire_after: Duration,
-    pub checkpointer: Checkpointer,
+    pub max_pending_uploads: usize,
+    /// How often the checkpoints are written while idle, only along with
+    /// uploads if not set.
+    pub checkpoint_flush_interval: Option<Duration>,
 }
 
 impl S3UploadFileSink {
-    pub fn new(
-        bucket: String,
-        options: S3Options,
-        delay_upload: Duration,
-        expire_after: Duration,
-        service: S3Service,
-        checkpointer: Checkpointer,
-    ) -> Self {
+    pub fn new(uploader: S3Uploader, checkpointer: Checkpointer, options: SinkOptions) -> Self {
         Self {
-            bucket,
-            options,
-            delay_upload,
-            expire_after,
-            service,
+            uploader,
             checkpointer,
+            options,
         }
     }
 
-    async fn file_modified_time(filename: &str) -> io::Result<SystemTime> {
-        tokio::fs::metadata(filename).await?.modified()
+    async fn file_modified_time_and_size(filename: &str) -> io::Result<(SystemTime, u64)> {
+        let metadata = tokio::fs::metadata(filename).await?;
+        Ok((metadata.modified()?, metadata.len()))
     }
 }
 
@@ -53,21 +65,52 @@ impl S3UploadFileSink {
 impl StreamSink<Event> for S3UploadFileSink {
     async fn run(self: Box<Self>, mut input: BoxStream<'_, Event>) -> Result<(), ()> {
         let Self {
-            service,
-            bucket,
-            options,
-            delay_upload,
-            expire_after,
+            mut uploader,
             mut checkpointer,
+            options:
+                SinkOptions {
+                    bucket,
+                    bucket_field,
+                    base_dir,
+                    key_normalization,
+                    key_prefix,
+                    manifest,
+                    object_url,
+                    allow_empty_marker,
+                    delay_upload,
+                    expire_after,
+                    max_pending_uploads,
+                    checkpoint_flush_interval,
+                },
         } = *self;
 
+        if let Err(error) = checkpointer.lock().await {
+            error!(message = "Failed to lock the data dir.", %error);
+            return Err(());
+        }
+        checkpointer.read_checkpoints();
+
+        // before any upload, which may replace the tracked upload of its key,
+        // in every bucket uploaded to before as events may override it
+        let buckets = checkpointer
+            .sessions()
+            .map(|(upload_key, _)| upload_key.bucket.clone())
+            .collect::<BTreeSet<_>>();
+        for bucket in buckets {
+            uploader
+                .abort_orphan_multiparts(&bucket, &mut checkpointer)
+                .await;
+        }
+
         let mut delay_queue = DelayQueue::new();
         let mut pending_uploads = HashSet::new();
-        let mut uploader = S3Uploader::new(service.client(), options);
+        let mut backpressure = Backpressure::new(max_pending_uploads);
+        let mut checkpoint_flush = checkpoint_flush_interval
+            .map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
 
         loop {
             tokio::select! {
-                event = input.next() => {
+                event = input.next(), if backpressure.accepts(pending_uploads.len()) => {
                     let mut event = if let Some(event) = event {
                         event
                     } else {
@@ -75,9 +118,92 @@ impl StreamSink<Event> for S3UploadFileSink {
                     };
 
                     let finalizers = event.take_finalizers();
-                    if let Some(upload_key) = UploadKey::from_event(&event, &bucket) {
-                        let modified_time = match Self::file_modified_time(&upload_key.filename).await {
-                            Ok(modified_time) => modified_time,
+                    let marker = if allow_empty_marker {
+                        UploadKey::marker_from_event(&event, &bucket, bucket_field.as_deref())
+                    } else {
+                        None
+                    };
+                    let is_marker = marker.is_some();
+                    if let Some(mut upload_key) = marker.or_else(|| UploadKey::from_event_with_fields(&event, &bucket, bucket_field.as_deref(), base_dir.as_deref(), Some(&key_normalization))) {
+                        if upload_key.bucket != bucket {
+                            if let Err(error) = validate_bucket_name(&upload_key.bucket) {
+                                finalizers.update_status(EventStatus::Rejected);
+                                error!(message = "Invalid bucket.", %error, filename = %upload_key.filename);
+                                continue;
+                            }
+                        }
+                        if let Some(key_prefix) = &key_prefix {
+                            match key_prefix.render(&event) {
+                                Ok(prefix) => upload_key.object_key.insert_str(0, &prefix),
+                                Err(error) => {
+                                    finalizers.update_status(EventStatus::Rejected);
+                                    error!(message = "Failed to render key prefix.", %error, filename = %upload_key.filename);
+                                    continue;
+                                }
+                            }
+                        }
+                        if !is_marker {
+                            upload_key.object_key.push_str(uploader.compress().extension());
+                        } else if !upload_key.object_key.ends_with('/') {
+                            finalizers.update_status(EventStatus::Rejected);
+                            error!(message = "Marker key must end with '/'.", key = %upload_key.object_key);
+                            continue;
+                        }
+                        let storage_class = match S3Uploader::storage_class_from_event(&event) {
+                            Ok(storage_class) => storage_class,
+                            Err(error) => {
+                                finalizers.update_status(EventStatus::Rejected);
+                                error!(message = "Invalid storage class.", %error, filename = %upload_key.filename);
+                                continue;
+                            }
+                        };
+                        let metadata = match uploader.metadata_from_event(&event) {
+                            Ok(metadata) => metadata,
+                            Err(error) => {
+                                finalizers.update_status(EventStatus::Rejected);
+                                error!(message = "Invalid metadata.", %error, filename = %upload_key.filename);
+                                continue;
+                            }
+                        };
+                        if is_marker {
+                            match uploader.put_marker(&upload_key, storage_class, metadata).await {
+                                Ok(response) => {
+                                    if response.count > 0 {
+                                        info!(
+                                            message = "Created directory marker.",
+                                            bucket = %upload_key.bucket,
+                                            key = %upload_key.object_key,
+                                        );
+                                    }
+                                    finalizers.update_status(EventStatus::Delivered);
+                                    emit!(EventsSent {
+                                        count: response.count,
+                                        byte_size: response.events_byte_size,
+                                        output: None,
+                                    });
+                                }
+                                Err(error) => {
+                                    error!(
+                                        message = "Failed to create directory marker.",
+                                        %error,
+                                        bucket = %upload_key.bucket,
+                                        key = %upload_key.object_key,
+                                    );
+                                    finalizers.update_status(EventStatus::Rejected);
+                                }
+                            }
+                            continue;
+                        }
+                        let manifest_key = match manifest.as_ref().map(|manifest| manifest.render_key(&event)).transpose() {
+                            Ok(manifest_key) => manifest_key,
+                            Err(error) => {
+                                finalizers.update_status(EventStatus::Rejected);
+                                error!(message = "Failed to render manifest key.", %error, filename = %upload_key.filename);
+                                continue;
+                            }
+                        };
+                        let (modified_time, size) = match Self::file_modified_time_and_size(&upload_key.filename).await {
+                            Ok(modified_time_and_size) => modified_time_and_size,
                             Err(err) => {
                                 finalizers.update_status(EventStatus::Rejected);
                                 error!(message = "Failed to get file modified time.", %err);
@@ -85,11 +211,29 @@ impl StreamSink<Event> for S3UploadFileSink {
                             }
                         };
 
-                        if !checkpointer.contains(&upload_key, modified_time) && !pending_uploads.contains(&upload_key) {
-                            delay_queue.insert((upload_key.clone(), finalizers), delay_upload);
-                            pending_uploads.insert(upload_key);
+                        let skip_reason = if uploader.dedup().checks_checkpoint()
+                            && checkpointer.contains(&upload_key, modified_time)
+                        {
+                            Some("checkpoint_hit")
+                        } else if pending_uploads.contains(&upload_key) {
+                            Some("pending")
                         } else {
+                            None
+                        };
+                        if let Some(reason) = skip_reason {
+                            emit!(UploadSkipped { reason, filename: &upload_key.filename });
                             finalizers.update_status(EventStatus::Delivered);
+                        } else {
+                            let pending_upload = PendingUpload {
+                                upload_key: upload_key.clone(),
+                                modified_time,
+                                storage_class,
+                                metadata,
+                                manifest_key,
+                                finalizers,
+                            };
+                            delay_queue.insert(pending_upload, delay_upload.for_size(size));
+                            pending_uploads.insert(upload_key);
                         }
                     } else {
                         finalizers.update_status(EventStatus::Rejected);
@@ -97,7 +241,14 @@ impl StreamSink<Event> for S3UploadFileSink {
                 }
 
                 entry = delay_queue.next(), if !delay_queue.is_empty() => {
-                    let (upload_key, finalizers) = if let Some(entry) = entry {
+                    let PendingUpload {
+                        upload_key,
+                        modified_time,
+                        storage_class,
+                        metadata,
+                        manifest_key,
+                        finalizers,
+                    } = if let Some(entry) = entry {
                         entry.into_inner()
                     } else {
                         // DelayQueue returns None if the queue is exhausted,
@@ -107,17 +258,35 @@ impl StreamSink<Event> for S3UploadFileSink {
                     };
                     pending_uploads.remove(&upload_key);
 
+                    if uploader.dedup().checks_checkpoint()
+                        && checkpointer.contains_expired(&upload_key, modified_time)
+                    {
+                        emit!(UploadCheckpointExpired { filename: &upload_key.filename });
+                    }
+
                     let upload_time = SystemTime::now();
-                    match uploader.upload(&upload_key).await {
+                    match uploader.upload(&upload_key, storage_class, metadata, &mut checkpointer).await {
                         Ok(response) => {
                             if response.count > 0 {
-                                info!(
-                                    message = "Uploaded file.",
-                                    filename = %upload_key.filename,
-                                    bucket = %upload_key.bucket,
-                                    key = %upload_key.object_key,
-                                    size = %response.events_byte_size,
-                                );
+                                emit!(FileUploaded {
+                                    filename: &upload_key.filename,
+                                    bucket: &upload_key.bucket,
+                                    key: &upload_key.object_key,
+                                    url: &object_url.of(&upload_key.bucket, &upload_key.object_key),
+                                    size: response.events_byte_size,
+                                });
+                                if let (Some(manifest), Some(manifest_key)) = (&manifest, &manifest_key) {
+                                    // the object is in place, so a failed manifest update
+                                    // doesn't fail the upload
+                                    if let Err(error) = manifest.append(&upload_key, manifest_key).await {
+                                        error!(
+                                            message = "Failed to append to manifest.",
+                                            %error,
+                                            key = %upload_key.object_key,
+                                            manifest = %manifest_key,
+                                        );
+                                    }
+                                }
                             }
                             finalizers.update_status(EventStatus::Delivered);
                             emit!(EventsSent {
@@ -125,7 +294,13 @@ impl StreamSink<Event> for S3UploadFileSink {
                                 byte_size: response.events_byte_size,
                                 output: None,
                             });
-                            checkpointer.update(upload_key, upload_time, expire_after);
+                            checkpointer.update(upload_key.clone(), upload_time, modified_time, expire_after);
+                            if response.count > 0 {
+                                checkpointer.update_metadata(&upload_key, CheckpointMetadata {
+                                    file_size: Some(response.events_byte_size as u64),
+                                    upload_duration_ms: upload_time.elapsed().ok().map(|elapsed| elapsed.as_millis() as u64),
+                                });
+                            }
                         }
                         Err(error) => {
                             error!(
@@ -138,10 +313,12 @@ impl StreamSink<Event> for S3UploadFileSink {
                             finalizers.update_status(EventStatus::Rejected);
                         }
                     }
-                    match checkpointer.write_checkpoints() {
-                        Ok(count) => trace!(message = "Checkpoints written", %count),
-                        Err(error) => error!(message = "Failed to write checkpoints.", %error),
-                    }
+                    write_checkpoints(&mut checkpointer);
+                }
+
+                _ = tick(&mut checkpoint_flush), if checkpoint_flush.is_some() => {
+                    // persists checkpoints expired while idle
+                    write_checkpoints(&mut checkpointer);
                 }
             }
         }
@@ -149,3 +326,180 @@ impl StreamSink<Event> for S3UploadFileSink {
         Ok(())
     }
 }
+
+fn write_checkpoints(checkpointer: &mut Checkpointer) {
+    match checkpointer.write_checkpoints() {
+        Ok(count) => trace!(message = "Checkpoints written", %count),
+        Err(error) => error!(message = "Failed to write checkpoints.", %error),
+    }
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    if let Some(interval) = interval {
+        interval.tick().await;
+    }
+}
+
+struct PendingUpload {
+    upload_key: UploadKey,
+    modified_time: SystemTime,
+    storage_class: Option<StorageClass>,
+    metadata: Option<HashMap<String, String>>,
+    manifest_key: Option<String>,
+    finalizers: EventFinalizers,
+}
+
+/// The delay before uploading a file, growing with its size as larger files
+/// take longer to finish being written: `base` plus `per_mib` for every MiB,
+/// capped at `max`.
+#[derive(Clone, Copy, Debug)]
+pub struct UploadDelay {
+    base: Duration,
+    per_mib: Duration,
+    max: Duration,
+}
+
+impl UploadDelay {
+    pub fn new(base: Duration, per_mib: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            per_mib,
+            max: max.max(base),
+        }
+    }
+
+    fn for_size(&self, size: u64) -> Duration {
+        let mib = size as f64 / (1024.0 * 1024.0);
+        let delay = self.base.as_secs_f64() + self.per_mib.as_secs_f64() * mib;
+        if delay >= self.max.as_secs_f64() {
+            self.max
+        } else {
+            Duration::from_secs_f64(delay)
+        }
+    }
+}
+
+/// Stops accepting upload events once `max` uploads are pending, and resumes
+/// only after they drain to half of it, so a flood of events can't grow the
+/// delay queue without bound when uploads can't keep up.
+struct Backpressure {
+    max: usize,
+    low_water: usize,
+    paused: bool,
+}
+
+impl Backpressure {
+    fn new(max: usize) -> Self {
+        let max = max.max(1);
+        Self {
+            max,
+            low_water: max / 2,
+            paused: false,
+        }
+    }
+
+    fn accepts(&mut self, pending: usize) -> bool {
+        if self.paused {
+            self.paused = pending > self.low_water;
+        } else {
+            self.paused = pending >= self.max;
+        }
+        !self.paused
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_s3::{self, config, upload_key};
+
+    #[test]
+    fn upload_delay_grows_with_size() {
+        const MIB: u64 = 1024 * 1024;
+        let delay = UploadDelay::new(
+            Duration::from_secs(10),
+            Duration::from_secs(1),
+            Duration::from_secs(60),
+        );
+
+        assert_eq!(delay.for_size(0), Duration::from_secs(10));
+        assert_eq!(delay.for_size(MIB / 2), Duration::from_millis(10_500));
+        assert_eq!(delay.for_size(20 * MIB), Duration::from_secs(30));
+        assert!(delay.for_size(20 * MIB + 1) > delay.for_size(20 * MIB));
+        // capped
+        assert_eq!(delay.for_size(1000 * MIB), Duration::from_secs(60));
+        assert_eq!(delay.for_size(u64::MAX), Duration::from_secs(60));
+
+        let fixed = UploadDelay::new(Duration::from_secs(10), Duration::ZERO, Duration::ZERO);
+        assert_eq!(fixed.for_size(1000 * MIB), Duration::from_secs(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flush_checkpoints_while_idle() {
+        let data_dir = mock_s3::data_dir("processor-flush");
+        let mut checkpointer = Checkpointer::new(data_dir.clone(), Default::default()).unwrap();
+        // updated, but not yet written
+        checkpointer.update(
+            upload_key("/idle/file", "key"),
+            SystemTime::now(),
+            SystemTime::now(),
+            Duration::from_secs(3600),
+        );
+
+        // never sent a request
+        let config = config("http://127.0.0.1:1", "");
+        let client = mock_s3::client(&config).await;
+        let options = SinkOptions {
+            checkpoint_flush_interval: Some(Duration::from_millis(50)),
+            ..config.sink_options(client).unwrap()
+        };
+        let sink = S3UploadFileSink::new(mock_s3::uploader(&config).await, checkpointer, options);
+        // an input that never sends an event
+        let sink =
+            tokio::spawn(
+                async move { Box::new(sink).run(futures::stream::pending().boxed()).await },
+            );
+
+        // on the paused clock, which skips straight to the first flush
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let written = std::fs::read_to_string(data_dir.join("checkpoints.json")).unwrap();
+        assert!(written.contains("/idle/file"));
+
+        sink.abort();
+        let _ = sink.await;
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn backpressure_bounds_pending_uploads() {
+        let mut backpressure = Backpressure::new(10);
+        let mut pending = 0;
+        let mut max_seen = 0;
+
+        // Events keep arriving, while only one upload finishes every 3 rounds.
+        for round in 0..1000 {
+            if backpressure.accepts(pending) {
+                pending += 1;
+            }
+            if round % 3 == 0 && pending > 0 {
+                pending -= 1;
+            }
+            max_seen = max_seen.max(pending);
+        }
+        assert_eq!(max_seen, 10);
+    }
+
+    #[test]
+    fn backpressure_resumes_below_low_water() {
+        let mut backpressure = Backpressure::new(10);
+        assert!(backpressure.accepts(9));
+        assert!(!backpressure.accepts(10));
+        assert!(!backpressure.accepts(6));
+        assert!(backpressure.accepts(5));
+        assert!(backpressure.accepts(9));
+
+        let mut backpressure = Backpressure::new(0);
+        assert!(!backpressure.accepts(1));
+        assert!(backpressure.accepts(0));
+    }
+}
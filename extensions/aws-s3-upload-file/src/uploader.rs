@@ -1,23 +1,96 @@
+use std::collections::HashMap;
 use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
 
-use aws_sdk_s3::model::{CompletedMultipartUpload, CompletedPart};
-use aws_sdk_s3::types::ByteStream;
+use aws_sdk_s3::client::fluent_builders::PutObject;
+use aws_sdk_s3::model::{CompletedMultipartUpload, CompletedPart, ObjectAttributes, StorageClass};
+use aws_sdk_s3::types::{ByteStream, SdkError};
 use aws_sdk_s3::Client as S3Client;
-use common::checkpointer::UploadKey;
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use common::checkpointer::{Checkpointer, UploadKey, UploadSession};
+use common::internal_events::UploadSkipped;
+use serde::{Deserialize, Serialize};
+use vector::emit;
 use vector::sinks::s3_common::config::S3Options;
+use vector_core::event::Event;
 
+use crate::compress::Compress;
 use crate::etag_calculator::EtagCalculator;
+use crate::file_region::FileRegion;
+use crate::head_cache::HeadCache;
+use crate::orphan_multiparts::{abort_orphan_multiparts, TRACK_GRACE_PERIOD};
 
 // limit the chunk size to 8MB to avoid OOM
 const S3_MULTIPART_UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
 const S3_MULTIPART_UPLOAD_MAX_CHUNKS: usize = 10000;
+// the step automatic chunk sizes grow in
+const S3_MULTIPART_UPLOAD_CHUNK_SIZE_STEP: u64 = 1024 * 1024;
+// counted over the UTF-8 bytes of every key and value
+const S3_USER_METADATA_MAX_BYTES: usize = 2 * 1024;
+
+/// How a file that was uploaded before is told apart from a new one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dedup {
+    /// Skip files checkpointed with the same mtime, and files whose content
+    /// matches the etag of the existing object. Costs a `HEAD` and a full read
+    /// of the file to compute its etag whenever the object exists.
+    Etag,
+    /// Skip files checkpointed with the same mtime only. Once the checkpoint
+    /// expires, or if it's lost with the data dir, the file is uploaded again
+    /// even if the object is identical, so it only suits files written once.
+    CheckpointOnly,
+    /// Never skip, every upload event uploads the file, re-uploading identical
+    /// content as often as the event is seen.
+    Always,
+}
+
+impl Default for Dedup {
+    fn default() -> Self {
+        Dedup::Etag
+    }
+}
+
+impl Dedup {
+    pub fn checks_checkpoint(self) -> bool {
+        self != Dedup::Always
+    }
+
+    fn checks_etag(self) -> bool {
+        self == Dedup::Etag
+    }
+}
 
 pub struct S3Uploader {
     client: S3Client,
     options: S3Options,
+    overwrite: bool,
+    dedup: Dedup,
+    compress: Compress,
+    metadata: HashMap<String, String>,
+    orphan_multipart_age: Option<Duration>,
+    auto_part_size: bool,
     etag_calculator: EtagCalculator,
+    head_cache: Option<HeadCache>,
+    bucket_key_enabled: bool,
+    verify_object: bool,
+}
+
+/// How `S3Uploader` uploads files, see the options of the same names on
+/// `S3UploadFileConfig`.
+#[derive(Clone, Debug)]
+pub struct UploaderOptions {
+    pub overwrite: bool,
+    pub dedup: Dedup,
+    pub compress: Compress,
+    pub metadata: HashMap<String, String>,
+    /// Multipart uploads are tracked, to be aborted once this old, if set.
+    pub orphan_multipart_age: Option<Duration>,
+    pub auto_part_size: bool,
+    /// How long the `HEAD` of an object is cached, not at all if not set.
+    pub head_cache_ttl: Option<Duration>,
+    pub bucket_key_enabled: bool,
+    pub verify_object: bool,
 }
 
 pub struct UploadResponse {
@@ -26,42 +99,179 @@ pub struct UploadResponse {
 }
 
 impl S3Uploader {
-    pub fn new(client: S3Client, options: S3Options) -> Self {
+    pub fn new(client: S3Client, options: S3Options, uploader_options: UploaderOptions) -> Self {
+        let UploaderOptions {
+            overwrite,
+            dedup,
+            compress,
+            metadata,
+            orphan_multipart_age,
+            auto_part_size,
+            head_cache_ttl,
+            bucket_key_enabled,
+            verify_object,
+        } = uploader_options;
         Self {
             client,
             options,
-            etag_calculator: EtagCalculator::new(
-                S3_MULTIPART_UPLOAD_CHUNK_SIZE,
-                S3_MULTIPART_UPLOAD_MAX_CHUNKS,
-            ),
+            overwrite,
+            dedup,
+            compress,
+            metadata,
+            orphan_multipart_age,
+            auto_part_size,
+            etag_calculator: EtagCalculator::new(S3_MULTIPART_UPLOAD_MAX_CHUNKS),
+            head_cache: head_cache_ttl.map(HeadCache::new),
+            bucket_key_enabled,
+            verify_object,
         }
     }
 
-    pub async fn upload(&mut self, upload_key: &UploadKey) -> io::Result<UploadResponse> {
-        Ok(if self.need_upload(upload_key).await? {
-            UploadResponse {
-                count: 1,
-                events_byte_size: self.do_upload(upload_key).await?,
+    /// Reads the `storage_class` field off the event, which overrides the
+    /// storage class configured for the sink. Values not known to S3 are
+    /// rejected.
+    pub fn storage_class_from_event(event: &Event) -> Result<Option<StorageClass>, String> {
+        let value = match event
+            .maybe_as_log()
+            .and_then(|log| log.get("storage_class"))
+        {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let value = value
+            .as_bytes()
+            .ok_or_else(|| "storage_class must be a string".to_owned())?;
+        match StorageClass::from(String::from_utf8_lossy(value).as_ref()) {
+            StorageClass::Unknown(class) => Err(format!("unknown storage_class {:?}", class)),
+            class => Ok(Some(class)),
+        }
+    }
+
+    /// The user metadata of the upload carried by `event`, the `metadata`
+    /// object of the event merged over the configured metadata.
+    pub fn metadata_from_event(
+        &self,
+        event: &Event,
+    ) -> Result<Option<HashMap<String, String>>, String> {
+        merge_metadata(&self.metadata, event)
+    }
+
+    /// Uploads the file of `upload_key`. With `orphan_multipart_age`, multipart
+    /// uploads are tracked in `checkpointer` while in progress, so the ones
+    /// interrupted by a crash can be aborted on a later startup.
+    pub async fn upload(
+        &mut self,
+        upload_key: &UploadKey,
+        storage_class: Option<StorageClass>,
+        metadata: Option<HashMap<String, String>>,
+        checkpointer: &mut Checkpointer,
+    ) -> io::Result<UploadResponse> {
+        let storage_class = storage_class.or_else(|| self.options.storage_class.map(Into::into));
+        // The existing object is looked up before compressing, so files left
+        // alone aren't compressed for nothing.
+        let object_etag = self.existing_object_etag(upload_key).await?;
+        if object_etag.is_some() && !self.overwrite {
+            return Ok(skip_upload(upload_key, "object_exists"));
+        }
+        let compressed = self
+            .compress
+            .compress_file(Path::new(&upload_key.filename))
+            .await?;
+        let path = compressed
+            .as_ref()
+            .map_or_else(|| Path::new(&upload_key.filename), |file| file.path());
+        // etags are compared against the compressed file, as that's what the
+        // object holds
+        if let Some(object_etag) = object_etag {
+            if self.file_etag(path).await? == object_etag {
+                return Ok(skip_upload(upload_key, "etag_match"));
             }
-        } else {
-            UploadResponse {
-                count: 0,
-                events_byte_size: 0,
+        }
+
+        let result = self
+            .do_upload(upload_key, path, storage_class, metadata, checkpointer)
+            .await;
+        // the object may have changed even if the upload failed
+        if let Some(head_cache) = &mut self.head_cache {
+            head_cache.invalidate(upload_key);
+        }
+        match result {
+            Ok(size) => Ok(UploadResponse {
+                count: 1,
+                events_byte_size: size,
+            }),
+            // the object was created after the existence check
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {
+                Ok(skip_upload(upload_key, "object_exists"))
             }
-        })
+            Err(error) => Err(error),
+        }
     }
 
-    async fn need_upload(&mut self, upload_key: &UploadKey) -> io::Result<bool> {
-        if let Some(object_etag) = self.fetch_object_etag(upload_key).await {
-            let etag = self.etag_calculator.file(&upload_key.filename).await?;
-            if etag == object_etag {
-                return Ok(false);
-            }
+    /// Aborts the multipart uploads of `bucket` this agent left behind, if
+    /// `orphan_multipart_age` is set.
+    pub async fn abort_orphan_multiparts(&self, bucket: &str, checkpointer: &mut Checkpointer) {
+        if let Some(max_age) = self.orphan_multipart_age {
+            abort_orphan_multiparts(&self.client, bucket, checkpointer, max_age).await;
         }
-        Ok(true)
     }
 
-    async fn fetch_object_etag(&self, upload_key: &UploadKey) -> Option<String> {
+    pub const fn dedup(&self) -> Dedup {
+        self.dedup
+    }
+
+    pub const fn compress(&self) -> Compress {
+        self.compress
+    }
+
+    fn content_encoding(&self) -> Option<String> {
+        match self.compress.content_encoding() {
+            Some(content_encoding) => Some(content_encoding.to_owned()),
+            None => self.options.content_encoding.clone(),
+        }
+    }
+
+    /// The etag of the object at `upload_key`, if it exists and has a say in
+    /// whether the file is uploaded.
+    async fn existing_object_etag(&mut self, upload_key: &UploadKey) -> io::Result<Option<String>> {
+        // Without overwrite, an existing object is still left alone by the
+        // conditional put or complete. Multipart uploads are checked for an
+        // existing object all the same, rather than uploading every part only
+        // to have the complete refused, and so are files to compress, whose
+        // size isn't known until they're compressed.
+        if !self.dedup.checks_etag()
+            && (self.overwrite
+                || (self.compress == Compress::None
+                    && !is_multipart(tokio::fs::metadata(&upload_key.filename).await?.len())))
+        {
+            return Ok(None);
+        }
+        Ok(self.fetch_object_etag(upload_key).await)
+    }
+
+    // the etag `path` gets once uploaded, which depends on its part size
+    async fn file_etag(&mut self, path: &Path) -> io::Result<String> {
+        let size = tokio::fs::metadata(path).await?.len();
+        let part_size = part_size(size, self.auto_part_size);
+        self.etag_calculator.file(path, part_size as usize).await
+    }
+
+    async fn fetch_object_etag(&mut self, upload_key: &UploadKey) -> Option<String> {
+        if let Some(etag) = self
+            .head_cache
+            .as_ref()
+            .and_then(|cache| cache.get(upload_key))
+        {
+            return etag;
+        }
+        let etag = self.head_object_etag(upload_key).await;
+        if let Some(head_cache) = &mut self.head_cache {
+            head_cache.insert(upload_key.clone(), etag.clone());
+        }
+        etag
+    }
+
+    async fn head_object_etag(&self, upload_key: &UploadKey) -> Option<String> {
         self.client
             .head_object()
             .bucket(&upload_key.bucket)
@@ -73,40 +283,94 @@ impl S3Uploader {
             .flatten()
     }
 
-    async fn do_upload(&mut self, upload_key: &UploadKey) -> io::Result<usize> {
-        let mut file = File::open(&upload_key.filename).await?;
-
-        let mut chunk = Vec::new();
-        let n = (&mut file)
-            .take(S3_MULTIPART_UPLOAD_CHUNK_SIZE as u64)
-            .read_to_end(&mut chunk)
-            .await?;
-        if n < S3_MULTIPART_UPLOAD_CHUNK_SIZE {
-            self.put_object(upload_key, chunk).await
+    async fn do_upload(
+        &mut self,
+        upload_key: &UploadKey,
+        path: &Path,
+        storage_class: Option<StorageClass>,
+        metadata: Option<HashMap<String, String>>,
+        checkpointer: &mut Checkpointer,
+    ) -> io::Result<usize> {
+        // Anything appended while uploading is left to the next upload.
+        let size = tokio::fs::metadata(path).await?.len();
+        if !is_multipart(size) {
+            let region = FileRegion {
+                path: path.to_owned(),
+                offset: 0,
+                length: size,
+            };
+            self.put_object(upload_key, storage_class, metadata, region)
+                .await
         } else {
-            let uploader = self.multipart_uploader(upload_key, chunk, file);
-            Ok(uploader.upload().await?)
+            let uploader = self.multipart_uploader(upload_key, path, storage_class, metadata, size);
+            Ok(uploader.upload(checkpointer).await?)
         }
     }
 
-    async fn put_object(&self, upload_key: &UploadKey, body: Vec<u8>) -> io::Result<usize> {
-        let content_md5 = EtagCalculator::content_md5(&body);
-        let size = body.len();
-        let tagging = self.options.tags.as_ref().map(|tags| {
-            let mut tagging = url::form_urlencoded::Serializer::new(String::new());
-            for (p, v) in tags {
-                tagging.append_pair(p, v);
+    /// Writes a zero-byte object at `upload_key`, e.g. a directory marker,
+    /// leaving an existing object alone without `overwrite`.
+    pub async fn put_marker(
+        &self,
+        upload_key: &UploadKey,
+        storage_class: Option<StorageClass>,
+        metadata: Option<HashMap<String, String>>,
+    ) -> io::Result<UploadResponse> {
+        let storage_class = storage_class.or_else(|| self.options.storage_class.map(Into::into));
+        let request = self
+            .put_object_request(upload_key, storage_class, metadata)
+            .body(ByteStream::from_static(b""))
+            .content_length(0)
+            // there's nothing to decode
+            .set_content_encoding(None);
+        match self.send_put_object(request).await {
+            Ok(()) => Ok(UploadResponse {
+                count: 1,
+                events_byte_size: 0,
+            }),
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {
+                emit!(UploadSkipped {
+                    reason: "object_exists",
+                    filename: &upload_key.object_key,
+                });
+                Ok(UploadResponse {
+                    count: 0,
+                    events_byte_size: 0,
+                })
             }
-            tagging.finish()
-        });
+            Err(error) => Err(error),
+        }
+    }
 
-        let _ = self
-            .client
+    async fn put_object(
+        &self,
+        upload_key: &UploadKey,
+        storage_class: Option<StorageClass>,
+        metadata: Option<HashMap<String, String>>,
+        region: FileRegion,
+    ) -> io::Result<usize> {
+        let content_md5 = region.content_md5().await?;
+        let size = region.length as usize;
+        let request = self
+            .put_object_request(upload_key, storage_class, metadata)
+            .body(region.byte_stream())
+            .content_length(region.length as i64)
+            .content_md5(content_md5);
+        self.send_put_object(request).await?;
+        Ok(size)
+    }
+
+    // a `PutObject` of `upload_key` with every configured option, but no body
+    fn put_object_request(
+        &self,
+        upload_key: &UploadKey,
+        storage_class: Option<StorageClass>,
+        metadata: Option<HashMap<String, String>>,
+    ) -> PutObject {
+        self.client
             .put_object()
-            .body(ByteStream::from(body))
             .bucket(&upload_key.bucket)
             .key(&upload_key.object_key)
-            .set_content_encoding(self.options.content_encoding.clone())
+            .set_content_encoding(self.content_encoding())
             .set_content_type(self.options.content_type.clone())
             .set_acl(self.options.acl.map(Into::into))
             .set_grant_full_control(self.options.grant_full_control.clone())
@@ -115,30 +379,57 @@ impl S3Uploader {
             .set_grant_write_acp(self.options.grant_write_acp.clone())
             .set_server_side_encryption(self.options.server_side_encryption.map(Into::into))
             .set_ssekms_key_id(self.options.ssekms_key_id.clone())
-            .set_storage_class(self.options.storage_class.map(Into::into))
-            .set_tagging(tagging)
-            .content_md5(content_md5)
-            .send()
-            .await
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            .set_bucket_key_enabled(self.bucket_key_enabled.then(|| true))
+            .set_storage_class(storage_class)
+            .set_metadata(metadata)
+            .set_tagging(tagging(&self.options))
+    }
 
-        Ok(size)
+    async fn send_put_object(&self, request: PutObject) -> io::Result<()> {
+        let result = if self.overwrite {
+            request.send().await
+        } else {
+            // only create the object if it doesn't exist yet
+            request
+                .customize()
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+                .map_request(|mut request| {
+                    request
+                        .headers_mut()
+                        .insert("if-none-match", "*".parse().unwrap());
+                    Ok::<_, io::Error>(request)
+                })?
+                .send()
+                .await
+        };
+        conditional_write_result(result).map(|_| ())
     }
 
     fn multipart_uploader<'a, 'b>(
         &'a mut self,
         upload_key: &'b UploadKey,
-        chunk: Vec<u8>,
-        file: File,
+        path: &'b Path,
+        storage_class: Option<StorageClass>,
+        metadata: Option<HashMap<String, String>>,
+        size: u64,
     ) -> MultipartUploader<'a, 'b> {
         MultipartUploader {
             client: &self.client,
             options: &self.options,
             upload_key,
+            path,
+            content_encoding: self.content_encoding(),
+            storage_class,
+            metadata,
+            orphan_multipart_age: self.orphan_multipart_age,
+            bucket_key_enabled: self.bucket_key_enabled,
+            verify_object: self.verify_object,
+            overwrite: self.overwrite,
 
             upload_id: "".to_owned(),
-            file,
-            chunk,
+            size,
+            part_size: part_size(size, self.auto_part_size),
             part_number: 1,
             completed_parts: vec![],
         }
@@ -149,45 +440,73 @@ struct MultipartUploader<'a, 'b> {
     client: &'a S3Client,
     options: &'a S3Options,
     upload_key: &'b UploadKey,
+    path: &'b Path,
+    content_encoding: Option<String>,
+    storage_class: Option<StorageClass>,
+    metadata: Option<HashMap<String, String>>,
+    orphan_multipart_age: Option<Duration>,
+    bucket_key_enabled: bool,
+    verify_object: bool,
+    overwrite: bool,
 
     upload_id: String,
-    file: File,
-    chunk: Vec<u8>,
+    size: u64,
+    part_size: u64,
     part_number: i32,
     completed_parts: Vec<CompletedPart>,
 }
 
 impl<'a, 'b> MultipartUploader<'a, 'b> {
-    async fn upload(mut self) -> io::Result<usize> {
-        match self.do_upload().await {
-            Ok(size) => Ok(size),
+    async fn upload(mut self, checkpointer: &mut Checkpointer) -> io::Result<usize> {
+        match self.do_upload(checkpointer).await {
+            Ok(size) => {
+                self.untrack(checkpointer);
+                if self.verify_object {
+                    self.verify(size).await?;
+                }
+                Ok(size)
+            }
             Err(e) => {
                 if !self.upload_id.is_empty() {
-                    self.abort_upload().await?;
+                    match self.abort_upload().await {
+                        Ok(()) => self.untrack(checkpointer),
+                        // still tracked, to be aborted on a later startup
+                        Err(error) => warn!(
+                            message = "Failed to abort multipart upload.",
+                            upload_id = %self.upload_id,
+                            %error
+                        ),
+                    }
                 }
                 Err(e)
             }
         }
     }
 
-    async fn do_upload(&mut self) -> io::Result<usize> {
+    async fn do_upload(&mut self, checkpointer: &mut Checkpointer) -> io::Result<usize> {
+        let chunk_size = self.part_size;
+        if (self.size + chunk_size - 1) / chunk_size > S3_MULTIPART_UPLOAD_MAX_CHUNKS as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "file is too large, enable `auto_part_size` to upload it in larger parts",
+            ));
+        }
+
         self.upload_id = self.create_upload().await?;
+        self.track(checkpointer).await;
 
         let mut uploaded_size = 0;
-        while !self.chunk.is_empty() {
-            if self.part_number as usize > S3_MULTIPART_UPLOAD_MAX_CHUNKS {
-                return Err(io::Error::new(io::ErrorKind::Other, "file is too large"));
-            }
+        let mut offset = 0;
+        while offset < self.size {
+            let region = FileRegion {
+                path: self.path.to_owned(),
+                offset,
+                length: chunk_size.min(self.size - offset),
+            };
+            offset += region.length;
 
-            let n = self.upload_part().await?;
+            let n = self.upload_part(region).await?;
             uploaded_size += n;
-
-            self.chunk.clear();
-            self.chunk.reserve(S3_MULTIPART_UPLOAD_CHUNK_SIZE);
-            (&mut self.file)
-                .take(S3_MULTIPART_UPLOAD_CHUNK_SIZE as u64)
-                .read_to_end(&mut self.chunk)
-                .await?;
             self.part_number += 1;
         }
 
@@ -195,21 +514,46 @@ impl<'a, 'b> MultipartUploader<'a, 'b> {
         Ok(uploaded_size)
     }
 
-    async fn create_upload(&mut self) -> io::Result<String> {
-        let tagging = self.options.tags.as_ref().map(|tags| {
-            let mut tagging = url::form_urlencoded::Serializer::new(String::new());
-            for (p, v) in tags {
-                tagging.append_pair(p, v);
-            }
-            tagging.finish()
-        });
+    /// Persists the upload id before any part is sent, so the upload can be
+    /// aborted on a later startup if it's interrupted by a crash.
+    async fn track(&self, checkpointer: &mut Checkpointer) {
+        let orphan_multipart_age = match self.orphan_multipart_age {
+            Some(orphan_multipart_age) => orphan_multipart_age,
+            None => return,
+        };
+        let modified_time = match tokio::fs::metadata(&self.upload_key.filename).await {
+            Ok(metadata) => metadata.modified().unwrap_or_else(|_| SystemTime::now()),
+            Err(_) => SystemTime::now(),
+        };
+        let session = UploadSession {
+            session_uri: self.upload_id.clone(),
+            committed_bytes: 0,
+            modified_at: modified_time.into(),
+            expire_at: (SystemTime::now() + orphan_multipart_age + TRACK_GRACE_PERIOD).into(),
+        };
+        checkpointer.update_session(self.upload_key.clone(), session);
+        if let Err(error) = checkpointer.write_checkpoints() {
+            warn!(message = "Failed to persist multipart upload.", %error);
+        }
+    }
+
+    fn untrack(&self, checkpointer: &mut Checkpointer) {
+        if self.orphan_multipart_age.is_none() {
+            return;
+        }
+        checkpointer.remove_session(self.upload_key);
+        if let Err(error) = checkpointer.write_checkpoints() {
+            warn!(message = "Failed to persist multipart upload.", %error);
+        }
+    }
 
+    async fn create_upload(&mut self) -> io::Result<String> {
         let response = self
             .client
             .create_multipart_upload()
             .bucket(&self.upload_key.bucket)
             .key(&self.upload_key.object_key)
-            .set_content_encoding(self.options.content_encoding.clone())
+            .set_content_encoding(self.content_encoding.clone())
             .set_content_type(self.options.content_type.clone())
             .set_acl(self.options.acl.map(Into::into))
             .set_grant_full_control(self.options.grant_full_control.clone())
@@ -218,8 +562,10 @@ impl<'a, 'b> MultipartUploader<'a, 'b> {
             .set_grant_write_acp(self.options.grant_write_acp.clone())
             .set_server_side_encryption(self.options.server_side_encryption.map(Into::into))
             .set_ssekms_key_id(self.options.ssekms_key_id.clone())
-            .set_storage_class(self.options.storage_class.map(Into::into))
-            .set_tagging(tagging)
+            .set_bucket_key_enabled(self.bucket_key_enabled.then(|| true))
+            .set_storage_class(self.storage_class.clone())
+            .set_metadata(self.metadata.clone())
+            .set_tagging(tagging(self.options))
             .send()
             .await
             .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
@@ -240,14 +586,14 @@ impl<'a, 'b> MultipartUploader<'a, 'b> {
         Ok(())
     }
 
-    async fn upload_part(&mut self) -> io::Result<usize> {
-        let body = std::mem::take(&mut self.chunk);
-        let size = body.len();
-        let content_md5 = EtagCalculator::content_md5(&body);
+    async fn upload_part(&mut self, region: FileRegion) -> io::Result<usize> {
+        let size = region.length as usize;
+        let content_md5 = region.content_md5().await?;
         let response = self
             .client
             .upload_part()
-            .body(ByteStream::from(body))
+            .body(region.byte_stream())
+            .content_length(region.length as i64)
             .bucket(&self.upload_key.bucket)
             .key(&self.upload_key.object_key)
             .part_number(self.part_number)
@@ -271,17 +617,656 @@ impl<'a, 'b> MultipartUploader<'a, 'b> {
         let completed_multipart_upload = CompletedMultipartUpload::builder()
             .set_parts(Some(completed_parts))
             .build();
-        let _ = self
+        let request = self
             .client
             .complete_multipart_upload()
             .bucket(&self.upload_key.bucket)
             .key(&self.upload_key.object_key)
             .upload_id(&self.upload_id)
-            .multipart_upload(completed_multipart_upload)
+            .multipart_upload(completed_multipart_upload);
+        let result = if self.overwrite {
+            request.send().await
+        } else {
+            // only complete the object if it doesn't exist yet, as the upload
+            // may have raced with another writer since the existence check
+            request
+                .customize()
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+                .map_request(|mut request| {
+                    request
+                        .headers_mut()
+                        .insert("if-none-match", "*".parse().unwrap());
+                    Ok::<_, io::Error>(request)
+                })?
+                .send()
+                .await
+        };
+        conditional_write_result(result).map(|_| ())
+    }
+
+    /// Checks the size and the number of parts of the completed object
+    /// against what was uploaded, failing the upload on a mismatch so the
+    /// file is uploaded again.
+    async fn verify(&self, size: usize) -> io::Result<()> {
+        let parts = self.part_number - 1;
+        let response = self
+            .client
+            .get_object_attributes()
+            .bucket(&self.upload_key.bucket)
+            .key(&self.upload_key.object_key)
+            .object_attributes(ObjectAttributes::ObjectSize)
+            .object_attributes(ObjectAttributes::ObjectParts)
             .send()
             .await
             .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
 
+        let object_parts = response
+            .object_parts
+            .map(|object_parts| object_parts.total_parts_count);
+        if response.object_size != size as i64 || object_parts != Some(parts) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "uploaded {} bytes in {} parts, but the object has {} bytes in {:?} parts",
+                    size, parts, response.object_size, object_parts
+                ),
+            ));
+        }
         Ok(())
     }
 }
+
+// Files of at least a part are uploaded in parts.
+fn is_multipart(size: u64) -> bool {
+    size >= S3_MULTIPART_UPLOAD_CHUNK_SIZE as u64
+}
+
+// Reports the upload of `upload_key` skipped for `reason`.
+fn skip_upload(upload_key: &UploadKey, reason: &'static str) -> UploadResponse {
+    emit!(UploadSkipped {
+        reason,
+        filename: &upload_key.filename,
+    });
+    UploadResponse {
+        count: 0,
+        events_byte_size: 0,
+    }
+}
+
+// Maps the 412 of a write conditional on the object not existing yet to
+// `AlreadyExists`.
+fn conditional_write_result<T, E>(result: Result<T, SdkError<E>>) -> io::Result<T>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    match result {
+        Ok(output) => Ok(output),
+        // 412 Precondition Failed, the object already exists
+        Err(SdkError::ServiceError { raw, .. }) if raw.http().status().as_u16() == 412 => Err(
+            io::Error::new(io::ErrorKind::AlreadyExists, "object already exists"),
+        ),
+        Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+    }
+}
+
+fn merge_metadata(
+    configured: &HashMap<String, String>,
+    event: &Event,
+) -> Result<Option<HashMap<String, String>>, String> {
+    let mut metadata = configured.clone();
+    if let Some(value) = event.maybe_as_log().and_then(|log| log.get("metadata")) {
+        let object = value
+            .as_object()
+            .ok_or_else(|| "metadata must be an object".to_owned())?;
+        for (key, value) in object {
+            let value = value
+                .as_bytes()
+                .ok_or_else(|| format!("metadata {:?} must be a string", key))?;
+            metadata.insert(key.clone(), String::from_utf8_lossy(value).into_owned());
+        }
+    }
+    validate_metadata(&metadata)?;
+
+    if metadata.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(metadata))
+    }
+}
+
+/// The `tags` as the value of the `x-amz-tagging` header.
+pub fn tagging(options: &S3Options) -> Option<String> {
+    options.tags.as_ref().map(|tags| {
+        let mut tagging = url::form_urlencoded::Serializer::new(String::new());
+        for (p, v) in tags {
+            tagging.append_pair(p, v);
+        }
+        tagging.finish()
+    })
+}
+
+/// The size of the parts a file of `size` bytes is uploaded in, 8 MiB unless
+/// `auto_part_size`, which grows it in steps of 1 MiB as far as needed to keep
+/// within the 10000 parts S3 allows, e.g. to 525 MiB for a 5 TiB file.
+pub fn part_size(size: u64, auto_part_size: bool) -> u64 {
+    let chunk_size = S3_MULTIPART_UPLOAD_CHUNK_SIZE as u64;
+    if !auto_part_size {
+        return chunk_size;
+    }
+    let max_chunks = S3_MULTIPART_UPLOAD_MAX_CHUNKS as u64;
+    let step = S3_MULTIPART_UPLOAD_CHUNK_SIZE_STEP;
+    let min_chunk_size = (size + max_chunks - 1) / max_chunks;
+    ((min_chunk_size + step - 1) / step * step).max(chunk_size)
+}
+
+/// Rejects user metadata S3 wouldn't accept for being over its size limit.
+pub fn validate_metadata(metadata: &HashMap<String, String>) -> Result<(), String> {
+    let size = metadata
+        .iter()
+        .map(|(key, value)| key.len() + value.len())
+        .sum::<usize>();
+    if size > S3_USER_METADATA_MAX_BYTES {
+        return Err(format!(
+            "metadata takes {} bytes, over the limit of {} bytes",
+            size, S3_USER_METADATA_MAX_BYTES
+        ));
+    }
+    Ok(())
+}
+
+/// Rejects bucket names S3 wouldn't accept, so a bad bucket read from an event
+/// fails with a clear error rather than whatever S3 makes of it.
+pub fn validate_bucket_name(bucket: &str) -> Result<(), String> {
+    if !(3..=63).contains(&bucket.len()) {
+        return Err(format!(
+            "bucket name {:?} must be 3 to 63 characters long",
+            bucket
+        ));
+    }
+    if !bucket
+        .bytes()
+        .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'.' || b == b'-')
+    {
+        return Err(format!(
+            "bucket name {:?} may only contain lowercase letters, digits, dots and hyphens",
+            bucket
+        ));
+    }
+    let alphanumeric = |b: u8| b.is_ascii_lowercase() || b.is_ascii_digit();
+    if !alphanumeric(bucket.as_bytes()[0]) || !alphanumeric(bucket.as_bytes()[bucket.len() - 1]) {
+        return Err(format!(
+            "bucket name {:?} must begin and end with a letter or digit",
+            bucket
+        ));
+    }
+    if bucket.contains("..") || bucket.parse::<std::net::Ipv4Addr>().is_ok() {
+        return Err(format!(
+            "bucket name {:?} must not contain adjacent dots nor be an IP address",
+            bucket
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use vector_core::event::LogEvent;
+
+    use super::*;
+    use crate::mock_s3::{
+        self, checkpointer, config, existing_object, upload_key, Request, Response,
+    };
+
+    // Answers multipart uploads, with `GetObjectAttributes` reporting an
+    // object of `object_size` bytes in two parts.
+    fn multipart(object_size: u64) -> impl Fn(&Request) -> Response {
+        move |request| {
+            let body = if request.method() == "POST" && request.line.contains("uploads") {
+                "<InitiateMultipartUploadResult><Bucket>bucket</Bucket><Key>key</Key>\
+                 <UploadId>upload</UploadId></InitiateMultipartUploadResult>"
+                    .to_owned()
+            } else if request.method() == "POST" {
+                "<CompleteMultipartUploadResult><Bucket>bucket</Bucket><Key>key</Key>\
+                 <ETag>\"etag-2\"</ETag></CompleteMultipartUploadResult>"
+                    .to_owned()
+            } else if request.method() == "GET" && request.line.contains("attributes") {
+                format!(
+                    "<GetObjectAttributesResponse><ObjectSize>{}</ObjectSize>\
+                     <ObjectParts><PartsCount>2</PartsCount></ObjectParts>\
+                     </GetObjectAttributesResponse>",
+                    object_size
+                )
+            } else {
+                String::new()
+            };
+            Response::ok(body).header("ETag", "\"etag\"")
+        }
+    }
+
+    fn methods(requests: &[Request]) -> Vec<&str> {
+        requests.iter().map(Request::method).collect()
+    }
+
+    #[tokio::test]
+    async fn skip_existing_object_without_overwrite() {
+        let (endpoint, requests) = mock_s3::serve(existing_object).await;
+        let mut uploader = mock_s3::uploader(&config(&endpoint, "overwrite = false")).await;
+
+        let response = uploader
+            .upload(
+                &upload_key("/nonexistent/file", "key"),
+                None,
+                None,
+                &mut checkpointer("without-overwrite"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.count, 0);
+        assert_eq!(methods(&requests.lock().unwrap()), vec!["HEAD"]);
+    }
+
+    #[tokio::test]
+    async fn skip_file_etag_of_missing_object() {
+        let upload_key = upload_key("/nonexistent/file", "key");
+
+        let (endpoint, requests) = mock_s3::serve(|_| Response::status(404)).await;
+        let mut missing = mock_s3::uploader(&config(&endpoint, "")).await;
+        assert_eq!(
+            missing.existing_object_etag(&upload_key).await.unwrap(),
+            None
+        );
+        assert_eq!(methods(&requests.lock().unwrap()), vec!["HEAD"]);
+
+        // only an existing object is compared with the file, whose etag can't
+        // be read
+        let (endpoint, _) = mock_s3::serve(existing_object).await;
+        let mut existing = mock_s3::uploader(&config(&endpoint, "")).await;
+        let uploaded = existing
+            .upload(&upload_key, None, None, &mut checkpointer("file-etag"))
+            .await;
+        assert!(uploaded.is_err());
+    }
+
+    #[tokio::test]
+    async fn skip_existing_object_before_compressing() {
+        let (endpoint, requests) = mock_s3::serve(existing_object).await;
+        let mut uploader = mock_s3::uploader(&config(
+            &endpoint,
+            r#"
+            overwrite = false
+            dedup = "checkpoint_only"
+            compress = "gzip"
+            "#,
+        ))
+        .await;
+
+        // compressing the file would fail
+        let response = uploader
+            .upload(
+                &upload_key("/nonexistent/file", "key.gz"),
+                None,
+                None,
+                &mut checkpointer("before-compressing"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.count, 0);
+        assert_eq!(methods(&requests.lock().unwrap()), vec!["HEAD"]);
+    }
+
+    #[tokio::test]
+    async fn cache_head_object() {
+        let (endpoint, requests) = mock_s3::serve(existing_object).await;
+        let mut uploader = mock_s3::uploader(&config(
+            &endpoint,
+            r#"
+            overwrite = false
+            head_cache_ttl_secs = 60
+            "#,
+        ))
+        .await;
+
+        let upload_key = upload_key("/nonexistent/file", "key");
+        let mut checkpointer = checkpointer("head-cache");
+        for _ in 0..3 {
+            let response = uploader
+                .upload(&upload_key, None, None, &mut checkpointer)
+                .await
+                .unwrap();
+            assert_eq!(response.count, 0);
+        }
+        assert_eq!(methods(&requests.lock().unwrap()), vec!["HEAD"]);
+
+        // other objects are checked
+        let other_key = UploadKey {
+            object_key: "other".to_owned(),
+            ..upload_key.clone()
+        };
+        uploader
+            .upload(&other_key, None, None, &mut checkpointer)
+            .await
+            .unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn upload_without_etag_check() {
+        let (endpoint, requests) = mock_s3::serve(existing_object).await;
+        let config = config(&endpoint, r#"dedup = "checkpoint_only""#);
+        assert_eq!(config.dedup, Dedup::CheckpointOnly);
+        let mut uploader = mock_s3::uploader(&config).await;
+
+        let path = std::env::temp_dir().join(format!("s3-dedup-{}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let response = uploader
+            .upload(
+                &upload_key(&path.to_string_lossy(), "key"),
+                None,
+                None,
+                &mut checkpointer("dedup"),
+            )
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // the object "exists", but is uploaded without a HEAD to compare etags
+        assert_eq!(response.count, 1);
+        assert_eq!(methods(&requests.lock().unwrap()), vec!["PUT"]);
+    }
+
+    #[tokio::test]
+    async fn skip_existing_object_before_multipart_upload() {
+        let (endpoint, requests) = mock_s3::serve(existing_object).await;
+        let mut uploader = mock_s3::uploader(&config(
+            &endpoint,
+            r#"
+            overwrite = false
+            dedup = "checkpoint_only"
+            "#,
+        ))
+        .await;
+
+        let path = std::env::temp_dir().join(format!("s3-multipart-exists-{}", std::process::id()));
+        std::fs::write(&path, vec![b'x'; S3_MULTIPART_UPLOAD_CHUNK_SIZE]).unwrap();
+        let response = uploader
+            .upload(
+                &upload_key(&path.to_string_lossy(), "key"),
+                None,
+                None,
+                &mut checkpointer("multipart-exists"),
+            )
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // the existing object is found before any part is uploaded
+        assert_eq!(response.count, 0);
+        assert_eq!(methods(&requests.lock().unwrap()), vec!["HEAD"]);
+    }
+
+    #[tokio::test]
+    async fn upload_compressed() {
+        use std::io::Read;
+
+        use flate2::read::GzDecoder;
+
+        let (endpoint, requests) = mock_s3::serve(|_| Response::ok("")).await;
+        let config = config(
+            &endpoint,
+            r#"
+            dedup = "checkpoint_only"
+            compress = "gzip"
+            "#,
+        );
+        assert_eq!(config.compress, Compress::Gzip);
+        let mut uploader = mock_s3::uploader(&config).await;
+
+        let path = std::env::temp_dir().join(format!("s3-compressed-{}", std::process::id()));
+        let content = "{\"message\":\"hello\"}\n".repeat(1000);
+        std::fs::write(&path, &content).unwrap();
+        let object_key = format!("key{}", uploader.compress().extension());
+        let response = uploader
+            .upload(
+                &upload_key(&path.to_string_lossy(), &object_key),
+                None,
+                None,
+                &mut checkpointer("compressed"),
+            )
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.method(), "PUT");
+        assert!(request.line.contains("key.gz"));
+        assert!(request.headers.contains("content-encoding: gzip"));
+        // the compressed size is what's sent
+        assert_eq!(response.events_byte_size, request.body.len());
+        assert!(request.body.len() < content.len());
+        let mut decompressed = String::new();
+        GzDecoder::new(request.body.as_slice())
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, content);
+    }
+
+    #[tokio::test]
+    async fn put_directory_marker() {
+        let (endpoint, requests) = mock_s3::serve(|_| Response::ok("")).await;
+        let uploader = mock_s3::uploader(&config(
+            &endpoint,
+            r#"
+            compress = "gzip"
+            allow_empty_marker = true
+            "#,
+        ))
+        .await;
+
+        let response = uploader
+            .put_marker(&upload_key("", "dir/"), None, None)
+            .await
+            .unwrap();
+        assert_eq!(response.count, 1);
+        assert_eq!(response.events_byte_size, 0);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.method(), "PUT");
+        assert!(request.line.ends_with("dir/ HTTP/1.1"));
+        assert!(request.headers.contains("content-length: 0"));
+        // an empty object isn't compressed
+        assert!(!request.headers.contains("content-encoding"));
+        assert!(request.body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn request_bucket_key() {
+        let (endpoint, requests) = mock_s3::serve(|_| Response::ok("")).await;
+        let uploader = mock_s3::uploader(&config(
+            &endpoint,
+            r#"
+            server_side_encryption = "aws:kms"
+            bucket_key_enabled = true
+            "#,
+        ))
+        .await;
+
+        uploader
+            .put_marker(&upload_key("", "dir/"), None, None)
+            .await
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+        let headers = &requests[0].headers;
+        assert!(headers.contains("x-amz-server-side-encryption: aws:kms"));
+        assert!(headers.contains("x-amz-server-side-encryption-bucket-key-enabled: true"));
+    }
+
+    #[tokio::test]
+    async fn verify_multipart_object() {
+        let path = std::env::temp_dir().join(format!("s3-verify-{}", std::process::id()));
+        // just over one part
+        let size = S3_MULTIPART_UPLOAD_CHUNK_SIZE as u64 + 1;
+        std::fs::write(&path, vec![b'x'; size as usize]).unwrap();
+        let upload_key = upload_key(&path.to_string_lossy(), "key");
+
+        for (object_size, verified) in [(size, true), (size - 1, false)] {
+            let (endpoint, requests) = mock_s3::serve(multipart(object_size)).await;
+            let mut uploader = mock_s3::uploader(&config(
+                &endpoint,
+                r#"
+                dedup = "checkpoint_only"
+                verify_object = true
+                "#,
+            ))
+            .await;
+
+            let result = uploader
+                .upload(&upload_key, None, None, &mut checkpointer("verify"))
+                .await;
+            assert_eq!(result.is_ok(), verified, "{}", object_size);
+            let requests = requests.lock().unwrap();
+            // checked once the upload completed, without aborting it
+            assert!(requests.last().unwrap().line.contains("?attributes"));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keep_upload_error_when_abort_fails() {
+        let path = std::env::temp_dir().join(format!("s3-abort-{}", std::process::id()));
+        std::fs::write(&path, vec![b'x'; S3_MULTIPART_UPLOAD_CHUNK_SIZE]).unwrap();
+        let upload_key = upload_key(&path.to_string_lossy(), "key");
+
+        let complete = multipart(0);
+        let (endpoint, requests) = mock_s3::serve(move |request| match request.method() {
+            "PUT" => Response::status(400).body("<Error><Code>PartRejected</Code></Error>"),
+            "DELETE" => Response::status(400).body("<Error><Code>AbortRejected</Code></Error>"),
+            _ => complete(request),
+        })
+        .await;
+        let mut uploader = mock_s3::uploader(&config(
+            &endpoint,
+            r#"
+            dedup = "checkpoint_only"
+            cleanup_orphan_multiparts = true
+            "#,
+        ))
+        .await;
+
+        let mut checkpointer = checkpointer("abort");
+        let error = uploader
+            .upload(&upload_key, None, None, &mut checkpointer)
+            .await
+            .unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        // the failed part is reported rather than the failed abort
+        let error = format!("{:?}", error);
+        assert!(error.contains("PartRejected"), "{}", error);
+        assert!(!error.contains("AbortRejected"), "{}", error);
+        assert_eq!(
+            methods(&requests.lock().unwrap()),
+            vec!["POST", "PUT", "DELETE"]
+        );
+        // still tracked, to be aborted on a later startup
+        assert!(checkpointer.session(&upload_key).is_some());
+    }
+
+    #[test]
+    fn auto_part_size_fits_huge_files() {
+        const MIB: u64 = 1024 * 1024;
+        const TIB: u64 = 1024 * 1024 * MIB;
+
+        assert_eq!(part_size(5 * TIB, false), 8 * MIB);
+        for size in [0, 8 * MIB, 80_000 * MIB, 80_000 * MIB + 1, TIB, 5 * TIB] {
+            let part_size = part_size(size, true);
+            assert!(part_size >= 8 * MIB, "{}", size);
+            assert_eq!(part_size % MIB, 0, "{}", size);
+            assert!((size + part_size - 1) / part_size <= 10000, "{}", size);
+        }
+        assert_eq!(part_size(80_000 * MIB, true), 8 * MIB);
+        assert_eq!(part_size(80_000 * MIB + 1, true), 9 * MIB);
+        assert_eq!(part_size(5 * TIB, true), 525 * MIB);
+    }
+
+    #[test]
+    fn bucket_names() {
+        for bucket in ["tenant-1", "logs.tenant-1", "abc"] {
+            assert_eq!(validate_bucket_name(bucket), Ok(()), "{}", bucket);
+        }
+        for bucket in [
+            "",
+            "ab",
+            "a".repeat(64).as_str(),
+            "Tenant-1",
+            "tenant_1",
+            "-tenant",
+            "tenant.",
+            "tenant..1",
+            "192.168.1.1",
+        ] {
+            assert!(validate_bucket_name(bucket).is_err(), "{}", bucket);
+        }
+    }
+
+    #[test]
+    fn storage_class_override() {
+        let mut log = LogEvent::from("/tmp/file");
+        assert_eq!(
+            S3Uploader::storage_class_from_event(&log.clone().into()),
+            Ok(None)
+        );
+
+        log.insert("storage_class", "GLACIER");
+        assert_eq!(
+            S3Uploader::storage_class_from_event(&log.clone().into()),
+            Ok(Some(StorageClass::Glacier))
+        );
+
+        log.insert("storage_class", "FROZEN");
+        assert!(S3Uploader::storage_class_from_event(&log.into()).is_err());
+    }
+
+    #[test]
+    fn metadata_override() {
+        let configured = [("team", "tidb"), ("schema", "v1")]
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect::<HashMap<_, _>>();
+
+        let log = LogEvent::from("/tmp/file");
+        assert_eq!(
+            merge_metadata(&configured, &log.clone().into()),
+            Ok(Some(configured.clone()))
+        );
+        assert_eq!(merge_metadata(&HashMap::new(), &log.into()), Ok(None));
+
+        let mut log = LogEvent::from("/tmp/file");
+        log.insert("metadata.schema", "v2");
+        log.insert("metadata.source", "tikv");
+        let metadata = merge_metadata(&configured, &log.into()).unwrap().unwrap();
+        assert_eq!(metadata["team"], "tidb");
+        assert_eq!(metadata["schema"], "v2");
+        assert_eq!(metadata["source"], "tikv");
+
+        let mut log = LogEvent::from("/tmp/file");
+        log.insert("metadata", "schema=v2");
+        assert!(merge_metadata(&configured, &log.into()).is_err());
+
+        let mut log = LogEvent::from("/tmp/file");
+        log.insert("metadata.version", 2_i64);
+        assert!(merge_metadata(&configured, &log.into()).is_err());
+
+        // 2 KiB including the configured metadata
+        let mut log = LogEvent::from("/tmp/file");
+        log.insert("metadata.blob", "x".repeat(2048 - 16 - 4));
+        assert!(merge_metadata(&configured, &log.clone().into()).is_ok());
+        log.insert("metadata.blob", "x".repeat(2048 - 16 - 4 + 1));
+        assert!(merge_metadata(&configured, &log.into()).is_err());
+    }
+}
@@ -0,0 +1,694 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use http::header::AUTHORIZATION;
+use http::{HeaderValue, Request, Uri};
+use hyper::body::Bytes;
+use hyper::Body;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use vector::gcp::GcpAuthenticator;
+use vector::http::HttpClient;
+
+const EXTERNAL_ACCOUNT_TYPE: &str = "external_account";
+const TOKEN_EXCHANGE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+const AWS_ENVIRONMENT_ID: &str = "aws1";
+// the lifetime of the IMDSv2 session tokens requested, in seconds
+const AWS_SESSION_TOKEN_TTL: &str = "300";
+
+// refresh the token a bit before it actually expires
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub enum GcsAuthenticator {
+    Gcp(GcpAuthenticator),
+    ExternalAccount(ExternalAccountAuthenticator),
+}
+
+impl GcsAuthenticator {
+    pub async fn apply<T>(&self, request: &mut Request<T>) -> io::Result<()> {
+        match self {
+            GcsAuthenticator::Gcp(auth) => auth.apply(request),
+            GcsAuthenticator::ExternalAccount(auth) => {
+                let token = auth
+                    .token()
+                    .await
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+                let value = HeaderValue::from_str(&format!("Bearer {}", token))
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+                request.headers_mut().insert(AUTHORIZATION, value);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Credentials of workload identity federation, exchanging a token issued by an
+/// external identity provider for a short-lived Google access token, so no
+/// long-lived service account key is needed.
+///
+/// The subject token is read from a `file` or fetched from a `url`, or for AWS
+/// (`environment_id` `aws1`) is a `GetCallerIdentity` request signed with the
+/// credentials of the environment or of the EC2 instance metadata.
+#[derive(Clone)]
+pub struct ExternalAccountAuthenticator {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: ExternalAccountConfig,
+    client: HttpClient,
+    token: Mutex<Option<AccessToken>>,
+}
+
+struct AccessToken {
+    value: String,
+    expires_at: Instant,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExternalAccountConfig {
+    #[serde(rename = "type")]
+    credential_type: String,
+    audience: String,
+    subject_token_type: String,
+    token_url: String,
+    service_account_impersonation_url: Option<String>,
+    credential_source: CredentialSource,
+}
+
+#[derive(Debug, Deserialize)]
+struct CredentialSource {
+    file: Option<PathBuf>,
+    url: Option<String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    environment_id: Option<String>,
+    region_url: Option<String>,
+    regional_cred_verification_url: Option<String>,
+    imdsv2_session_token_url: Option<String>,
+    #[serde(default)]
+    format: CredentialFormat,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum CredentialFormat {
+    Text,
+    Json { subject_token_field_name: String },
+}
+
+impl Default for CredentialFormat {
+    fn default() -> Self {
+        CredentialFormat::Text
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenExchangeResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImpersonationResponse {
+    access_token: String,
+    expire_time: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: Option<String>,
+}
+
+impl AwsCredentials {
+    fn from_env() -> Option<Self> {
+        Some(Self {
+            access_key_id: std::env::var("AWS_ACCESS_KEY_ID").ok()?,
+            secret_access_key: std::env::var("AWS_SECRET_ACCESS_KEY").ok()?,
+            token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+impl ExternalAccountAuthenticator {
+    /// Loads and validates an external account credential configuration file.
+    pub fn from_file(path: &Path, client: HttpClient) -> vector::Result<Self> {
+        let content = std::fs::read(path)
+            .map_err(|error| format!("Failed to read credential file {:?}: {}", path, error))?;
+        let config =
+            serde_json::from_slice::<ExternalAccountConfig>(&content).map_err(|error| {
+                format!(
+                    "Invalid external account credential file {:?}: {}",
+                    path, error
+                )
+            })?;
+        config.validate()?;
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                config,
+                client,
+                token: Mutex::new(None),
+            }),
+        })
+    }
+
+    /// Returns a valid access token, exchanging a new one if the cached token is
+    /// about to expire.
+    pub async fn token(&self) -> vector::Result<String> {
+        let mut token = self.inner.token.lock().await;
+        if let Some(token) = token.as_ref() {
+            if token.expires_at > Instant::now() + TOKEN_REFRESH_MARGIN {
+                return Ok(token.value.clone());
+            }
+        }
+
+        let new_token = self.exchange_token().await?;
+        let value = new_token.value.clone();
+        *token = Some(new_token);
+        Ok(value)
+    }
+
+    async fn exchange_token(&self) -> vector::Result<AccessToken> {
+        let config = &self.inner.config;
+        let subject_token = self.subject_token().await?;
+
+        let body = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("grant_type", TOKEN_EXCHANGE_GRANT_TYPE)
+            .append_pair("audience", &config.audience)
+            .append_pair("scope", CLOUD_PLATFORM_SCOPE)
+            .append_pair("requested_token_type", ACCESS_TOKEN_TYPE)
+            .append_pair("subject_token", &subject_token)
+            .append_pair("subject_token_type", &config.subject_token_type)
+            .finish();
+        let request = Request::post(&config.token_url)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(Body::from(body))?;
+        let body = self.send(request, "exchange token").await?;
+        let response = serde_json::from_slice::<TokenExchangeResponse>(&body)?;
+        let mut token = AccessToken {
+            value: response.access_token,
+            expires_at: Instant::now() + Duration::from_secs(response.expires_in),
+        };
+
+        if let Some(url) = &config.service_account_impersonation_url {
+            let body = serde_json::json!({ "scope": [CLOUD_PLATFORM_SCOPE] }).to_string();
+            let request = Request::post(url)
+                .header("content-type", "application/json")
+                .header(AUTHORIZATION, format!("Bearer {}", token.value))
+                .body(Body::from(body))?;
+            let body = self.send(request, "impersonate service account").await?;
+            let response = serde_json::from_slice::<ImpersonationResponse>(&body)?;
+            token = AccessToken {
+                value: response.access_token,
+                expires_at: instant_at(response.expire_time),
+            };
+        }
+
+        Ok(token)
+    }
+
+    async fn subject_token(&self) -> vector::Result<String> {
+        let source = &self.inner.config.credential_source;
+        if source.environment_id.is_some() {
+            return self.aws_subject_token().await;
+        }
+        let content = if let Some(file) = &source.file {
+            tokio::fs::read(file).await.map_err(|error| {
+                format!("Failed to read subject token from {:?}: {}", file, error)
+            })?
+        } else {
+            // validated to be present when loading the config
+            let url = source.url.as_ref().unwrap();
+            self.get(url, &source.headers, "fetch subject token")
+                .await?
+                .to_vec()
+        };
+
+        match &source.format {
+            CredentialFormat::Text => Ok(String::from_utf8_lossy(&content).trim().to_owned()),
+            CredentialFormat::Json {
+                subject_token_field_name,
+            } => {
+                let value = serde_json::from_slice::<serde_json::Value>(&content)?;
+                value
+                    .get(subject_token_field_name)
+                    .and_then(|token| token.as_str())
+                    .map(ToOwned::to_owned)
+                    .ok_or_else(|| {
+                        format!("Missing subject token field {:?}", subject_token_field_name).into()
+                    })
+            }
+        }
+    }
+
+    /// Signs a `GetCallerIdentity` request for STS to verify, with the region
+    /// and credentials of the environment, or else of the instance metadata.
+    async fn aws_subject_token(&self) -> vector::Result<String> {
+        let config = &self.inner.config;
+        let source = &config.credential_source;
+        let region = std::env::var("AWS_REGION")
+            .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+            .ok();
+        let credentials = AwsCredentials::from_env();
+
+        let mut metadata_headers = HashMap::new();
+        if region.is_none() || credentials.is_none() {
+            if let Some(url) = &source.imdsv2_session_token_url {
+                let request = Request::put(url)
+                    .header(
+                        "x-aws-ec2-metadata-token-ttl-seconds",
+                        AWS_SESSION_TOKEN_TTL,
+                    )
+                    .body(Body::empty())?;
+                let token = self.send(request, "fetch AWS session token").await?;
+                metadata_headers.insert(
+                    "x-aws-ec2-metadata-token".to_owned(),
+                    String::from_utf8_lossy(&token).trim().to_owned(),
+                );
+            }
+        }
+
+        let region = match region {
+            Some(region) => region,
+            None => {
+                let url = source
+                    .region_url
+                    .as_ref()
+                    .ok_or("No AWS region in the environment nor `region_url` to fetch it")?;
+                let zone = self.get(url, &metadata_headers, "fetch AWS region").await?;
+                region_of_zone(&String::from_utf8_lossy(&zone))?
+            }
+        };
+        let credentials = match credentials {
+            Some(credentials) => credentials,
+            None => {
+                let url = source
+                    .url
+                    .as_ref()
+                    .ok_or("No AWS credentials in the environment nor `url` to fetch them")?;
+                let role = self.get(url, &metadata_headers, "fetch AWS role").await?;
+                let url = format!("{}/{}", url, String::from_utf8_lossy(&role).trim());
+                let body = self
+                    .get(&url, &metadata_headers, "fetch AWS credentials")
+                    .await?;
+                serde_json::from_slice::<AwsCredentials>(&body)?
+            }
+        };
+
+        // validated to be present when loading the config
+        let url = source
+            .regional_cred_verification_url
+            .as_ref()
+            .unwrap()
+            .replace("{region}", &region);
+        signed_caller_identity(&url, &region, &credentials, &config.audience, Utc::now())
+    }
+
+    async fn get(
+        &self,
+        url: &str,
+        headers: &HashMap<String, String>,
+        action: &str,
+    ) -> vector::Result<Bytes> {
+        let mut builder = Request::get(url);
+        for (name, value) in headers {
+            builder = builder.header(name, value);
+        }
+        let request = builder.body(Body::empty())?;
+        self.send(request, action).await
+    }
+
+    async fn send(&self, request: Request<Body>, action: &str) -> vector::Result<Bytes> {
+        let response = self.inner.client.send(request).await?;
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        if !parts.status.is_success() {
+            return Err(format!(
+                "Failed to {} status: {} body: {}",
+                action,
+                parts.status,
+                String::from_utf8_lossy(&body)
+            )
+            .into());
+        }
+        Ok(body)
+    }
+}
+
+impl ExternalAccountConfig {
+    fn validate(&self) -> vector::Result<()> {
+        if self.credential_type != EXTERNAL_ACCOUNT_TYPE {
+            return Err(format!(
+                "Expected credential type {:?}, got {:?}",
+                EXTERNAL_ACCOUNT_TYPE, self.credential_type
+            )
+            .into());
+        }
+        let source = &self.credential_source;
+        match source.environment_id.as_deref() {
+            Some(AWS_ENVIRONMENT_ID) => {
+                return match source.regional_cred_verification_url {
+                    Some(_) => Ok(()),
+                    None => Err(
+                        "`regional_cred_verification_url` must be set in an AWS credential_source"
+                            .into(),
+                    ),
+                };
+            }
+            Some(environment_id) => {
+                return Err(format!(
+                    "Unsupported credential_source environment {:?}, expected {:?}",
+                    environment_id, AWS_ENVIRONMENT_ID
+                )
+                .into());
+            }
+            None => {}
+        }
+        match (&source.file, &source.url) {
+            (Some(_), None) | (None, Some(_)) => Ok(()),
+            _ => Err("Exactly one of `file` and `url` must be set in credential_source".into()),
+        }
+    }
+}
+
+/// The `Instant` at `time`, or now if it's past.
+fn instant_at(time: DateTime<Utc>) -> Instant {
+    Instant::now() + (time - Utc::now()).to_std().unwrap_or_default()
+}
+
+/// The region of an availability zone, which is the region followed by a
+/// letter, e.g. `us-east-1` of `us-east-1a`.
+fn region_of_zone(zone: &str) -> vector::Result<String> {
+    let zone = zone.trim();
+    match zone.char_indices().next_back() {
+        Some((letter, c)) if letter > 0 && c.is_ascii_alphabetic() => Ok(zone[..letter].to_owned()),
+        _ => Err(format!("Unexpected AWS availability zone {:?}", zone).into()),
+    }
+}
+
+/// The subject token of AWS, a `GetCallerIdentity` request to `url` signed at
+/// `now` and bound to `audience`, serialized as STS expects it.
+fn signed_caller_identity(
+    url: &str,
+    region: &str,
+    credentials: &AwsCredentials,
+    audience: &str,
+    now: DateTime<Utc>,
+) -> vector::Result<String> {
+    let uri = url.parse::<Uri>()?;
+    let host = uri
+        .authority()
+        .ok_or_else(|| format!("Missing host in {:?}", url))?;
+    let mut headers = BTreeMap::new();
+    headers.insert("host", host.to_string());
+    headers.insert("x-amz-date", now.format("%Y%m%dT%H%M%SZ").to_string());
+    if let Some(token) = &credentials.token {
+        headers.insert("x-amz-security-token", token.clone());
+    }
+    headers.insert("x-goog-cloud-target-resource", audience.to_owned());
+    let authorization = aws_authorization("POST", &uri, &headers, region, "sts", credentials, now);
+
+    let mut headers = headers
+        .into_iter()
+        .map(|(key, value)| serde_json::json!({ "key": key, "value": value }))
+        .collect::<Vec<_>>();
+    headers.push(serde_json::json!({ "key": "Authorization", "value": authorization }));
+    let request = serde_json::json!({ "url": url, "method": "POST", "headers": headers });
+    Ok(url::form_urlencoded::byte_serialize(request.to_string().as_bytes()).collect())
+}
+
+/// The `Authorization` of AWS Signature Version 4 for a request without body,
+/// signing all of `headers`, which are lowercase.
+fn aws_authorization(
+    method: &str,
+    uri: &Uri,
+    headers: &BTreeMap<&str, String>,
+    region: &str,
+    service: &str,
+    credentials: &AwsCredentials,
+    now: DateTime<Utc>,
+) -> String {
+    let hmac = |key: &[u8], data: &str| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+        mac.update(data.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    };
+
+    let mut query = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .collect::<Vec<_>>();
+    query.retain(|param| !param.is_empty());
+    query.sort_unstable();
+    let canonical_headers = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect::<String>();
+    let signed_headers = headers.keys().copied().collect::<Vec<_>>().join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        uri.path(),
+        query.join("&"),
+        canonical_headers,
+        signed_headers,
+        hex::encode(Sha256::digest(b""))
+    );
+
+    let date = now.format("%Y%m%d").to_string();
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        now.format("%Y%m%dT%H%M%SZ"),
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = [date.as_str(), region, service, "aws4_request"]
+        .into_iter()
+        .fold(
+            format!("AWS4{}", credentials.secret_access_key).into_bytes(),
+            |key, data| hmac(&key, data),
+        );
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id,
+        scope,
+        signed_headers,
+        hex::encode(hmac(&key, &string_to_sign))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(config: serde_json::Value) -> vector::Result<ExternalAccountConfig> {
+        let config = serde_json::from_value::<ExternalAccountConfig>(config)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn validate_external_account_config() {
+        let config = |credential_type: &str, credential_source: serde_json::Value| {
+            serde_json::json!({
+                "type": credential_type,
+                "audience": "//iam.googleapis.com/projects/1/locations/global/workloadIdentityPools/pool/providers/oidc",
+                "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+                "token_url": "https://sts.googleapis.com/v1/token",
+                "credential_source": credential_source,
+            })
+        };
+
+        let file = parse(config(
+            "external_account",
+            serde_json::json!({ "file": "/var/run/token" }),
+        ))
+        .unwrap();
+        assert!(matches!(
+            file.credential_source.format,
+            CredentialFormat::Text
+        ));
+
+        let url = parse(config(
+            "external_account",
+            serde_json::json!({
+                "url": "http://169.254.169.254/token",
+                "format": { "type": "json", "subject_token_field_name": "access_token" },
+            }),
+        ))
+        .unwrap();
+        assert!(matches!(
+            url.credential_source.format,
+            CredentialFormat::Json { .. }
+        ));
+
+        assert!(parse(config(
+            "service_account",
+            serde_json::json!({ "file": "/var/run/token" }),
+        ))
+        .is_err());
+        let aws = parse(config(
+            "external_account",
+            serde_json::json!({
+                "environment_id": "aws1",
+                "region_url": "http://169.254.169.254/latest/meta-data/placement/availability-zone",
+                "url": "http://169.254.169.254/latest/meta-data/iam/security-credentials",
+                "regional_cred_verification_url": "https://sts.{region}.amazonaws.com?Action=GetCallerIdentity&Version=2011-06-15",
+            }),
+        ))
+        .unwrap();
+        assert!(aws
+            .credential_source
+            .regional_cred_verification_url
+            .is_some());
+        assert!(parse(config(
+            "external_account",
+            serde_json::json!({ "environment_id": "aws1" }),
+        ))
+        .is_err());
+        assert!(parse(config(
+            "external_account",
+            serde_json::json!({
+                "environment_id": "aws2",
+                "regional_cred_verification_url": "https://sts.{region}.amazonaws.com",
+            }),
+        ))
+        .is_err());
+        assert!(parse(config("external_account", serde_json::json!({}))).is_err());
+    }
+
+    fn credentials(token: Option<&str>) -> AwsCredentials {
+        AwsCredentials {
+            access_key_id: "id".to_owned(),
+            secret_access_key: "secret".to_owned(),
+            token: token.map(ToOwned::to_owned),
+        }
+    }
+
+    #[test]
+    fn aws_authorization_for_test_vector() {
+        // `get-vanilla` of the AWS Signature Version 4 test suite
+        let credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_owned(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_owned(),
+            token: None,
+        };
+        let now = "2015-08-30T12:36:00Z".parse::<DateTime<Utc>>().unwrap();
+        let headers = BTreeMap::from([
+            ("host", "example.amazonaws.com".to_owned()),
+            ("x-amz-date", "20150830T123600Z".to_owned()),
+        ]);
+        let uri = Uri::from_static("https://example.amazonaws.com/");
+
+        assert_eq!(
+            aws_authorization(
+                "GET",
+                &uri,
+                &headers,
+                "us-east-1",
+                "service",
+                &credentials,
+                now
+            ),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn region_of_availability_zone() {
+        assert_eq!(region_of_zone("us-east-1a\n").unwrap(), "us-east-1");
+        for zone in ["", "a", "us-east-1", "us-east-1é"] {
+            assert!(region_of_zone(zone).is_err(), "{:?}", zone);
+        }
+    }
+
+    #[test]
+    fn aws_subject_token() {
+        let url = "https://sts.us-east-1.amazonaws.com?Action=GetCallerIdentity&Version=2011-06-15";
+        let audience = "//iam.googleapis.com/projects/1/locations/global/workloadIdentityPools/pool/providers/aws";
+        let now = "2022-10-16T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+
+        let token = signed_caller_identity(
+            url,
+            "us-east-1",
+            &credentials(Some("session")),
+            audience,
+            now,
+        )
+        .unwrap();
+        let (request, _) = url::form_urlencoded::parse(token.as_bytes())
+            .next()
+            .unwrap();
+        let request = serde_json::from_str::<serde_json::Value>(&request).unwrap();
+        assert_eq!(request["url"], url);
+        assert_eq!(request["method"], "POST");
+        let headers = request["headers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|header| {
+                (
+                    header["key"].as_str().unwrap(),
+                    header["value"].as_str().unwrap(),
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(
+            headers,
+            BTreeMap::from([
+                (
+                    "Authorization",
+                    "AWS4-HMAC-SHA256 Credential=id/20221016/us-east-1/sts/aws4_request, \
+                     SignedHeaders=host;x-amz-date;x-amz-security-token;x-goog-cloud-target-resource, \
+                     Signature=a564faba92c62b0116c749529a40b7d34d7c4f4482e30f32d2a8ec462b57cb5b"
+                ),
+                ("host", "sts.us-east-1.amazonaws.com"),
+                ("x-amz-date", "20221016T120000Z"),
+                ("x-amz-security-token", "session"),
+                ("x-goog-cloud-target-resource", audience),
+            ])
+        );
+
+        // no session token without one in the credentials
+        let token =
+            signed_caller_identity(url, "us-east-1", &credentials(None), audience, now).unwrap();
+        assert!(!token.contains("x-amz-security-token"));
+    }
+
+    #[test]
+    fn impersonated_token_expire_time() {
+        let response = serde_json::from_str::<ImpersonationResponse>(
+            r#"{"accessToken": "token", "expireTime": "2014-10-02T15:01:23Z"}"#,
+        )
+        .unwrap();
+        assert_eq!(response.access_token, "token");
+        assert_eq!(
+            response.expire_time,
+            "2014-10-02T15:01:23Z".parse::<DateTime<Utc>>().unwrap()
+        );
+        // long past, so expired already
+        assert!(instant_at(response.expire_time) <= Instant::now());
+
+        let expire_time = Utc::now() + chrono::Duration::seconds(1800);
+        let expires_in = instant_at(expire_time) - Instant::now();
+        assert!(expires_in > Duration::from_secs(1790) && expires_in <= Duration::from_secs(1800));
+    }
+}
@@ -2,22 +2,27 @@ use std::collections::HashMap;
 use std::path::PathBuf;
 use std::time::Duration;
 
-use common::checkpointer::Checkpointer;
+use common::checkpointer::{Checkpointer, ExpirePolicy};
+use futures::FutureExt;
 use goauth::scopes::Scope;
+use http::header::AUTHORIZATION;
+use http::Request;
+use hyper::Body;
 use serde::{Deserialize, Serialize};
 use vector::config::{GenerateConfig, SinkConfig, SinkContext};
-use vector::gcp::{GcpAuthConfig, GcpAuthenticator};
+use vector::gcp::GcpAuthConfig;
 use vector::http::HttpClient;
 use vector::sinks::gcs_common::config::{
     build_healthcheck, GcsPredefinedAcl, GcsStorageClass, BASE_URL,
 };
-use vector::sinks::Healthcheck;
+use vector::sinks::{Healthcheck, HealthcheckError};
 use vector::tls::{TlsConfig, TlsSettings};
 use vector_core::config::{AcknowledgementsConfig, DataType, Input};
 use vector_core::sink::VectorSink;
 
+use crate::auth::{ExternalAccountAuthenticator, GcsAuthenticator};
 use crate::processor::GcsUploadFileSink;
-use crate::uploader::RequestSettings;
+use crate::uploader::{GCSUploader, RequestSettings};
 
 #[derive(Deserialize, Serialize, Debug)]
 #[serde(deny_unknown_fields)]
@@ -28,6 +33,11 @@ pub struct GcsUploadFileSinkConfig {
     pub metadata: Option<HashMap<String, String>>,
     #[serde(flatten)]
     pub auth: GcpAuthConfig,
+    /// Path to an external account credential configuration file, used for
+    /// workload identity federation instead of `credentials_path` or `api_key`.
+    /// Its subject token is read from a file, fetched from a URL, or signed with
+    /// the AWS credentials of the environment or the EC2 instance metadata.
+    pub credentials_source: Option<PathBuf>,
     pub tls: Option<TlsConfig>,
     #[serde(
         default,
@@ -39,6 +49,8 @@ pub struct GcsUploadFileSinkConfig {
     /// The directory used to persist file checkpoint.
     ///
     /// By default, the global `data_dir` option is used. Please make sure the user Vector is running as has write permissions to this directory.
+    ///
+    /// The checkpoints are kept in a subdirectory named after the sink type, locked while the sink runs, so sinks of the same type need a `data_dir` each.
     pub data_dir: Option<PathBuf>,
 
     /// Delay between receiving upload event and beginning to upload file.
@@ -48,6 +60,14 @@ pub struct GcsUploadFileSinkConfig {
     /// The expire time of uploaded file records which used to prevent duplicate uploads.
     #[serde(alias = "expire_after", default = "default_expire_after_secs")]
     pub expire_after_secs: u64,
+
+    /// The point in time that `expire_after_secs` is counted from, either `upload_time` or `file_mtime`.
+    #[serde(default)]
+    pub expire_policy: ExpirePolicy,
+
+    /// Persist the sessions of in-progress uploads in `data_dir`, so uploads interrupted by an error or a restart resume from the last committed chunk instead of starting over.
+    #[serde(default = "default_resume_uploads")]
+    pub resume_uploads: bool,
 }
 
 pub const fn default_delay_upload_secs() -> u64 {
@@ -58,6 +78,10 @@ pub const fn default_expire_after_secs() -> u64 {
     1800
 }
 
+pub const fn default_resume_uploads() -> bool {
+    true
+}
+
 impl GenerateConfig for GcsUploadFileSinkConfig {
     fn generate_config() -> toml::Value {
         toml::Value::try_from(Self {
@@ -66,11 +90,14 @@ impl GenerateConfig for GcsUploadFileSinkConfig {
             storage_class: None,
             metadata: None,
             auth: GcpAuthConfig::default(),
+            credentials_source: None,
             tls: None,
             acknowledgements: AcknowledgementsConfig::default(),
             data_dir: None,
             delay_upload_secs: default_delay_upload_secs(),
             expire_after_secs: default_expire_after_secs(),
+            expire_policy: ExpirePolicy::default(),
+            resume_uploads: default_resume_uploads(),
         })
         .unwrap()
     }
@@ -80,15 +107,33 @@ impl GenerateConfig for GcsUploadFileSinkConfig {
 #[typetag::serde(name = "gcp_cloud_storage_upload_file")]
 impl SinkConfig for GcsUploadFileSinkConfig {
     async fn build(&self, cx: SinkContext) -> vector::Result<(VectorSink, Healthcheck)> {
-        let auth = self.auth.build(Scope::DevStorageReadWrite).await?;
         let tls = TlsSettings::from_options(&self.tls)?;
         let client = HttpClient::new(tls, cx.proxy())?;
-        let healthcheck = build_healthcheck(
-            self.bucket.clone(),
-            client.clone(),
-            format!("{}{}", BASE_URL, self.bucket),
-            auth.clone(),
-        )?;
+        let (auth, healthcheck) = match &self.credentials_source {
+            Some(path) => {
+                if self.auth.credentials_path.is_some() || self.auth.api_key.is_some() {
+                    return Err(
+                        "`credentials_source` conflicts with `credentials_path` and `api_key`"
+                            .into(),
+                    );
+                }
+                let auth = ExternalAccountAuthenticator::from_file(path, client.clone())?;
+                let healthcheck =
+                    external_account_healthcheck(self.bucket.clone(), client.clone(), auth.clone())
+                        .boxed();
+                (GcsAuthenticator::ExternalAccount(auth), healthcheck)
+            }
+            None => {
+                let auth = self.auth.build(Scope::DevStorageReadWrite).await?;
+                let healthcheck = build_healthcheck(
+                    self.bucket.clone(),
+                    client.clone(),
+                    format!("{}{}", BASE_URL, self.bucket),
+                    auth.clone(),
+                )?;
+                (GcsAuthenticator::Gcp(auth), healthcheck)
+            }
+        };
         let sink = self.build_sink(client, self.bucket.clone(), auth, cx)?;
 
         Ok((sink, healthcheck))
@@ -112,29 +157,51 @@ impl GcsUploadFileSinkConfig {
         &self,
         client: HttpClient,
         bucket: String,
-        auth: GcpAuthenticator,
+        auth: GcsAuthenticator,
         cx: SinkContext,
     ) -> vector::Result<VectorSink> {
         let data_dir = cx
             .globals
             .resolve_and_make_data_subdir(self.data_dir.as_ref(), self.sink_type())?;
-        let mut checkpointer = Checkpointer::new(data_dir);
-        checkpointer.read_checkpoints();
+        // locked and read once running
+        let checkpointer = Checkpointer::new(data_dir, self.expire_policy)?;
         let req_settings = RequestSettings::new(self)?;
+        let uploader = GCSUploader::new(client, auth, req_settings, self.resume_uploads);
         let sink = GcsUploadFileSink::new(
-            client,
             bucket,
-            auth,
             Duration::from_secs(self.delay_upload_secs),
             Duration::from_secs(self.expire_after_secs),
+            uploader,
             checkpointer,
-            req_settings,
         );
 
         Ok(VectorSink::from_event_streamsink(sink))
     }
 }
 
+async fn external_account_healthcheck(
+    bucket: String,
+    client: HttpClient,
+    auth: ExternalAccountAuthenticator,
+) -> vector::Result<()> {
+    let token = auth.token().await.map_err(|error| {
+        format!(
+            "Failed to exchange token with the external account credentials: {}",
+            error
+        )
+    })?;
+    let request = Request::head(format!("{}{}", BASE_URL, bucket))
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .body(Body::empty())?;
+    let response = client.send(request).await?;
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(HealthcheckError::UnexpectedStatus { status }.into())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,6 +1,7 @@
 #[macro_use]
 extern crate tracing;
 
+mod auth;
 mod config;
 mod processor;
 mod uploader;
@@ -3,47 +3,40 @@ use std::io;
 use std::time::{Duration, SystemTime};
 
 use common::checkpointer::{Checkpointer, UploadKey};
+use common::internal_events::{FileUploaded, UploadCheckpointExpired, UploadSkipped};
 use futures_util::stream::BoxStream;
 use futures_util::StreamExt;
 use tokio_util::time::DelayQueue;
 use vector::emit;
 use vector::event::Finalizable;
-use vector::gcp::GcpAuthenticator;
-use vector::http::HttpClient;
 use vector_core::event::{Event, EventStatus};
 use vector_core::internal_event::EventsSent;
 use vector_core::sink::StreamSink;
 
-use crate::uploader::{GCSUploader, RequestSettings};
+use crate::uploader::GCSUploader;
 
 pub struct GcsUploadFileSink {
-    client: HttpClient,
+    uploader: GCSUploader,
     bucket: String,
-    auth: GcpAuthenticator,
     delay_upload: Duration,
     expire_after: Duration,
     checkpointer: Checkpointer,
-    request_settings: RequestSettings,
 }
 
 impl GcsUploadFileSink {
     pub const fn new(
-        client: HttpClient,
         bucket: String,
-        auth: GcpAuthenticator,
         delay_upload: Duration,
         expire_after: Duration,
+        uploader: GCSUploader,
         checkpointer: Checkpointer,
-        request_settings: RequestSettings,
     ) -> Self {
         Self {
-            client,
+            uploader,
             bucket,
-            auth,
             delay_upload,
             expire_after,
             checkpointer,
-            request_settings,
         }
     }
 
@@ -56,18 +49,21 @@ impl GcsUploadFileSink {
 impl StreamSink<Event> for GcsUploadFileSink {
     async fn run(self: Box<Self>, mut input: BoxStream<'_, Event>) -> Result<(), ()> {
         let Self {
-            client,
+            mut uploader,
             bucket,
-            auth,
             delay_upload,
             expire_after,
             mut checkpointer,
-            request_settings,
         } = *self;
 
+        if let Err(error) = checkpointer.lock().await {
+            error!(message = "Failed to lock the data dir.", %error);
+            return Err(());
+        }
+        checkpointer.read_checkpoints();
+
         let mut delay_queue = DelayQueue::new();
         let mut pending_uploads = HashSet::new();
-        let mut uploader = GCSUploader::new(client, auth, request_settings);
 
         loop {
             tokio::select! {
@@ -89,11 +85,19 @@ impl StreamSink<Event> for GcsUploadFileSink {
                             }
                         };
 
-                        if !checkpointer.contains(&upload_key, modified_time) && !pending_uploads.contains(&upload_key) {
-                            delay_queue.insert((upload_key.clone(), finalizers), delay_upload);
-                            pending_uploads.insert(upload_key);
+                        let skip_reason = if checkpointer.contains(&upload_key, modified_time) {
+                            Some("checkpoint_hit")
+                        } else if pending_uploads.contains(&upload_key) {
+                            Some("pending")
                         } else {
+                            None
+                        };
+                        if let Some(reason) = skip_reason {
+                            emit!(UploadSkipped { reason, filename: &upload_key.filename });
                             finalizers.update_status(EventStatus::Delivered);
+                        } else {
+                            delay_queue.insert((upload_key.clone(), modified_time, finalizers), delay_upload);
+                            pending_uploads.insert(upload_key);
                         }
                     } else {
                         finalizers.update_status(EventStatus::Rejected);
@@ -101,7 +105,7 @@ impl StreamSink<Event> for GcsUploadFileSink {
                 }
 
                 entry = delay_queue.next(), if !delay_queue.is_empty() => {
-                    let (upload_key, finalizers) = if let Some(entry) = entry {
+                    let (upload_key, modified_time, finalizers) = if let Some(entry) = entry {
                         entry.into_inner()
                     } else {
                         // DelayQueue returns None if the queue is exhausted,
@@ -111,17 +115,21 @@ impl StreamSink<Event> for GcsUploadFileSink {
                     };
                     pending_uploads.remove(&upload_key);
 
+                    if checkpointer.contains_expired(&upload_key, modified_time) {
+                        emit!(UploadCheckpointExpired { filename: &upload_key.filename });
+                    }
+
                     let upload_time = SystemTime::now();
-                    match uploader.upload(&upload_key).await {
+                    match uploader.upload(&upload_key, modified_time, &mut checkpointer).await {
                         Ok(response) => {
                             if response.count > 0 {
-                                info!(
-                                    message = "Uploaded file.",
-                                    filename = %upload_key.filename,
-                                    bucket = %upload_key.bucket,
-                                    key = %upload_key.object_key,
-                                    size = %response.events_byte_size,
-                                );
+                                emit!(FileUploaded {
+                                    filename: &upload_key.filename,
+                                    bucket: &upload_key.bucket,
+                                    key: &upload_key.object_key,
+                                    url: &uploader.object_url(&upload_key),
+                                    size: response.events_byte_size,
+                                });
                             }
                             finalizers.update_status(EventStatus::Delivered);
                             emit!(EventsSent {
@@ -129,7 +137,7 @@ impl StreamSink<Event> for GcsUploadFileSink {
                                 byte_size: response.events_byte_size,
                                 output: None,
                             });
-                            checkpointer.update(upload_key, upload_time, expire_after);
+                            checkpointer.update(upload_key, upload_time, modified_time, expire_after);
                         }
                         Err(error) => {
                             error!(
@@ -1,27 +1,42 @@
-use std::io;
+use std::io::{self, SeekFrom};
+use std::time::{Duration, SystemTime};
 
-use common::checkpointer::UploadKey;
+use chrono::{DateTime, Utc};
+use common::checkpointer::{Checkpointer, UploadKey, UploadSession};
+use common::internal_events::UploadSkipped;
+use common::object_url::ObjectUrl;
 use http::header::HeaderName;
 use http::{HeaderValue, Request, Uri};
 use hyper::service::Service;
 use hyper::Body;
 use md5::{Digest, Md5};
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
-use vector::gcp::GcpAuthenticator;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use vector::emit;
 use vector::http::HttpClient;
 use vector::serde::json;
 use vector::sinks::gcs_common::config::BASE_URL;
 
+use crate::auth::GcsAuthenticator;
 use crate::config::GcsUploadFileSinkConfig;
 
 // limit the chunk size to 8MB to avoid OOM
 const GCS_UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
 
+// GCS expires resumable upload sessions a week after they're created
+const SESSION_EXPIRE_AFTER: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
 pub struct GCSUploader {
     client: HttpClient,
-    auth: GcpAuthenticator,
+    auth: GcsAuthenticator,
     request_settings: RequestSettings,
+    resume_uploads: bool,
+    object_url: ObjectUrl,
+}
+
+// objects are addressed with the bucket in the path
+fn gcs_object_url(base_url: &str) -> ObjectUrl {
+    ObjectUrl::new(base_url.parse().expect("invalid base URL"), true)
 }
 
 pub struct UploadResponse {
@@ -30,23 +45,44 @@ pub struct UploadResponse {
 }
 
 impl GCSUploader {
-    pub const fn new(
+    pub fn new(
         client: HttpClient,
-        auth: GcpAuthenticator,
+        auth: GcsAuthenticator,
         request_settings: RequestSettings,
+        resume_uploads: bool,
     ) -> Self {
         Self {
             client,
             auth,
             request_settings,
+            resume_uploads,
+            object_url: gcs_object_url(BASE_URL),
         }
     }
 
-    pub async fn upload(&mut self, upload_key: &UploadKey) -> io::Result<UploadResponse> {
+    /// Sends the requests to `base_url`, e.g. a mock, rather than to GCS.
+    #[cfg(test)]
+    fn with_base_url(mut self, base_url: String) -> Self {
+        self.object_url = gcs_object_url(&base_url);
+        self
+    }
+
+    /// Uploads the file of `upload_key`. With `resume_uploads`, the session of
+    /// a resumable upload is persisted in `checkpointer` as chunks are
+    /// committed, so an upload interrupted by an error or a restart continues
+    /// where it left off instead of starting over.
+    pub async fn upload(
+        &mut self,
+        upload_key: &UploadKey,
+        modified_time: SystemTime,
+        checkpointer: &mut Checkpointer,
+    ) -> io::Result<UploadResponse> {
         Ok(if self.need_upload(upload_key).await? {
             UploadResponse {
                 count: 1,
-                events_byte_size: self.do_upload(upload_key).await?,
+                events_byte_size: self
+                    .do_upload(upload_key, modified_time, checkpointer)
+                    .await?,
             }
         } else {
             UploadResponse {
@@ -59,32 +95,150 @@ impl GCSUploader {
     async fn need_upload(&mut self, upload_key: &UploadKey) -> io::Result<bool> {
         if let Some(object_hash) = self.fetch_md5_hash(upload_key).await {
             let file_hash = self.calculate_file_md5_hash(&upload_key.filename).await?;
-            Ok(object_hash != file_hash)
+            if object_hash == file_hash {
+                // GCS exposes the content md5 rather than an etag, the reason
+                // is shared with the S3 sink
+                emit!(UploadSkipped {
+                    reason: "etag_match",
+                    filename: &upload_key.filename,
+                });
+                return Ok(false);
+            }
+            Ok(true)
         } else {
             Ok(true)
         }
     }
 
-    async fn do_upload(&mut self, upload_key: &UploadKey) -> io::Result<usize> {
-        let session_uri = self.create_resumable_upload(upload_key).await?;
-        self.resumable_upload(&session_uri, &upload_key.filename)
+    async fn do_upload(
+        &mut self,
+        upload_key: &UploadKey,
+        modified_time: SystemTime,
+        checkpointer: &mut Checkpointer,
+    ) -> io::Result<usize> {
+        let (session_uri, session) = match self
+            .resumable_session(upload_key, modified_time, checkpointer)
+            .await
+        {
+            Some(resumed) => resumed,
+            None => {
+                let session_uri = self.create_resumable_upload(upload_key).await?;
+                let session = UploadSession {
+                    session_uri: session_uri.to_string(),
+                    committed_bytes: 0,
+                    modified_at: modified_time.into(),
+                    expire_at: (SystemTime::now() + SESSION_EXPIRE_AFTER).into(),
+                };
+                (session_uri, session)
+            }
+        };
+        self.resumable_upload(upload_key, &session_uri, session, checkpointer)
+            .await
+    }
+
+    /// Looks up a persisted session of `upload_key` that can still be resumed,
+    /// returning it with the offset committed by GCS. Sessions that can't be
+    /// resumed are forgotten.
+    async fn resumable_session(
+        &mut self,
+        upload_key: &UploadKey,
+        modified_time: SystemTime,
+        checkpointer: &mut Checkpointer,
+    ) -> Option<(Uri, UploadSession)> {
+        if !self.resume_uploads {
+            return None;
+        }
+        let mut session = checkpointer.session(upload_key)?.clone();
+        checkpointer.remove_session(upload_key);
+
+        let session_uri = session.session_uri.parse::<Uri>().ok()?;
+        if session.modified_at != DateTime::<Utc>::from(modified_time) {
+            // the committed bytes may no longer match the file
+            self.cancel_upload(&session_uri).await;
+            return None;
+        }
+
+        match self.query_upload_status(&session_uri).await {
+            Ok(Some(committed_bytes)) => {
+                info!(
+                    message = "Resuming upload.",
+                    filename = %upload_key.filename,
+                    key = %upload_key.object_key,
+                    %committed_bytes,
+                );
+                session.committed_bytes = committed_bytes;
+                Some((session_uri, session))
+            }
+            Ok(None) => None,
+            Err(error) => {
+                warn!(
+                    message = "Failed to query upload status, starting over.",
+                    %error,
+                    filename = %upload_key.filename,
+                );
+                None
+            }
+        }
+    }
+
+    /// Returns the bytes committed to an active session, or `None` if the
+    /// session has completed or expired.
+    async fn query_upload_status(&mut self, session_uri: &Uri) -> io::Result<Option<u64>> {
+        let mut builder = Request::put(session_uri);
+        let headers = builder.headers_mut().unwrap();
+        headers.insert("content-length", HeaderValue::from_static("0"));
+        headers.insert("content-range", HeaderValue::from_static("bytes */*"));
+
+        let mut http_request = builder.body(Body::empty()).unwrap();
+        self.auth.apply(&mut http_request).await?;
+
+        let resp = self
+            .client
+            .call(http_request)
             .await
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+
+        match resp.status().as_u16() {
+            308 => committed_bytes(resp.headers().get("range")).map(Some),
+            200 | 201 | 404 | 410 => Ok(None),
+            status => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Failed to query upload status status: {}", status),
+            )),
+        }
+    }
+
+    fn save_session(
+        &self,
+        checkpointer: &mut Checkpointer,
+        upload_key: &UploadKey,
+        session: &UploadSession,
+    ) {
+        if !self.resume_uploads {
+            return;
+        }
+        checkpointer.update_session(upload_key.clone(), session.clone());
+        if let Err(error) = checkpointer.write_checkpoints() {
+            warn!(message = "Failed to persist upload session.", %error);
+        }
+    }
+
+    /// The URL of the object `upload_key` is uploaded to, its key
+    /// percent-encoded.
+    pub fn object_url(&self, upload_key: &UploadKey) -> String {
+        self.object_url
+            .of(&upload_key.bucket, &upload_key.object_key)
     }
 
     async fn fetch_md5_hash(&mut self, upload_key: &UploadKey) -> Option<String> {
-        let uri = format!(
-            "{}{}/{}",
-            BASE_URL, upload_key.bucket, upload_key.object_key
-        )
-        .parse::<Uri>()
-        .unwrap();
+        let uri = self.object_url(upload_key).parse::<Uri>().ok()?;
 
         let mut builder = Request::head(uri);
         let headers = builder.headers_mut().unwrap();
         self.request_settings.clone().apply(headers);
 
         let mut http_request = builder.body(Body::empty()).unwrap();
-        self.auth.apply(&mut http_request);
+        self.auth.apply(&mut http_request).await.ok()?;
 
         let resp = self.client.call(http_request).await.ok()?;
         for v in resp.headers().get_all("x-goog-hash") {
@@ -112,12 +266,10 @@ impl GCSUploader {
     }
 
     async fn create_resumable_upload(&mut self, upload_key: &UploadKey) -> io::Result<Uri> {
-        let uri = format!(
-            "{}{}/{}",
-            BASE_URL, upload_key.bucket, upload_key.object_key
-        )
-        .parse::<Uri>()
-        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        let uri = self
+            .object_url(upload_key)
+            .parse::<Uri>()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
 
         let mut builder = Request::post(uri);
         let headers = builder.headers_mut().unwrap();
@@ -127,7 +279,7 @@ impl GCSUploader {
         headers.insert("x-goog-resumable", HeaderValue::from_static("start"));
 
         let mut http_request = builder.body(Body::empty()).unwrap();
-        self.auth.apply(&mut http_request);
+        self.auth.apply(&mut http_request).await?;
 
         let resp = self
             .client
@@ -158,10 +310,19 @@ impl GCSUploader {
             .map_err(|error| io::Error::new(io::ErrorKind::Other, error))
     }
 
-    async fn resumable_upload(&mut self, session_uri: &Uri, filename: &str) -> io::Result<usize> {
-        let mut file = File::open(filename).await?;
+    async fn resumable_upload(
+        &mut self,
+        upload_key: &UploadKey,
+        session_uri: &Uri,
+        mut session: UploadSession,
+        checkpointer: &mut Checkpointer,
+    ) -> io::Result<usize> {
+        self.save_session(checkpointer, upload_key, &session);
+
+        let mut file = File::open(&upload_key.filename).await?;
+        file.seek(SeekFrom::Start(session.committed_bytes)).await?;
 
-        let mut uploaded_bytes = 0;
+        let mut uploaded_bytes = session.committed_bytes as usize;
         let mut chunk = vec![];
         loop {
             chunk.clear();
@@ -178,9 +339,13 @@ impl GCSUploader {
                 .upload_chunk(session_uri, std::mem::take(&mut chunk), uploaded_bytes)
                 .await;
             match chunk_res {
-                Ok(bytes) => uploaded_bytes += bytes,
+                Ok(bytes) => {
+                    uploaded_bytes += bytes;
+                    session.committed_bytes = uploaded_bytes as u64;
+                    self.save_session(checkpointer, upload_key, &session);
+                }
                 Err(error) => {
-                    self.cancel_upload(session_uri).await;
+                    self.abort_upload(session_uri).await;
                     return Err(error);
                 }
             }
@@ -190,14 +355,25 @@ impl GCSUploader {
             .complete_upload(session_uri, chunk, uploaded_bytes)
             .await;
         match upload_res {
-            Ok(n) => Ok(uploaded_bytes + n),
+            Ok(n) => {
+                checkpointer.remove_session(upload_key);
+                Ok(uploaded_bytes + n)
+            }
             Err(error) => {
-                self.cancel_upload(session_uri).await;
+                self.abort_upload(session_uri).await;
                 Err(error)
             }
         }
     }
 
+    // A failed upload is kept for a later retry to resume if resuming is
+    // enabled, otherwise it's cancelled.
+    async fn abort_upload(&mut self, session_uri: &Uri) {
+        if !self.resume_uploads {
+            self.cancel_upload(session_uri).await;
+        }
+    }
+
     async fn upload_chunk(
         &mut self,
         session_uri: &Uri,
@@ -230,7 +406,7 @@ impl GCSUploader {
         );
 
         let mut http_request = builder.body(Body::from(chunk)).unwrap();
-        self.auth.apply(&mut http_request);
+        self.auth.apply(&mut http_request).await?;
 
         let resp = self
             .client
@@ -319,7 +495,7 @@ impl GCSUploader {
         }
 
         let mut http_request = builder.body(Body::from(chunk)).unwrap();
-        self.auth.apply(&mut http_request);
+        self.auth.apply(&mut http_request).await?;
 
         let resp = self
             .client
@@ -349,7 +525,9 @@ impl GCSUploader {
         headers.insert("content-length", HeaderValue::from_static("0"));
 
         let mut http_request = builder.body(Body::empty()).unwrap();
-        self.auth.apply(&mut http_request);
+        if self.auth.apply(&mut http_request).await.is_err() {
+            return;
+        }
 
         self.client.call(http_request).await.ok();
     }
@@ -398,6 +576,23 @@ impl RequestSettings {
     }
 }
 
+// Parse the `range` header of a resumable upload status, e.g. `bytes=0-42`,
+// into the number of committed bytes. A missing header means nothing has been
+// committed yet.
+fn committed_bytes(range: Option<&HeaderValue>) -> io::Result<u64> {
+    let range = match range {
+        Some(range) => range,
+        None => return Ok(0),
+    };
+    range
+        .to_str()
+        .ok()
+        .and_then(|r| r.split_once('-').map(|x| x.1))
+        .and_then(|r| r.parse::<u64>().ok())
+        .map(|end| end + 1)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Failed to parse range header"))
+}
+
 // Make a header pair from a key-value string pair
 fn make_header((name, value): (&String, &String)) -> vector::Result<(HeaderName, HeaderValue)> {
     Ok((
@@ -405,3 +600,234 @@ fn make_header((name, value): (&String, &String)) -> vector::Result<(HeaderName,
         HeaderValue::from_str(value)?,
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use common::mock_http::{serve, Request, Response};
+    use vector::config::ProxyConfig;
+    use vector::gcp::GcpAuthenticator;
+    use vector::tls::TlsSettings;
+
+    use super::*;
+
+    const CONTENT: &[u8] = b"hello world";
+
+    /// Answers as GCS does for an object not uploaded yet, whose resumable
+    /// uploads have committed `hello `.
+    fn mock_gcs(request: &Request) -> Response {
+        let host = request
+            .headers
+            .lines()
+            .find_map(|header| header.strip_prefix("host: "))
+            .unwrap_or_default();
+        match request.method() {
+            "HEAD" => Response::status(404),
+            "POST" => Response::ok("").header(
+                "Location",
+                format!("http://{}/upload/new-session", host.trim()),
+            ),
+            "PUT" if request.headers.contains("content-range: bytes */*") => {
+                Response::status(308).header("Range", "bytes=0-5")
+            }
+            "PUT" => Response::ok(""),
+            _ => Response::status(204),
+        }
+    }
+
+    fn uploader(endpoint: &str) -> GCSUploader {
+        let config = toml::from_str::<GcsUploadFileSinkConfig>(r#"bucket = "bucket""#).unwrap();
+        let client = HttpClient::new(
+            TlsSettings::from_options(&None).unwrap(),
+            &ProxyConfig::default(),
+        )
+        .unwrap();
+        GCSUploader::new(
+            client,
+            GcsAuthenticator::Gcp(GcpAuthenticator::None),
+            RequestSettings::new(&config).unwrap(),
+            true,
+        )
+        .with_base_url(format!("{}/", endpoint))
+    }
+
+    /// A data dir of its own for `name`, as the checkpointer locks it, holding
+    /// the file to upload. Returns its upload and modified time.
+    fn data_dir(name: &str) -> (PathBuf, UploadKey, SystemTime) {
+        let data_dir = std::env::temp_dir().join(format!("gcs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let filename = data_dir.join("file.log");
+        std::fs::write(&filename, CONTENT).unwrap();
+        let modified_time = std::fs::metadata(&filename).unwrap().modified().unwrap();
+        let upload_key = UploadKey {
+            filename: filename.to_string_lossy().into_owned(),
+            bucket: "bucket".to_owned(),
+            object_key: "file.log".to_owned(),
+        };
+        (data_dir, upload_key, modified_time)
+    }
+
+    fn session(endpoint: &str, modified_time: SystemTime) -> UploadSession {
+        UploadSession {
+            session_uri: format!("{}/upload/session", endpoint),
+            committed_bytes: 0,
+            modified_at: modified_time.into(),
+            expire_at: (SystemTime::now() + SESSION_EXPIRE_AFTER).into(),
+        }
+    }
+
+    fn lines(requests: &[Request]) -> Vec<&str> {
+        requests
+            .iter()
+            .map(|request| request.line.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn resume_from_committed_offset() {
+        let (endpoint, requests) = serve(mock_gcs).await;
+        let mut uploader = uploader(&endpoint);
+        let (data_dir, upload_key, modified_time) = data_dir("resume");
+        let mut checkpointer = Checkpointer::new(data_dir, Default::default()).unwrap();
+        checkpointer.update_session(upload_key.clone(), session(&endpoint, modified_time));
+
+        let response = uploader
+            .upload(&upload_key, modified_time, &mut checkpointer)
+            .await
+            .unwrap();
+        assert_eq!(response.count, 1);
+        assert_eq!(response.events_byte_size, CONTENT.len());
+
+        // only the bytes GCS hasn't committed are sent
+        let requests = requests.lock().unwrap();
+        assert_eq!(
+            lines(&requests),
+            [
+                "HEAD /bucket/file.log HTTP/1.1",
+                "PUT /upload/session HTTP/1.1",
+                "PUT /upload/session HTTP/1.1",
+            ]
+        );
+        assert!(requests[2].headers.contains("content-range: bytes 6-10/11"));
+        assert_eq!(requests[2].body, b"world");
+        assert!(checkpointer.session(&upload_key).is_none());
+    }
+
+    #[tokio::test]
+    async fn discard_session_of_modified_file() {
+        let (endpoint, requests) = serve(mock_gcs).await;
+        let mut uploader = uploader(&endpoint);
+        let (data_dir, upload_key, modified_time) = data_dir("discard");
+        let mut checkpointer = Checkpointer::new(data_dir, Default::default()).unwrap();
+        let modified_before = modified_time - Duration::from_secs(60);
+        checkpointer.update_session(upload_key.clone(), session(&endpoint, modified_before));
+
+        uploader
+            .upload(&upload_key, modified_time, &mut checkpointer)
+            .await
+            .unwrap();
+
+        // the session is cancelled and the whole file uploaded anew
+        let requests = requests.lock().unwrap();
+        assert_eq!(
+            lines(&requests),
+            [
+                "HEAD /bucket/file.log HTTP/1.1",
+                "DELETE /upload/session HTTP/1.1",
+                "POST /bucket/file.log HTTP/1.1",
+                "PUT /upload/new-session HTTP/1.1",
+            ]
+        );
+        assert!(requests[3].headers.contains("content-range: bytes 0-10/11"));
+        assert_eq!(requests[3].body, CONTENT);
+        assert!(checkpointer.session(&upload_key).is_none());
+    }
+
+    #[tokio::test]
+    async fn remove_session_after_completion() {
+        let failed = AtomicBool::new(false);
+        let (endpoint, requests) = serve(move |request| {
+            let completing =
+                request.method() == "PUT" && !request.headers.contains("content-range: bytes */*");
+            if completing && !failed.swap(true, Ordering::SeqCst) {
+                Response::status(503)
+            } else {
+                mock_gcs(request)
+            }
+        })
+        .await;
+        let mut uploader = uploader(&endpoint);
+        let (data_dir, upload_key, modified_time) = data_dir("complete");
+        let mut checkpointer = Checkpointer::new(data_dir.clone(), Default::default()).unwrap();
+
+        // the session of the failed upload is kept to resume it
+        assert!(uploader
+            .upload(&upload_key, modified_time, &mut checkpointer)
+            .await
+            .is_err());
+        assert_eq!(
+            checkpointer.session(&upload_key).unwrap().session_uri,
+            format!("{}/upload/new-session", endpoint)
+        );
+
+        uploader
+            .upload(&upload_key, modified_time, &mut checkpointer)
+            .await
+            .unwrap();
+        assert_eq!(
+            lines(&requests.lock().unwrap())[3..],
+            [
+                "HEAD /bucket/file.log HTTP/1.1",
+                "PUT /upload/new-session HTTP/1.1",
+                "PUT /upload/new-session HTTP/1.1",
+            ]
+        );
+        assert!(checkpointer.session(&upload_key).is_none());
+
+        // and is gone from the checkpoints persisted
+        checkpointer.write_checkpoints().unwrap();
+        drop(checkpointer);
+        let mut checkpointer = Checkpointer::new(data_dir, Default::default()).unwrap();
+        checkpointer.read_checkpoints();
+        assert!(checkpointer.session(&upload_key).is_none());
+    }
+
+    #[tokio::test]
+    async fn percent_encode_object_key() {
+        let (endpoint, requests) = serve(mock_gcs).await;
+        let mut uploader = uploader(&endpoint);
+        let (data_dir, mut upload_key, modified_time) = data_dir("encode");
+        let mut checkpointer = Checkpointer::new(data_dir, Default::default()).unwrap();
+        upload_key.object_key = "logs/tidb 1?.log".to_owned();
+
+        uploader
+            .upload(&upload_key, modified_time, &mut checkpointer)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            lines(&requests.lock().unwrap())[..2],
+            [
+                "HEAD /bucket/logs/tidb%201%3F.log HTTP/1.1",
+                "POST /bucket/logs/tidb%201%3F.log HTTP/1.1",
+            ]
+        );
+        assert_eq!(
+            uploader.object_url(&upload_key),
+            format!("{}/bucket/logs/tidb%201%3F.log", endpoint)
+        );
+    }
+
+    #[test]
+    fn parse_committed_bytes() {
+        assert_eq!(committed_bytes(None).unwrap(), 0);
+        assert_eq!(
+            committed_bytes(Some(&HeaderValue::from_static("bytes=0-8388607"))).unwrap(),
+            8 * 1024 * 1024
+        );
+        assert!(committed_bytes(Some(&HeaderValue::from_static("bytes=0"))).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+//! Compares the peak memory of keeping the series parsed from TiDB, with
+//! labels copied, interned per response after parsing as previously done, and
+//! interned while parsing. Run with
+//! `cargo bench -p topsql --features bench --bench intern_labels`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::Bytes;
+use topsql::bench::{
+    LabelInterner, ParserOptions, RespOneof, TopSqlRecord, TopSqlRecordItem, TopSqlSubResponse,
+    TopSqlSubResponseParser, UpstreamEventParser,
+};
+use vector::event::{LogEvent, Value};
+
+const MINUTES: u64 = 10;
+const DIGESTS: u64 = 1000;
+
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(allocated, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// A response per digest and minute, as TiDB reports them.
+fn responses() -> Vec<TopSqlSubResponse> {
+    (0..MINUTES)
+        .flat_map(|minute| {
+            (0..DIGESTS).map(move |digest| TopSqlSubResponse {
+                resp_oneof: Some(RespOneof::Record(TopSqlRecord {
+                    sql_digest: format!("sql_digest_{:022}", digest).into_bytes(),
+                    plan_digest: format!("plan_digest_{:021}", digest).into_bytes(),
+                    items: (0..60)
+                        .map(|second| TopSqlRecordItem {
+                            timestamp_sec: 1661396787 + minute * 60 + second,
+                            cpu_time_ms: 10,
+                            stmt_exec_count: 20,
+                            stmt_kv_exec_count: (0..3)
+                                .map(|tikv| (format!("127.0.0.{}:20160", tikv), 10))
+                                .collect(),
+                            stmt_duration_sum_ns: 30,
+                            stmt_duration_count: 20,
+                        })
+                        .collect(),
+                })),
+            })
+        })
+        .collect()
+}
+
+/// Makes equal labels of the series of a response share one buffer, as the
+/// source did before interning while parsing.
+fn intern_response(events: &mut [LogEvent]) {
+    let mut interned = HashMap::<Bytes, Bytes>::new();
+    for event in events {
+        let labels = match event.get_mut("labels") {
+            Some(Value::Object(labels)) => labels,
+            _ => continue,
+        };
+        for value in labels.values_mut() {
+            if let Value::Bytes(bytes) = value {
+                match interned.get(bytes) {
+                    Some(shared) => *bytes = shared.clone(),
+                    None => {
+                        interned.insert(bytes.clone(), bytes.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Parses all `responses` and keeps their series, returning the peak memory
+/// allocated meanwhile.
+fn peak_memory(intern_while_parsing: bool, intern_per_response: bool) -> usize {
+    let responses = responses();
+    let base = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(base, Ordering::Relaxed);
+
+    let mut interner = LabelInterner::new(intern_while_parsing);
+    let mut kept = vec![];
+    for response in responses {
+        let mut events = TopSqlSubResponseParser::parse(
+            response,
+            "127.0.0.1:10080".to_owned(),
+            &ParserOptions::default(),
+            &mut interner,
+        );
+        if intern_per_response {
+            intern_response(&mut events);
+        }
+        kept.push(events);
+    }
+    assert_eq!(
+        kept.iter().map(Vec::len).sum::<usize>(),
+        (MINUTES * DIGESTS * 7) as usize
+    );
+    PEAK.load(Ordering::Relaxed) - base
+}
+
+fn main() {
+    let copied = peak_memory(false, false);
+    let per_response = peak_memory(false, true);
+    let while_parsing = peak_memory(true, false);
+
+    let mib = |bytes: usize| bytes as f64 / (1 << 20) as f64;
+    println!("copied:             {:>8.1} MiB", mib(copied));
+    println!("interned/response:  {:>8.1} MiB", mib(per_response));
+    println!("interned/parsing:   {:>8.1} MiB", mib(while_parsing));
+    assert!(while_parsing < per_response);
+}
@@ -0,0 +1,152 @@
+//! Compares the CPU time of parsing records of many distinct digests, each
+//! spread over several series, with the labels of every series copied as
+//! previously done, and shared by the series of a record. Run with
+//! `cargo bench -p topsql --features bench --bench tidb_parser`.
+
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use topsql::bench::{
+    make_metric_like_log_event, LabelInterner, ParserOptions, RespOneof, TopSqlRecord,
+    TopSqlRecordItem, TopSqlSubResponse, TopSqlSubResponseParser, UpstreamEventParser,
+};
+use vector::event::LogEvent;
+
+const DIGESTS: u64 = 10_000;
+const ROUNDS: usize = 10;
+const INSTANCE: &str = "127.0.0.1:10080";
+
+fn records() -> Vec<TopSqlRecord> {
+    (0..DIGESTS)
+        .map(|digest| TopSqlRecord {
+            sql_digest: format!("sql_digest_{:022}", digest).into_bytes(),
+            plan_digest: format!("plan_digest_{:021}", digest).into_bytes(),
+            items: (0..60)
+                .map(|second| TopSqlRecordItem {
+                    timestamp_sec: 1661396787 + second,
+                    cpu_time_ms: 10,
+                    stmt_exec_count: 20,
+                    stmt_kv_exec_count: (0..3)
+                        .map(|tikv| (format!("127.0.0.{}:20160", tikv), 10))
+                        .collect(),
+                    stmt_duration_sum_ns: 30,
+                    stmt_duration_count: 20,
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+fn parse(record: TopSqlRecord) -> Vec<LogEvent> {
+    TopSqlSubResponseParser::parse(
+        TopSqlSubResponse {
+            resp_oneof: Some(RespOneof::Record(record)),
+        },
+        INSTANCE.to_owned(),
+        &ParserOptions::default(),
+        &mut LabelInterner::default(),
+    )
+}
+
+/// Parses `record` as previously done, copying the labels, digests included,
+/// into every series built.
+fn parse_copying(record: TopSqlRecord) -> Vec<LogEvent> {
+    let mut labels = vec![
+        ("__name__", String::new()),
+        ("instance", INSTANCE.to_owned()),
+        ("instance_type", "tidb".to_owned()),
+        ("sql_digest", hex::encode_upper(&record.sql_digest)),
+        ("plan_digest", hex::encode_upper(&record.plan_digest)),
+        ("tag_label", String::new()),
+    ];
+    let mut events = vec![];
+    let mut build = |labels: &[(&'static str, String)], points: Vec<(u64, f64)>| {
+        if points.is_empty() {
+            return;
+        }
+        let timestamps = points
+            .iter()
+            .map(|(timestamp_sec, _)| {
+                DateTime::<Utc>::from_utc(
+                    NaiveDateTime::from_timestamp(*timestamp_sec as i64, 0),
+                    Utc,
+                )
+            })
+            .collect::<Vec<_>>();
+        let values = points.iter().map(|(_, value)| *value).collect::<Vec<_>>();
+        events.push(make_metric_like_log_event(labels, &timestamps, &values));
+    };
+
+    let series: [(&str, fn(&TopSqlRecordItem) -> u64); 4] = [
+        ("topsql_cpu_time_ms", |item| item.cpu_time_ms as u64),
+        ("topsql_stmt_exec_count", |item| item.stmt_exec_count),
+        ("topsql_stmt_duration_sum_ns", |item| {
+            item.stmt_duration_sum_ns
+        }),
+        ("topsql_stmt_duration_count", |item| {
+            item.stmt_duration_count
+        }),
+    ];
+    for (name, value) in series {
+        labels[0].1 = name.to_owned();
+        let points = record
+            .items
+            .iter()
+            .map(|item| (item.timestamp_sec, value(item) as f64))
+            .filter(|(_, value)| *value > 0.0)
+            .collect();
+        build(&labels, points);
+    }
+
+    labels[0].1 = "topsql_stmt_exec_count".to_owned();
+    labels[2].1 = "tikv".to_owned();
+    for address in record
+        .items
+        .iter()
+        .flat_map(|item| item.stmt_kv_exec_count.keys())
+        .collect::<BTreeSet<_>>()
+    {
+        labels[1].1 = address.clone();
+        let points = record
+            .items
+            .iter()
+            .filter_map(|item| {
+                let count = item.stmt_kv_exec_count.get(address)?;
+                Some((item.timestamp_sec, *count as f64))
+            })
+            .filter(|(_, count)| *count > 0.0)
+            .collect();
+        build(&labels, points);
+    }
+
+    events
+}
+
+/// The least time `parse` takes to parse all records over the rounds.
+fn time(parse: fn(TopSqlRecord) -> Vec<LogEvent>) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let records = records();
+            let start = Instant::now();
+            let series = records.into_iter().map(parse).map(|events| events.len());
+            assert_eq!(series.sum::<usize>(), (DIGESTS * 7) as usize);
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    assert_eq!(
+        records().into_iter().map(parse).collect::<Vec<_>>(),
+        records().into_iter().map(parse_copying).collect::<Vec<_>>(),
+    );
+
+    let copying = time(parse_copying);
+    let sharing = time(parse);
+    let rate = |elapsed: Duration| (DIGESTS * 7) as f64 / elapsed.as_secs_f64();
+    println!("copied: {:>10?}, {:>10.0} series/s", copying, rate(copying));
+    println!("shared: {:>10?}, {:>10.0} series/s", sharing, rate(sharing));
+    assert!(sharing < copying);
+}
@@ -1,11 +1,18 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
 use std::time::Duration;
 
+use common::startup_guard::startup_guard;
 use serde::{Deserialize, Serialize};
 use vector::config::{self, GenerateConfig, Output, SourceConfig, SourceContext};
 use vector::sources;
 use vector::tls::TlsConfig;
 
 use crate::controller::Controller;
+use crate::topology::EtcdOptions;
+use crate::upstream::parser::ParserOptions;
+use crate::upstream::SourceOptions;
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct TopSQLConfig {
@@ -16,6 +23,217 @@ pub struct TopSQLConfig {
     pub init_retry_delay_seconds: f64,
     #[serde(default = "default_topology_fetch_interval")]
     pub topology_fetch_interval_seconds: f64,
+
+    /// How long the source waits after startup before fetching the topology
+    /// and connecting to any instance, e.g. to let a cluster restarted along
+    /// with the agent settle first. Starts right away by default.
+    #[serde(default)]
+    pub startup_delay_seconds: f64,
+
+    /// The shape of emitted events. `log` keeps the `labels/timestamps/values`
+    /// layout expected by `vm_import`, `metric` emits Vector native metrics.
+    #[serde(default)]
+    pub output_format: OutputFormat,
+
+    /// Emit the per-TiKV execution counts reported by TiDB as
+    /// `topsql_stmt_kv_exec_count`, instead of sharing `topsql_stmt_exec_count`
+    /// with the TiDB execution counts.
+    #[serde(default)]
+    pub separate_stmt_kv_exec_count: bool,
+
+    /// Override the TopSQL port advertised by the topology, e.g. when the
+    /// pubsub service sits behind a sidecar on a fixed port.
+    pub tidb_topsql_port: Option<u16>,
+    pub tikv_topsql_port: Option<u16>,
+
+    /// How TiDB and TiKV instances are connected to, `auto` (default) with TLS
+    /// if `tls` is set, `tls` to require it, failing to build without `tls`,
+    /// or `plaintext` to connect without TLS even if `tls` is set, e.g. while
+    /// only TiKV is migrated to TLS. Takes precedence over `tls` for the
+    /// instance type it's set for, PD is always connected to as `tls` says.
+    #[serde(default)]
+    pub tidb_transport: Transport,
+    #[serde(default)]
+    pub tikv_transport: Transport,
+
+    /// Reconnect to an instance if its subscription delivers no records for
+    /// this long, recovering from half-open connections that neither error nor
+    /// close. Set it well above the reporting interval of the instances, which
+    /// is 1 minute by default. `0` disables it.
+    #[serde(default = "default_max_idle")]
+    pub max_idle_secs: f64,
+
+    /// Emit a snapshot of the fetched topology, i.e. the `instance_type`,
+    /// `host` and ports of every component, under `topology` once every
+    /// topology fetch, for tracking cluster membership over time. The
+    /// snapshots are not metric-like, so route them away from `vm_import`.
+    /// Requires the `log` output format.
+    #[serde(default)]
+    pub emit_topology: bool,
+
+    /// The compression accepted for the subscription streams, `none`, `gzip`
+    /// or `auto`, which advertises every supported codec and lets the server
+    /// choose. Servers not supporting any of them answer uncompressed.
+    ///
+    /// Only gzip is supported, zstd needs tonic 0.9 or later (with its `zstd`
+    /// feature), while this crate builds with tonic 0.7 and its `compression`
+    /// feature.
+    #[serde(default)]
+    pub stream_compression: StreamCompression,
+
+    /// Drop records carrying more items, i.e. per-second points, than this,
+    /// with a warning counted by `topsql_records_dropped_total`. Guards the
+    /// agent against a malformed or malicious upstream sending an enormous
+    /// record. Instances report a record per minute, so the default of 10000
+    /// leaves plenty of headroom. `0` disables it.
+    #[serde(default = "default_max_items_per_record")]
+    pub max_items_per_record: usize,
+
+    /// How often the `tls` CA, certificate and key files are checked for
+    /// changes, reconnecting every instance once they do, so rotated
+    /// certificates (e.g. short-lived ones issued by cert-manager) are picked
+    /// up without a restart. `0` disables it.
+    #[serde(default = "default_tls_reload_interval")]
+    pub tls_reload_interval_seconds: f64,
+
+    /// The credentials of PD's etcd, which the TiDB topology is read from, for
+    /// clusters with etcd authentication enabled. Both or neither must be set.
+    pub etcd_username: Option<String>,
+    pub etcd_password: Option<Secret>,
+
+    /// How long connecting to PD's etcd may take before failing. Unlimited by
+    /// default.
+    pub etcd_connect_timeout_secs: Option<f64>,
+
+    /// Emit every `TopSqlSubResponse` of TiDB and `ResourceUsageRecord` of
+    /// TiKV unparsed, as its protobuf encoding in base64 under `raw`, along
+    /// with `instance`, `instance_type` and `timestamp`, for archiving and
+    /// replaying the stream through the parser offline. `instance_type` tells
+    /// which message `raw` holds. Requires the `log` output format.
+    #[serde(default)]
+    pub raw_passthrough: bool,
+
+    /// Emit, every 30 seconds along with `topsql_instance`, how long the
+    /// subscription of every upstream has been up as
+    /// `topsql_self_connected_seconds`, and how many records it has sent since
+    /// the source started as `topsql_self_records_total`, so the health of the
+    /// source can be tracked in VictoriaMetrics next to the data it collects.
+    #[serde(default)]
+    pub emit_self_metrics: bool,
+
+    /// Make the series parsed from an instance share a single copy of every
+    /// distinct label value, e.g. metric names, instances and digests, across
+    /// responses, shrinking the memory of batches in flight or buffered in
+    /// memory. Up to 100k values are kept per instance. Only applies to the
+    /// `log` output format, metrics own their tags. Disk buffers serialize
+    /// every copy either way.
+    #[serde(default)]
+    pub intern_labels: bool,
+
+    /// Label every series with its metric type under `__type__`, `counter`
+    /// for the per-second resource usage such as `topsql_cpu_time_ms`,
+    /// `topsql_read_keys` and `topsql_stmt_exec_count`, or `gauge` for meta
+    /// and instance series, so they can be aggregated correctly once
+    /// converted into metrics downstream. Requires the `log` output format,
+    /// the `metric` output format tells them apart by their kind already.
+    #[serde(default)]
+    pub emit_metric_type: bool,
+
+    /// Display names of instances by their address, e.g.
+    /// `"10.0.1.5:10080" = "tidb-0"`, replacing the `instance` label of their
+    /// series, with the address kept under `address` for joins. Addresses are
+    /// the ones series carry, which for TiKV is the TopSQL address, e.g.
+    /// `10.0.1.6:20180`. Instances without an alias keep their address.
+    #[serde(default)]
+    pub instance_aliases: BTreeMap<String, String>,
+
+    /// Queue up to this many events of every instance between parsing its
+    /// responses and sending them, so its subscription keeps being read while
+    /// the downstream is slow, rather than the upstream buffering and
+    /// eventually resetting the stream. Queued events are still sent on
+    /// shutdown. `0` (default) sends every response before reading the next
+    /// one.
+    #[serde(default)]
+    pub send_queue_size: usize,
+
+    /// What a full send queue does, `drop_oldest` (default) drops its oldest
+    /// events, counted by `topsql_samples_dropped_total`, favoring fresh
+    /// samples, while `block` stops reading the subscription until there's
+    /// room again.
+    #[serde(default)]
+    pub send_queue_policy: SendQueuePolicy,
+}
+
+/// A secret kept out of the `Debug` output, so it's never logged along with
+/// the config.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"**REDACTED**\"")
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    Log,
+    Metric,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        Self::Log
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    Auto,
+    Tls,
+    Plaintext,
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Self::Auto
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamCompression {
+    None,
+    Gzip,
+    Auto,
+}
+
+impl Default for StreamCompression {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl StreamCompression {
+    pub fn accepts_gzip(self) -> bool {
+        matches!(self, Self::Gzip | Self::Auto)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SendQueuePolicy {
+    DropOldest,
+    Block,
+}
+
+impl Default for SendQueuePolicy {
+    fn default() -> Self {
+        Self::DropOldest
+    }
 }
 
 pub const fn default_init_retry_delay() -> f64 {
@@ -26,6 +244,18 @@ pub const fn default_topology_fetch_interval() -> f64 {
     30.0
 }
 
+pub const fn default_max_idle() -> f64 {
+    300.0
+}
+
+pub const fn default_max_items_per_record() -> usize {
+    10_000
+}
+
+pub const fn default_tls_reload_interval() -> f64 {
+    30.0
+}
+
 impl GenerateConfig for TopSQLConfig {
     fn generate_config() -> toml::Value {
         toml::Value::try_from(Self {
@@ -33,6 +263,28 @@ impl GenerateConfig for TopSQLConfig {
             tls: None,
             init_retry_delay_seconds: default_init_retry_delay(),
             topology_fetch_interval_seconds: default_topology_fetch_interval(),
+            startup_delay_seconds: 0.0,
+            output_format: OutputFormat::default(),
+            separate_stmt_kv_exec_count: false,
+            tidb_topsql_port: None,
+            tikv_topsql_port: None,
+            tidb_transport: Transport::default(),
+            tikv_transport: Transport::default(),
+            max_idle_secs: default_max_idle(),
+            emit_topology: false,
+            stream_compression: StreamCompression::default(),
+            max_items_per_record: default_max_items_per_record(),
+            tls_reload_interval_seconds: default_tls_reload_interval(),
+            etcd_username: None,
+            etcd_password: None,
+            etcd_connect_timeout_secs: None,
+            raw_passthrough: false,
+            emit_self_metrics: false,
+            intern_labels: false,
+            emit_metric_type: false,
+            instance_aliases: BTreeMap::new(),
+            send_queue_size: 0,
+            send_queue_policy: SendQueuePolicy::default(),
         })
         .unwrap()
     }
@@ -43,31 +295,91 @@ impl GenerateConfig for TopSQLConfig {
 impl SourceConfig for TopSQLConfig {
     async fn build(&self, cx: SourceContext) -> vector::Result<sources::Source> {
         self.validate_tls()?;
+        self.validate_ports()?;
+        self.validate_transports()?;
+        let etcd_options = self.etcd_options()?;
+        let max_idle = optional_duration("max_idle_secs", self.max_idle_secs)?;
+        let tls_reload_interval = optional_duration(
+            "tls_reload_interval_seconds",
+            self.tls_reload_interval_seconds,
+        )?;
+        if self.emit_topology && self.output_format != OutputFormat::Log {
+            return Err("`emit_topology` requires the `log` output format.".into());
+        }
+        if self.raw_passthrough && self.output_format != OutputFormat::Log {
+            return Err("`raw_passthrough` requires the `log` output format.".into());
+        }
+        if self.emit_metric_type && self.output_format != OutputFormat::Log {
+            return Err("`emit_metric_type` requires the `log` output format.".into());
+        }
+        if !self.startup_delay_seconds.is_finite() || self.startup_delay_seconds < 0.0 {
+            return Err("`startup_delay_seconds` can't be negative.".into());
+        }
 
         let pd_address = self.pd_address.clone();
         let tls = self.tls.clone();
         let topology_fetch_interval = Duration::from_secs_f64(self.topology_fetch_interval_seconds);
         let init_retry_delay = Duration::from_secs_f64(self.init_retry_delay_seconds);
+        let startup_delay = Duration::from_secs_f64(self.startup_delay_seconds);
+        let source_options = SourceOptions {
+            output_format: self.output_format,
+            parser: ParserOptions {
+                separate_stmt_kv_exec_count: self.separate_stmt_kv_exec_count,
+                max_items_per_record: if self.max_items_per_record > 0 {
+                    Some(self.max_items_per_record)
+                } else {
+                    None
+                },
+                tikv_topsql_port: self.tikv_topsql_port,
+            },
+            tidb_topsql_port: self.tidb_topsql_port,
+            tikv_topsql_port: self.tikv_topsql_port,
+            tidb_transport: self.tidb_transport,
+            tikv_transport: self.tikv_transport,
+            stream_compression: self.stream_compression,
+            max_idle,
+            tls_reload_interval,
+            raw_passthrough: self.raw_passthrough,
+            emit_self_metrics: self.emit_self_metrics,
+            intern_labels: self.intern_labels,
+            emit_metric_type: self.emit_metric_type,
+            instance_aliases: Arc::new(self.instance_aliases.clone()),
+            send_queue: (self.send_queue_size > 0)
+                .then(|| (self.send_queue_size, self.send_queue_policy)),
+        };
+        let emit_topology = self.emit_topology;
         Ok(Box::pin(async move {
+            let mut shutdown = cx.shutdown;
+            tokio::select! {
+                _ = startup_guard("topsql", startup_delay) => {},
+                _ = &mut shutdown => return Ok(()),
+            }
+
             let controller = Controller::new(
                 pd_address,
                 topology_fetch_interval,
                 init_retry_delay,
                 tls,
+                etcd_options,
                 &cx.proxy,
+                source_options,
+                emit_topology,
                 cx.out,
             )
             .await
             .map_err(|error| error!(message = "Source failed.", %error))?;
 
-            controller.run(cx.shutdown).await;
+            controller.run(shutdown).await;
 
             Ok(())
         }))
     }
 
     fn outputs(&self) -> Vec<Output> {
-        vec![Output::default(config::DataType::Log)]
+        match self.output_format {
+            OutputFormat::Log => vec![Output::default(config::DataType::Log)],
+            OutputFormat::Metric => vec![Output::default(config::DataType::Metric)],
+        }
     }
 
     fn source_type(&self) -> &'static str {
@@ -99,6 +411,52 @@ impl TopSQLConfig {
         Ok(())
     }
 
+    fn validate_ports(&self) -> vector::Result<()> {
+        for (name, port) in [
+            ("tidb_topsql_port", self.tidb_topsql_port),
+            ("tikv_topsql_port", self.tikv_topsql_port),
+        ] {
+            if port == Some(0) {
+                return Err(format!("{} should be in range 1-65535.", name).into());
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_transports(&self) -> vector::Result<()> {
+        for (name, transport) in [
+            ("tidb_transport", self.tidb_transport),
+            ("tikv_transport", self.tikv_transport),
+        ] {
+            if transport == Transport::Tls && self.tls.is_none() {
+                return Err(format!("`{} = \"tls\"` requires `tls` to be set.", name).into());
+            }
+        }
+        Ok(())
+    }
+
+    fn etcd_options(&self) -> vector::Result<EtcdOptions> {
+        let credentials = match (&self.etcd_username, &self.etcd_password) {
+            (Some(username), Some(Secret(password))) => Some((username.clone(), password.clone())),
+            (None, None) => None,
+            _ => {
+                return Err(
+                    "`etcd_username` and `etcd_password` should be configured together.".into(),
+                )
+            }
+        };
+        let connect_timeout = match self.etcd_connect_timeout_secs {
+            Some(secs) if !secs.is_finite() || secs <= 0.0 => {
+                return Err("`etcd_connect_timeout_secs` should be positive.".into())
+            }
+            secs => secs.map(Duration::from_secs_f64),
+        };
+        Ok(EtcdOptions {
+            credentials,
+            connect_timeout,
+        })
+    }
+
     fn check_key_file(
         tag: &str,
         path: &Option<std::path::PathBuf>,
@@ -113,6 +471,15 @@ impl TopSQLConfig {
     }
 }
 
+/// `secs` as a duration, or `None` if it isn't positive, failing on values
+/// that don't fit a duration, e.g. `inf`.
+fn optional_duration(name: &str, secs: f64) -> vector::Result<Option<Duration>> {
+    if !secs.is_finite() || secs >= u64::MAX as f64 {
+        return Err(format!("`{}` should be a finite number of seconds.", name).into());
+    }
+    Ok((secs > 0.0).then(|| Duration::from_secs_f64(secs)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -121,4 +488,105 @@ mod tests {
     fn generate_config() {
         vector::test_util::test_generate_config::<TopSQLConfig>();
     }
+
+    #[test]
+    fn validate_topsql_ports() {
+        let parse = |ports: &str| {
+            toml::from_str::<TopSQLConfig>(&format!("pd_address = \"127.0.0.1:2379\"\n{}", ports))
+        };
+
+        let config = parse("tidb_topsql_port = 10080\ntikv_topsql_port = 20160").unwrap();
+        assert!(config.validate_ports().is_ok());
+        let config = parse("tidb_topsql_port = 0").unwrap();
+        assert!(config.validate_ports().is_err());
+        assert!(parse("tikv_topsql_port = 65536").is_err());
+    }
+
+    #[test]
+    fn validate_transports() {
+        let parse = |transports: &str| {
+            toml::from_str::<TopSQLConfig>(&format!(
+                "pd_address = \"127.0.0.1:2379\"\n{}",
+                transports
+            ))
+            .unwrap()
+        };
+
+        let config = parse("tidb_transport = \"plaintext\"\ntikv_transport = \"auto\"");
+        assert!(config.validate_transports().is_ok());
+        // never falls back to plaintext without `tls`
+        let config = parse("tikv_transport = \"tls\"");
+        assert!(config.validate_transports().is_err());
+        let config = parse("tikv_transport = \"tls\"\n[tls]");
+        assert!(config.validate_transports().is_ok());
+    }
+
+    #[test]
+    fn generated_config_leaves_etcd_unauthenticated() {
+        let config = toml::from_str::<TopSQLConfig>(
+            &toml::to_string(&TopSQLConfig::generate_config()).unwrap(),
+        )
+        .unwrap();
+        let options = config.etcd_options().unwrap();
+        assert!(options.credentials.is_none());
+        assert!(options.connect_timeout.is_none());
+    }
+
+    #[test]
+    fn validate_etcd_options() {
+        let parse = |options: &str| {
+            toml::from_str::<TopSQLConfig>(&format!("pd_address = \"127.0.0.1:2379\"\n{}", options))
+                .unwrap()
+        };
+
+        let config = parse(
+            "etcd_username = \"root\"\netcd_password = \"hunter2\"\netcd_connect_timeout_secs = 5",
+        );
+        let options = config.etcd_options().unwrap();
+        assert_eq!(
+            options.credentials,
+            Some(("root".to_owned(), "hunter2".to_owned()))
+        );
+        assert_eq!(options.connect_timeout, Some(Duration::from_secs(5)));
+        // never logged
+        assert!(!format!("{:?}", config).contains("hunter2"));
+
+        assert!(parse("etcd_username = \"root\"").etcd_options().is_err());
+        assert!(parse("etcd_password = \"hunter2\"").etcd_options().is_err());
+        assert!(parse("etcd_connect_timeout_secs = 0")
+            .etcd_options()
+            .is_err());
+    }
+
+    #[test]
+    fn validate_max_idle() {
+        assert_eq!(
+            optional_duration("max_idle_secs", 90.0).unwrap(),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(optional_duration("max_idle_secs", 0.0).unwrap(), None);
+        for secs in [f64::INFINITY, f64::NAN, 1e30] {
+            assert!(
+                optional_duration("max_idle_secs", secs).is_err(),
+                "{}",
+                secs
+            );
+        }
+    }
+
+    #[test]
+    fn parse_stream_compression() {
+        let parse = |compression: &str| {
+            toml::from_str::<TopSQLConfig>(&format!(
+                "pd_address = \"127.0.0.1:2379\"\nstream_compression = \"{}\"",
+                compression
+            ))
+            .map(|config| config.stream_compression)
+        };
+
+        assert_eq!(parse("none").unwrap(), StreamCompression::None);
+        assert_eq!(parse("gzip").unwrap(), StreamCompression::Gzip);
+        assert_eq!(parse("auto").unwrap(), StreamCompression::Auto);
+        assert!(parse("zstd").is_err());
+    }
 }
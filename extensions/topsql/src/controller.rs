@@ -1,15 +1,21 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::time::Duration;
 
+use chrono::Utc;
+use tokio::sync::watch;
 use tracing::instrument::Instrument;
-use vector::config::ProxyConfig;
+use vector::config::{log_schema, ProxyConfig};
+use vector::event::{Event, LogEvent, Value};
+use vector::internal_events::StreamClosedError;
 use vector::shutdown::ShutdownSignal;
 use vector::tls::TlsConfig;
 use vector::SourceSender;
+use vector_core::internal_event::InternalEvent;
 
+use crate::internal_events::TopSQLRunningComponents;
 use crate::shutdown::{pair, ShutdownNotifier, ShutdownSubscriber};
-use crate::topology::{Component, FetchError, TopologyFetcher};
-use crate::upstream::TopSQLSource;
+use crate::topology::{Component, EtcdOptions, FetchError, InstanceType, TopologyFetcher};
+use crate::upstream::{watch_cert_files, SourceOptions, TopSQLSource};
 
 pub struct Controller {
     topo_fetch_interval: Duration,
@@ -23,22 +29,36 @@ pub struct Controller {
 
     tls: Option<TlsConfig>,
     init_retry_delay: Duration,
+    source_options: SourceOptions,
+    emit_topology: bool,
+    cert_changes: Option<watch::Receiver<()>>,
 
     out: SourceSender,
 }
 
 impl Controller {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         pd_address: String,
         topo_fetch_interval: Duration,
         init_retry_delay: Duration,
         tls_config: Option<TlsConfig>,
+        etcd_options: EtcdOptions,
         proxy_config: &ProxyConfig,
+        source_options: SourceOptions,
+        emit_topology: bool,
         out: SourceSender,
     ) -> vector::Result<Self> {
         let topo_fetcher =
-            TopologyFetcher::new(pd_address, tls_config.clone(), proxy_config).await?;
+            TopologyFetcher::new(pd_address, tls_config.clone(), etcd_options, proxy_config)
+                .await?;
         let (shutdown_notifier, shutdown_subscriber) = pair();
+        let cert_changes = match (&tls_config, source_options.tls_reload_interval) {
+            (Some(tls_config), Some(interval)) => {
+                watch_cert_files(tls_config, interval, shutdown_subscriber.clone())
+            }
+            _ => None,
+        };
         Ok(Self {
             topo_fetch_interval,
             topo_fetcher,
@@ -48,6 +68,9 @@ impl Controller {
             shutdown_subscriber,
             tls: tls_config,
             init_retry_delay,
+            source_options,
+            emit_topology,
+            cert_changes,
             out,
         })
     }
@@ -66,14 +89,19 @@ impl Controller {
         loop {
             let res = self.fetch_and_update().await;
             match res {
-                Ok(has_change) if has_change => {
-                    info!(message = "Topology has changed.", latest_components = ?self.components);
+                Ok(has_change) => {
+                    if has_change {
+                        info!(message = "Topology has changed.", latest_components = ?self.components);
+                    }
+                    if self.emit_topology {
+                        self.send_topology().await;
+                    }
                 }
                 Err(error) => {
                     error!(message = "Failed to fetch topology.", error = %error);
                 }
-                _ => {}
             }
+            self.emit_running_components();
 
             tokio::time::sleep(self.topo_fetch_interval).await;
         }
@@ -112,19 +140,22 @@ impl Controller {
             self.tls.clone(),
             self.out.clone(),
             self.init_retry_delay,
+            self.source_options.clone(),
+            self.cert_changes.clone(),
         );
         let source = match source {
             Some(source) => source,
             None => return false,
         };
 
+        let transport = source.transport();
         let (shutdown_notifier, shutdown_subscriber) = self.shutdown_subscriber.extend();
         tokio::spawn(
             source
                 .run(shutdown_subscriber)
                 .instrument(tracing::info_span!("topsql_source", topsql_source = %component)),
         );
-        info!(message = "Started TopSQL source.", topsql_source = %component);
+        info!(message = "Started TopSQL source.", topsql_source = %component, %transport);
         self.running_components
             .insert(component.clone(), shutdown_notifier);
 
@@ -144,6 +175,28 @@ impl Controller {
         true
     }
 
+    async fn send_topology(&mut self) {
+        let event = topology_event(&self.components);
+        if let Err(error) = self.out.send_batch(vec![Event::from(event)]).await {
+            StreamClosedError { error, count: 1 }.emit();
+        }
+    }
+
+    fn emit_running_components(&self) {
+        for instance_type in [InstanceType::TiDB, InstanceType::TiKV] {
+            let count = self
+                .running_components
+                .keys()
+                .filter(|component| component.instance_type == instance_type)
+                .count();
+            TopSQLRunningComponents {
+                instance_type,
+                count,
+            }
+            .emit();
+        }
+    }
+
     async fn shutdown_all_components(self) {
         for (component, shutdown_notifier) in self.running_components {
             info!(message = "Shutting down TopSQL source.", topsql_source = %component);
@@ -157,3 +210,93 @@ impl Controller {
         info!(message = "All TopSQL sources have been shut down.");
     }
 }
+
+/// A snapshot of the components last fetched from the topology, sorted for a
+/// stable layout, so downstream can track cluster membership over time.
+fn topology_event(components: &HashSet<Component>) -> LogEvent {
+    let mut components = components.iter().collect::<Vec<_>>();
+    components.sort_by_key(|component| {
+        (
+            component.instance_type.to_string(),
+            component.host.clone(),
+            component.primary_port,
+            component.secondary_port,
+        )
+    });
+    let components = components
+        .into_iter()
+        .map(|component| {
+            let mut object = BTreeMap::new();
+            object.insert(
+                "instance_type".to_owned(),
+                Value::from(component.instance_type.to_string()),
+            );
+            object.insert("host".to_owned(), Value::from(component.host.clone()));
+            object.insert(
+                "primary_port".to_owned(),
+                Value::Integer(component.primary_port as i64),
+            );
+            object.insert(
+                "secondary_port".to_owned(),
+                Value::Integer(component.secondary_port as i64),
+            );
+            Value::Object(object)
+        })
+        .collect::<Vec<_>>();
+
+    let mut log = LogEvent::default();
+    log.insert("topology", Value::Array(components));
+    log.insert(log_schema().timestamp_key(), Utc::now());
+    log
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topology_snapshot() {
+        let component = |instance_type, host: &str, primary_port, secondary_port| Component {
+            instance_type,
+            host: host.to_owned(),
+            primary_port,
+            secondary_port,
+        };
+        let components = [
+            component(InstanceType::TiKV, "kv-0", 20160, 20180),
+            component(InstanceType::TiDB, "db-1", 4000, 10080),
+            component(InstanceType::TiDB, "db-0", 4000, 10080),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let event = topology_event(&components);
+        assert!(event.get(log_schema().timestamp_key()).is_some());
+
+        let topology = event.get("topology").unwrap().as_array().unwrap();
+        let topology = topology
+            .iter()
+            .map(|component| {
+                let field = |name: &str| component.as_object().unwrap()[name].to_string_lossy();
+                format!(
+                    "{} {}:{}/{}",
+                    field("instance_type"),
+                    field("host"),
+                    field("primary_port"),
+                    field("secondary_port"),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            topology,
+            vec![
+                "tidb db-0:4000/10080",
+                "tidb db-1:4000/10080",
+                "tikv kv-0:20160/20180",
+            ]
+        );
+
+        let empty = topology_event(&HashSet::new());
+        assert_eq!(empty.get("topology").unwrap().as_array().unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+use metrics::{counter, gauge};
+use vector_core::internal_event::InternalEvent;
+
+use crate::topology::InstanceType;
+
+#[derive(Debug)]
+pub struct TopSQLRunningComponents {
+    pub instance_type: InstanceType,
+    pub count: usize,
+}
+
+impl InternalEvent for TopSQLRunningComponents {
+    fn emit(self) {
+        trace!(
+            message = "Running TopSQL sources.",
+            instance_type = %self.instance_type,
+            count = %self.count,
+        );
+        gauge!(
+            "topsql_running_components", self.count as f64,
+            "instance_type" => self.instance_type.to_string(),
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct TopSQLStreamClosed<'a> {
+    pub instance: &'a str,
+    pub instance_type: InstanceType,
+}
+
+impl<'a> InternalEvent for TopSQLStreamClosed<'a> {
+    fn emit(self) {
+        info!(
+            message = "Upstream closed the subscription.",
+            instance = %self.instance,
+            instance_type = %self.instance_type,
+        );
+        counter!(
+            "topsql_stream_closed_total", 1,
+            "instance" => self.instance.to_owned(),
+            "instance_type" => self.instance_type.to_string(),
+            "reason" => "clean",
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct TopSQLStreamIdle<'a> {
+    pub instance: &'a str,
+    pub instance_type: InstanceType,
+    pub max_idle: Duration,
+}
+
+impl<'a> InternalEvent for TopSQLStreamIdle<'a> {
+    fn emit(self) {
+        warn!(
+            message = "No records received from the subscription for too long, reconnecting.",
+            instance = %self.instance,
+            instance_type = %self.instance_type,
+            max_idle_secs = %self.max_idle.as_secs_f64(),
+        );
+        counter!(
+            "topsql_stream_closed_total", 1,
+            "instance" => self.instance.to_owned(),
+            "instance_type" => self.instance_type.to_string(),
+            "reason" => "idle",
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct TopSQLRecordOversized<'a> {
+    pub instance: &'a str,
+    pub instance_type: InstanceType,
+    pub items: usize,
+    pub max_items: usize,
+}
+
+impl<'a> InternalEvent for TopSQLRecordOversized<'a> {
+    fn emit(self) {
+        warn!(
+            message = "Dropped record with too many items.",
+            instance = %self.instance,
+            instance_type = %self.instance_type,
+            items = %self.items,
+            max_items = %self.max_items,
+            internal_log_rate_secs = 10,
+        );
+        counter!(
+            "topsql_records_dropped_total", 1,
+            "instance" => self.instance.to_owned(),
+            "instance_type" => self.instance_type.to_string(),
+            "reason" => "too_many_items",
+        );
+    }
+}
+
+/// The first message of a subscription isn't understood, i.e. the proto
+/// definitions the extension is built with are out of date with the cluster.
+#[derive(Debug)]
+pub struct TopSQLProtoMismatch<'a> {
+    pub instance: &'a str,
+    pub instance_type: InstanceType,
+    pub error: &'a str,
+}
+
+impl<'a> InternalEvent for TopSQLProtoMismatch<'a> {
+    fn emit(self) {
+        error!(
+            message = "Proto mismatch with the upstream, upgrade the extension.",
+            instance = %self.instance,
+            instance_type = %self.instance_type,
+            error = %self.error,
+        );
+        counter!(
+            "topsql_proto_mismatch_total", 1,
+            "instance" => self.instance.to_owned(),
+            "instance_type" => self.instance_type.to_string(),
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct TopSQLStreamError<'a> {
+    pub instance: &'a str,
+    pub instance_type: InstanceType,
+    pub error: &'a tonic::Status,
+}
+
+impl<'a> InternalEvent for TopSQLStreamError<'a> {
+    fn emit(self) {
+        error!(
+            message = "Failed to fetch events.",
+            instance = %self.instance,
+            instance_type = %self.instance_type,
+            error = %self.error,
+        );
+        counter!(
+            "topsql_stream_closed_total", 1,
+            "instance" => self.instance.to_owned(),
+            "instance_type" => self.instance_type.to_string(),
+            "reason" => "error",
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct TopSQLSendQueueDropped<'a> {
+    pub instance: &'a str,
+    pub instance_type: InstanceType,
+    pub count: usize,
+}
+
+impl<'a> InternalEvent for TopSQLSendQueueDropped<'a> {
+    fn emit(self) {
+        warn!(
+            message = "Dropped the oldest events of a full send queue.",
+            instance = %self.instance,
+            instance_type = %self.instance_type,
+            count = %self.count,
+            internal_log_rate_secs = 10,
+        );
+        counter!(
+            "topsql_samples_dropped_total", self.count as u64,
+            "instance" => self.instance.to_owned(),
+            "instance_type" => self.instance_type.to_string(),
+            "reason" => "send_queue_full",
+        );
+    }
+}
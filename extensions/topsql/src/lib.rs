@@ -3,6 +3,7 @@ extern crate tracing;
 
 mod config;
 mod controller;
+mod internal_events;
 mod shutdown;
 mod topology;
 mod upstream;
@@ -12,6 +13,9 @@ pub use config::TopSQLConfig;
 // expose the event builder to vm_import for test.
 #[cfg(feature = "vm-test")]
 pub use upstream::parser;
+// Exposes the parsers to the benchmarks under `benches`.
+#[cfg(feature = "bench")]
+pub use upstream::bench;
 
 // #[cfg(test)]
 // mod tests {
@@ -2,13 +2,14 @@ mod models;
 mod pd;
 mod store;
 mod tidb;
-mod utils;
+pub(super) mod utils;
 
 #[cfg(test)]
 mod mock;
 
 use std::collections::HashSet;
 use std::fs::read;
+use std::time::Duration;
 
 use snafu::{ResultExt, Snafu};
 use vector::config::ProxyConfig;
@@ -41,6 +42,14 @@ pub enum FetchError {
     FetchStoreTopology { source: store::FetchError },
 }
 
+/// Options of the client of PD's etcd, which the TiDB topology is read from.
+#[derive(Default)]
+pub struct EtcdOptions {
+    /// `(username, password)`, for etcd with authentication enabled.
+    pub credentials: Option<(String, String)>,
+    pub connect_timeout: Option<Duration>,
+}
+
 pub struct TopologyFetcher {
     pd_address: String,
     http_client: HttpClient<hyper::Body>,
@@ -51,11 +60,12 @@ impl TopologyFetcher {
     pub async fn new(
         pd_address: String,
         tls_config: Option<TlsConfig>,
+        etcd_options: EtcdOptions,
         proxy_config: &ProxyConfig,
     ) -> Result<Self, FetchError> {
         let pd_address = Self::polish_address(pd_address, &tls_config)?;
         let http_client = Self::build_http_client(&tls_config, proxy_config)?;
-        let etcd_client = Self::build_etcd_client(&pd_address, &tls_config).await?;
+        let etcd_client = Self::build_etcd_client(&pd_address, &tls_config, etcd_options).await?;
 
         Ok(Self {
             pd_address,
@@ -117,8 +127,9 @@ impl TopologyFetcher {
     async fn build_etcd_client(
         pd_address: &str,
         tls_config: &Option<TlsConfig>,
+        etcd_options: EtcdOptions,
     ) -> Result<etcd_client::Client, FetchError> {
-        let etcd_connect_opt = Self::build_etcd_connect_opt(tls_config)?;
+        let etcd_connect_opt = Self::build_etcd_connect_opt(tls_config, etcd_options)?;
         let etcd_client = etcd_client::Client::connect(&[pd_address], etcd_connect_opt)
             .await
             .context(BuildEtcdClientSnafu)?;
@@ -127,8 +138,9 @@ impl TopologyFetcher {
 
     fn build_etcd_connect_opt(
         tls_config: &Option<TlsConfig>,
+        etcd_options: EtcdOptions,
     ) -> Result<Option<etcd_client::ConnectOptions>, FetchError> {
-        let conn_opt = if let Some(tls_config) = tls_config.as_ref() {
+        let mut conn_opt = if let Some(tls_config) = tls_config.as_ref() {
             let mut tls_options = etcd_client::TlsOptions::new();
 
             if let Some(ca_file) = tls_config.ca_file.as_ref() {
@@ -148,6 +160,21 @@ impl TopologyFetcher {
             None
         };
 
+        if let Some((username, password)) = etcd_options.credentials {
+            conn_opt = Some(
+                conn_opt
+                    .unwrap_or_else(etcd_client::ConnectOptions::new)
+                    .with_user(username, password),
+            );
+        }
+        if let Some(timeout) = etcd_options.connect_timeout {
+            conn_opt = Some(
+                conn_opt
+                    .unwrap_or_else(etcd_client::ConnectOptions::new)
+                    .with_connect_timeout(timeout),
+            );
+        }
+
         Ok(conn_opt)
     }
 }
@@ -2,7 +2,7 @@ mod fetch;
 
 use std::fmt;
 
-pub use fetch::{FetchError, TopologyFetcher};
+pub use fetch::{EtcdOptions, FetchError, TopologyFetcher};
 
 #[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
 pub enum InstanceType {
@@ -23,6 +23,11 @@ impl fmt::Display for InstanceType {
     }
 }
 
+/// A component as advertised by the topology, i.e. by its addresses alone: TiDB
+/// by its etcd key and status port, TiKV and TiFlash by their store addresses,
+/// none with a scheme. So the topology never tells whether a component serves
+/// TLS, nor advertises a TLS and a plaintext endpoint of the same component,
+/// and the transport is up to the config, see `SourceOptions::transport`.
 #[derive(Debug, Clone, Eq, Hash, PartialEq)]
 pub struct Component {
     pub instance_type: InstanceType,
@@ -32,12 +37,27 @@ pub struct Component {
 }
 
 impl Component {
-    pub fn topsql_address(&self) -> Option<String> {
-        match self.instance_type {
-            InstanceType::TiDB => Some(format!("{}:{}", self.host, self.secondary_port)),
-            InstanceType::TiKV => Some(format!("{}:{}", self.host, self.primary_port)),
-            _ => None,
-        }
+    /// The TiKV listening on `address`, as reported by TiDB for the requests it
+    /// sent there, e.g. in `stmt_kv_exec_count`. Its status port is unknown.
+    pub fn tikv(address: &str) -> Option<Self> {
+        let (host, primary_port) = fetch::utils::parse_host_port(address).ok()?;
+        Some(Self {
+            instance_type: InstanceType::TiKV,
+            host,
+            primary_port,
+            secondary_port: 0,
+        })
+    }
+
+    /// The address of the TopSQL pubsub service, where `port` overrides the
+    /// port advertised by the topology.
+    pub fn topsql_address(&self, port: Option<u16>) -> Option<String> {
+        let advertised_port = match self.instance_type {
+            InstanceType::TiDB => self.secondary_port,
+            InstanceType::TiKV => self.primary_port,
+            _ => return None,
+        };
+        Some(format!("{}:{}", self.host, port.unwrap_or(advertised_port)))
     }
 }
 
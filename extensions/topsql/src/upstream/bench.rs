@@ -0,0 +1,7 @@
+//! The parsers of upstream responses, for the benchmarks under `benches`.
+
+pub use crate::upstream::parser::{ParserOptions, UpstreamEventParser};
+pub use crate::upstream::tidb::proto::top_sql_sub_response::RespOneof;
+pub use crate::upstream::tidb::proto::{TopSqlRecord, TopSqlRecordItem, TopSqlSubResponse};
+pub use crate::upstream::tidb::TopSqlSubResponseParser;
+pub use crate::upstream::utils::{make_metric_like_log_event, LabelInterner};
@@ -0,0 +1,125 @@
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::watch;
+use tracing_futures::Instrument;
+use vector::tls::TlsConfig;
+
+use crate::shutdown::ShutdownSubscriber;
+
+/// Polls the CA, certificate and key files of `tls_config` every `interval`,
+/// notifying the returned receiver whenever any of them changes, e.g. when
+/// rotated by cert-manager. Returns `None` if no files are configured.
+///
+/// Connections only read the files when established, so subscribers are
+/// expected to reconnect on change to pick up the new credentials.
+pub fn watch_cert_files(
+    tls_config: &TlsConfig,
+    interval: Duration,
+    mut shutdown_subscriber: ShutdownSubscriber,
+) -> Option<watch::Receiver<()>> {
+    let paths = [
+        &tls_config.ca_file,
+        &tls_config.crt_file,
+        &tls_config.key_file,
+    ]
+    .into_iter()
+    .flatten()
+    .cloned()
+    .collect::<Vec<_>>();
+    if paths.is_empty() {
+        return None;
+    }
+
+    let (tx, rx) = watch::channel(());
+    tokio::spawn(
+        async move {
+            let mut seen = snapshot(&paths);
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = shutdown_subscriber.done() => break,
+                    _ = interval.tick() => {}
+                }
+                let latest = snapshot(&paths);
+                if latest != seen {
+                    info!(message = "TLS files changed, reconnecting to pick them up.", files = ?paths);
+                    seen = latest;
+                    if tx.send(()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        .in_current_span(),
+    );
+    Some(rx)
+}
+
+// The modification time and size of every file, or `None` for files that
+// can't be read, e.g. while being replaced.
+fn snapshot(paths: &[PathBuf]) -> Vec<Option<(SystemTime, u64)>> {
+    paths
+        .iter()
+        .map(|path| {
+            let metadata = std::fs::metadata(path).ok()?;
+            Some((metadata.modified().ok()?, metadata.len()))
+        })
+        .collect()
+}
+
+/// Resolves once `cert_changes` is notified, or never without a watcher.
+pub async fn cert_changed(cert_changes: &mut Option<watch::Receiver<()>>) {
+    if let Some(rx) = cert_changes {
+        if rx.changed().await.is_ok() {
+            return;
+        }
+    }
+    // the watcher is gone, e.g. shutting down
+    futures::future::pending::<()>().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shutdown;
+
+    #[tokio::test]
+    async fn notify_on_rotation() {
+        let dir = std::env::temp_dir().join(format!("topsql-certs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut tls_config = TlsConfig::default();
+        for (file, name) in [
+            (&mut tls_config.ca_file, "ca.pem"),
+            (&mut tls_config.crt_file, "client.pem"),
+            (&mut tls_config.key_file, "client-key.pem"),
+        ] {
+            std::fs::write(dir.join(name), "old").unwrap();
+            *file = Some(dir.join(name));
+        }
+
+        let (notifier, subscriber) = shutdown::pair();
+        let mut cert_changes = watch_cert_files(&tls_config, Duration::from_millis(50), subscriber);
+        assert!(cert_changes.is_some());
+
+        // unchanged files don't notify
+        let unchanged =
+            tokio::time::timeout(Duration::from_millis(300), cert_changed(&mut cert_changes)).await;
+        assert!(unchanged.is_err());
+
+        std::fs::write(dir.join("client.pem"), "rotated").unwrap();
+        tokio::time::timeout(Duration::from_secs(5), cert_changed(&mut cert_changes))
+            .await
+            .expect("rotation was not noticed");
+
+        notifier.shutdown();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // nothing to watch
+        let (_notifier, subscriber) = shutdown::pair();
+        assert!(
+            watch_cert_files(&TlsConfig::default(), Duration::from_millis(50), subscriber)
+                .is_none()
+        );
+    }
+}
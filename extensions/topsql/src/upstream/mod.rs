@@ -2,57 +2,147 @@ pub mod parser;
 pub mod tidb;
 pub mod tikv;
 
+#[cfg(feature = "bench")]
+pub mod bench;
+
+mod cert_watcher;
 mod consts;
+mod send_queue;
 mod tls_proxy;
 mod utils;
 
+use std::collections::BTreeMap;
+use std::sync::Arc;
 use std::time::Duration;
 
 use futures::StreamExt;
+use tokio::sync::watch;
 use tokio_stream::wrappers::IntervalStream;
 use tonic::transport::{Channel, Endpoint};
+use vector::event::{Event, LogEvent};
 use vector::internal_events::{BytesReceived, EventsReceived, StreamClosedError};
 use vector::tls::TlsConfig;
 use vector::SourceSender;
 use vector_core::internal_event::InternalEvent;
 use vector_core::ByteSizeOf;
 
+use crate::config::{OutputFormat, SendQueuePolicy, StreamCompression, Transport};
+use crate::internal_events::{
+    TopSQLProtoMismatch, TopSQLSendQueueDropped, TopSQLStreamClosed, TopSQLStreamError,
+    TopSQLStreamIdle,
+};
 use crate::shutdown::ShutdownSubscriber;
 use crate::topology::{Component, InstanceType};
-use crate::upstream::parser::UpstreamEventParser;
+use crate::upstream::cert_watcher::cert_changed;
+pub use crate::upstream::cert_watcher::watch_cert_files;
+use crate::upstream::consts::{METRIC_NAME_SELF_CONNECTED_SECONDS, METRIC_NAME_SELF_RECORDS_TOTAL};
+use crate::upstream::parser::{ParserOptions, UpstreamEventParser};
+use crate::upstream::send_queue::SendQueue;
 use crate::upstream::tidb::TiDBUpstream;
 use crate::upstream::tikv::TiKVUpstream;
-use crate::upstream::utils::instance_event;
+use crate::upstream::utils::{
+    alias_instances, instance_event, into_metrics, label_metric_types, raw_event,
+    self_metric_event, LabelInterner,
+};
 
 #[async_trait::async_trait]
 pub trait Upstream: Send {
     type Client: Send;
-    type UpstreamEvent: ByteSizeOf + Send;
+    type UpstreamEvent: ByteSizeOf + prost::Message + Send;
     type UpstreamEventParser: parser::UpstreamEventParser<UpstreamEvent = Self::UpstreamEvent>;
 
     async fn build_endpoint(
         address: String,
         tls_config: &Option<vector::tls::TlsConfig>,
+        proxy_port: &mut Option<u16>,
         shutdown_subscriber: ShutdownSubscriber,
     ) -> vector::Result<Endpoint>;
 
-    fn build_client(channel: Channel) -> Self::Client;
+    fn build_client(channel: Channel, compression: StreamCompression) -> Self::Client;
 
     async fn build_stream(
         client: Self::Client,
     ) -> Result<tonic::codec::Streaming<Self::UpstreamEvent>, tonic::Status>;
 }
 
+/// Options shared by every `TopSQLSource`.
+#[derive(Clone, Debug, Default)]
+pub struct SourceOptions {
+    pub output_format: OutputFormat,
+    pub parser: ParserOptions,
+    pub stream_compression: StreamCompression,
+    pub tidb_topsql_port: Option<u16>,
+    pub tikv_topsql_port: Option<u16>,
+    /// Whether TiDB and TiKV are connected to with TLS, see `transport`.
+    pub tidb_transport: Transport,
+    pub tikv_transport: Transport,
+    /// Reconnect if the subscription delivers nothing for this long, as a
+    /// half-open connection may never error nor close.
+    pub max_idle: Option<Duration>,
+    /// How often the TLS files are checked for changes, reconnecting every
+    /// source once they do. Not checked if `None`.
+    pub tls_reload_interval: Option<Duration>,
+    /// Emit every response as is, see `raw_event`, rather than parsing it.
+    pub raw_passthrough: bool,
+    /// Share equal label values across the events parsed from an upstream,
+    /// see `LabelInterner`.
+    pub intern_labels: bool,
+    /// Emit `topsql_self_connected_seconds` and `topsql_self_records_total`
+    /// of every upstream along with its instance event.
+    pub emit_self_metrics: bool,
+    /// Label every series with its metric type, see `label_metric_types`.
+    pub emit_metric_type: bool,
+    /// Display names of instances by their address, see `alias_instances`.
+    pub instance_aliases: Arc<BTreeMap<String, String>>,
+    /// The capacity and policy of the queue events are sent from, see
+    /// `SendQueue`. Sent as soon as parsed if `None`.
+    pub send_queue: Option<(usize, SendQueuePolicy)>,
+}
+
+impl SourceOptions {
+    fn topsql_port(&self, instance_type: InstanceType) -> Option<u16> {
+        match instance_type {
+            InstanceType::TiDB => self.tidb_topsql_port,
+            InstanceType::TiKV => self.tikv_topsql_port,
+            _ => None,
+        }
+    }
+
+    /// How instances of `instance_type` are connected to, explicitly set
+    /// transports taking precedence over whether TLS is configured. It's
+    /// never inferred from the topology, which advertises no scheme.
+    fn transport(&self, instance_type: InstanceType) -> Transport {
+        match instance_type {
+            InstanceType::TiDB => self.tidb_transport,
+            InstanceType::TiKV => self.tikv_transport,
+            _ => Transport::Auto,
+        }
+    }
+}
+
 pub struct TopSQLSource {
     instance: String,
     instance_type: InstanceType,
     uri: String,
 
     tls: Option<TlsConfig>,
+    // Port of the local TLS proxy, kept across reconnects.
+    proxy_port: Option<u16>,
     out: SourceSender,
+    send_queue: Option<SendQueue>,
+    options: SourceOptions,
+    // Notified when the TLS files change.
+    cert_changes: Option<watch::Receiver<()>>,
 
     init_retry_delay: Duration,
     retry_delay: Duration,
+
+    // when the current subscription was set up
+    connected_at: Option<tokio::time::Instant>,
+    // responses received over every subscription
+    records_total: u64,
+    // shares the labels of the events parsed, kept across reconnects
+    interner: LabelInterner,
 }
 
 enum State {
@@ -68,8 +158,15 @@ impl TopSQLSource {
         tls: Option<TlsConfig>,
         out: SourceSender,
         init_retry_delay: Duration,
+        options: SourceOptions,
+        cert_changes: Option<watch::Receiver<()>>,
     ) -> Option<Self> {
-        match component.topsql_address() {
+        let tls = match options.transport(component.instance_type) {
+            Transport::Auto | Transport::Tls => tls,
+            Transport::Plaintext => None,
+        };
+        let interner = LabelInterner::new(options.intern_labels);
+        match component.topsql_address(options.topsql_port(component.instance_type)) {
             Some(address) => Some(TopSQLSource {
                 instance: address.clone(),
                 instance_type: component.instance_type,
@@ -80,20 +177,50 @@ impl TopSQLSource {
                 },
 
                 tls,
+                proxy_port: None,
                 out,
+                send_queue: options
+                    .send_queue
+                    .map(|(capacity, policy)| SendQueue::new(capacity, policy)),
+                options,
+                cert_changes,
                 init_retry_delay,
                 retry_delay: init_retry_delay,
+                connected_at: None,
+                records_total: 0,
+                interner,
             }),
             None => None,
         }
     }
 
+    /// `tls` or `plaintext`, as chosen by `SourceOptions::transport`.
+    pub fn transport(&self) -> &'static str {
+        if self.tls.is_some() {
+            "tls"
+        } else {
+            "plaintext"
+        }
+    }
+
     pub async fn run(mut self, mut shutdown: ShutdownSubscriber) {
         let shutdown_subscriber = shutdown.clone();
+        let mut drain = self
+            .send_queue
+            .clone()
+            .map(|send_queue| Box::pin(send_queue.drain(self.out.clone())));
         tokio::select! {
             _ = self.run_loop(shutdown_subscriber) => {}
+            // the downstream is closed
+            _ = async { drain.as_mut().unwrap().await }, if drain.is_some() => return,
             _ = shutdown.done() => {}
         }
+
+        // the events queued before the shutdown are still sent
+        if let (Some(send_queue), Some(drain)) = (&self.send_queue, drain) {
+            send_queue.close();
+            drain.await;
+        }
     }
 
     async fn run_loop(&mut self, shutdown_subscriber: ShutdownSubscriber) {
@@ -123,6 +250,10 @@ impl TopSQLSource {
     }
 
     async fn run_once<U: Upstream>(&mut self, shutdown_subscriber: ShutdownSubscriber) -> State {
+        // the files are read when connecting, so earlier changes are taken in
+        if let Some(cert_changes) = &mut self.cert_changes {
+            cert_changes.borrow_and_update();
+        }
         let response_stream = self.build_stream::<U>(shutdown_subscriber).await;
         let mut response_stream = match response_stream {
             Ok(stream) => stream,
@@ -130,30 +261,80 @@ impl TopSQLSource {
         };
         let mut instance_stream =
             IntervalStream::new(tokio::time::interval(Duration::from_secs(30)));
+        let max_idle = self.options.max_idle;
+        let idle = tokio::time::sleep(max_idle.unwrap_or_default());
+        tokio::pin!(idle);
 
         self.on_connected();
+        // whether the first message of the subscription was checked
+        let mut probed = false;
         loop {
             tokio::select! {
                 response = response_stream.next() => {
                     match response {
-                        Some(Ok(response)) => self.handle_response::<U>(response).await,
+                        Some(Ok(response)) => {
+                            if let Some(max_idle) = max_idle {
+                                idle.as_mut().reset(tokio::time::Instant::now() + max_idle);
+                            }
+                            if !probed {
+                                probed = true;
+                                if let Err(error) = U::UpstreamEventParser::check_compatibility(&response) {
+                                    self.proto_mismatch(&error);
+                                }
+                            }
+                            self.handle_response::<U>(response).await
+                        },
                         Some(Err(error)) => {
-                            error!(message = "Failed to fetch events.", error = %error);
+                            if !probed && is_decode_error(&error) {
+                                self.proto_mismatch(error.message());
+                            }
+                            TopSQLStreamError {
+                                instance: &self.instance,
+                                instance_type: self.instance_type,
+                                error: &error,
+                            }
+                            .emit();
                             break State::RetryDelay;
                         },
-                        None => break State::RetryNow,
+                        None => {
+                            TopSQLStreamClosed {
+                                instance: &self.instance,
+                                instance_type: self.instance_type,
+                            }
+                            .emit();
+                            break State::RetryNow;
+                        },
                     }
                 }
                 _ = instance_stream.next() => self.handle_instance().await,
+                _ = cert_changed(&mut self.cert_changes) => {
+                    info!("Reconnecting with the changed TLS files.");
+                    break State::RetryNow;
+                }
+                _ = &mut idle, if max_idle.is_some() => {
+                    TopSQLStreamIdle {
+                        instance: &self.instance,
+                        instance_type: self.instance_type,
+                        max_idle: max_idle.unwrap(),
+                    }
+                    .emit();
+                    break State::RetryDelay;
+                }
             }
         }
     }
 
     async fn build_stream<U: Upstream>(
-        &self,
+        &mut self,
         shutdown_subscriber: ShutdownSubscriber,
     ) -> Result<tonic::codec::Streaming<U::UpstreamEvent>, State> {
-        let endpoint = U::build_endpoint(self.uri.clone(), &self.tls, shutdown_subscriber).await;
+        let endpoint = U::build_endpoint(
+            self.uri.clone(),
+            &self.tls,
+            &mut self.proxy_port,
+            shutdown_subscriber,
+        )
+        .await;
         let endpoint = match endpoint {
             Ok(endpoint) => endpoint,
             Err(error) => {
@@ -171,7 +352,7 @@ impl TopSQLSource {
             }
         };
 
-        let client = U::build_client(channel);
+        let client = U::build_client(channel, self.options.stream_compression);
         let response_stream = match U::build_stream(client).await {
             Ok(stream) => stream,
             Err(error) => {
@@ -189,28 +370,541 @@ impl TopSQLSource {
             protocol: if self.tls.is_none() { "http" } else { "https" },
         }
         .emit();
+        self.records_total += 1;
 
-        let events = U::UpstreamEventParser::parse(response, self.instance.clone());
+        let events = if self.options.raw_passthrough {
+            vec![raw_event(
+                &response,
+                self.instance.clone(),
+                self.instance_type.to_string(),
+            )]
+        } else {
+            U::UpstreamEventParser::parse(
+                response,
+                self.instance.clone(),
+                &self.options.parser,
+                &mut self.interner,
+            )
+        };
+        let events = self.format_events(events);
         let count = events.len();
         EventsReceived {
             byte_size: events.size_of(),
             count,
         }
         .emit();
+        self.send(events).await;
+    }
+
+    async fn handle_instance(&mut self) {
+        let mut events = vec![instance_event(
+            self.instance.clone(),
+            self.instance_type.to_string(),
+        )];
+        if self.options.emit_self_metrics {
+            events.extend(self.self_metric_events());
+        }
+        let events = self.format_events(events);
+        self.send(events).await;
+    }
+
+    async fn send(&mut self, events: Vec<Event>) {
+        if let Some(send_queue) = &self.send_queue {
+            let dropped = send_queue.push(events).await;
+            if dropped > 0 {
+                TopSQLSendQueueDropped {
+                    instance: &self.instance,
+                    instance_type: self.instance_type,
+                    count: dropped,
+                }
+                .emit();
+            }
+            return;
+        }
+
+        let count = events.len();
         if let Err(error) = self.out.send_batch(events).await {
-            StreamClosedError { error, count }.emit()
+            StreamClosedError { error, count }.emit();
         }
     }
 
-    async fn handle_instance(&mut self) {
-        let event = instance_event(self.instance.clone(), self.instance_type.to_string());
-        if let Err(error) = self.out.send_event(event).await {
-            StreamClosedError { error, count: 1 }.emit();
+    fn self_metric_events(&self) -> Vec<LogEvent> {
+        let connected = self
+            .connected_at
+            .map_or(Duration::ZERO, |connected_at| connected_at.elapsed());
+        vec![
+            self_metric_event(
+                METRIC_NAME_SELF_CONNECTED_SECONDS,
+                self.instance.clone(),
+                self.instance_type.to_string(),
+                connected.as_secs_f64(),
+            ),
+            self_metric_event(
+                METRIC_NAME_SELF_RECORDS_TOTAL,
+                self.instance.clone(),
+                self.instance_type.to_string(),
+                self.records_total as f64,
+            ),
+        ]
+    }
+
+    fn format_events(&self, mut events: Vec<LogEvent>) -> Vec<Event> {
+        if !self.options.instance_aliases.is_empty() {
+            alias_instances(&mut events, &self.options.instance_aliases);
+        }
+        match self.options.output_format {
+            OutputFormat::Log => {
+                if self.options.emit_metric_type {
+                    label_metric_types(&mut events);
+                }
+                events.into_iter().map(Event::from).collect()
+            }
+            OutputFormat::Metric => events
+                .into_iter()
+                .flat_map(into_metrics)
+                .map(Event::from)
+                .collect(),
+        }
+    }
+
+    fn proto_mismatch(&self, error: &str) {
+        TopSQLProtoMismatch {
+            instance: &self.instance,
+            instance_type: self.instance_type,
+            error,
         }
+        .emit();
     }
 
     fn on_connected(&mut self) {
         self.retry_delay = self.init_retry_delay;
+        self.connected_at = Some(tokio::time::Instant::now());
         info!("Connected to the upstream.");
     }
 }
+
+// tonic reports messages prost fails to decode as internal errors
+fn is_decode_error(error: &tonic::Status) -> bool {
+    error.code() == tonic::Code::Internal
+        && error
+            .message()
+            .starts_with("failed to decode Protobuf message")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use vector::event::EventArray;
+
+    use super::*;
+    use crate::shutdown;
+    use crate::upstream::consts::{
+        LABEL_INSTANCE, LABEL_INSTANCE_TYPE, LABEL_NAME, METRIC_NAME_CPU_TIME_MS,
+        METRIC_NAME_INSTANCE, METRIC_NAME_PLAN_META, METRIC_NAME_READ_KEYS, METRIC_NAME_SQL_META,
+        METRIC_NAME_STMT_DURATION_COUNT, METRIC_NAME_STMT_DURATION_SUM_NS,
+        METRIC_NAME_STMT_EXEC_COUNT, METRIC_NAME_WRITE_KEYS,
+    };
+    use crate::upstream::tidb::mock_upstream::{MockTopSqlPubSubServer, SilentTopSqlPubSubServer};
+    use crate::upstream::tikv::mock_upstream::MockResourceMeteringPubSubServer;
+
+    fn free_address() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    fn source(
+        address: SocketAddr,
+        instance_type: InstanceType,
+        options: SourceOptions,
+    ) -> (
+        TopSQLSource,
+        impl futures::Stream<Item = EventArray> + Unpin,
+    ) {
+        let component = Component {
+            instance_type,
+            host: address.ip().to_string(),
+            primary_port: address.port(),
+            secondary_port: address.port(),
+        };
+        let (out, rx) = SourceSender::new_with_buffer(100);
+        let source = TopSQLSource::new(
+            component,
+            None,
+            out,
+            Duration::from_millis(100),
+            options,
+            None,
+        )
+        .unwrap();
+        (source, rx)
+    }
+
+    /// Drives `TopSQLSource::run_once` against a mock upstream serving at
+    /// `address` until the mock closes the stream, returning the emitted
+    /// events.
+    async fn scrape<U: Upstream>(
+        address: SocketAddr,
+        instance_type: InstanceType,
+        options: SourceOptions,
+    ) -> Vec<LogEvent> {
+        let (mut source, mut rx) = source(address, instance_type, options);
+
+        let (notifier, subscriber) = shutdown::pair();
+        let mut closed = false;
+        // the mock server may not be listening yet
+        for _ in 0..50 {
+            match source.run_once::<U>(subscriber.clone()).await {
+                State::RetryNow => {
+                    closed = true;
+                    break;
+                }
+                State::RetryDelay => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+        assert!(closed, "failed to subscribe to the mock upstream");
+        notifier.shutdown();
+        drop(source);
+
+        let mut events = vec![];
+        while let Some(array) = rx.next().await {
+            match array {
+                EventArray::Logs(logs) => events.extend(logs),
+                _ => panic!("expected log events"),
+            }
+        }
+        events
+    }
+
+    fn label(event: &LogEvent, name: &str) -> String {
+        let value = event.get(format!("labels.{}", name).as_str()).unwrap();
+        String::from_utf8_lossy(value.as_bytes().unwrap()).into_owned()
+    }
+
+    /// `(__name__, instance_type)` of every series, skipping the instance
+    /// event, which races with the mock closing the stream.
+    fn series(events: &[LogEvent]) -> Vec<(String, String)> {
+        let mut series = events
+            .iter()
+            .filter(|event| label(event, LABEL_NAME) != METRIC_NAME_INSTANCE)
+            .map(|event| {
+                let instance_type = event
+                    .get(format!("labels.{}", LABEL_INSTANCE_TYPE).as_str())
+                    .map(|_| label(event, LABEL_INSTANCE_TYPE))
+                    .unwrap_or_default();
+                (label(event, LABEL_NAME), instance_type)
+            })
+            .collect::<Vec<_>>();
+        series.sort();
+        series
+    }
+
+    fn find<'a>(events: &'a [LogEvent], name: &str) -> &'a LogEvent {
+        events
+            .iter()
+            .find(|event| label(event, LABEL_NAME) == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn select_transport() {
+        let transport = |instance_type, tls: Option<TlsConfig>, options: &SourceOptions| {
+            let component = Component {
+                instance_type,
+                host: "127.0.0.1".to_owned(),
+                primary_port: 20160,
+                secondary_port: 10080,
+            };
+            let (out, _) = SourceSender::new_with_buffer(1);
+            let source = TopSQLSource::new(
+                component,
+                tls,
+                out,
+                Duration::from_millis(100),
+                options.clone(),
+                None,
+            )
+            .unwrap();
+            (source.transport(), source.uri)
+        };
+
+        // both transports are available with `tls` set, TLS is preferred by
+        // default and plaintext only without `tls`
+        let options = SourceOptions::default();
+        assert_eq!(
+            transport(InstanceType::TiDB, Some(TlsConfig::default()), &options),
+            ("tls", "https://127.0.0.1:10080".to_owned())
+        );
+        assert_eq!(
+            transport(InstanceType::TiKV, Some(TlsConfig::default()), &options),
+            ("tls", "https://127.0.0.1:20160".to_owned())
+        );
+        assert_eq!(
+            transport(InstanceType::TiKV, None, &options),
+            ("plaintext", "http://127.0.0.1:20160".to_owned())
+        );
+
+        // the transport set for an instance type wins over `tls`, e.g. with
+        // only TiKV opted out of TLS halfway through a migration
+        let options = SourceOptions {
+            tidb_transport: Transport::Tls,
+            tikv_transport: Transport::Plaintext,
+            ..Default::default()
+        };
+        assert_eq!(
+            transport(InstanceType::TiDB, Some(TlsConfig::default()), &options),
+            ("tls", "https://127.0.0.1:10080".to_owned())
+        );
+        assert_eq!(
+            transport(InstanceType::TiKV, Some(TlsConfig::default()), &options),
+            ("plaintext", "http://127.0.0.1:20160".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn scrape_tidb_mock_upstream() {
+        let address = free_address();
+        tokio::spawn(MockTopSqlPubSubServer::run(address, None));
+
+        let options = SourceOptions {
+            stream_compression: StreamCompression::Auto,
+            ..Default::default()
+        };
+        let events = scrape::<TiDBUpstream>(address, InstanceType::TiDB, options).await;
+
+        let expected = [
+            (METRIC_NAME_CPU_TIME_MS, "tidb"),
+            (METRIC_NAME_PLAN_META, ""),
+            (METRIC_NAME_SQL_META, ""),
+            (METRIC_NAME_STMT_DURATION_COUNT, "tidb"),
+            (METRIC_NAME_STMT_DURATION_SUM_NS, "tidb"),
+            (METRIC_NAME_STMT_EXEC_COUNT, "tidb"),
+            (METRIC_NAME_STMT_EXEC_COUNT, "tikv"),
+        ]
+        .iter()
+        .map(|(name, instance_type)| (name.to_string(), instance_type.to_string()))
+        .collect::<Vec<_>>();
+        assert_eq!(series(&events), expected);
+
+        let cpu_time = find(&events, METRIC_NAME_CPU_TIME_MS);
+        assert_eq!(label(cpu_time, LABEL_INSTANCE), address.to_string());
+        assert_eq!(
+            label(cpu_time, "sql_digest"),
+            hex::encode_upper("sql_digest")
+        );
+        assert_eq!(
+            cpu_time.get("timestamps").unwrap().as_array().unwrap()[0]
+                .as_timestamp()
+                .unwrap()
+                .timestamp(),
+            1655363650
+        );
+        assert_eq!(
+            cpu_time.get("values").unwrap().as_array().unwrap()[0]
+                .as_float()
+                .unwrap()
+                .into_inner(),
+            10.0
+        );
+    }
+
+    #[tokio::test]
+    async fn raw_passthrough_round_trips() {
+        use prost::Message;
+
+        use crate::upstream::tidb::proto::top_sql_sub_response::RespOneof;
+        use crate::upstream::tidb::proto::TopSqlSubResponse;
+
+        let address = free_address();
+        tokio::spawn(MockTopSqlPubSubServer::run(address, None));
+
+        let options = SourceOptions {
+            raw_passthrough: true,
+            ..Default::default()
+        };
+        let events = scrape::<TiDBUpstream>(address, InstanceType::TiDB, options).await;
+
+        let responses = events
+            .iter()
+            .filter_map(|event| {
+                let raw = event.get("raw")?.as_bytes()?;
+                assert_eq!(
+                    event.get(LABEL_INSTANCE).unwrap().as_bytes().unwrap(),
+                    address.to_string().as_bytes()
+                );
+                assert_eq!(
+                    event.get(LABEL_INSTANCE_TYPE).unwrap().as_bytes().unwrap(),
+                    "tidb".as_bytes()
+                );
+                let bytes = base64::decode(raw).unwrap();
+                let response = TopSqlSubResponse::decode(bytes.as_slice()).unwrap();
+                assert_eq!(response.encode_to_vec(), bytes);
+                Some(response)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(responses.len(), 3);
+
+        let record = responses
+            .iter()
+            .find_map(|response| match &response.resp_oneof {
+                Some(RespOneof::Record(record)) => Some(record),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(record.sql_digest, b"sql_digest");
+        assert_eq!(record.items[0].timestamp_sec, 1655363650);
+        assert_eq!(record.items[0].cpu_time_ms, 10);
+    }
+
+    #[tokio::test]
+    async fn scrape_tikv_mock_upstream() {
+        let address = free_address();
+        tokio::spawn(MockResourceMeteringPubSubServer::run(address, None));
+
+        let events =
+            scrape::<TiKVUpstream>(address, InstanceType::TiKV, SourceOptions::default()).await;
+
+        let expected = [
+            METRIC_NAME_CPU_TIME_MS,
+            METRIC_NAME_READ_KEYS,
+            METRIC_NAME_WRITE_KEYS,
+        ]
+        .iter()
+        .map(|name| (name.to_string(), "tikv".to_owned()))
+        .collect::<Vec<_>>();
+        assert_eq!(series(&events), expected);
+
+        let write_keys = find(&events, METRIC_NAME_WRITE_KEYS);
+        assert_eq!(label(write_keys, LABEL_INSTANCE), address.to_string());
+        assert_eq!(label(write_keys, "tag_label"), "row");
+        assert_eq!(
+            write_keys.get("values").unwrap().as_array().unwrap()[0]
+                .as_float()
+                .unwrap()
+                .into_inner(),
+            30.0
+        );
+    }
+
+    #[tokio::test]
+    async fn self_metrics() {
+        let address = free_address();
+        tokio::spawn(MockTopSqlPubSubServer::run(address, None));
+
+        let options = SourceOptions {
+            emit_self_metrics: true,
+            ..Default::default()
+        };
+        let (mut source, _rx) = source(address, InstanceType::TiDB, options);
+        let (notifier, subscriber) = shutdown::pair();
+        for _ in 0..50 {
+            match source.run_once::<TiDBUpstream>(subscriber.clone()).await {
+                State::RetryNow => break,
+                State::RetryDelay => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+        notifier.shutdown();
+
+        let events = source.self_metric_events();
+        let value = |name: &str| {
+            find(&events, name)
+                .get("values")
+                .unwrap()
+                .as_array()
+                .unwrap()[0]
+                .as_float()
+                .unwrap()
+                .into_inner()
+        };
+        // the mock sends a record, an SQL meta and a plan meta
+        assert_eq!(value(METRIC_NAME_SELF_RECORDS_TOTAL), 3.0);
+        assert!(value(METRIC_NAME_SELF_CONNECTED_SECONDS) >= 0.0);
+        for event in &events {
+            assert_eq!(label(event, LABEL_INSTANCE), address.to_string());
+            assert_eq!(label(event, LABEL_INSTANCE_TYPE), "tidb");
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnect_idle_stream() {
+        let address = free_address();
+        tokio::spawn(SilentTopSqlPubSubServer::run(address));
+        // the mock server may not be listening yet
+        for _ in 0..50 {
+            if tokio::net::TcpStream::connect(address).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let max_idle = Duration::from_millis(500);
+        let options = SourceOptions {
+            max_idle: Some(max_idle),
+            ..Default::default()
+        };
+        let (mut source, mut rx) = source(address, InstanceType::TiDB, options);
+        let (notifier, subscriber) = shutdown::pair();
+
+        let start = tokio::time::Instant::now();
+        let state = tokio::time::timeout(
+            Duration::from_secs(10),
+            source.run_once::<TiDBUpstream>(subscriber),
+        )
+        .await
+        .expect("the idle stream was not dropped");
+        assert!(matches!(state, State::RetryDelay));
+        assert!(start.elapsed() >= max_idle);
+        notifier.shutdown();
+        drop(source);
+
+        // subscribed, as the instance event is only sent once connected
+        let mut events = vec![];
+        while let Some(array) = rx.next().await {
+            match array {
+                EventArray::Logs(logs) => events.extend(logs),
+                _ => panic!("expected log events"),
+            }
+        }
+        assert_eq!(events.len(), 1);
+        assert_eq!(label(&events[0], LABEL_NAME), METRIC_NAME_INSTANCE);
+    }
+
+    #[tokio::test]
+    async fn reconnect_on_cert_change() {
+        let address = free_address();
+        tokio::spawn(SilentTopSqlPubSubServer::run(address));
+        // the mock server may not be listening yet
+        for _ in 0..50 {
+            if tokio::net::TcpStream::connect(address).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let (mut source, _rx) = source(address, InstanceType::TiDB, SourceOptions::default());
+        let (cert_tx, cert_rx) = watch::channel(());
+        // changes before connecting are already picked up
+        cert_tx.send(()).unwrap();
+        source.cert_changes = Some(cert_rx);
+        let (notifier, subscriber) = shutdown::pair();
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            cert_tx.send(()).unwrap();
+            // keep the sender alive until the source reconnects
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+        let start = tokio::time::Instant::now();
+        let state = tokio::time::timeout(
+            Duration::from_secs(5),
+            source.run_once::<TiDBUpstream>(subscriber),
+        )
+        .await
+        .expect("the changed TLS files were not picked up");
+        assert!(matches!(state, State::RetryNow));
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        notifier.shutdown();
+    }
+}
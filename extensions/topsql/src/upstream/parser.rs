@@ -1,3 +1,4 @@
+use bytes::Bytes;
 use chrono::{DateTime, NaiveDateTime, Utc};
 use vector_core::event::LogEvent;
 
@@ -5,66 +6,113 @@ use crate::upstream::consts::{
     LABEL_INSTANCE, LABEL_INSTANCE_TYPE, LABEL_NAME, LABEL_PLAN_DIGEST, LABEL_SQL_DIGEST,
     LABEL_TAG_LABEL,
 };
-use crate::upstream::utils::make_metric_like_log_event;
+use crate::upstream::utils::{make_metric_like_log_event, LabelInterner};
 
 pub trait UpstreamEventParser {
     type UpstreamEvent;
 
-    fn parse(response: Self::UpstreamEvent, instance: String) -> Vec<LogEvent>;
+    fn parse(
+        response: Self::UpstreamEvent,
+        instance: String,
+        options: &ParserOptions,
+        interner: &mut LabelInterner,
+    ) -> Vec<LogEvent>;
+
+    /// Checks that `response`, the first of a subscription, is understood, so
+    /// proto definitions out of date with the cluster are reported once when
+    /// connecting rather than by a warning per record.
+    fn check_compatibility(_response: &Self::UpstreamEvent) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ParserOptions {
+    /// Emit the per-TiKV `stmt_kv_exec_count` reported by TiDB as
+    /// `topsql_stmt_kv_exec_count` rather than `topsql_stmt_exec_count`.
+    pub separate_stmt_kv_exec_count: bool,
+    /// Drop records carrying more items than this, so a single pathological
+    /// record can't blow up the allocations of parsing it. Unlimited if `None`.
+    pub max_items_per_record: Option<usize>,
+    /// The TopSQL port of TiKV overriding the advertised one, so the per-TiKV
+    /// series reported by TiDB carry the `instance` of the TiKV series.
+    pub tikv_topsql_port: Option<u16>,
 }
 
-pub struct Buf {
-    labels: Vec<(&'static str, String)>,
+impl ParserOptions {
+    /// The limit `items` is over, if any.
+    pub fn items_over_limit(&self, items: usize) -> Option<usize> {
+        self.max_items_per_record.filter(|max| items > *max)
+    }
+}
+
+/// Builds the series of a record. Labels are kept as `Bytes`, so the digests
+/// are encoded once per record and shared by all of its series, and with the
+/// series of other records too if they're interned.
+pub struct Buf<'a> {
+    labels: Vec<(&'static str, Bytes)>,
     timestamps: Vec<DateTime<Utc>>,
     values: Vec<f64>,
+    interner: Option<&'a mut LabelInterner>,
 }
 
-impl Default for Buf {
+impl Default for Buf<'_> {
     fn default() -> Self {
         Self {
             labels: vec![
-                (LABEL_NAME, String::new()),
-                (LABEL_INSTANCE, String::new()),
-                (LABEL_INSTANCE_TYPE, String::new()),
-                (LABEL_SQL_DIGEST, String::new()),
-                (LABEL_PLAN_DIGEST, String::new()),
-                (LABEL_TAG_LABEL, String::new()),
+                (LABEL_NAME, Bytes::new()),
+                (LABEL_INSTANCE, Bytes::new()),
+                (LABEL_INSTANCE_TYPE, Bytes::new()),
+                (LABEL_SQL_DIGEST, Bytes::new()),
+                (LABEL_PLAN_DIGEST, Bytes::new()),
+                (LABEL_TAG_LABEL, Bytes::new()),
             ],
             timestamps: vec![],
             values: vec![],
+            interner: None,
         }
     }
 }
 
-impl Buf {
-    pub fn label_name(&mut self, label_name: impl Into<String>) -> &mut Self {
-        self.labels[0].1 = label_name.into();
+impl<'a> Buf<'a> {
+    /// A `Buf` interning the labels of its series with `interner`.
+    pub fn new(interner: &'a mut LabelInterner) -> Self {
+        Self {
+            interner: Some(interner),
+            ..Default::default()
+        }
+    }
+
+    fn set_label(&mut self, index: usize, value: String) -> &mut Self {
+        self.labels[index].1 = match &mut self.interner {
+            Some(interner) => interner.intern(value),
+            None => Bytes::from(value),
+        };
         self
     }
 
+    pub fn label_name(&mut self, label_name: impl Into<String>) -> &mut Self {
+        self.set_label(0, label_name.into())
+    }
+
     pub fn instance(&mut self, instance: impl Into<String>) -> &mut Self {
-        self.labels[1].1 = instance.into();
-        self
+        self.set_label(1, instance.into())
     }
 
     pub fn instance_type(&mut self, instance_type: impl Into<String>) -> &mut Self {
-        self.labels[2].1 = instance_type.into();
-        self
+        self.set_label(2, instance_type.into())
     }
 
     pub fn sql_digest(&mut self, sql_digest: impl Into<String>) -> &mut Self {
-        self.labels[3].1 = sql_digest.into();
-        self
+        self.set_label(3, sql_digest.into())
     }
 
     pub fn plan_digest(&mut self, plan_digest: impl Into<String>) -> &mut Self {
-        self.labels[4].1 = plan_digest.into();
-        self
+        self.set_label(4, plan_digest.into())
     }
 
     pub fn tag_label(&mut self, tag_label: impl Into<String>) -> &mut Self {
-        self.labels[5].1 = tag_label.into();
-        self
+        self.set_label(5, tag_label.into())
     }
 
     pub fn points(&mut self, points: impl Iterator<Item = (u64, f64)>) -> &mut Self {
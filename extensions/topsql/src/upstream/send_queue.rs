@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+use vector::event::Event;
+use vector::internal_events::StreamClosedError;
+use vector::SourceSender;
+use vector_core::internal_event::InternalEvent;
+
+use crate::config::SendQueuePolicy;
+
+/// A bounded queue of events between parsing responses and sending them
+/// downstream, so the subscription keeps being read while the sink is slow.
+#[derive(Clone)]
+pub struct SendQueue {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    events: Mutex<VecDeque<Event>>,
+    capacity: usize,
+    policy: SendQueuePolicy,
+    pushed: Notify,
+    popped: Notify,
+    // no more events are pushed, e.g. on shutdown
+    closed: AtomicBool,
+}
+
+impl SendQueue {
+    pub fn new(capacity: usize, policy: SendQueuePolicy) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                events: Mutex::new(VecDeque::with_capacity(capacity)),
+                capacity,
+                policy,
+                pushed: Notify::new(),
+                popped: Notify::new(),
+                closed: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Queues `events`, returning how many queued events were dropped to make
+    /// room for them. Never waits under `DropOldest`, while under `Block` it
+    /// waits until they fit, or the queue is empty for batches larger than
+    /// the queue.
+    pub async fn push(&self, events: Vec<Event>) -> usize {
+        let inner = &self.inner;
+        loop {
+            {
+                let mut queue = inner.events.lock().unwrap();
+                let fits = queue.is_empty() || queue.len() + events.len() <= inner.capacity;
+                if fits || inner.policy == SendQueuePolicy::DropOldest {
+                    queue.extend(events);
+                    let dropped = match inner.policy {
+                        SendQueuePolicy::DropOldest => queue.len().saturating_sub(inner.capacity),
+                        SendQueuePolicy::Block => 0,
+                    };
+                    queue.drain(..dropped);
+                    inner.pushed.notify_one();
+                    return dropped;
+                }
+            }
+            inner.popped.notified().await;
+        }
+    }
+
+    /// Stops `drain` once it has sent the events queued so far.
+    pub fn close(&self) {
+        self.inner.closed.store(true, Ordering::Release);
+        self.inner.pushed.notify_one();
+    }
+
+    /// Sends the queued events downstream until the queue is closed and sent
+    /// out, or the downstream is closed.
+    pub async fn drain(self, mut out: SourceSender) {
+        let inner = &self.inner;
+        loop {
+            let events = inner.events.lock().unwrap().drain(..).collect::<Vec<_>>();
+            if events.is_empty() {
+                if inner.closed.load(Ordering::Acquire) {
+                    return;
+                }
+                inner.pushed.notified().await;
+                continue;
+            }
+            inner.popped.notify_one();
+
+            let count = events.len();
+            if let Err(error) = out.send_batch(events).await {
+                StreamClosedError { error, count }.emit();
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use vector::event::{EventArray, LogEvent};
+
+    use super::*;
+
+    fn event(n: i64) -> Event {
+        let mut log = LogEvent::default();
+        log.insert("n", n);
+        log.into()
+    }
+
+    fn count(array: EventArray) -> usize {
+        match array {
+            EventArray::Logs(logs) => logs.len(),
+            _ => panic!("expected log events"),
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_behind_slow_sink() {
+        // room for a single batch, which nobody reads until every push is done
+        let (out, mut rx) = SourceSender::new_with_buffer(1);
+        let queue = SendQueue::new(3, SendQueuePolicy::DropOldest);
+        tokio::spawn(queue.clone().drain(out));
+
+        let mut dropped = 0;
+        for n in 0..10 {
+            let push = queue.push(vec![event(n)]);
+            dropped += tokio::time::timeout(Duration::from_secs(1), push)
+                .await
+                .expect("pushing waited for the sink");
+            tokio::task::yield_now().await;
+        }
+        assert!(dropped > 0);
+
+        let mut received = vec![];
+        while received.last() != Some(&9) {
+            let array = tokio::time::timeout(Duration::from_secs(5), rx.next())
+                .await
+                .unwrap()
+                .unwrap();
+            match array {
+                EventArray::Logs(logs) => received.extend(
+                    logs.iter()
+                        .map(|log| log.get("n").unwrap().as_integer().unwrap()),
+                ),
+                _ => panic!("expected log events"),
+            }
+        }
+        // the oldest events were dropped, the rest sent in order
+        assert_eq!(received.len() + dropped, 10);
+        assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[tokio::test]
+    async fn block_behind_slow_sink() {
+        let (out, mut rx) = SourceSender::new_with_buffer(1);
+        let queue = SendQueue::new(1, SendQueuePolicy::Block);
+        tokio::spawn(queue.clone().drain(out));
+
+        let mut blocked = false;
+        for n in 0..10 {
+            let push = queue.push(vec![event(n)]);
+            match tokio::time::timeout(Duration::from_millis(100), push).await {
+                Ok(dropped) => assert_eq!(dropped, 0),
+                Err(_) => {
+                    blocked = true;
+                    break;
+                }
+            }
+        }
+        assert!(blocked);
+        // reading frees up the sink
+        assert!(rx.next().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn block_keeps_batches_larger_than_the_queue() {
+        let (out, mut rx) = SourceSender::new_with_buffer(10);
+        let queue = SendQueue::new(2, SendQueuePolicy::Block);
+
+        // taken whole into the empty queue
+        assert_eq!(queue.push((0..5).map(event).collect()).await, 0);
+        queue.close();
+        queue.drain(out).await;
+
+        assert_eq!(count(rx.next().await.unwrap()), 5);
+    }
+
+    #[tokio::test]
+    async fn send_queued_events_once_closed() {
+        let (out, rx) = SourceSender::new_with_buffer(10);
+        let queue = SendQueue::new(10, SendQueuePolicy::DropOldest);
+        for n in 0..3 {
+            queue.push(vec![event(n)]).await;
+        }
+
+        queue.close();
+        // returns once every queued event is sent
+        queue.clone().drain(out).await;
+        let sent = rx.map(count).collect::<Vec<_>>().await;
+        assert_eq!(sent.iter().sum::<usize>(), 3);
+    }
+}
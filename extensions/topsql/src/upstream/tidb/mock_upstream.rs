@@ -18,7 +18,8 @@ pub struct MockTopSqlPubSubServer;
 
 impl MockTopSqlPubSubServer {
     pub async fn run(address: SocketAddr, tls_config: Option<ServerTlsConfig>) {
-        let svc = TopSqlPubSubServer::new(Self);
+        // only compressed for clients accepting gzip
+        let svc = TopSqlPubSubServer::new(Self).send_gzip();
         let mut sb = tonic::transport::Server::builder();
         if tls_config.is_some() {
             sb = sb.tls_config(tls_config.unwrap()).unwrap();
@@ -76,3 +77,31 @@ impl TopSqlPubSub for MockTopSqlPubSubServer {
         ])) as Self::SubscribeStream))
     }
 }
+
+/// Accepts subscriptions but never sends anything, like a wedged upstream.
+pub struct SilentTopSqlPubSubServer;
+
+impl SilentTopSqlPubSubServer {
+    pub async fn run(address: SocketAddr) {
+        tonic::transport::Server::builder()
+            .add_service(TopSqlPubSubServer::new(Self))
+            .serve(address)
+            .await
+            .unwrap();
+    }
+}
+
+#[tonic::async_trait]
+impl TopSqlPubSub for SilentTopSqlPubSubServer {
+    type SubscribeStream =
+        Pin<Box<dyn Stream<Item = Result<TopSqlSubResponse, Status>> + Send + 'static>>;
+
+    async fn subscribe(
+        &self,
+        _: Request<TopSqlSubRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        Ok(Response::new(
+            Box::pin(stream::pending()) as Self::SubscribeStream
+        ))
+    }
+}
@@ -1,39 +1,67 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
+use bytes::Bytes;
 use chrono::Utc;
 use vector::event::LogEvent;
+use vector_core::internal_event::InternalEvent;
 
+use crate::internal_events::TopSQLRecordOversized;
+use crate::topology::{Component, InstanceType};
 use crate::upstream::consts::{
     INSTANCE_TYPE_TIDB, INSTANCE_TYPE_TIKV, LABEL_ENCODED_NORMALIZED_PLAN, LABEL_IS_INTERNAL_SQL,
     LABEL_NAME, LABEL_NORMALIZED_PLAN, LABEL_NORMALIZED_SQL, LABEL_PLAN_DIGEST, LABEL_SQL_DIGEST,
     METRIC_NAME_CPU_TIME_MS, METRIC_NAME_PLAN_META, METRIC_NAME_SQL_META,
     METRIC_NAME_STMT_DURATION_COUNT, METRIC_NAME_STMT_DURATION_SUM_NS, METRIC_NAME_STMT_EXEC_COUNT,
+    METRIC_NAME_STMT_KV_EXEC_COUNT,
 };
-use crate::upstream::parser::{Buf, UpstreamEventParser};
+use crate::upstream::parser::{Buf, ParserOptions, UpstreamEventParser};
 use crate::upstream::tidb::proto::top_sql_sub_response::RespOneof;
 use crate::upstream::tidb::proto::{PlanMeta, SqlMeta, TopSqlRecord, TopSqlSubResponse};
-use crate::upstream::utils::make_metric_like_log_event;
+use crate::upstream::utils::{make_metric_like_log_event, LabelInterner};
 
 pub struct TopSqlSubResponseParser;
 
 impl UpstreamEventParser for TopSqlSubResponseParser {
     type UpstreamEvent = TopSqlSubResponse;
 
-    fn parse(response: Self::UpstreamEvent, instance: String) -> Vec<LogEvent> {
+    fn parse(
+        response: Self::UpstreamEvent,
+        instance: String,
+        options: &ParserOptions,
+        interner: &mut LabelInterner,
+    ) -> Vec<LogEvent> {
         match response.resp_oneof {
-            Some(RespOneof::Record(record)) => Self::parse_tidb_record(record, instance),
-            Some(RespOneof::SqlMeta(sql_meta)) => Self::parse_tidb_sql_meta(sql_meta),
-            Some(RespOneof::PlanMeta(plan_meta)) => Self::parse_tidb_plan_meta(plan_meta),
+            Some(RespOneof::Record(record)) => {
+                Self::parse_tidb_record(record, instance, options, interner)
+            }
+            Some(RespOneof::SqlMeta(sql_meta)) => Self::parse_tidb_sql_meta(sql_meta, interner),
+            Some(RespOneof::PlanMeta(plan_meta)) => Self::parse_tidb_plan_meta(plan_meta, interner),
             None => vec![],
         }
     }
 }
 
 impl TopSqlSubResponseParser {
-    fn parse_tidb_record(record: TopSqlRecord, instance: String) -> Vec<LogEvent> {
+    fn parse_tidb_record(
+        record: TopSqlRecord,
+        instance: String,
+        options: &ParserOptions,
+        interner: &mut LabelInterner,
+    ) -> Vec<LogEvent> {
+        if let Some(max_items) = options.items_over_limit(record.items.len()) {
+            TopSQLRecordOversized {
+                instance: &instance,
+                instance_type: InstanceType::TiDB,
+                items: record.items.len(),
+                max_items,
+            }
+            .emit();
+            return vec![];
+        }
+
         let mut logs = vec![];
 
-        let mut buf = Buf::default();
+        let mut buf = Buf::new(interner);
         buf.instance(instance)
             .instance_type(INSTANCE_TYPE_TIDB)
             .sql_digest(hex::encode_upper(record.sql_digest))
@@ -68,22 +96,35 @@ impl TopSqlSubResponseParser {
         );
 
         // stmt_kv_exec_count
-        buf.label_name(METRIC_NAME_STMT_EXEC_COUNT)
+        let kv_exec_count_name = if options.separate_stmt_kv_exec_count {
+            METRIC_NAME_STMT_KV_EXEC_COUNT
+        } else {
+            METRIC_NAME_STMT_EXEC_COUNT
+        };
+        buf.label_name(kv_exec_count_name)
             .instance_type(INSTANCE_TYPE_TIKV);
 
-        let tikv_instances = record
+        // the keys are the addresses TiDB sent the requests to, which may differ
+        // from the `instance` of the TiKV series, e.g. by a scheme or port
+        let mut tikv_instances = BTreeMap::<String, Vec<&String>>::new();
+        for address in record
             .items
             .iter()
             .flat_map(|item| item.stmt_kv_exec_count.keys())
-            .collect::<BTreeSet<_>>();
-        for tikv_instance in tikv_instances {
+            .collect::<BTreeSet<_>>()
+        {
+            tikv_instances
+                .entry(Self::tikv_instance(address, options))
+                .or_default()
+                .push(address);
+        }
+        for (tikv_instance, addresses) in tikv_instances {
             buf.instance(tikv_instance)
                 .points(record.items.iter().filter_map(|item| {
-                    let count = item
-                        .stmt_kv_exec_count
-                        .get(tikv_instance)
-                        .copied()
-                        .unwrap_or_default();
+                    let count = addresses
+                        .iter()
+                        .filter_map(|address| item.stmt_kv_exec_count.get(*address))
+                        .sum::<u64>();
 
                     if count > 0 {
                         Some((item.timestamp_sec, count as f64))
@@ -99,28 +140,50 @@ impl TopSqlSubResponseParser {
         logs
     }
 
-    fn parse_tidb_sql_meta(sql_meta: SqlMeta) -> Vec<LogEvent> {
+    // the `instance` of the TiKV series of the TiKV at `address`, or `address`
+    // itself if it's not a `host:port`
+    fn tikv_instance(address: &str, options: &ParserOptions) -> String {
+        Component::tikv(address)
+            .and_then(|component| component.topsql_address(options.tikv_topsql_port))
+            .unwrap_or_else(|| address.to_owned())
+    }
+
+    // the normalized SQL and plans are sent once per digest, so only the
+    // labels shared with the series of their records are interned
+    fn parse_tidb_sql_meta(sql_meta: SqlMeta, interner: &mut LabelInterner) -> Vec<LogEvent> {
         vec![make_metric_like_log_event(
             &[
-                (LABEL_NAME, METRIC_NAME_SQL_META.to_owned()),
-                (LABEL_SQL_DIGEST, hex::encode_upper(sql_meta.sql_digest)),
-                (LABEL_NORMALIZED_SQL, sql_meta.normalized_sql),
-                (LABEL_IS_INTERNAL_SQL, sql_meta.is_internal_sql.to_string()),
+                (LABEL_NAME, interner.intern(METRIC_NAME_SQL_META)),
+                (
+                    LABEL_SQL_DIGEST,
+                    interner.intern(hex::encode_upper(sql_meta.sql_digest)),
+                ),
+                (LABEL_NORMALIZED_SQL, Bytes::from(sql_meta.normalized_sql)),
+                (
+                    LABEL_IS_INTERNAL_SQL,
+                    interner.intern(sql_meta.is_internal_sql.to_string()),
+                ),
             ],
             &[Utc::now()],
             &[1.0],
         )]
     }
 
-    fn parse_tidb_plan_meta(plan_meta: PlanMeta) -> Vec<LogEvent> {
+    fn parse_tidb_plan_meta(plan_meta: PlanMeta, interner: &mut LabelInterner) -> Vec<LogEvent> {
         vec![make_metric_like_log_event(
             &[
-                (LABEL_NAME, METRIC_NAME_PLAN_META.to_owned()),
-                (LABEL_PLAN_DIGEST, hex::encode_upper(plan_meta.plan_digest)),
-                (LABEL_NORMALIZED_PLAN, plan_meta.normalized_plan),
+                (LABEL_NAME, interner.intern(METRIC_NAME_PLAN_META)),
+                (
+                    LABEL_PLAN_DIGEST,
+                    interner.intern(hex::encode_upper(plan_meta.plan_digest)),
+                ),
+                (
+                    LABEL_NORMALIZED_PLAN,
+                    Bytes::from(plan_meta.normalized_plan),
+                ),
                 (
                     LABEL_ENCODED_NORMALIZED_PLAN,
-                    plan_meta.encoded_normalized_plan,
+                    Bytes::from(plan_meta.encoded_normalized_plan),
                 ),
             ],
             &[Utc::now()],
@@ -128,3 +191,280 @@ impl TopSqlSubResponseParser {
         )]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::upstream::consts::{LABEL_INSTANCE, LABEL_INSTANCE_TYPE};
+    use crate::upstream::tidb::proto::TopSqlRecordItem;
+
+    fn names_by_instance_type(options: &ParserOptions) -> Vec<(String, String)> {
+        let record = TopSqlRecord {
+            sql_digest: b"sql_digest".to_vec(),
+            plan_digest: b"plan_digest".to_vec(),
+            items: vec![TopSqlRecordItem {
+                timestamp_sec: 1661396787,
+                cpu_time_ms: 0,
+                stmt_exec_count: 20,
+                stmt_kv_exec_count: HashMap::from([("127.0.0.1:20160".to_owned(), 10)]),
+                stmt_duration_sum_ns: 0,
+                stmt_duration_count: 0,
+            }],
+        };
+
+        TopSqlSubResponseParser::parse_tidb_record(
+            record,
+            "127.0.0.1:10080".to_owned(),
+            options,
+            &mut LabelInterner::default(),
+        )
+        .into_iter()
+        .map(|event| {
+            let label = |name: &str| {
+                let value = event.get(format!("labels.{}", name).as_str()).unwrap();
+                String::from_utf8_lossy(value.as_bytes().unwrap()).into_owned()
+            };
+            (label(LABEL_NAME), label(LABEL_INSTANCE_TYPE))
+        })
+        .collect()
+    }
+
+    #[test]
+    fn kv_exec_count_shares_exec_count_name_by_default() {
+        let names = names_by_instance_type(&ParserOptions::default());
+        assert_eq!(
+            names,
+            vec![
+                (METRIC_NAME_STMT_EXEC_COUNT.to_owned(), "tidb".to_owned()),
+                (METRIC_NAME_STMT_EXEC_COUNT.to_owned(), "tikv".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn separate_kv_exec_count() {
+        let options = ParserOptions {
+            separate_stmt_kv_exec_count: true,
+            ..Default::default()
+        };
+        let names = names_by_instance_type(&options);
+        assert_eq!(
+            names,
+            vec![
+                (METRIC_NAME_STMT_EXEC_COUNT.to_owned(), "tidb".to_owned()),
+                (METRIC_NAME_STMT_KV_EXEC_COUNT.to_owned(), "tikv".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn kv_exec_count_instance_matches_tikv_series() {
+        let record = TopSqlRecord {
+            sql_digest: b"sql_digest".to_vec(),
+            plan_digest: b"plan_digest".to_vec(),
+            items: vec![TopSqlRecordItem {
+                timestamp_sec: 1661396787,
+                stmt_kv_exec_count: HashMap::from([
+                    ("127.0.0.1:20160".to_owned(), 10),
+                    ("http://127.0.0.1:20160/".to_owned(), 5),
+                    ("tikv-1:20160".to_owned(), 7),
+                    ("unknown".to_owned(), 3),
+                ]),
+                ..Default::default()
+            }],
+        };
+        // the way `TopSQLSource` names the TiKV it subscribes to
+        let tikv = Component {
+            instance_type: InstanceType::TiKV,
+            host: "127.0.0.1".to_owned(),
+            primary_port: 20160,
+            secondary_port: 20180,
+        };
+
+        for tikv_topsql_port in [None, Some(20161)] {
+            let options = ParserOptions {
+                tikv_topsql_port,
+                ..Default::default()
+            };
+            let port = tikv_topsql_port.unwrap_or(20160);
+            let instances = TopSqlSubResponseParser::parse_tidb_record(
+                record.clone(),
+                "127.0.0.1:10080".to_owned(),
+                &options,
+                &mut LabelInterner::default(),
+            )
+            .into_iter()
+            .map(|event| {
+                let instance = event.get("labels.instance").unwrap();
+                let instance = String::from_utf8_lossy(instance.as_bytes().unwrap()).into_owned();
+                let value = event.get("values").unwrap().as_array().unwrap()[0]
+                    .as_float()
+                    .unwrap()
+                    .into_inner();
+                (instance, value)
+            })
+            .collect::<Vec<_>>();
+            assert_eq!(
+                instances,
+                vec![
+                    (tikv.topsql_address(tikv_topsql_port).unwrap(), 15.0),
+                    (format!("tikv-1:{}", port), 7.0),
+                    ("unknown".to_owned(), 3.0),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn stable_order() {
+        // every `HashMap` is seeded differently, so would iterate in another
+        // order on every parse
+        let record = || TopSqlRecord {
+            sql_digest: b"sql_digest".to_vec(),
+            plan_digest: b"plan_digest".to_vec(),
+            items: (0..3)
+                .map(|i| TopSqlRecordItem {
+                    timestamp_sec: 1661396787 + i,
+                    cpu_time_ms: 10,
+                    stmt_exec_count: 20,
+                    stmt_kv_exec_count: (0..16)
+                        .map(|tikv| (format!("tikv-{}:20160", tikv), 1))
+                        .collect(),
+                    ..Default::default()
+                })
+                .collect(),
+        };
+        let series = || {
+            TopSqlSubResponseParser::parse_tidb_record(
+                record(),
+                "127.0.0.1:10080".to_owned(),
+                &ParserOptions::default(),
+                &mut LabelInterner::default(),
+            )
+            .into_iter()
+            .map(|event| {
+                let label = |name: &str| {
+                    let value = event.get(format!("labels.{}", name).as_str()).unwrap();
+                    String::from_utf8_lossy(value.as_bytes().unwrap()).into_owned()
+                };
+                (label(LABEL_NAME), label(LABEL_INSTANCE))
+            })
+            .collect::<Vec<_>>()
+        };
+
+        let first = series();
+        // the TiDB series by metric, then the TiKV ones by instance
+        assert_eq!(first[0].0, METRIC_NAME_CPU_TIME_MS);
+        assert_eq!(first[1].0, METRIC_NAME_STMT_EXEC_COUNT);
+        let tikv_instances = first[2..]
+            .iter()
+            .map(|(_, instance)| instance.clone())
+            .collect::<Vec<_>>();
+        let mut sorted = tikv_instances.clone();
+        sorted.sort();
+        assert_eq!(tikv_instances.len(), 16);
+        assert_eq!(tikv_instances, sorted);
+        for _ in 0..10 {
+            assert_eq!(series(), first);
+        }
+    }
+
+    #[test]
+    fn drop_oversized_record() {
+        let record = |items: u64| TopSqlRecord {
+            sql_digest: b"sql_digest".to_vec(),
+            plan_digest: b"plan_digest".to_vec(),
+            items: (0..items)
+                .map(|i| TopSqlRecordItem {
+                    timestamp_sec: 1661396787 + i,
+                    cpu_time_ms: 10,
+                    ..Default::default()
+                })
+                .collect(),
+        };
+        let options = ParserOptions {
+            max_items_per_record: Some(60),
+            ..Default::default()
+        };
+
+        let events = TopSqlSubResponseParser::parse_tidb_record(
+            record(60),
+            "127.0.0.1:10080".to_owned(),
+            &options,
+            &mut LabelInterner::default(),
+        );
+        assert_eq!(events.len(), 1);
+
+        let events = TopSqlSubResponseParser::parse_tidb_record(
+            record(61),
+            "127.0.0.1:10080".to_owned(),
+            &options,
+            &mut LabelInterner::default(),
+        );
+        assert!(events.is_empty());
+
+        // unlimited by default
+        let events = TopSqlSubResponseParser::parse_tidb_record(
+            record(100_000),
+            "127.0.0.1:10080".to_owned(),
+            &ParserOptions::default(),
+            &mut LabelInterner::default(),
+        );
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn intern_labels_across_records() {
+        let record = || TopSqlRecord {
+            sql_digest: b"sql_digest".to_vec(),
+            plan_digest: b"plan_digest".to_vec(),
+            items: vec![TopSqlRecordItem {
+                timestamp_sec: 1661396787,
+                cpu_time_ms: 10,
+                stmt_exec_count: 20,
+                ..Default::default()
+            }],
+        };
+        let parse = |interner: &mut LabelInterner| {
+            TopSqlSubResponseParser::parse_tidb_record(
+                record(),
+                "127.0.0.1:10080".to_owned(),
+                &ParserOptions::default(),
+                interner,
+            )
+        };
+        let label = |event: &LogEvent, name: &str| {
+            event
+                .get(format!("labels.{}", name).as_str())
+                .unwrap()
+                .as_bytes()
+                .unwrap()
+                .as_ptr()
+        };
+
+        let mut interner = LabelInterner::new(true);
+        let first = parse(&mut interner);
+        let second = parse(&mut interner);
+        assert_eq!(first, second);
+        for name in [
+            LABEL_NAME,
+            LABEL_INSTANCE,
+            LABEL_SQL_DIGEST,
+            LABEL_PLAN_DIGEST,
+        ] {
+            assert_eq!(label(&first[0], name), label(&second[0], name), "{}", name);
+        }
+
+        // copied by default
+        let mut interner = LabelInterner::default();
+        let first = parse(&mut interner);
+        let second = parse(&mut interner);
+        assert_eq!(first, second);
+        assert_ne!(
+            label(&first[0], LABEL_SQL_DIGEST),
+            label(&second[0], LABEL_SQL_DIGEST)
+        );
+    }
+}
@@ -7,6 +7,7 @@ pub mod mock_upstream;
 use tonic::transport::{Channel, Endpoint};
 use tonic::{Status, Streaming};
 
+use crate::config::StreamCompression;
 use crate::shutdown::ShutdownSubscriber;
 use crate::upstream::{tls_proxy, Upstream};
 
@@ -21,21 +22,29 @@ impl Upstream for TiKVUpstream {
     async fn build_endpoint(
         address: String,
         tls_config: &Option<vector::tls::TlsConfig>,
+        proxy_port: &mut Option<u16>,
         shutdown_subscriber: ShutdownSubscriber,
     ) -> vector::Result<Endpoint> {
         let endpoint = if tls_config.is_none() {
             Channel::from_shared(address.clone())?
         } else {
             // do proxy
-            let port = tls_proxy::tls_proxy(tls_config, &address, shutdown_subscriber).await?;
+            let port =
+                tls_proxy::cached_tls_proxy(proxy_port, tls_config, &address, shutdown_subscriber)
+                    .await?;
             Channel::from_shared(format!("http://127.0.0.1:{}", port))?
         };
 
         Ok(endpoint)
     }
 
-    fn build_client(channel: Channel) -> Self::Client {
-        Self::Client::new(channel)
+    fn build_client(channel: Channel, compression: StreamCompression) -> Self::Client {
+        let client = Self::Client::new(channel);
+        if compression.accepts_gzip() {
+            client.accept_gzip()
+        } else {
+            client
+        }
     }
 
     async fn build_stream(
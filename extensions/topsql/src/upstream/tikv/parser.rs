@@ -1,30 +1,72 @@
 use prost::Message;
 use vector::event::LogEvent;
+use vector_core::internal_event::InternalEvent;
 
+use crate::internal_events::TopSQLRecordOversized;
+use crate::topology::InstanceType;
 use crate::upstream::consts::{
     INSTANCE_TYPE_TIKV, KV_TAG_LABEL_INDEX, KV_TAG_LABEL_ROW, KV_TAG_LABEL_UNKNOWN,
     METRIC_NAME_CPU_TIME_MS, METRIC_NAME_READ_KEYS, METRIC_NAME_WRITE_KEYS,
 };
-use crate::upstream::parser::{Buf, UpstreamEventParser};
+use crate::upstream::parser::{Buf, ParserOptions, UpstreamEventParser};
 use crate::upstream::tidb::proto::ResourceGroupTag;
 use crate::upstream::tikv::proto::resource_usage_record::RecordOneof;
 use crate::upstream::tikv::proto::{GroupTagRecord, ResourceUsageRecord};
+use crate::upstream::utils::LabelInterner;
 
 pub struct ResourceUsageRecordParser;
 
 impl UpstreamEventParser for ResourceUsageRecordParser {
     type UpstreamEvent = ResourceUsageRecord;
 
-    fn parse(response: Self::UpstreamEvent, instance: String) -> Vec<LogEvent> {
+    fn parse(
+        response: Self::UpstreamEvent,
+        instance: String,
+        options: &ParserOptions,
+        interner: &mut LabelInterner,
+    ) -> Vec<LogEvent> {
         match response.record_oneof {
-            Some(RecordOneof::Record(record)) => Self::parse_tikv_record(record, instance),
+            Some(RecordOneof::Record(record)) => {
+                Self::parse_tikv_record(record, instance, options, interner)
+            }
             None => vec![],
         }
     }
+
+    fn check_compatibility(response: &Self::UpstreamEvent) -> Result<(), String> {
+        let tag = match &response.record_oneof {
+            Some(RecordOneof::Record(record)) => record.resource_group_tag.as_slice(),
+            None => return Ok(()),
+        };
+        if tag.is_empty() {
+            return Ok(());
+        }
+        match ResourceGroupTag::decode(tag) {
+            Ok(resource_tag) if resource_tag.sql_digest.is_some() => Ok(()),
+            Ok(_) => Err("resource group tag without an SQL digest".to_owned()),
+            Err(error) => Err(format!("failed to decode resource group tag: {}", error)),
+        }
+    }
 }
 
 impl ResourceUsageRecordParser {
-    fn parse_tikv_record(record: GroupTagRecord, instance: String) -> Vec<LogEvent> {
+    fn parse_tikv_record(
+        record: GroupTagRecord,
+        instance: String,
+        options: &ParserOptions,
+        interner: &mut LabelInterner,
+    ) -> Vec<LogEvent> {
+        if let Some(max_items) = options.items_over_limit(record.items.len()) {
+            TopSQLRecordOversized {
+                instance: &instance,
+                instance_type: InstanceType::TiKV,
+                items: record.items.len(),
+                max_items,
+            }
+            .emit();
+            return vec![];
+        }
+
         let decoded = Self::decode_tag(record.resource_group_tag.as_slice());
         if decoded.is_none() {
             return vec![];
@@ -33,7 +75,7 @@ impl ResourceUsageRecordParser {
         let mut logs = vec![];
 
         let (sql_digest, plan_digest, tag_label) = decoded.unwrap();
-        let mut buf = Buf::default();
+        let mut buf = Buf::new(interner);
         buf.instance(instance)
             .instance_type(INSTANCE_TYPE_TIKV)
             .sql_digest(sql_digest)
@@ -87,9 +129,44 @@ impl ResourceUsageRecordParser {
                 }
             }
             Err(error) => {
-                warn!(message = "Failed to decode resource tag", tag = %hex::encode(tag), %error);
+                warn!(
+                    message = "Failed to decode resource tag",
+                    tag = %hex::encode(tag),
+                    %error,
+                    internal_log_rate_secs = 10,
+                );
                 None
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(tag: Vec<u8>) -> ResourceUsageRecord {
+        ResourceUsageRecord {
+            record_oneof: Some(RecordOneof::Record(GroupTagRecord {
+                resource_group_tag: tag,
+                ..Default::default()
+            })),
+        }
+    }
+
+    #[test]
+    fn check_compatibility() {
+        let tag = ResourceGroupTag {
+            sql_digest: Some(b"sql_digest".to_vec()),
+            ..Default::default()
+        };
+        let check = ResourceUsageRecordParser::check_compatibility;
+
+        assert_eq!(check(&response(tag.encode_to_vec())), Ok(()));
+        assert_eq!(check(&response(vec![])), Ok(()));
+        assert_eq!(check(&ResourceUsageRecord::default()), Ok(()));
+        // not a resource group tag, as sent by a newer TiKV
+        assert!(check(&response(vec![0xff, 0xff, 0xff])).is_err());
+        assert!(check(&response(ResourceGroupTag::default().encode_to_vec())).is_err());
+    }
+}
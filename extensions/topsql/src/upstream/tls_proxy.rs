@@ -1,4 +1,5 @@
 use std::pin::Pin;
+use std::time::Duration;
 
 use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
@@ -8,22 +9,39 @@ use vector::tls::{tls_connector_builder, MaybeTlsSettings, TlsConfig};
 
 use crate::shutdown::ShutdownSubscriber;
 
-pub async fn tls_proxy(
+/// Returns the port of the proxy cached in `proxy_port`, spawning a new one on
+/// first use. The proxy outlives the connections going through it and is only
+/// torn down on shutdown, so reconnects reuse the same local port.
+pub async fn cached_tls_proxy(
+    proxy_port: &mut Option<u16>,
+    tls_config: &Option<TlsConfig>,
+    address: &str,
+    shutdown_subscriber: ShutdownSubscriber,
+) -> vector::Result<u16> {
+    if let Some(port) = *proxy_port {
+        return Ok(port);
+    }
+
+    let port = tls_proxy(tls_config, address, shutdown_subscriber).await?;
+    *proxy_port = Some(port);
+    Ok(port)
+}
+
+async fn tls_proxy(
     tls_config: &Option<TlsConfig>,
     address: &str,
     mut shutdown_subscriber: ShutdownSubscriber,
 ) -> vector::Result<u16> {
-    let outbound = tls_connect(tls_config, address).await?;
     let listener = TcpListener::bind("0.0.0.0:0").await?;
     let local_address = listener.local_addr()?;
 
+    let tls_config = tls_config.clone();
+    let address = address.to_owned();
     tokio::spawn(
         async move {
             tokio::select! {
                 _ = shutdown_subscriber.done() => {},
-                res = accept_and_proxy(listener, outbound) => if let Err(error) = res {
-                    error!(message = "Proxy failed to connect to the server.", error = %error);
-                }
+                _ = accept_and_proxy(listener, tls_config, address) => {},
             }
         }
         .in_current_span(),
@@ -55,14 +73,32 @@ async fn tls_connect(
     Ok(stream)
 }
 
-async fn accept_and_proxy(
-    listener: TcpListener,
-    outbound: SslStream<TcpStream>,
-) -> vector::Result<()> {
-    let (inbound, _) = listener.accept().await?;
-    drop(listener);
-    transfer(inbound, outbound).await?;
-    Ok(())
+async fn accept_and_proxy(listener: TcpListener, tls_config: Option<TlsConfig>, address: String) {
+    loop {
+        let inbound = match listener.accept().await {
+            Ok((inbound, _)) => inbound,
+            Err(error) => {
+                error!(message = "Proxy failed to accept a connection.", error = %error);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let tls_config = tls_config.clone();
+        let address = address.clone();
+        tokio::spawn(
+            async move {
+                let res = match tls_connect(&tls_config, &address).await {
+                    Ok(outbound) => transfer(inbound, outbound).await,
+                    Err(error) => Err(error),
+                };
+                if let Err(error) = res {
+                    error!(message = "Proxy failed to connect to the server.", error = %error);
+                }
+            }
+            .in_current_span(),
+        );
+    }
 }
 
 async fn transfer(
@@ -86,3 +122,38 @@ async fn transfer(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shutdown;
+    use crate::upstream::tidb::TiDBUpstream;
+    use crate::upstream::Upstream;
+
+    #[tokio::test]
+    async fn reuse_proxy_across_reconnects() {
+        let (notifier, subscriber) = shutdown::pair();
+        let tls_config = Some(TlsConfig::default());
+        let mut proxy_port = None;
+
+        let mut uris = vec![];
+        for _ in 0..3 {
+            let endpoint = TiDBUpstream::build_endpoint(
+                "https://127.0.0.1:10080".to_owned(),
+                &tls_config,
+                &mut proxy_port,
+                subscriber.clone(),
+            )
+            .await
+            .unwrap();
+            uris.push(endpoint.uri().clone());
+        }
+
+        let port = proxy_port.unwrap();
+        for uri in uris {
+            assert_eq!(uri.port_u16(), Some(port));
+        }
+
+        notifier.shutdown();
+    }
+}
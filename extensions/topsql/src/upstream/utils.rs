@@ -1,22 +1,28 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
 
 use bytes::Bytes;
 use chrono::{DateTime, Utc};
 use ordered_float::NotNan;
-use vector::event::{LogEvent, Value};
+use vector::event::{LogEvent, Metric, MetricKind, MetricValue, Value};
 
 use crate::upstream::consts::{
-    LABEL_INSTANCE, LABEL_INSTANCE_TYPE, LABEL_NAME, METRIC_NAME_INSTANCE,
+    LABEL_ADDRESS, LABEL_INSTANCE, LABEL_INSTANCE_TYPE, LABEL_NAME, LABEL_TYPE,
+    METRIC_NAME_CPU_TIME_MS, METRIC_NAME_INSTANCE, METRIC_NAME_READ_KEYS,
+    METRIC_NAME_SELF_RECORDS_TOTAL, METRIC_NAME_STMT_DURATION_COUNT,
+    METRIC_NAME_STMT_DURATION_SUM_NS, METRIC_NAME_STMT_EXEC_COUNT, METRIC_NAME_STMT_KV_EXEC_COUNT,
+    METRIC_NAME_WRITE_KEYS,
 };
 
-pub fn make_metric_like_log_event(
-    labels: &[(&'static str, String)],
+/// Labels given as `Bytes` are shared by the built event rather than copied,
+/// which is cheaper for labels repeated across many series, e.g. digests.
+pub fn make_metric_like_log_event<V: Clone + Into<Bytes>>(
+    labels: &[(&'static str, V)],
     timestamps: &[DateTime<Utc>],
     values: &[f64],
 ) -> LogEvent {
     let mut labels_map = BTreeMap::new();
     for (k, v) in labels {
-        labels_map.insert(k.to_string(), Value::Bytes(Bytes::from(v.clone())));
+        labels_map.insert(k.to_string(), Value::Bytes(v.clone().into()));
     }
 
     let timestamps_vec = timestamps
@@ -46,3 +52,427 @@ pub fn instance_event(instance: String, instance_type: String) -> LogEvent {
         &[1.0],
     )
 }
+
+/// A series of the source's own health, labeled by the upstream.
+pub fn self_metric_event(
+    name: &'static str,
+    instance: String,
+    instance_type: String,
+    value: f64,
+) -> LogEvent {
+    make_metric_like_log_event(
+        &[
+            (LABEL_NAME, name.to_owned()),
+            (LABEL_INSTANCE, instance),
+            (LABEL_INSTANCE_TYPE, instance_type),
+        ],
+        &[Utc::now()],
+        &[value],
+    )
+}
+
+/// The protobuf encoding of an upstream response, base64 encoded under `raw`,
+/// for replaying it through the parser later.
+pub fn raw_event(
+    response: &impl prost::Message,
+    instance: String,
+    instance_type: String,
+) -> LogEvent {
+    let mut log = BTreeMap::new();
+    log.insert(
+        "raw".to_owned(),
+        Value::from(base64::encode(response.encode_to_vec())),
+    );
+    log.insert(LABEL_INSTANCE.to_owned(), Value::from(instance));
+    log.insert(LABEL_INSTANCE_TYPE.to_owned(), Value::from(instance_type));
+    log.insert("timestamp".to_owned(), Value::Timestamp(Utc::now()));
+    log.into()
+}
+
+// The distinct label values a `LabelInterner` keeps at most, starting over
+// once full, so the digests of statements long gone aren't kept forever.
+const MAX_INTERNED_LABELS: usize = 100_000;
+
+/// Hands out one shared buffer per distinct label value, e.g. the metric
+/// names, instances and digests repeated across the series of every response
+/// of an upstream, so the events built with it hold every distinct value
+/// once. A disabled interner, the default, hands every value out as is.
+#[derive(Debug, Default)]
+pub struct LabelInterner {
+    values: Option<HashSet<Bytes>>,
+}
+
+impl LabelInterner {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            values: enabled.then(HashSet::new),
+        }
+    }
+
+    pub fn intern(&mut self, value: impl Into<Bytes>) -> Bytes {
+        let value = value.into();
+        let values = match &mut self.values {
+            Some(values) => values,
+            None => return value,
+        };
+        if let Some(shared) = values.get(&value) {
+            return shared.clone();
+        }
+        if values.len() >= MAX_INTERNED_LABELS {
+            values.clear();
+        }
+        values.insert(value.clone());
+        value
+    }
+}
+
+/// Replaces the `instance` of the series of `events` by its alias, if any,
+/// keeping the raw address under `address`, e.g. for joining with series
+/// collected elsewhere.
+pub fn alias_instances(events: &mut [LogEvent], aliases: &BTreeMap<String, String>) {
+    for event in events {
+        let labels = match event.get_mut("labels") {
+            Some(Value::Object(labels)) => labels,
+            _ => continue,
+        };
+        let alias = match labels.get(LABEL_INSTANCE) {
+            Some(Value::Bytes(instance)) => {
+                match aliases.get(&*String::from_utf8_lossy(instance)) {
+                    Some(alias) => alias,
+                    None => continue,
+                }
+            }
+            _ => continue,
+        };
+        let address = labels.insert(LABEL_INSTANCE.to_owned(), Value::from(alias.as_str()));
+        if let Some(address) = address {
+            labels.insert(LABEL_ADDRESS.to_owned(), address);
+        }
+    }
+}
+
+/// Labels every series of `events` with its metric type under `__type__`,
+/// `counter` or `gauge`, for converting them into typed metrics downstream.
+pub fn label_metric_types(events: &mut [LogEvent]) {
+    for event in events {
+        let labels = match event.get_mut("labels") {
+            Some(Value::Object(labels)) => labels,
+            _ => continue,
+        };
+        let metric_type = match labels.get(LABEL_NAME) {
+            Some(Value::Bytes(name)) => metric_type(&String::from_utf8_lossy(name)),
+            _ => continue,
+        };
+        labels.insert(LABEL_TYPE.to_owned(), Value::from(metric_type.as_str()));
+    }
+}
+
+/// Convert a log event built by `make_metric_like_log_event` into one metric per point.
+pub fn into_metrics(mut log: LogEvent) -> Vec<Metric> {
+    let labels = match log.remove("labels") {
+        Some(Value::Object(labels)) => labels,
+        _ => return vec![],
+    };
+    let timestamps = match log.remove("timestamps") {
+        Some(Value::Array(timestamps)) => timestamps,
+        _ => return vec![],
+    };
+    let values = match log.remove("values") {
+        Some(Value::Array(values)) => values,
+        _ => return vec![],
+    };
+
+    let mut name = String::new();
+    let mut tags = BTreeMap::new();
+    for (key, value) in labels {
+        let value = match value {
+            Value::Bytes(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+            _ => continue,
+        };
+        if key == LABEL_NAME {
+            name = value;
+        } else if key != LABEL_TYPE {
+            tags.insert(key, value);
+        }
+    }
+
+    timestamps
+        .into_iter()
+        .zip(values)
+        .filter_map(|(timestamp, value)| match (timestamp, value) {
+            (Value::Timestamp(timestamp), Value::Float(value)) => {
+                let (kind, value) = metric_value(&name, value.into_inner());
+                Some(
+                    Metric::new(name.clone(), kind, value)
+                        .with_tags(Some(tags.clone()))
+                        .with_timestamp(Some(timestamp)),
+                )
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+}
+
+impl MetricType {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Counter => "counter",
+            Self::Gauge => "gauge",
+        }
+    }
+}
+
+// Resource usage is reported as per-second deltas, and the records of the
+// source are a running total, so they're counters. Meta, instance and uptime
+// series only signal presence or a current value and stay gauges.
+pub fn metric_type(name: &str) -> MetricType {
+    match name {
+        METRIC_NAME_CPU_TIME_MS
+        | METRIC_NAME_READ_KEYS
+        | METRIC_NAME_WRITE_KEYS
+        | METRIC_NAME_STMT_EXEC_COUNT
+        | METRIC_NAME_STMT_KV_EXEC_COUNT
+        | METRIC_NAME_STMT_DURATION_SUM_NS
+        | METRIC_NAME_STMT_DURATION_COUNT
+        | METRIC_NAME_SELF_RECORDS_TOTAL => MetricType::Counter,
+        _ => MetricType::Gauge,
+    }
+}
+
+fn metric_value(name: &str, value: f64) -> (MetricKind, MetricValue) {
+    match metric_type(name) {
+        // a running total rather than a delta
+        MetricType::Counter if name == METRIC_NAME_SELF_RECORDS_TOTAL => {
+            (MetricKind::Absolute, MetricValue::Counter { value })
+        }
+        MetricType::Counter => (MetricKind::Incremental, MetricValue::Counter { value }),
+        MetricType::Gauge => (MetricKind::Absolute, MetricValue::Gauge { value }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::upstream::consts::{
+        METRIC_NAME_PLAN_META, METRIC_NAME_SELF_CONNECTED_SECONDS, METRIC_NAME_SQL_META,
+    };
+
+    #[test]
+    fn log_into_metrics() {
+        let t1 = Utc.timestamp(1661396787, 0);
+        let t2 = Utc.timestamp(1661396788, 0);
+        let log = make_metric_like_log_event(
+            &[
+                (LABEL_NAME, METRIC_NAME_CPU_TIME_MS.to_owned()),
+                (LABEL_INSTANCE, "db:10080".to_owned()),
+            ],
+            &[t1, t2],
+            &[80.0, 443.0],
+        );
+
+        let metrics = into_metrics(log);
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics[0].name(), METRIC_NAME_CPU_TIME_MS);
+        assert_eq!(metrics[0].kind(), MetricKind::Incremental);
+        assert_eq!(metrics[0].value(), &MetricValue::Counter { value: 80.0 });
+        assert_eq!(metrics[0].timestamp(), Some(t1));
+        assert_eq!(metrics[1].value(), &MetricValue::Counter { value: 443.0 });
+        assert_eq!(metrics[1].timestamp(), Some(t2));
+        assert_eq!(
+            metrics[0].tags().and_then(|tags| tags.get(LABEL_INSTANCE)),
+            Some(&"db:10080".to_owned())
+        );
+        assert!(metrics[0]
+            .tags()
+            .map(|tags| !tags.contains_key(LABEL_NAME))
+            .unwrap_or_default());
+    }
+
+    #[test]
+    fn alias_instance() {
+        let aliases = BTreeMap::from([("db:10080".to_owned(), "tidb-0".to_owned())]);
+        let mut events = vec![
+            instance_event("db:10080".to_owned(), "tidb".to_owned()),
+            instance_event("kv:20180".to_owned(), "tikv".to_owned()),
+        ];
+        alias_instances(&mut events, &aliases);
+
+        let label = |event: &LogEvent, name: &str| {
+            event
+                .get(format!("labels.{}", name).as_str())
+                .map(|value| String::from_utf8_lossy(value.as_bytes().unwrap()).into_owned())
+        };
+        assert_eq!(label(&events[0], LABEL_INSTANCE).as_deref(), Some("tidb-0"));
+        assert_eq!(
+            label(&events[0], LABEL_ADDRESS).as_deref(),
+            Some("db:10080")
+        );
+        // left alone without an alias
+        assert_eq!(
+            label(&events[1], LABEL_INSTANCE).as_deref(),
+            Some("kv:20180")
+        );
+        assert_eq!(label(&events[1], LABEL_ADDRESS), None);
+
+        // the address is carried over as a tag of metrics
+        let metrics = into_metrics(events.remove(0));
+        let tags = metrics[0].tags().unwrap();
+        assert_eq!(tags.get(LABEL_INSTANCE).map(String::as_str), Some("tidb-0"));
+        assert_eq!(
+            tags.get(LABEL_ADDRESS).map(String::as_str),
+            Some("db:10080")
+        );
+    }
+
+    #[test]
+    fn classify_metric_types() {
+        for name in [
+            METRIC_NAME_CPU_TIME_MS,
+            METRIC_NAME_READ_KEYS,
+            METRIC_NAME_WRITE_KEYS,
+            METRIC_NAME_STMT_EXEC_COUNT,
+            METRIC_NAME_STMT_KV_EXEC_COUNT,
+            METRIC_NAME_STMT_DURATION_SUM_NS,
+            METRIC_NAME_STMT_DURATION_COUNT,
+            METRIC_NAME_SELF_RECORDS_TOTAL,
+        ] {
+            assert_eq!(metric_type(name), MetricType::Counter, "{}", name);
+        }
+        for name in [
+            METRIC_NAME_SQL_META,
+            METRIC_NAME_PLAN_META,
+            METRIC_NAME_INSTANCE,
+            METRIC_NAME_SELF_CONNECTED_SECONDS,
+        ] {
+            assert_eq!(metric_type(name), MetricType::Gauge, "{}", name);
+        }
+
+        let mut events = vec![
+            make_metric_like_log_event(
+                &[(LABEL_NAME, METRIC_NAME_CPU_TIME_MS.to_owned())],
+                &[Utc::now()],
+                &[80.0],
+            ),
+            instance_event("db:10080".to_owned(), "tidb".to_owned()),
+        ];
+        label_metric_types(&mut events);
+        assert_eq!(
+            events[0].get("labels.__type__"),
+            Some(&Value::from("counter"))
+        );
+        assert_eq!(
+            events[1].get("labels.__type__"),
+            Some(&Value::from("gauge"))
+        );
+
+        // typed metrics carry the type by their kind instead
+        let metrics = into_metrics(events.remove(0));
+        assert!(metrics[0]
+            .tags()
+            .map(|tags| !tags.contains_key(LABEL_TYPE))
+            .unwrap_or_default());
+    }
+
+    // the bytes of the distinct label buffers of `events`
+    fn label_buffer_bytes(events: &[LogEvent]) -> usize {
+        let mut buffers = HashMap::new();
+        for event in events {
+            if let Some(Value::Object(labels)) = event.get("labels") {
+                for value in labels.values() {
+                    if let Value::Bytes(bytes) = value {
+                        buffers.insert(bytes.as_ptr(), bytes.len());
+                    }
+                }
+            }
+        }
+        buffers.values().sum()
+    }
+
+    #[test]
+    fn intern_equal_labels() {
+        let event = |interner: &mut LabelInterner, name: &str| {
+            make_metric_like_log_event(
+                &[
+                    (LABEL_NAME, interner.intern(name.to_owned())),
+                    (LABEL_INSTANCE, interner.intern("db:10080".to_owned())),
+                ],
+                &[Utc::now()],
+                &[1.0],
+            )
+        };
+        let events = |interner: &mut LabelInterner| {
+            vec![
+                event(interner, METRIC_NAME_CPU_TIME_MS),
+                event(interner, METRIC_NAME_CPU_TIME_MS),
+                event(interner, METRIC_NAME_READ_KEYS),
+            ]
+        };
+
+        let copied = events(&mut LabelInterner::default());
+        let mut interner = LabelInterner::new(true);
+        let interned = events(&mut interner);
+        assert_eq!(interned, copied);
+        let label = |event: &LogEvent, name: &str| {
+            event
+                .get(format!("labels.{}", name).as_str())
+                .unwrap()
+                .as_bytes()
+                .unwrap()
+                .as_ptr()
+        };
+        assert_eq!(
+            label(&interned[0], LABEL_NAME),
+            label(&interned[1], LABEL_NAME)
+        );
+        assert_ne!(
+            label(&interned[0], LABEL_NAME),
+            label(&interned[2], LABEL_NAME)
+        );
+        assert_eq!(
+            label(&interned[0], LABEL_INSTANCE),
+            label(&interned[2], LABEL_INSTANCE)
+        );
+        assert_eq!(
+            label_buffer_bytes(&interned),
+            "db:10080".len() + METRIC_NAME_CPU_TIME_MS.len() + METRIC_NAME_READ_KEYS.len()
+        );
+        assert!(label_buffer_bytes(&copied) > label_buffer_bytes(&interned));
+
+        // shared with the events built later on
+        let later = event(&mut interner, METRIC_NAME_CPU_TIME_MS);
+        assert_eq!(label(&later, LABEL_NAME), label(&interned[0], LABEL_NAME));
+    }
+
+    #[test]
+    fn bound_interned_labels() {
+        let mut interner = LabelInterner::new(true);
+        let first = interner.intern("0".to_owned());
+        for i in 1..MAX_INTERNED_LABELS {
+            interner.intern(i.to_string());
+        }
+        assert_eq!(interner.intern("0".to_owned()).as_ptr(), first.as_ptr());
+
+        // starts over once full
+        interner.intern(MAX_INTERNED_LABELS.to_string());
+        assert_ne!(interner.intern("0".to_owned()).as_ptr(), first.as_ptr());
+    }
+
+    #[test]
+    fn instance_into_gauge() {
+        let metrics = into_metrics(instance_event("db:10080".to_owned(), "tidb".to_owned()));
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].name(), METRIC_NAME_INSTANCE);
+        assert_eq!(metrics[0].kind(), MetricKind::Absolute);
+        assert_eq!(metrics[0].value(), &MetricValue::Gauge { value: 1.0 });
+    }
+}
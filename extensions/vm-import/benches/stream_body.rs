@@ -0,0 +1,124 @@
+//! Compares the peak memory of gzipping a batch of 10 MiB of series into a
+//! request body buffered whole, as with `chunked_transfer = false`, with
+//! sending it as it's compressed, the default. Peaks are taken above the
+//! batch, which is kept for retries either way. Run with
+//! `cargo bench -p vm-import --features bench --bench stream_body`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures_util::future::join;
+use hyper::body::HttpBody;
+use serde_json::value::{to_raw_value, RawValue};
+use vm_import::bench::{EncoderSettings, Gzip, VMImportSink};
+
+const BATCH_BYTES: usize = 10 * 1024 * 1024;
+
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(allocated, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Series of TopSQL metrics in the JSON line format of `/api/v1/import`,
+/// adding up to `BATCH_BYTES`.
+fn batch() -> Vec<Box<RawValue>> {
+    let mut events = vec![];
+    let mut bytes = 0;
+    let mut i = 0u64;
+    while bytes < BATCH_BYTES {
+        let series = to_raw_value(&serde_json::json!({
+            "metric": {
+                "__name__": "topsql_cpu_time_ms",
+                "instance": format!("10.0.{}.{}:10080", i / 256 % 256, i % 256),
+                "sql_digest": format!("{:064x}", i.wrapping_mul(0x9e37_79b9_7f4a_7c15)),
+            },
+            "timestamps": (0..60u64)
+                .map(|second| 1661396787000 + second * 1000)
+                .collect::<Vec<_>>(),
+            "values": (0..60).map(|second| (i * 7 + second) % 1000).collect::<Vec<_>>(),
+        }))
+        .unwrap();
+        bytes += series.get().len() + 1;
+        events.push(series);
+        i += 1;
+    }
+    events
+}
+
+enum Body {
+    Buffered,
+    Streamed,
+}
+
+/// Compresses a fresh batch into a body of the kind of `body`, reading it as
+/// it's sent, and returns the peak memory allocated meanwhile along with the
+/// size of the body.
+fn peak_memory(
+    sink: &VMImportSink,
+    runtime: &tokio::runtime::Runtime,
+    body: Body,
+) -> (usize, usize) {
+    let events = batch();
+    let base = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(base, Ordering::Relaxed);
+
+    let compressed = match body {
+        Body::Buffered => sink.compress(&events).unwrap().len(),
+        Body::Streamed => runtime.block_on(async {
+            let (sender, mut body) = hyper::Body::channel();
+            let read = async {
+                let mut read = 0;
+                while let Some(chunk) = body.data().await {
+                    read += chunk.unwrap().len();
+                }
+                read
+            };
+            let (compressed, read) = join(
+                sink.compress_into(&events, Default::default(), sender),
+                read,
+            )
+            .await;
+            compressed.unwrap();
+            read
+        }),
+    };
+    (PEAK.load(Ordering::Relaxed) - base, compressed)
+}
+
+fn main() {
+    let sink = VMImportSink::new(
+        "http://localhost:8428/api/v1/import".try_into().unwrap(),
+        EncoderSettings::default(),
+        None,
+        Gzip::default(),
+        None,
+    );
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    let (buffered, compressed) = peak_memory(&sink, &runtime, Body::Buffered);
+    let (streamed, _) = peak_memory(&sink, &runtime, Body::Streamed);
+
+    let mib = |bytes: usize| bytes as f64 / (1 << 20) as f64;
+    println!("batch:            {:>8.1} MiB", mib(BATCH_BYTES));
+    println!("body:             {:>8.1} MiB", mib(compressed));
+    println!("buffered:         {:>8.1} MiB", mib(buffered));
+    println!("streamed:         {:>8.1} MiB", mib(streamed));
+}
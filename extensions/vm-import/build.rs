@@ -0,0 +1,7 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    println!("cargo:rerun-if-changed=proto/metrics_service.proto");
+
+    prost_build::compile_protos(&["proto/metrics_service.proto"], &["proto/"]).unwrap();
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+use vector::emit;
+use vector::sinks::util::{
+    Batch, BoxedRawValue, JsonArrayBuffer, Partition, PartitionBuffer, PartitionInnerBuffer,
+    PushResult,
+};
+
+use crate::internal_events::VMImportBatchLimit;
+use crate::partition::PartitionKey;
+
+/// The number of events per batch of every endpoint, adapted to how fast the
+/// endpoint answers.
+///
+/// A batch answered within `target_latency` grows the limit of its endpoint by
+/// a quarter, while slow, failed or timed out batches halve it, always within
+/// `min_events` and `max_events`. Endpoints start at `min_events`, so a cold
+/// or overloaded cluster isn't sent the largest batches first. Endpoints not
+/// sent a batch for `IDLE_TIMEOUT` are forgotten, and start over at
+/// `min_events`, so endpoints rendered once, e.g. by a tenant gone since, don't
+/// pile up.
+#[derive(Clone)]
+pub struct AdaptiveBatchLimits {
+    min_events: usize,
+    max_events: usize,
+    target_latency: Duration,
+    limits: Arc<Mutex<Limits>>,
+}
+
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+struct Limits {
+    // the limit of every endpoint, with when it was last observed
+    by_key: HashMap<PartitionKey, (usize, Instant)>,
+    // idle endpoints are looked for at most once per `IDLE_TIMEOUT`
+    last_evicted: Instant,
+}
+
+impl AdaptiveBatchLimits {
+    pub fn new(min_events: usize, max_events: usize, target_latency: Duration) -> Self {
+        let min_events = min_events.max(1);
+        Self {
+            min_events,
+            max_events: max_events.max(min_events),
+            target_latency,
+            limits: Arc::new(Mutex::new(Limits {
+                by_key: HashMap::new(),
+                last_evicted: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn limit(&self, key: &PartitionKey) -> usize {
+        self.limits
+            .lock()
+            .unwrap()
+            .by_key
+            .get(key)
+            .map_or(self.min_events, |(limit, _)| *limit)
+    }
+
+    /// Adapts the limit of `key` to a batch answered after `latency`, or to a
+    /// failed one if `None`.
+    pub fn observe(&self, key: &PartitionKey, latency: Option<Duration>) {
+        let now = Instant::now();
+        let mut limits = self.limits.lock().unwrap();
+        if now.duration_since(limits.last_evicted) >= IDLE_TIMEOUT {
+            limits
+                .by_key
+                .retain(|_, (_, observed)| now.duration_since(*observed) < IDLE_TIMEOUT);
+            limits.last_evicted = now;
+        }
+        let (limit, observed) = limits
+            .by_key
+            .entry(key.clone())
+            .or_insert((self.min_events, now));
+        *observed = now;
+        let adapted = match latency {
+            Some(latency) if latency <= self.target_latency => {
+                (*limit + (*limit / 4).max(1)).min(self.max_events)
+            }
+            _ => (*limit / 2).max(self.min_events),
+        };
+        if adapted != *limit {
+            *limit = adapted;
+            emit!(VMImportBatchLimit {
+                endpoint: &key.endpoint,
+                limit: adapted,
+            });
+        }
+    }
+}
+
+/// A `PartitionBuffer` also full once it holds as many events as the adaptive
+/// limit of its endpoint, read when the first event is pushed. Without limits
+/// it behaves as the plain `PartitionBuffer`.
+pub struct AdaptiveBuffer {
+    inner: PartitionBuffer<JsonArrayBuffer, PartitionKey>,
+    limits: Option<AdaptiveBatchLimits>,
+    limit: Option<usize>,
+}
+
+impl AdaptiveBuffer {
+    pub const fn new(
+        inner: PartitionBuffer<JsonArrayBuffer, PartitionKey>,
+        limits: Option<AdaptiveBatchLimits>,
+    ) -> Self {
+        Self {
+            inner,
+            limits,
+            limit: None,
+        }
+    }
+}
+
+impl Batch for AdaptiveBuffer {
+    type Input = PartitionInnerBuffer<serde_json::Value, PartitionKey>;
+    type Output = PartitionInnerBuffer<Vec<BoxedRawValue>, PartitionKey>;
+
+    fn push(&mut self, item: Self::Input) -> PushResult<Self::Input> {
+        let limit = match (&self.limits, self.limit) {
+            (None, _) => return self.inner.push(item),
+            (Some(_), Some(limit)) => limit,
+            (Some(limits), None) => *self.limit.insert(limits.limit(&item.partition())),
+        };
+        if self.inner.num_items() >= limit {
+            return PushResult::Overflow(item);
+        }
+        match self.inner.push(item) {
+            PushResult::Ok(full) => PushResult::Ok(full || self.inner.num_items() >= limit),
+            overflow => overflow,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    fn fresh(&self) -> Self {
+        Self::new(self.inner.fresh(), self.limits.clone())
+    }
+
+    fn finish(self) -> Self::Output {
+        self.inner.finish()
+    }
+
+    fn num_items(&self) -> usize {
+        self.inner.num_items()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vector::sinks::util::BatchConfig;
+
+    use super::*;
+    use crate::config::VMImportDefaultBatchSettings;
+
+    #[test]
+    fn adapt_to_endpoint_latency() {
+        let limits = AdaptiveBatchLimits::new(10, 1000, Duration::from_secs(1));
+        let fast = PartitionKey::new("http://fast:8428".to_owned(), "agent".to_owned());
+        let slow = PartitionKey::new("http://slow:8428".to_owned(), "agent".to_owned());
+
+        for _ in 0..20 {
+            limits.observe(&fast, Some(Duration::from_millis(50)));
+            limits.observe(&slow, Some(Duration::from_millis(50)));
+        }
+        let warmed_up = limits.limit(&slow);
+        assert!(warmed_up > 100);
+
+        // the slow endpoint starts timing out, the fast one keeps answering fast
+        for _ in 0..3 {
+            limits.observe(&fast, Some(Duration::from_millis(50)));
+            limits.observe(&slow, Some(Duration::from_secs(5)));
+        }
+        assert!(limits.limit(&slow) < warmed_up / 4);
+        assert!(limits.limit(&fast) > warmed_up);
+
+        for _ in 0..100 {
+            limits.observe(&fast, Some(Duration::from_millis(50)));
+            limits.observe(&slow, None);
+        }
+        assert_eq!(limits.limit(&fast), 1000);
+        assert_eq!(limits.limit(&slow), 10);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forget_idle_endpoints() {
+        let limits = AdaptiveBatchLimits::new(10, 1000, Duration::from_secs(1));
+        let busy = PartitionKey::new("http://busy:8428".to_owned(), "agent".to_owned());
+        let idle = PartitionKey::new("http://idle:8428".to_owned(), "agent".to_owned());
+
+        for _ in 0..10 {
+            limits.observe(&busy, Some(Duration::from_millis(50)));
+            limits.observe(&idle, Some(Duration::from_millis(50)));
+        }
+        let warmed_up = limits.limit(&idle);
+        assert!(warmed_up > 10);
+
+        // only the busy endpoint is sent batches past the idle timeout
+        for _ in 0..3 {
+            tokio::time::advance(IDLE_TIMEOUT / 2).await;
+            limits.observe(&busy, Some(Duration::from_millis(50)));
+        }
+        assert_eq!(limits.limits.lock().unwrap().by_key.len(), 1);
+        assert_eq!(limits.limit(&idle), 10);
+        assert!(limits.limit(&busy) > warmed_up);
+    }
+
+    #[test]
+    fn buffer_full_at_limit() {
+        let limits = AdaptiveBatchLimits::new(2, 1000, Duration::from_secs(1));
+        let key = PartitionKey::new("http://localhost:8428".to_owned(), "agent".to_owned());
+        // up to 1000 events
+        let size = || {
+            BatchConfig::<VMImportDefaultBatchSettings>::default()
+                .into_batch_settings::<JsonArrayBuffer>()
+                .unwrap()
+                .size
+        };
+        let item = || PartitionInnerBuffer::new(serde_json::json!({}), key.clone());
+
+        let mut buffer = AdaptiveBuffer::new(
+            PartitionBuffer::new(JsonArrayBuffer::new(size())),
+            Some(limits.clone()),
+        );
+        assert!(matches!(buffer.push(item()), PushResult::Ok(false)));
+        assert!(matches!(buffer.push(item()), PushResult::Ok(true)));
+        assert!(matches!(buffer.push(item()), PushResult::Overflow(_)));
+
+        // a fresh batch picks up the grown limit
+        limits.observe(&key, Some(Duration::from_millis(50)));
+        let mut buffer = buffer.fresh();
+        for _ in 0..2 {
+            assert!(matches!(buffer.push(item()), PushResult::Ok(false)));
+        }
+        assert!(matches!(buffer.push(item()), PushResult::Ok(true)));
+        assert_eq!(buffer.num_items(), 3);
+
+        // unlimited without adaptive limits
+        let mut buffer =
+            AdaptiveBuffer::new(PartitionBuffer::new(JsonArrayBuffer::new(size())), None);
+        for _ in 0..10 {
+            assert!(matches!(buffer.push(item()), PushResult::Ok(false)));
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use vector::emit;
+
+use crate::internal_events::VMImportCompressionLevel;
+
+const MIN_AUTO_LEVEL: u32 = 1;
+const MAX_AUTO_LEVEL: u32 = 9;
+
+/// The gzip level of request bodies, from `0` (stored) to `9` (smallest), or
+/// `auto` to adapt it to `compression_cpu_budget`.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum CompressionLevel {
+    Fixed(u32),
+    Auto(Auto),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Auto {
+    Auto,
+}
+
+/// How request bodies are gzipped.
+#[derive(Clone, Debug)]
+pub enum Gzip {
+    Fixed(Compression),
+    Auto(AutoGzipLevel),
+}
+
+impl Default for Gzip {
+    fn default() -> Self {
+        Self::Fixed(Compression::default())
+    }
+}
+
+impl Gzip {
+    pub fn new(level: Option<CompressionLevel>, cpu_budget: f64) -> Result<Self, String> {
+        match level {
+            None => Ok(Self::default()),
+            Some(CompressionLevel::Fixed(level)) if level > 9 => {
+                Err(format!("`compression_level` must be 0 to 9, got {}", level))
+            }
+            Some(CompressionLevel::Fixed(level)) => Ok(Self::Fixed(Compression::new(level))),
+            Some(CompressionLevel::Auto(_)) if !cpu_budget.is_finite() || cpu_budget <= 0.0 => {
+                Err("`compression_cpu_budget` must be positive".to_owned())
+            }
+            Some(CompressionLevel::Auto(_)) => Ok(Self::Auto(AutoGzipLevel::new(cpu_budget))),
+        }
+    }
+
+    /// The level to compress the next body at.
+    pub fn level(&self) -> Compression {
+        match self {
+            Self::Fixed(level) => *level,
+            Self::Auto(auto) => auto.level(),
+        }
+    }
+
+    /// Reports that compressing a body took `busy`.
+    pub fn observe(&self, busy: Duration) {
+        if let Self::Auto(auto) = self {
+            auto.observe(busy);
+        }
+    }
+}
+
+/// A gzip level adapted to the CPU time spent compressing.
+///
+/// Every body reports how long it took to compress, which is CPU bound, and
+/// the share of the wall time since the previous report spent compressing is
+/// held against `cpu_budget`, a fraction of one core. Over budget the level
+/// drops by one, under half the budget it rises by one, always within 1 and 9.
+/// The level starts at gzip's default of 6 and is shared by every request.
+#[derive(Clone, Debug)]
+pub struct AutoGzipLevel {
+    cpu_budget: f64,
+    state: Arc<Mutex<AutoState>>,
+}
+
+#[derive(Debug)]
+struct AutoState {
+    level: u32,
+    last_observed: Option<Instant>,
+}
+
+impl AutoGzipLevel {
+    pub fn new(cpu_budget: f64) -> Self {
+        Self {
+            cpu_budget,
+            state: Arc::new(Mutex::new(AutoState {
+                level: Compression::default().level(),
+                last_observed: None,
+            })),
+        }
+    }
+
+    pub fn level(&self) -> Compression {
+        Compression::new(self.state.lock().unwrap().level)
+    }
+
+    fn observe(&self, busy: Duration) {
+        self.observe_at(busy, Instant::now());
+    }
+
+    fn observe_at(&self, busy: Duration, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        // the first body has nothing to be compared against
+        let elapsed = match state.last_observed.replace(now) {
+            Some(last_observed) => now.saturating_duration_since(last_observed),
+            None => return,
+        };
+        let usage = if elapsed.is_zero() {
+            f64::INFINITY
+        } else {
+            busy.as_secs_f64() / elapsed.as_secs_f64()
+        };
+
+        let adapted = if usage > self.cpu_budget {
+            state.level.saturating_sub(1).max(MIN_AUTO_LEVEL)
+        } else if usage < self.cpu_budget / 2.0 {
+            (state.level + 1).min(MAX_AUTO_LEVEL)
+        } else {
+            state.level
+        };
+        if adapted != state.level {
+            state.level = adapted;
+            emit!(VMImportCompressionLevel { level: adapted });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_level() {
+        #[derive(Deserialize)]
+        struct Config {
+            compression_level: CompressionLevel,
+        }
+        let parse = |config: &str| toml::from_str::<Config>(config).map(|c| c.compression_level);
+
+        assert_eq!(
+            parse("compression_level = 3").unwrap(),
+            CompressionLevel::Fixed(3)
+        );
+        assert_eq!(
+            parse(r#"compression_level = "auto""#).unwrap(),
+            CompressionLevel::Auto(Auto::Auto)
+        );
+        assert!(parse(r#"compression_level = "best""#).is_err());
+
+        assert!(Gzip::new(Some(CompressionLevel::Fixed(10)), 0.1).is_err());
+        assert!(Gzip::new(Some(CompressionLevel::Auto(Auto::Auto)), 0.0).is_err());
+        assert_eq!(
+            Gzip::new(Some(CompressionLevel::Fixed(3)), 0.0)
+                .unwrap()
+                .level(),
+            Compression::new(3)
+        );
+        assert_eq!(
+            Gzip::new(None, 0.0).unwrap().level(),
+            Compression::default()
+        );
+    }
+
+    #[test]
+    fn auto_level_follows_cpu_usage() {
+        let auto = AutoGzipLevel::new(0.1);
+        let mut now = Instant::now();
+        let mut batch = |auto: &AutoGzipLevel, busy_ms: u64| {
+            now += Duration::from_millis(100);
+            auto.observe_at(Duration::from_millis(busy_ms), now);
+            auto.level().level()
+        };
+
+        // the first batch sets the baseline
+        assert_eq!(batch(&auto, 50), 6);
+
+        // half a core spent compressing, over the budget of a tenth
+        let mut level = 6;
+        for _ in 0..3 {
+            let lowered = batch(&auto, 50);
+            assert!(lowered < level);
+            level = lowered;
+        }
+        for _ in 0..10 {
+            batch(&auto, 50);
+        }
+        assert_eq!(auto.level().level(), MIN_AUTO_LEVEL);
+
+        // within budget the level holds
+        assert_eq!(batch(&auto, 7), MIN_AUTO_LEVEL);
+
+        // idle, it climbs back up
+        for _ in 0..20 {
+            batch(&auto, 1);
+        }
+        assert_eq!(auto.level().level(), MAX_AUTO_LEVEL);
+
+        // shared by clones, as by the requests of a sink
+        let clone = auto.clone();
+        batch(&clone, 50);
+        assert_eq!(auto.level().level(), MAX_AUTO_LEVEL - 1);
+    }
+}
@@ -0,0 +1,178 @@
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Semaphore;
+use vector::emit;
+
+use crate::internal_events::VMImportConcurrencyLimit;
+
+/// A slow start of the number of requests in flight.
+///
+/// Starts at `initial` and grows by one with every successful response, so a
+/// burst of successes roughly doubles it, up to `max`. Failed or timed out
+/// requests halve it, though never below `initial`. Requests over the limit
+/// wait for a permit before being sent.
+#[derive(Clone)]
+pub struct ConcurrencyRamp {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    initial: usize,
+    max: usize,
+    semaphore: Semaphore,
+    state: Mutex<State>,
+}
+
+struct State {
+    limit: usize,
+    in_flight: usize,
+    // permits to withhold as they're returned, after the limit was lowered
+    debt: usize,
+}
+
+impl ConcurrencyRamp {
+    pub fn new(initial: usize, max: usize) -> Self {
+        let initial = initial.max(1);
+        Self {
+            inner: Arc::new(Inner {
+                initial,
+                max: max.max(initial),
+                semaphore: Semaphore::new(initial),
+                state: Mutex::new(State {
+                    limit: initial,
+                    in_flight: 0,
+                    debt: 0,
+                }),
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.inner.state.lock().unwrap().limit
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.inner.state.lock().unwrap().in_flight
+    }
+
+    /// Waits until another request may be sent.
+    pub async fn acquire(&self) -> RampPermit {
+        self.inner
+            .semaphore
+            .acquire()
+            .await
+            .expect("the semaphore is never closed")
+            .forget();
+        self.inner.state.lock().unwrap().in_flight += 1;
+        RampPermit {
+            ramp: self.clone(),
+            released: false,
+        }
+    }
+
+    fn release(&self, success: bool) {
+        let inner = &self.inner;
+        let mut state = inner.state.lock().unwrap();
+        state.in_flight -= 1;
+
+        let limit = if success {
+            (state.limit + 1).min(inner.max)
+        } else {
+            (state.limit / 2).max(inner.initial)
+        };
+        // the permit of this request, plus or minus the change of the limit
+        let mut permits = 1 + limit.saturating_sub(state.limit);
+        state.debt += state.limit.saturating_sub(limit);
+        let paid = permits.min(state.debt);
+        state.debt -= paid;
+        permits -= paid;
+        inner.semaphore.add_permits(permits);
+
+        if limit != state.limit {
+            state.limit = limit;
+            emit!(VMImportConcurrencyLimit { limit });
+        }
+    }
+}
+
+/// A request in flight, counted as failed if dropped before released, e.g.
+/// when the request timed out.
+pub struct RampPermit {
+    ramp: ConcurrencyRamp,
+    released: bool,
+}
+
+impl RampPermit {
+    pub fn release(mut self, success: bool) {
+        self.ramp.release(success);
+        self.released = true;
+    }
+}
+
+impl Drop for RampPermit {
+    fn drop(&mut self) {
+        if !self.released {
+            self.ramp.release(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    async fn blocked(ramp: &ConcurrencyRamp) -> bool {
+        tokio::time::timeout(Duration::from_millis(50), ramp.acquire())
+            .await
+            .is_err()
+    }
+
+    #[tokio::test]
+    async fn ramp_up_from_cold_start() {
+        let ramp = ConcurrencyRamp::new(1, 4);
+
+        let first = ramp.acquire().await;
+        assert!(blocked(&ramp).await);
+        first.release(true);
+
+        // every success lets one more request in, up to the maximum
+        let mut permits = vec![ramp.acquire().await, ramp.acquire().await];
+        assert!(blocked(&ramp).await);
+        assert_eq!(ramp.in_flight(), 2);
+        permits.pop().unwrap().release(true);
+        permits.push(ramp.acquire().await);
+        permits.push(ramp.acquire().await);
+        assert!(blocked(&ramp).await);
+        assert_eq!(ramp.in_flight(), 3);
+        permits.pop().unwrap().release(true);
+        permits.push(ramp.acquire().await);
+        permits.push(ramp.acquire().await);
+        assert_eq!(ramp.in_flight(), 4);
+        permits.pop().unwrap().release(true);
+        permits.push(ramp.acquire().await);
+        assert!(blocked(&ramp).await);
+        assert_eq!(ramp.limit(), 4);
+
+        // a failure halves the limit, dropped permits count as failed
+        permits.pop().unwrap().release(false);
+        assert_eq!(ramp.limit(), 2);
+        assert!(blocked(&ramp).await);
+        drop(permits.pop());
+        assert_eq!(ramp.limit(), 1);
+        assert!(blocked(&ramp).await);
+        permits.pop().unwrap().release(true);
+        assert_eq!(ramp.limit(), 2);
+        assert_eq!(ramp.in_flight(), 1);
+        permits.pop().unwrap().release(true);
+        assert_eq!(ramp.limit(), 3);
+        assert_eq!(ramp.in_flight(), 0);
+        let _permits = [
+            ramp.acquire().await,
+            ramp.acquire().await,
+            ramp.acquire().await,
+        ];
+        assert!(blocked(&ramp).await);
+    }
+}
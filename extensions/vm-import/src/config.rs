@@ -1,21 +1,185 @@
-use futures_util::{FutureExt, SinkExt};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use futures_util::{stream, FutureExt, SinkExt};
 use serde::{Deserialize, Serialize};
+use tower::ServiceBuilder;
 use vector::config::{AcknowledgementsConfig, GenerateConfig, Input, SinkConfig};
+use vector::event::Event;
 use vector::http::HttpClient;
-use vector::sinks::util::http::PartitionHttpSink;
+use vector::sinks::util::http::{HttpEventEncoder, HttpRetryLogic, HttpSink};
 use vector::sinks::util::{
-    BatchConfig, JsonArrayBuffer, PartitionBuffer, SinkBatchSettings, TowerRequestConfig,
+    Batch, BatchConfig, EncodedEvent, JsonArrayBuffer, PartitionBatchSink, PartitionBuffer,
+    ServiceBuilderExt, SinkBatchSettings, TowerRequestConfig,
 };
+use vector::template::Template;
 use vector::tls::{TlsConfig, TlsSettings};
 use vector::{config, sinks};
+use vector_core::buffers::Acker;
+use vector_core::config::proxy::ProxyConfig;
+use vector_core::ByteSizeOf;
 
-use crate::sink::VMImportSink;
+use crate::adaptive_batch::{AdaptiveBatchLimits, AdaptiveBuffer};
+use crate::auth::Auth;
+use crate::compression::{CompressionLevel, Gzip};
+use crate::concurrency_ramp::ConcurrencyRamp;
+use crate::dead_letter::{DeadLetter, DeadLetterService};
+use crate::encoder::{
+    EncoderSettings, FieldNames, InjectLabel, MaxLabels, MaxLabelsPolicy, TimestampUnit,
+    ValuePrecision,
+};
+use crate::flush::{AlignedBuffer, BoxedEventSink, FlushHandle, FlushableSink};
+use crate::otlp::{Format, OtlpConfig, OtlpEncoder, OTLP_CONTENT_TYPE};
+use crate::partition::default_user_agent;
+use crate::series_limit::{split_series, SeriesLimitBuffer};
+use crate::sink::{VMImportService, VMImportSink};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct VMImportConfig {
     pub endpoint: String,
+    /// Where to send events missing a field referred to by a templated
+    /// `endpoint`, e.g. `labels.cluster_id`, rather than dropping them.
+    pub default_endpoint: Option<String>,
+    /// Labels VictoriaMetrics adds to every series imported, sent as
+    /// `extra_label` query parameters rather than repeated in the body of
+    /// every series, e.g. `agent_region = "us-west-2"`. Values support
+    /// templates, e.g. `{{ labels.cluster_id }}`, batching events apart by
+    /// their rendered labels, and events failing to render are dropped.
+    #[serde(default)]
+    pub extra_query_labels: BTreeMap<String, String>,
+    /// Endpoints of series by their `__name__`, e.g.
+    /// `topsql_cpu_time_ms = "http://vm-cpu:8428/api/v1/import"`, taking
+    /// precedence over `endpoint` for the names listed. Values support
+    /// templates like `endpoint` and fall back to `default_endpoint` the same
+    /// way. Events packing several series under `series` are only routed if
+    /// all of them share the name.
+    #[serde(default)]
+    pub endpoint_by_metric_name: BTreeMap<String, String>,
+    /// How batches are sent, `vm_import` (default) for VictoriaMetrics'
+    /// `/api/v1/import`, or `otlp_http` to POST OTLP metrics in protobuf to an
+    /// OTLP/HTTP endpoint such as `http://127.0.0.1:4318/v1/metrics`. Series
+    /// are read the same either way.
+    #[serde(default)]
+    pub format: Format,
+    /// Options of the `otlp_http` format.
+    #[serde(default)]
+    pub otlp: OtlpConfig,
+    /// The fields series are read from, `labels_field`, `timestamps_field`
+    /// and `values_field`, defaulting to `labels`, `timestamps` and `values`
+    /// as produced by the topsql source. They apply to the elements of
+    /// `series` as well.
+    #[serde(default)]
+    pub field_names: FieldNames,
     pub healthcheck_endpoint: Option<String>,
+    /// Import a synthetic empty series into `endpoint` during the healthcheck,
+    /// so write-path problems are caught at startup. Opt-in, as it issues a real
+    /// write against the store.
+    #[serde(default)]
+    pub healthcheck_write_probe: bool,
     pub tls: Option<TlsConfig>,
+    /// Authenticate import and healthcheck requests, with `strategy` either
+    /// `basic` (`user` and `password`), `bearer` (`token`) or `vmcloud`
+    /// (`key_id` and `secret`), which signs every request as described on
+    /// `Auth::Vmcloud`.
+    pub auth: Option<Auth>,
+    /// Add a label to every series identifying where it was routed to. Off by
+    /// default, as it adds to the series cardinality.
+    pub inject_label: Option<InjectLabelConfig>,
+    /// The maximum number of labels of a series, counting the one added by
+    /// `inject_label`. Series over the limit are handled by
+    /// `max_labels_policy`, either `drop` or `trim`.
+    pub max_labels: Option<usize>,
+    #[serde(default)]
+    pub max_labels_policy: MaxLabelsPolicy,
+    /// How numeric `timestamps` are interpreted: `seconds`, `millis`, or `auto`
+    /// to guess by magnitude. Defaults to `timestamp`, accepting only timestamp
+    /// values.
+    #[serde(default)]
+    pub timestamp_unit: TimestampUnit,
+    /// Round values to `{ decimals = N }` decimal places or
+    /// `{ significant_digits = N }` significant digits, shrinking payloads of
+    /// metrics with more precision than they're worth. Unset by default.
+    pub value_precision: Option<ValuePrecision>,
+    /// A directory to keep the gzipped bodies of batches that failed for good,
+    /// i.e. rejected with a `4xx`, or still failing after `retries`, whether
+    /// with a `429` or `5xx`, a connection error or a timeout. Such batches are
+    /// dropped if unset.
+    pub dead_letter_dir: Option<PathBuf>,
+    /// Check every event against the expected shape before encoding it, i.e.
+    /// labels of string values, and as many timestamps as float values, for
+    /// every series. Failing events are rejected with a descriptive warning,
+    /// counted by `vm_import_invalid_events_total` and appended to
+    /// `invalid_events.ndjson` of `dead_letter_dir` if set, rather than left
+    /// to be dropped by a terse reason, or rejected by the store along with
+    /// their whole batch if misaligned.
+    #[serde(default)]
+    pub validate_events: bool,
+    /// The `User-Agent` of import and healthcheck requests, so the store can
+    /// tell where writes come from. Supports templates, e.g.
+    /// `vector-vm-import/{{ cluster_id }}`, though healthchecks have no event
+    /// to render against and fall back to the default,
+    /// `vector-vm-import/<vector version>`.
+    #[serde(default = "default_user_agent")]
+    pub user_agent: String,
+    /// Send request bodies with `Transfer-Encoding: chunked` rather than a
+    /// `Content-Length`, on by default. Only applies to HTTP/1.1. Bodies are
+    /// then sent as they're compressed, unless `auth` signs them, rather than
+    /// compressed whole first, so that only the events of a batch are held
+    /// while it's sent, and retries compress them again. Disable it for
+    /// endpoints that require a `Content-Length`.
+    #[serde(default = "default_chunked_transfer")]
+    pub chunked_transfer: bool,
+    /// The gzip level of request bodies, `0` to `9`, defaulting to `6`. With
+    /// `auto` the level is adapted to the CPU time spent compressing, lowered
+    /// while it's over `compression_cpu_budget` and raised while it's under
+    /// half of it, trading bandwidth for CPU on busy agents.
+    pub compression_level: Option<CompressionLevel>,
+    /// The share of one core the `auto` compression level aims to spend
+    /// compressing.
+    #[serde(default = "default_compression_cpu_budget")]
+    pub compression_cpu_budget: f64,
+    /// Adapt the number of events per batch to each endpoint, between
+    /// `min_events` and `batch.max_events`, growing it while the endpoint
+    /// answers within `target_latency_secs` and halving it on slow or failed
+    /// requests. Batches always take `batch.max_events` if unset.
+    pub adaptive_batch: Option<AdaptiveBatchConfig>,
+    /// Start with `initial` requests in flight rather than the whole
+    /// `request.concurrency`, letting one more in with every successful
+    /// response and halving the number on failures, so a backlog sent on
+    /// startup doesn't burst into a cold cluster. Requires a fixed
+    /// `request.concurrency`, as `adaptive` concurrency already starts low.
+    pub concurrency_ramp: Option<ConcurrencyRampConfig>,
+    /// The maximum number of distinct series per request, for stores capping
+    /// it, splitting batches before they reach `batch.max_events` or
+    /// `batch.max_bytes`. Series are told apart by their labels, and events
+    /// holding more series than this are split. Unlimited by default.
+    pub max_series_per_request: Option<usize>,
+    /// Cut batches on multiples of this many seconds since the Unix epoch,
+    /// e.g. `10` to match VictoriaMetrics' `-dedup.minScrapeInterval`, so
+    /// each batch carries the samples of one deduplication interval rather
+    /// than of a rolling `batch.timeout_secs`. A batch is sent once the first
+    /// event past a boundary arrives for its partition, and still once full
+    /// or timed out, which also sends partitions that stopped receiving events.
+    pub flush_align_secs: Option<u64>,
+    /// Send out every partial batch and wait for the requests in flight on
+    /// `SIGUSR1`, the same as on shutdown, e.g. to drain the agent before a
+    /// rolling upgrade. Unix only.
+    #[serde(default)]
+    pub flush_on_sigusr1: bool,
+    /// The number of times a batch failing with a `429` or `5xx` is retried,
+    /// overriding `request.retry_attempts`, e.g. to retry imports harder than
+    /// the default as dropped batches are lost for good.
+    pub retries: Option<usize>,
+    /// The backoff before the first retry, growing along the Fibonacci
+    /// sequence with every retry, overriding
+    /// `request.retry_initial_backoff_secs`.
+    pub retry_initial_backoff_secs: Option<u64>,
+    /// The longest backoff between retries, overriding
+    /// `request.retry_max_duration_secs`.
+    pub retry_max_duration_secs: Option<u64>,
 
     #[serde(default)]
     pub request: TowerRequestConfig,
@@ -23,6 +187,52 @@ pub struct VMImportConfig {
     pub batch: BatchConfig<VMImportDefaultBatchSettings>,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct InjectLabelConfig {
+    /// The label name, e.g. `vm_cluster`.
+    pub name: String,
+    /// A template for the label value. Defaults to the rendered endpoint.
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdaptiveBatchConfig {
+    /// The number of events per batch endpoints start with, and never go below.
+    #[serde(default = "default_adaptive_batch_min_events")]
+    pub min_events: usize,
+    #[serde(default = "default_adaptive_batch_target_latency")]
+    pub target_latency_secs: f64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConcurrencyRampConfig {
+    /// The number of requests in flight to start with, and never go below.
+    #[serde(default = "default_concurrency_ramp_initial")]
+    pub initial: usize,
+}
+
+pub const fn default_adaptive_batch_min_events() -> usize {
+    10
+}
+
+pub const fn default_adaptive_batch_target_latency() -> f64 {
+    1.0
+}
+
+pub const fn default_concurrency_ramp_initial() -> usize {
+    1
+}
+
+pub const fn default_compression_cpu_budget() -> f64 {
+    0.1
+}
+
+pub const fn default_chunked_transfer() -> bool {
+    true
+}
+
 #[derive(Clone, Copy, Debug, Default)]
 pub struct VMImportDefaultBatchSettings;
 
@@ -38,11 +248,38 @@ impl GenerateConfig for VMImportConfig {
 
         toml::Value::try_from(Self {
             tls: Default::default(),
+            auth: Default::default(),
             batch: Default::default(),
             request: Default::default(),
             healthcheck_endpoint: Default::default(),
+            healthcheck_write_probe: Default::default(),
+            inject_label: Default::default(),
+            max_labels: Default::default(),
+            max_labels_policy: Default::default(),
+            timestamp_unit: Default::default(),
+            value_precision: Default::default(),
+            dead_letter_dir: Default::default(),
+            validate_events: Default::default(),
+            user_agent: default_user_agent(),
+            chunked_transfer: default_chunked_transfer(),
+            compression_level: Default::default(),
+            compression_cpu_budget: default_compression_cpu_budget(),
+            adaptive_batch: Default::default(),
+            concurrency_ramp: Default::default(),
+            max_series_per_request: Default::default(),
+            flush_align_secs: Default::default(),
+            flush_on_sigusr1: Default::default(),
+            retries: Default::default(),
+            retry_initial_backoff_secs: Default::default(),
+            retry_max_duration_secs: Default::default(),
+            field_names: Default::default(),
 
             endpoint: sample_url.to_owned(),
+            default_endpoint: Default::default(),
+            extra_query_labels: Default::default(),
+            endpoint_by_metric_name: Default::default(),
+            format: Default::default(),
+            otlp: Default::default(),
         })
         .unwrap()
     }
@@ -55,49 +292,295 @@ impl SinkConfig for VMImportConfig {
         &self,
         cx: config::SinkContext,
     ) -> vector::Result<(sinks::VectorSink, sinks::Healthcheck)> {
-        let endpoint_tmp = self.endpoint.clone().try_into()?;
+        let (sink, healthcheck, flush) = self.build_sink(cx.proxy(), cx.acker())?;
+        if self.flush_on_sigusr1 {
+            #[cfg(unix)]
+            crate::flush::flush_on_sigusr1(flush)?;
+            #[cfg(not(unix))]
+            return Err("`flush_on_sigusr1` is only supported on Unix".into());
+        }
+        Ok((sink, healthcheck))
+    }
+
+    fn input(&self) -> Input {
+        Input::log()
+    }
+
+    fn sink_type(&self) -> &'static str {
+        "vm_import"
+    }
+
+    fn acknowledgements(&self) -> Option<&AcknowledgementsConfig> {
+        None
+    }
+}
+
+impl VMImportConfig {
+    fn build_sink(
+        &self,
+        proxy: &ProxyConfig,
+        acker: Acker,
+    ) -> vector::Result<(sinks::VectorSink, sinks::Healthcheck, FlushHandle)> {
+        let endpoint_tmp: Template = self.endpoint.clone().try_into()?;
+        let write_probe_endpoint = match (self.healthcheck_write_probe, endpoint_tmp.is_dynamic()) {
+            (false, _) => None,
+            (true, false) => Some(self.endpoint.clone()),
+            (true, true) => {
+                return Err(
+                    "`healthcheck_write_probe` requires an endpoint without templates".into(),
+                )
+            }
+        };
+
+        if let Some(default_endpoint) = &self.default_endpoint {
+            default_endpoint.parse::<http::Uri>()?;
+        }
+
+        let user_agent: Template = self.user_agent.clone().try_into()?;
+        let healthcheck_user_agent = if user_agent.is_dynamic() {
+            default_user_agent()
+        } else {
+            self.user_agent.clone()
+        };
 
         let tls_settings = TlsSettings::from_options(&self.tls)?;
         let batch_settings = self.batch.into_batch_settings()?;
-        let request_settings = self.request.unwrap_with(&Default::default());
+        let request_config = self.request_config()?;
+        let request_settings = request_config.unwrap_with(&Default::default());
 
-        let client = HttpClient::new(tls_settings, cx.proxy())?;
-        let sink = VMImportSink::new(endpoint_tmp);
-        let buffer = PartitionBuffer::new(JsonArrayBuffer::new(batch_settings.size));
+        let client = HttpClient::new(tls_settings, proxy)?;
+        let inject_label = match &self.inject_label {
+            Some(config) => Some(InjectLabel {
+                name: config.name.clone(),
+                value: config.value.clone().map(TryInto::try_into).transpose()?,
+            }),
+            None => None,
+        };
+        let max_labels = self.max_labels.map(|limit| MaxLabels {
+            limit,
+            policy: self.max_labels_policy,
+        });
+        let dead_letter = self
+            .dead_letter_dir
+            .clone()
+            .map(DeadLetter::new)
+            .transpose()?;
+        let sink = VMImportSink::new(
+            endpoint_tmp,
+            EncoderSettings {
+                field_names: self.field_names.clone(),
+                inject_label,
+                max_labels,
+                timestamp_unit: self.timestamp_unit,
+                value_precision: self.value_precision,
+                user_agent: Some(user_agent),
+                default_endpoint: self.default_endpoint.clone(),
+                extra_query_labels: self
+                    .extra_query_labels
+                    .iter()
+                    .map(|(name, value)| Ok((name.clone(), value.clone().try_into()?)))
+                    .collect::<vector::Result<_>>()?,
+                endpoint_by_metric_name: self
+                    .endpoint_by_metric_name
+                    .iter()
+                    .map(|(name, endpoint)| Ok((name.clone(), endpoint.clone().try_into()?)))
+                    .collect::<vector::Result<_>>()?,
+                validate_events: self.validate_events,
+                dead_letter: dead_letter.clone(),
+            },
+            self.auth.clone(),
+            Gzip::new(self.compression_level, self.compression_cpu_budget)?,
+            (self.format == Format::OtlpHttp).then(|| OtlpEncoder::new(&self.otlp)),
+        );
+        let batch_limits = match &self.adaptive_batch {
+            Some(config) if config.min_events > batch_settings.size.events => {
+                return Err(
+                    "`adaptive_batch.min_events` can't be more than `batch.max_events`".into(),
+                )
+            }
+            Some(config)
+                if !config.target_latency_secs.is_finite() || config.target_latency_secs <= 0.0 =>
+            {
+                return Err("`adaptive_batch.target_latency_secs` must be positive".into())
+            }
+            Some(config) => Some(AdaptiveBatchLimits::new(
+                config.min_events,
+                batch_settings.size.events,
+                Duration::from_secs_f64(config.target_latency_secs),
+            )),
+            None => None,
+        };
+        let concurrency_ramp = match (&self.concurrency_ramp, request_settings.concurrency) {
+            (None, _) => None,
+            (Some(_), None) => {
+                return Err("`concurrency_ramp` requires a fixed `request.concurrency`".into())
+            }
+            (Some(config), Some(_)) if config.initial == 0 => {
+                return Err("`concurrency_ramp.initial` must be positive".into())
+            }
+            (Some(config), Some(max)) => Some(ConcurrencyRamp::new(config.initial, max)),
+        };
+        if self.max_series_per_request == Some(0) {
+            return Err("`max_series_per_request` must be positive".into());
+        }
+        if self.flush_align_secs == Some(0) {
+            return Err("`flush_align_secs` must be positive".into());
+        }
+        let buffer = AlignedBuffer::new(
+            SeriesLimitBuffer::new(
+                AdaptiveBuffer::new(
+                    PartitionBuffer::new(JsonArrayBuffer::new(batch_settings.size)),
+                    batch_limits.clone(),
+                ),
+                self.max_series_per_request,
+            ),
+            self.flush_align_secs.map(Duration::from_secs),
+        );
 
-        let sink = PartitionHttpSink::new(
-            sink,
-            buffer,
-            request_settings,
-            batch_settings.timeout,
+        // Same as `PartitionHttpSink`, except that batches are sent by
+        // `VMImportService`, which keeps the events of every batch along with
+        // it, and that batches failing for good after the retries are
+        // dead-lettered.
+        //
+        // The input ending, e.g. on shutdown, or a flush closes the partition
+        // sink, which sends every partial batch and waits for the requests in
+        // flight. A flush then builds a new one for the events that follow.
+        let encoder_sink = sink.clone();
+        let max_series = self.max_series_per_request;
+        let keep_bodies = request_settings.retry_attempts > 0 || dead_letter.is_some();
+        let service = VMImportService::new(
             client.clone(),
-            cx.acker(),
+            sink,
+            self.chunked_transfer,
+            keep_bodies,
+            batch_limits,
+            concurrency_ramp,
+        );
+        let build_sink = move || -> BoxedEventSink {
+            let service = DeadLetterService::new(
+                ServiceBuilder::new()
+                    .settings(
+                        request_config.unwrap_with(&Default::default()),
+                        HttpRetryLogic,
+                    )
+                    .service(service.clone()),
+                encoder_sink.clone(),
+                dead_letter.clone(),
+            );
+            let mut encoder = encoder_sink.build_encoder();
+            let sink = PartitionBatchSink::new(
+                service,
+                buffer.fresh(),
+                batch_settings.timeout,
+                acker.clone(),
+            )
+            .with_flat_map(move |mut event: Event| {
+                let byte_size = event.size_of();
+                let finalizers = event.metadata_mut().take_finalizers();
+                // the parts of a split event share its finalizers and size
+                let parts = encoder
+                    .encode_event(event)
+                    .map_or_else(Vec::new, |item| split_series(item, max_series));
+                let count = parts.len();
+                let encoded = parts.into_iter().enumerate().map(move |(i, item)| {
+                    Ok(EncodedEvent {
+                        item,
+                        finalizers: finalizers.clone(),
+                        byte_size: byte_size / count + usize::from(i < byte_size % count),
+                    })
+                });
+                stream::iter(encoded)
+            })
+            .sink_map_err(|e| error!(message = "VM import sink error.", %e));
+            Box::pin(sink)
+        };
+        let (sink, flush) = FlushableSink::new(Box::new(build_sink));
+        let hc = healthcheck(
+            self.healthcheck_endpoint.clone(),
+            write_probe_endpoint,
+            healthcheck_user_agent,
+            self.format,
+            self.auth.clone(),
+            client,
         )
-        .sink_map_err(|e| error!(message = "VM import sink error.", %e));
-        let hc = healthcheck(self.healthcheck_endpoint.clone(), client).boxed();
+        .boxed();
 
-        Ok((sinks::VectorSink::from_event_sink(sink), hc))
+        Ok((sinks::VectorSink::from_event_streamsink(sink), hc, flush))
     }
 
-    fn input(&self) -> Input {
-        Input::log()
+    /// `request`, with the retry options of the sink taking precedence.
+    fn request_config(&self) -> vector::Result<TowerRequestConfig> {
+        let mut request = self.request;
+        if let Some(retries) = self.retries {
+            request.retry_attempts = Some(retries);
+        }
+        if let Some(secs) = self.retry_initial_backoff_secs {
+            request.retry_initial_backoff_secs = Some(secs);
+        }
+        if let Some(secs) = self.retry_max_duration_secs {
+            request.retry_max_duration_secs = Some(secs);
+        }
+
+        match (
+            request.retry_initial_backoff_secs,
+            request.retry_max_duration_secs,
+        ) {
+            (Some(0), _) => Err("`retry_initial_backoff_secs` must be positive".into()),
+            (_, Some(0)) => Err("`retry_max_duration_secs` must be positive".into()),
+            (Some(initial), Some(max)) if initial > max => Err(
+                "`retry_initial_backoff_secs` can't be more than `retry_max_duration_secs`".into(),
+            ),
+            _ => Ok(request),
+        }
     }
+}
 
-    fn sink_type(&self) -> &'static str {
-        "vm_import"
+async fn healthcheck(
+    endpoint: Option<String>,
+    write_probe_endpoint: Option<String>,
+    user_agent: String,
+    format: Format,
+    auth: Option<Auth>,
+    client: HttpClient,
+) -> vector::Result<()> {
+    let (client, auth) = (&client, auth.as_ref());
+    let send = move |mut request: http::Request<Bytes>| {
+        if let Some(auth) = auth {
+            auth.apply(&mut request);
+        }
+        check_response(client, request.map(hyper::Body::from))
+    };
+
+    if let Some(endpoint) = endpoint {
+        let request = http::Request::get(endpoint)
+            .header("User-Agent", &user_agent)
+            .body(Bytes::new())?;
+        send(request).await?;
     }
 
-    fn acknowledgements(&self) -> Option<&AcknowledgementsConfig> {
-        None
+    if let Some(endpoint) = write_probe_endpoint {
+        // A series without samples goes through the whole import path but
+        // leaves no data behind, the same as an OTLP request without metrics.
+        let request = http::Request::post(endpoint).header("User-Agent", &user_agent);
+        let request = match format {
+            Format::VmImport => {
+                let body = r#"{"metric":{"__name__":"vm_import_healthcheck"},"values":[],"timestamps":[]}"#;
+                request.body(Bytes::from_static(body.as_bytes()))?
+            }
+            Format::OtlpHttp => request
+                .header("Content-Type", OTLP_CONTENT_TYPE)
+                .body(Bytes::new())?,
+        };
+        send(request).await?;
     }
+
+    Ok(())
 }
 
-async fn healthcheck(endpoint: Option<String>, client: HttpClient) -> vector::Result<()> {
-    let endpoint = match endpoint {
-        Some(endpoint) => endpoint,
-        None => return Ok(()),
-    };
-    let request = http::Request::get(endpoint).body(hyper::Body::empty())?;
+async fn check_response(
+    client: &HttpClient,
+    request: http::Request<hyper::Body>,
+) -> vector::Result<()> {
     let response = client.send(request).await?;
     let status = response.status();
     if status.is_success() {
@@ -109,10 +592,194 @@ async fn healthcheck(endpoint: Option<String>, client: HttpClient) -> vector::Re
 
 #[cfg(test)]
 mod tests {
+    use std::sync::{Arc, Mutex};
+
     use super::*;
 
     #[test]
     fn generate_config() {
         vector::test_util::test_generate_config::<VMImportConfig>();
     }
+
+    /// Serves imports, answering each after `delay`, and returns the endpoint
+    /// along with the series received by answered requests.
+    fn receiving_vm(delay: Duration) -> (String, Arc<Mutex<Vec<String>>>) {
+        use std::io::Read;
+
+        use flate2::read::GzDecoder;
+
+        use crate::mock_vm;
+
+        let received = Arc::new(Mutex::new(vec![]));
+        let seen = Arc::clone(&received);
+        let endpoint = mock_vm::serve(move |request: http::Request<hyper::Body>| {
+            let seen = Arc::clone(&seen);
+            async move {
+                let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                let mut series = String::new();
+                GzDecoder::new(body.as_ref())
+                    .read_to_string(&mut series)
+                    .unwrap();
+                tokio::time::sleep(delay).await;
+                seen.lock()
+                    .unwrap()
+                    .extend(series.lines().map(str::to_owned));
+                mock_vm::status(204)
+            }
+        });
+        (endpoint, received)
+    }
+
+    /// A sink to `endpoint` holding partial batches while the input lasts,
+    /// one by the `tenant` label of the series.
+    fn partial_batch_sink(endpoint: &str) -> (sinks::VectorSink, FlushHandle) {
+        let config = toml::from_str::<VMImportConfig>(&format!(
+            r#"
+            endpoint = "{}"
+            extra_query_labels.tenant = "{{{{ labels.tenant }}}}"
+            batch.timeout_secs = 3600
+            "#,
+            endpoint
+        ))
+        .unwrap();
+        let (sink, _, flush) = config
+            .build_sink(&ProxyConfig::default(), Acker::passthrough())
+            .unwrap();
+        (sink, flush)
+    }
+
+    /// Series of two tenants.
+    fn series_events(range: std::ops::Range<usize>) -> impl Iterator<Item = Event> {
+        use chrono::{TimeZone, Utc};
+        use ordered_float::NotNan;
+        use vector::event::{LogEvent, Value};
+
+        range.map(|i| {
+            let mut series = BTreeMap::new();
+            series.insert(
+                "labels".to_owned(),
+                Value::Object(
+                    [
+                        ("__name__".to_owned(), Value::from(format!("series_{}", i))),
+                        ("tenant".to_owned(), Value::from((i % 2).to_string())),
+                    ]
+                    .into_iter()
+                    .collect(),
+                ),
+            );
+            series.insert(
+                "timestamps".to_owned(),
+                Value::Array(vec![Value::Timestamp(Utc.timestamp_millis(1661396787000))]),
+            );
+            series.insert(
+                "values".to_owned(),
+                Value::Array(vec![Value::Float(NotNan::new(1.0).unwrap())]),
+            );
+            Event::from(LogEvent::from(series))
+        })
+    }
+
+    #[tokio::test]
+    async fn flush_on_end_of_input() {
+        let (endpoint, received) = receiving_vm(Duration::ZERO);
+        let (sink, _) = partial_batch_sink(&endpoint);
+
+        // returns once the input ended and every batch was answered
+        sink.run(stream::iter(series_events(0..10))).await.unwrap();
+
+        assert_eq!(received.lock().unwrap().len(), 10);
+    }
+
+    #[tokio::test]
+    async fn flush_on_demand() {
+        let (endpoint, received) = receiving_vm(Duration::from_millis(100));
+        let (sink, flush) = partial_batch_sink(&endpoint);
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let input = stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|event| (event, rx))
+        });
+        let running = tokio::spawn(sink.run(input));
+
+        // a partial batch per tenant, waiting for its timeout
+        for event in series_events(0..10) {
+            tx.send(event).unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(received.lock().unwrap().is_empty());
+
+        // returns once every batch was sent and answered
+        assert!(flush.flush().await);
+        assert_eq!(received.lock().unwrap().len(), 10);
+
+        // keeps running after a flush
+        for event in series_events(10..15) {
+            tx.send(event).unwrap();
+        }
+        drop(tx);
+        running.await.unwrap().unwrap();
+        assert_eq!(received.lock().unwrap().len(), 15);
+        assert!(!flush.flush().await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_override_request() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use tower::Service;
+
+        use crate::mock_vm;
+
+        let parse = |config: &str| {
+            toml::from_str::<VMImportConfig>(&format!(
+                r#"
+                endpoint = "http://localhost:8428/api/v1/import"
+                {}
+                "#,
+                config
+            ))
+            .unwrap()
+            .request_config()
+        };
+        assert!(parse("retry_initial_backoff_secs = 0").is_err());
+        assert!(parse("retry_max_duration_secs = 0").is_err());
+        assert!(parse("retry_initial_backoff_secs = 10\nretry_max_duration_secs = 5").is_err());
+        // the sink's options take precedence over `request`
+        let request = parse("retries = 3\nrequest.retry_attempts = 10").unwrap();
+        assert_eq!(request.retry_attempts, Some(3));
+
+        // an endpoint failing every request
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let seen = Arc::clone(&attempts);
+        let endpoint = mock_vm::serve(move |_| {
+            seen.fetch_add(1, Ordering::SeqCst);
+            async { mock_vm::status(503) }
+        });
+
+        // the backoff between retries elapses on the paused clock
+        let request_settings = parse("retries = 2\nretry_initial_backoff_secs = 1")
+            .unwrap()
+            .unwrap_with(&Default::default());
+        let mut service = ServiceBuilder::new()
+            .settings(request_settings, HttpRetryLogic)
+            .service(VMImportService::new(
+                mock_vm::client(),
+                mock_vm::sink(&endpoint),
+                false,
+                true,
+                None,
+                None,
+            ));
+
+        futures_util::future::poll_fn(|cx| service.poll_ready(cx))
+            .await
+            .unwrap();
+        let response = service
+            .call(mock_vm::batch(&endpoint, vec![mock_vm::series("up")]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 503);
+        // the first attempt and two retries
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
 }
@@ -0,0 +1,281 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use chrono::Utc;
+use futures_util::future::BoxFuture;
+use http::{Response, StatusCode};
+use tower::Service;
+use vector::emit;
+use vector::event::Event;
+
+use crate::internal_events::VMImportDeadLettered;
+use crate::sink::{VMImportBatch, VMImportSink};
+
+static NEXT_BATCH_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Keeps the request bodies of batches that failed for good in a directory,
+/// gzipped as sent, for later inspection or replay.
+///
+/// A batch fails for good once the retries are done with it, however its last
+/// attempt ended: with a permanent failure, e.g. a `400` for bad data, with a
+/// transient one (`429` and `5xx` except `501`) past `retries`, or without a
+/// response, e.g. on connection errors and timeouts.
+#[derive(Clone)]
+pub struct DeadLetter {
+    dir: PathBuf,
+}
+
+impl DeadLetter {
+    pub fn new(dir: PathBuf) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Writes `body` of a batch sent to `endpoint` that failed for good, last
+    /// answered with `status` if answered at all.
+    pub async fn write(&self, endpoint: &str, body: &Bytes, status: Option<StatusCode>) {
+        let path = self.dir.join(format!(
+            "{}-{:016x}.ndjson.gz",
+            Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+            NEXT_BATCH_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        match tokio::fs::write(&path, body).await {
+            Ok(()) => emit!(VMImportDeadLettered {
+                endpoint,
+                status,
+                path: &path,
+            }),
+            Err(error) => {
+                error!(message = "Failed to write dead letter.", %error, path = ?path);
+            }
+        }
+    }
+
+    /// Appends `event`, rejected by validation with `error`, to
+    /// `invalid_events.ndjson` as a line of `{"error": ..., "event": ...}`.
+    /// Written in place, as encoding isn't async and such events are rare.
+    pub fn write_invalid_event(&self, event: &Event, error: &str) {
+        let path = self.dir.join(INVALID_EVENTS_FILE_NAME);
+        let line = serde_json::json!({
+            "error": error,
+            "event": event.maybe_as_log(),
+        });
+        let result = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "{}", line));
+        if let Err(error) = result {
+            error!(message = "Failed to write invalid event.", %error, path = ?path);
+        }
+    }
+}
+
+const INVALID_EVENTS_FILE_NAME: &str = "invalid_events.ndjson";
+
+/// Dead-letters the batches `inner` fails for good, compressed again by
+/// `sink` as their attempts sent them. Wraps the retries of the sink, so that
+/// it only sees how the last attempt of every batch ended.
+#[derive(Clone)]
+pub struct DeadLetterService<S> {
+    inner: S,
+    sink: VMImportSink,
+    dead_letter: Option<DeadLetter>,
+}
+
+impl<S> DeadLetterService<S> {
+    pub const fn new(inner: S, sink: VMImportSink, dead_letter: Option<DeadLetter>) -> Self {
+        Self {
+            inner,
+            sink,
+            dead_letter,
+        }
+    }
+}
+
+impl<S> Service<VMImportBatch> for DeadLetterService<S>
+where
+    S: Service<VMImportBatch, Response = Response<Bytes>>,
+    S::Error: Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, batch: VMImportBatch) -> Self::Future {
+        let dead_letter = self.dead_letter.clone();
+        let sink = self.sink.clone();
+        let response = self.inner.call(batch.clone());
+
+        Box::pin(async move {
+            let result = response.await;
+            let dead_letter = match dead_letter {
+                Some(dead_letter) => dead_letter,
+                None => return result,
+            };
+            // the status of the last attempt, if it was answered
+            let status = result.as_ref().ok().map(Response::status);
+            if status.map_or(false, |status| status.is_success()) {
+                return result;
+            }
+            match batch.compressed_body(&sink) {
+                Some(body) => dead_letter.write(&batch.endpoint(), &body, status).await,
+                // never attempted, or its events failed to encode
+                None => {
+                    error!(
+                        message = "Failed to dead-letter batch without a body.",
+                        endpoint = %batch.endpoint(),
+                    );
+                }
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    use tower::ServiceBuilder;
+    use vector::sinks::util::http::HttpRetryLogic;
+    use vector::sinks::util::{ServiceBuilderExt, TowerRequestConfig};
+
+    use super::*;
+    use crate::mock_vm;
+    use crate::sink::VMImportService;
+
+    fn temp_dead_letter(name: &str) -> (DeadLetter, PathBuf) {
+        let dir = std::env::temp_dir().join(format!(
+            "vm-import-dead-letter-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        (DeadLetter::new(dir.clone()).unwrap(), dir)
+    }
+
+    fn dead_letters(dir: &PathBuf) -> Vec<Vec<u8>> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| std::fs::read(entry.unwrap().path()).unwrap())
+            .collect()
+    }
+
+    /// Sends a batch to `endpoint` through the retries, returning whether it
+    /// was answered with a success.
+    async fn send(endpoint: &str, dead_letter: DeadLetter, retries: usize) -> bool {
+        let request_settings = TowerRequestConfig {
+            retry_attempts: Some(retries),
+            retry_initial_backoff_secs: Some(1),
+            ..Default::default()
+        }
+        .unwrap_with(&Default::default());
+        let retried = ServiceBuilder::new()
+            .settings(request_settings, HttpRetryLogic)
+            .service(VMImportService::new(
+                mock_vm::client(),
+                mock_vm::sink(endpoint),
+                false,
+                true,
+                None,
+                None,
+            ));
+        let mut service =
+            DeadLetterService::new(retried, mock_vm::sink(endpoint), Some(dead_letter));
+
+        futures_util::future::poll_fn(|cx| service.poll_ready(cx))
+            .await
+            .unwrap();
+        matches!(
+            service.call(mock_vm::batch(endpoint, vec![mock_vm::series("up")])).await,
+            Ok(response) if response.status().is_success()
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dead_letter_after_retries() {
+        let (dead_letter, dir) = temp_dead_letter("retries");
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let seen = Arc::clone(&attempts);
+        let endpoint = mock_vm::serve(move |_| {
+            seen.fetch_add(1, Ordering::SeqCst);
+            async { mock_vm::status(503) }
+        });
+
+        assert!(!send(&endpoint, dead_letter.clone(), 2).await);
+        // written once, after the first attempt and two retries
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        let expected = mock_vm::sink(&endpoint)
+            .compress(&[mock_vm::series("up")])
+            .unwrap();
+        assert_eq!(dead_letters(&dir), vec![expected.to_vec()]);
+
+        // permanent failures are dead-lettered right away
+        let endpoint = mock_vm::serve(|_| async { mock_vm::status(400) });
+        assert!(!send(&endpoint, dead_letter.clone(), 2).await);
+        assert_eq!(dead_letters(&dir).len(), 2);
+
+        // successes never are
+        let endpoint = mock_vm::serve(|_| async { mock_vm::status(204) });
+        assert!(send(&endpoint, dead_letter, 2).await);
+        assert_eq!(dead_letters(&dir).len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dead_letter_after_connection_errors() {
+        use hyper::service::{make_service_fn, service_fn};
+
+        // answers every other attempt with a `503`, and closes the connection
+        // without a response on the others
+        let (dead_letter, dir) = temp_dead_letter("connection-errors");
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let seen = Arc::clone(&attempts);
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(
+            move |_| {
+                let seen = Arc::clone(&seen);
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |_| {
+                        let attempt = seen.fetch_add(1, Ordering::SeqCst);
+                        async move {
+                            if attempt % 2 == 0 {
+                                Ok(mock_vm::status(503))
+                            } else {
+                                Err("connection closed")
+                            }
+                        }
+                    }))
+                }
+            },
+        ));
+        let endpoint = format!("http://{}/api/v1/import", server.local_addr());
+        tokio::spawn(server);
+
+        assert!(!send(&endpoint, dead_letter, 3).await);
+        assert!(attempts.load(Ordering::SeqCst) >= 2);
+        assert_eq!(dead_letters(&dir).len(), 1);
+
+        // never answered at all
+        let (dead_letter, unreachable_dir) = temp_dead_letter("unreachable");
+        let unreachable = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/api/v1/import", listener.local_addr().unwrap())
+        };
+        assert!(!send(&unreachable, dead_letter, 3).await);
+        assert_eq!(dead_letters(&unreachable_dir).len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&unreachable_dir).unwrap();
+    }
+}
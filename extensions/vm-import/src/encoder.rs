@@ -1,18 +1,191 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use vector::emit;
 use vector::event::Event;
 use vector::sinks::util::http::HttpEventEncoder;
 use vector::sinks::util::PartitionInnerBuffer;
-use vector::template::Template;
+use vector::template::{Template, TemplateRenderingError};
+
+use crate::dead_letter::DeadLetter;
+use crate::internal_events::{
+    VMImportDefaultEndpoint, VMImportInvalidEvent, VMImportMalformedEvent,
+    VMImportSeriesOverMaxLabels,
+};
+use crate::partition::{default_user_agent, PartitionKey};
+
+/// A label added to every emitted series, valued by `value` rendered against the
+/// event, or by the rendered endpoint if `value` is not set.
+#[derive(Clone)]
+pub struct InjectLabel {
+    pub name: String,
+    pub value: Option<Template>,
+}
+
+/// Caps the number of labels of a series, guarding the store against label
+/// explosions originating upstream.
+#[derive(Clone, Copy, Debug)]
+pub struct MaxLabels {
+    pub limit: usize,
+    pub policy: MaxLabelsPolicy,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MaxLabelsPolicy {
+    /// Drop series over the limit, leaving the other series of an event be.
+    Drop,
+    /// Keep `__name__`, the injected label and the first labels in name order
+    /// up to the limit.
+    Trim,
+}
+
+impl Default for MaxLabelsPolicy {
+    fn default() -> Self {
+        Self::Drop
+    }
+}
+
+/// How the elements of `timestamps` are interpreted. Timestamp values are always
+/// accepted, the unit only applies to numeric epoch values.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampUnit {
+    /// Guess by magnitude, numbers below `AUTO_MILLIS_THRESHOLD` are seconds.
+    Auto,
+    Seconds,
+    Millis,
+    /// Only accept timestamp values, series with numeric timestamps are dropped.
+    Timestamp,
+}
+
+impl Default for TimestampUnit {
+    fn default() -> Self {
+        Self::Timestamp
+    }
+}
+
+/// The fields of an event, or of each element of its `series`, a series is
+/// read from.
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FieldNames {
+    #[serde(default = "default_labels_field")]
+    pub labels_field: String,
+    #[serde(default = "default_timestamps_field")]
+    pub timestamps_field: String,
+    #[serde(default = "default_values_field")]
+    pub values_field: String,
+}
+
+fn default_labels_field() -> String {
+    "labels".to_owned()
+}
+
+fn default_timestamps_field() -> String {
+    "timestamps".to_owned()
+}
+
+fn default_values_field() -> String {
+    "values".to_owned()
+}
+
+impl Default for FieldNames {
+    fn default() -> Self {
+        Self {
+            labels_field: default_labels_field(),
+            timestamps_field: default_timestamps_field(),
+            values_field: default_values_field(),
+        }
+    }
+}
+
+// Epoch seconds stay below it until the year 5138, while epoch milliseconds
+// exceed it since 1973.
+const AUTO_MILLIS_THRESHOLD: f64 = 1e11;
+
+/// Rounds every value before serialization, trimming precision that only
+/// inflates the payload. Non-finite values are left as is, and still dropped.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ValuePrecision {
+    /// Round to this many decimal places.
+    Decimals(u32),
+    /// Round to this many significant digits.
+    SignificantDigits(u32),
+}
+
+impl ValuePrecision {
+    fn round(self, value: f64) -> f64 {
+        if value == 0.0 || !value.is_finite() {
+            return value;
+        }
+        let decimals = match self {
+            ValuePrecision::Decimals(decimals) => decimals as i32,
+            ValuePrecision::SignificantDigits(digits) => {
+                let magnitude = value.abs().log10().floor() as i32;
+                digits.max(1) as i32 - 1 - magnitude
+            }
+        };
+        let factor = 10f64.powi(decimals);
+        let rounded = (value * factor).round() / factor;
+        // the scaling overflows for extreme magnitudes, which have nothing to
+        // round at this precision anyway
+        if rounded.is_finite() {
+            rounded
+        } else {
+            value
+        }
+    }
+}
 
-use crate::partition::PartitionKey;
+/// Why a series didn't make it into the request.
+#[derive(Debug, Eq, PartialEq)]
+enum DropReason {
+    Malformed(&'static str),
+    OverMaxLabels,
+}
+
+#[derive(Clone, Default)]
+pub struct EncoderSettings {
+    pub field_names: FieldNames,
+    pub inject_label: Option<InjectLabel>,
+    pub max_labels: Option<MaxLabels>,
+    pub timestamp_unit: TimestampUnit,
+    pub value_precision: Option<ValuePrecision>,
+    /// Rendered into the `User-Agent` of the request, `default_user_agent` if
+    /// not set.
+    pub user_agent: Option<Template>,
+    /// The endpoint of events missing a field the endpoint template refers
+    /// to, which are dropped if not set.
+    pub default_endpoint: Option<String>,
+    /// Labels added to every series by VictoriaMetrics rather than in the
+    /// body, valued by their template rendered against the event.
+    pub extra_query_labels: BTreeMap<String, Template>,
+    /// Endpoint templates of series by their `__name__`, taking precedence
+    /// over the endpoint template. Events packing several series are only
+    /// routed if all of them share the name.
+    pub endpoint_by_metric_name: BTreeMap<String, Template>,
+    /// Check events against the expected shape before encoding, rejecting
+    /// failing ones with a descriptive error, see `validate`.
+    pub validate_events: bool,
+    /// Where events failing validation are kept, dropped if not set.
+    pub dead_letter: Option<DeadLetter>,
+}
 
 pub struct VMImportSinkEventEncoder {
     endpoint_template: Template,
+    settings: EncoderSettings,
 }
 
 impl VMImportSinkEventEncoder {
-    pub fn new(endpoint_template: Template) -> Self {
-        Self { endpoint_template }
+    pub fn new(endpoint_template: Template, settings: EncoderSettings) -> Self {
+        Self {
+            endpoint_template,
+            settings,
+        }
     }
 }
 
@@ -23,39 +196,347 @@ impl HttpEventEncoder<PartitionInnerBuffer<serde_json::Value, PartitionKey>>
         &mut self,
         event: Event,
     ) -> Option<PartitionInnerBuffer<serde_json::Value, PartitionKey>> {
-        let endpoint = self
-            .endpoint_template
-            .render_string(&event)
-            .map_err(|error| {
+        if self.settings.validate_events {
+            if let Err(error) = Self::validate(&event, &self.settings.field_names) {
+                emit!(VMImportInvalidEvent { error: &error });
+                if let Some(dead_letter) = &self.settings.dead_letter {
+                    dead_letter.write_invalid_event(&event, &error);
+                }
+                return None;
+            }
+        }
+        let endpoint_template = Self::metric_name(&event, &self.settings.field_names)
+            .and_then(|name| self.settings.endpoint_by_metric_name.get(&*name))
+            .unwrap_or(&self.endpoint_template);
+        let endpoint = match (
+            endpoint_template.render_string(&event),
+            &self.settings.default_endpoint,
+        ) {
+            (Ok(endpoint), _) => endpoint,
+            (Err(TemplateRenderingError::MissingKeys { missing_keys }), Some(default_endpoint)) => {
+                emit!(VMImportDefaultEndpoint {
+                    missing_keys: &missing_keys,
+                });
+                default_endpoint.clone()
+            }
+            (Err(error), _) => {
                 warn!(message = "Failed to render endpoint template.", %error);
-            })
-            .ok()?;
-        let json = Self::encode_log(event)?;
-        Some(PartitionInnerBuffer::new(json, PartitionKey::new(endpoint)))
+                return None;
+            }
+        };
+        let user_agent = match &self.settings.user_agent {
+            Some(user_agent) => user_agent.render_string(&event).unwrap_or_else(|error| {
+                warn!(message = "Failed to render user agent, using the default.", %error);
+                default_user_agent()
+            }),
+            None => default_user_agent(),
+        };
+        let label = match &self.settings.inject_label {
+            Some(InjectLabel {
+                name,
+                value: Some(value),
+            }) => {
+                let value = value
+                    .render_string(&event)
+                    .map_err(|error| {
+                        warn!(message = "Failed to render injected label.", %error);
+                    })
+                    .ok()?;
+                Some((name.as_str(), value))
+            }
+            Some(InjectLabel { name, value: None }) => Some((name.as_str(), endpoint.clone())),
+            None => None,
+        };
+
+        let mut extra_labels = BTreeMap::new();
+        for (name, value) in &self.settings.extra_query_labels {
+            let value = value
+                .render_string(&event)
+                .map_err(|error| {
+                    warn!(message = "Failed to render extra query label.", %error);
+                })
+                .ok()?;
+            extra_labels.insert(name.clone(), value);
+        }
+
+        let json = Self::encode_log(
+            event,
+            &self.settings.field_names,
+            label.as_ref().map(|(name, value)| (*name, value.as_str())),
+            self.settings.max_labels,
+            self.settings.timestamp_unit,
+            self.settings.value_precision,
+        )?;
+        Some(PartitionInnerBuffer::new(
+            json,
+            PartitionKey::new(endpoint, user_agent).with_extra_labels(extra_labels),
+        ))
     }
 }
 
 impl VMImportSinkEventEncoder {
-    fn encode_log(event: Event) -> Option<serde_json::Value> {
-        let mut log = event.try_into_log()?;
-        let labels = log.remove("labels")?;
-        let metric = Self::encode_metric(labels)?;
+    /// Checks that every series of `event` has labels with string values, and
+    /// as many timestamps, numbers or timestamp values, as float values. Unlike
+    /// `encode_log`, which drops malformed series by a terse reason, the error
+    /// tells which series and field are off, and it catches misaligned series
+    /// the store would reject along with their whole batch.
+    fn validate(event: &Event, field_names: &FieldNames) -> Result<(), String> {
+        let log = event
+            .maybe_as_log()
+            .ok_or_else(|| "not a log event".to_owned())?;
+        match log.get("series") {
+            Some(series) => {
+                let series = series
+                    .as_array()
+                    .ok_or_else(|| "`series` is not an array".to_owned())?;
+                for (i, series) in series.iter().enumerate() {
+                    let series = series
+                        .as_object()
+                        .ok_or_else(|| format!("`series[{}]` is not an object", i))?;
+                    Self::validate_series(|field| series.get(field), field_names)
+                        .map_err(|error| format!("`series[{}]`: {}", i, error))?;
+                }
+                Ok(())
+            }
+            None => Self::validate_series(|field| log.get(field), field_names),
+        }
+    }
+
+    fn validate_series<'a>(
+        get: impl Fn(&str) -> Option<&'a vector::event::Value>,
+        field_names: &FieldNames,
+    ) -> Result<(), String> {
+        use vector::event::Value;
+
+        let field = |name: &str| get(name).ok_or_else(|| format!("missing `{}`", name));
+        let array = |name: &str| {
+            field(name)?
+                .as_array()
+                .ok_or_else(|| format!("`{}` is not an array", name))
+        };
+
+        let labels_field = &field_names.labels_field;
+        let labels = field(labels_field)?
+            .as_object()
+            .ok_or_else(|| format!("`{}` is not an object", labels_field))?;
+        if let Some((name, _)) = labels.iter().find(|(_, value)| value.as_bytes().is_none()) {
+            return Err(format!("`{}.{}` is not a string", labels_field, name));
+        }
+
+        let timestamps_field = &field_names.timestamps_field;
+        let timestamps = array(timestamps_field)?;
+        let invalid = timestamps
+            .iter()
+            .position(|t| !matches!(t, Value::Timestamp(_) | Value::Integer(_) | Value::Float(_)));
+        if let Some(i) = invalid {
+            return Err(format!(
+                "`{}[{}]` is neither a timestamp nor a number",
+                timestamps_field, i
+            ));
+        }
+
+        let values_field = &field_names.values_field;
+        let values = array(values_field)?;
+        if let Some(i) = values.iter().position(|value| value.as_float().is_none()) {
+            return Err(format!("`{}[{}]` is not a float", values_field, i));
+        }
+
+        if timestamps.len() != values.len() {
+            return Err(format!(
+                "{} timestamps but {} values",
+                timestamps.len(),
+                values.len()
+            ));
+        }
+        Ok(())
+    }
+
+    fn metric_name<'a>(event: &'a Event, field_names: &FieldNames) -> Option<Cow<'a, str>> {
+        let name_of = |labels: &'a vector::event::Value| {
+            let name = labels.as_object()?.get("__name__")?.as_bytes()?;
+            Some(String::from_utf8_lossy(name))
+        };
+        let log = event.maybe_as_log()?;
+        match log.get("series") {
+            Some(series) => {
+                let mut names = series
+                    .as_array()?
+                    .iter()
+                    .map(|series| name_of(series.as_object()?.get(&field_names.labels_field)?));
+                let name = names.next()??;
+                names
+                    .all(|other| other.as_ref() == Some(&name))
+                    .then(|| name)
+            }
+            None => name_of(log.get(field_names.labels_field.as_str())?),
+        }
+    }
+
+    fn encode_log(
+        event: Event,
+        field_names: &FieldNames,
+        label: Option<(&str, &str)>,
+        max_labels: Option<MaxLabels>,
+        timestamp_unit: TimestampUnit,
+        value_precision: Option<ValuePrecision>,
+    ) -> Option<serde_json::Value> {
+        match Self::try_encode_log(
+            event,
+            field_names,
+            label,
+            max_labels,
+            timestamp_unit,
+            value_precision,
+        ) {
+            Ok(json) => Some(json),
+            Err(DropReason::Malformed(reason)) => {
+                emit!(VMImportMalformedEvent { reason });
+                None
+            }
+            // already reported when enforcing the limit
+            Err(DropReason::OverMaxLabels) => None,
+        }
+    }
+
+    fn try_encode_log(
+        event: Event,
+        field_names: &FieldNames,
+        label: Option<(&str, &str)>,
+        max_labels: Option<MaxLabels>,
+        timestamp_unit: TimestampUnit,
+        value_precision: Option<ValuePrecision>,
+    ) -> Result<serde_json::Value, DropReason> {
+        let mut log = event
+            .try_into_log()
+            .ok_or(DropReason::Malformed("not_a_log"))?;
+        if let Some(series) = log.remove("series") {
+            return Self::encode_multiple_series(
+                series,
+                field_names,
+                label,
+                max_labels,
+                timestamp_unit,
+                value_precision,
+            );
+        }
+
+        Self::encode_series(
+            log.remove(field_names.labels_field.as_str()),
+            log.remove(field_names.timestamps_field.as_str()),
+            log.remove(field_names.values_field.as_str()),
+            label,
+            max_labels,
+            timestamp_unit,
+            value_precision,
+        )
+    }
+
+    // An event may pack several series under `series`, each element carrying its
+    // own labels, timestamps and values fields. They're encoded as a JSON array
+    // and split into separate lines when building the request. Series over
+    // `max_labels` are dropped on their own, the event only if none is left.
+    fn encode_multiple_series(
+        v: vector::event::Value,
+        field_names: &FieldNames,
+        label: Option<(&str, &str)>,
+        max_labels: Option<MaxLabels>,
+        timestamp_unit: TimestampUnit,
+        value_precision: Option<ValuePrecision>,
+    ) -> Result<Value, DropReason> {
+        let series = match v {
+            vector::event::Value::Array(series) => series,
+            _ => return Err(DropReason::Malformed("invalid_series")),
+        };
+        let total = series.len();
+        let mut encoded = Vec::with_capacity(total);
+        for series in series {
+            let mut series = series
+                .into_object()
+                .ok_or(DropReason::Malformed("invalid_series"))?;
+            match Self::encode_series(
+                series.remove(&field_names.labels_field),
+                series.remove(&field_names.timestamps_field),
+                series.remove(&field_names.values_field),
+                label,
+                max_labels,
+                timestamp_unit,
+                value_precision,
+            ) {
+                Ok(series) => encoded.push(series),
+                Err(DropReason::OverMaxLabels) => {}
+                Err(reason) => return Err(reason),
+            }
+        }
+        if encoded.is_empty() && total > 0 {
+            return Err(DropReason::OverMaxLabels);
+        }
+        Ok(Value::Array(encoded))
+    }
 
-        let timestamps = log.remove("timestamps")?;
-        let timestamps = Self::encode_timestamps(timestamps)?;
+    fn encode_series(
+        labels: Option<vector::event::Value>,
+        timestamps: Option<vector::event::Value>,
+        values: Option<vector::event::Value>,
+        label: Option<(&str, &str)>,
+        max_labels: Option<MaxLabels>,
+        timestamp_unit: TimestampUnit,
+        value_precision: Option<ValuePrecision>,
+    ) -> Result<Value, DropReason> {
+        let labels = labels.ok_or(DropReason::Malformed("missing_labels"))?;
+        let timestamps = timestamps.ok_or(DropReason::Malformed("missing_timestamps"))?;
+        let values = values.ok_or(DropReason::Malformed("missing_values"))?;
 
-        let values = log.remove("values")?;
-        let values = Self::encode_values(values)?;
+        let metric = Self::encode_metric(labels, label, max_labels)?;
+        let timestamps = Self::encode_timestamps(timestamps, timestamp_unit)
+            .ok_or(DropReason::Malformed("invalid_timestamps"))?;
+        let values = Self::encode_values(values, value_precision)
+            .ok_or(DropReason::Malformed("invalid_values"))?;
 
         let mut target_map = serde_json::Map::with_capacity(3);
         target_map.insert("metric".to_owned(), metric);
         target_map.insert("timestamps".to_owned(), timestamps);
         target_map.insert("values".to_owned(), values);
-        Some(Value::Object(target_map))
+        Ok(Value::Object(target_map))
     }
 
-    fn encode_metric(v: vector::event::Value) -> Option<Value> {
-        let labels = v.into_object()?;
+    // The injected `label` counts towards `max_labels` like any other, and is
+    // kept along with `__name__` when trimming.
+    fn encode_metric(
+        v: vector::event::Value,
+        label: Option<(&str, &str)>,
+        max_labels: Option<MaxLabels>,
+    ) -> Result<Value, DropReason> {
+        let mut labels = v
+            .into_object()
+            .ok_or(DropReason::Malformed("labels_not_an_object"))?;
+        if let Some((name, value)) = label {
+            labels.insert(name.to_owned(), vector::event::Value::from(value));
+        }
+        if let Some(MaxLabels { limit, policy }) = max_labels {
+            if labels.len() > limit {
+                let action = match policy {
+                    MaxLabelsPolicy::Drop => "dropped",
+                    MaxLabelsPolicy::Trim => "trimmed",
+                };
+                emit!(VMImportSeriesOverMaxLabels {
+                    labels: labels.len(),
+                    limit,
+                    action,
+                });
+                if policy == MaxLabelsPolicy::Drop {
+                    return Err(DropReason::OverMaxLabels);
+                }
+
+                let kept = std::iter::once("__name__")
+                    .chain(label.map(|(name, _)| name))
+                    .filter_map(|name| labels.remove_entry(name))
+                    .collect::<Vec<_>>();
+                let rest = limit.saturating_sub(kept.len());
+                labels = labels.into_iter().take(rest).collect();
+                labels.extend(kept);
+            }
+        }
+
         let metric = labels
             .into_iter()
             .map(|(key, value)| {
@@ -63,16 +544,17 @@ impl VMImportSinkEventEncoder {
                 let value = Value::String(value.to_string());
                 Some((key, value))
             })
-            .collect::<Option<_>>()?;
-        Some(Value::Object(metric))
+            .collect::<Option<_>>()
+            .ok_or(DropReason::Malformed("invalid_label_value"))?;
+        Ok(Value::Object(metric))
     }
 
-    fn encode_timestamps(v: vector::event::Value) -> Option<Value> {
+    fn encode_timestamps(v: vector::event::Value, unit: TimestampUnit) -> Option<Value> {
         let timestamps = v.as_array()?;
         let timestamps = timestamps
             .iter()
             .map(|t| {
-                let ts = t.as_timestamp()?.timestamp_millis();
+                let ts = Self::timestamp_millis(t, unit)?;
                 let num = serde_json::Number::from(ts);
                 Some(Value::Number(num))
             })
@@ -80,13 +562,39 @@ impl VMImportSinkEventEncoder {
         Some(Value::Array(timestamps))
     }
 
-    fn encode_values(v: vector::event::Value) -> Option<Value> {
+    fn timestamp_millis(t: &vector::event::Value, unit: TimestampUnit) -> Option<i64> {
+        use vector::event::Value;
+
+        let number = match t {
+            Value::Timestamp(ts) => return Some(ts.timestamp_millis()),
+            Value::Integer(i) => *i as f64,
+            Value::Float(f) => f.into_inner(),
+            _ => return None,
+        };
+        let is_seconds = match unit {
+            TimestampUnit::Auto => number.abs() < AUTO_MILLIS_THRESHOLD,
+            TimestampUnit::Seconds => true,
+            TimestampUnit::Millis => false,
+            TimestampUnit::Timestamp => return None,
+        };
+        match (is_seconds, t) {
+            (false, Value::Integer(i)) => Some(*i),
+            (true, Value::Integer(i)) => i.checked_mul(1000),
+            (false, _) => Some(number.round() as i64),
+            (true, _) => Some((number * 1000.0).round() as i64),
+        }
+    }
+
+    fn encode_values(v: vector::event::Value, precision: Option<ValuePrecision>) -> Option<Value> {
         let values = v.as_array()?;
         let values = values
             .iter()
             .map(|value| {
-                let value = value.as_float()?;
-                let num = serde_json::Number::from_f64(*value)?;
+                let mut value = value.as_float()?.into_inner();
+                if let Some(precision) = precision {
+                    value = precision.round(value);
+                }
+                let num = serde_json::Number::from_f64(value)?;
                 Some(Value::Number(num))
             })
             .collect::<Option<_>>()?;
@@ -112,7 +620,15 @@ mod tests {
             .build_event()
             .unwrap();
 
-        let value = VMImportSinkEventEncoder::encode_log(event.into()).unwrap();
+        let value = VMImportSinkEventEncoder::encode_log(
+            event.into(),
+            &FieldNames::default(),
+            None,
+            None,
+            TimestampUnit::default(),
+            None,
+        )
+        .unwrap();
 
         let expected = serde_json::json!({
             "metric": {
@@ -129,6 +645,77 @@ mod tests {
         assert_eq!(value, expected);
     }
 
+    #[test]
+    fn multiple_series_event() {
+        use std::collections::BTreeMap;
+
+        use vector::event::{LogEvent, Value};
+
+        let series = |name: &str, points: [(u64, f64); 2]| {
+            let mut event = Buf::default()
+                .label_name(name)
+                .instance("db:10080")
+                .instance_type("tidb")
+                .points(points.into_iter())
+                .build_event()
+                .unwrap();
+            let mut series = BTreeMap::new();
+            for field in ["labels", "timestamps", "values"] {
+                series.insert(field.to_owned(), event.remove(field).unwrap());
+            }
+            Value::Object(series)
+        };
+
+        let mut event = LogEvent::default();
+        event.insert(
+            "series",
+            Value::Array(vec![
+                series(
+                    "topsql_cpu_time_ms",
+                    [(1661396787, 80.0), (1661396788, 443.0)],
+                ),
+                series(
+                    "topsql_stmt_exec_count",
+                    [(1661396787, 3.0), (1661396788, 5.0)],
+                ),
+            ]),
+        );
+
+        let value = VMImportSinkEventEncoder::encode_log(
+            event.into(),
+            &FieldNames::default(),
+            None,
+            None,
+            TimestampUnit::default(),
+            None,
+        )
+        .unwrap();
+
+        let expected_labels = |name: &str| {
+            serde_json::json!({
+                "__name__": name,
+                "instance": "db:10080",
+                "instance_type": "tidb",
+                "sql_digest": "",
+                "plan_digest": "",
+                "tag_label": "",
+            })
+        };
+        let expected = serde_json::json!([
+            {
+                "metric": expected_labels("topsql_cpu_time_ms"),
+                "timestamps": [1661396787000u64, 1661396788000u64],
+                "values": [80.0, 443.0],
+            },
+            {
+                "metric": expected_labels("topsql_stmt_exec_count"),
+                "timestamps": [1661396787000u64, 1661396788000u64],
+                "values": [3.0, 5.0],
+            },
+        ]);
+        assert_eq!(value, expected);
+    }
+
     #[test]
     fn partition_by_cluster_id() {
         use bytes::Bytes;
@@ -136,7 +723,7 @@ mod tests {
 
         let routine = |tmp_str: &str| {
             let tmp = tmp_str.try_into().unwrap();
-            let mut encoder = VMImportSinkEventEncoder::new(tmp);
+            let mut encoder = VMImportSinkEventEncoder::new(tmp, EncoderSettings::default());
 
             let mut event = Buf::default()
                 .label_name("topsql_cpu_time_ms")
@@ -174,4 +761,639 @@ mod tests {
         routine("http://localhost:8080/metrics/{{ .labels.cluster_id }}");
         routine("http://localhost:8080/metrics/{{ labels.cluster_id }}");
     }
+
+    #[test]
+    fn inject_label() {
+        use bytes::Bytes;
+        use vector::event::Value;
+
+        let routine = |value: Option<&str>, expected: &str| {
+            let tmp = "http://localhost:8080/metrics/{{ labels.cluster_id }}"
+                .try_into()
+                .unwrap();
+            let inject_label = InjectLabel {
+                name: "vm_cluster".to_owned(),
+                value: value.map(|value| value.try_into().unwrap()),
+            };
+            let settings = EncoderSettings {
+                inject_label: Some(inject_label),
+                ..Default::default()
+            };
+            let mut encoder = VMImportSinkEventEncoder::new(tmp, settings);
+
+            let mut event = Buf::default()
+                .label_name("topsql_cpu_time_ms")
+                .instance("db:10080")
+                .instance_type("tidb")
+                .points([(1661396787, 80.0)].into_iter())
+                .build_event()
+                .unwrap();
+            let labels = event.get_mut("labels").unwrap();
+            labels.insert("cluster_id", Value::Bytes(Bytes::from("10086")));
+
+            let value = encoder.encode_event(event.into()).unwrap();
+            let (json, _) = value.into_parts();
+            assert_eq!(json["metric"]["vm_cluster"], expected);
+        };
+
+        routine(None, "http://localhost:8080/metrics/10086");
+        routine(Some("vm-{{ labels.cluster_id }}"), "vm-10086");
+    }
+
+    #[test]
+    fn default_endpoint() {
+        use bytes::Bytes;
+        use vector::event::Value;
+
+        let routine = |default_endpoint: Option<&str>, cluster_id: Option<&str>| {
+            let settings = EncoderSettings {
+                default_endpoint: default_endpoint.map(ToOwned::to_owned),
+                ..Default::default()
+            };
+            let mut encoder = VMImportSinkEventEncoder::new(
+                "http://localhost:8428/{{ labels.cluster_id }}/api/v1/import"
+                    .try_into()
+                    .unwrap(),
+                settings,
+            );
+
+            let mut event = Buf::default()
+                .label_name("topsql_cpu_time_ms")
+                .instance("db:10080")
+                .instance_type("tidb")
+                .points([(1661396787, 80.0)].into_iter())
+                .build_event()
+                .unwrap();
+            if let Some(cluster_id) = cluster_id {
+                let labels = event.get_mut("labels").unwrap();
+                labels.insert(
+                    "cluster_id",
+                    Value::Bytes(Bytes::from(cluster_id.to_owned())),
+                );
+            }
+
+            encoder
+                .encode_event(event.into())
+                .map(|value| value.into_parts().1.endpoint)
+        };
+
+        let default_endpoint = "http://localhost:8428/unrouted/api/v1/import";
+        assert_eq!(
+            routine(Some(default_endpoint), Some("10086")).as_deref(),
+            Some("http://localhost:8428/10086/api/v1/import")
+        );
+        assert_eq!(
+            routine(Some(default_endpoint), None).as_deref(),
+            Some(default_endpoint)
+        );
+        // dropped without a default
+        assert_eq!(routine(None, None), None);
+    }
+
+    #[test]
+    fn route_by_metric_name() {
+        let settings = EncoderSettings {
+            endpoint_by_metric_name: [
+                (
+                    "topsql_cpu_time_ms",
+                    "http://localhost:8428/cpu/api/v1/import",
+                ),
+                (
+                    "topsql_read_keys",
+                    "http://localhost:8428/keys/api/v1/import",
+                ),
+            ]
+            .into_iter()
+            .map(|(name, endpoint)| (name.to_owned(), endpoint.try_into().unwrap()))
+            .collect(),
+            ..Default::default()
+        };
+        let mut encoder = VMImportSinkEventEncoder::new(
+            "http://localhost:8428/api/v1/import".try_into().unwrap(),
+            settings,
+        );
+
+        let mut endpoint_of = |name: &str| {
+            let event = Buf::default()
+                .label_name(name)
+                .instance("db:10080")
+                .instance_type("tidb")
+                .points([(1661396787, 80.0)].into_iter())
+                .build_event()
+                .unwrap();
+            let value = encoder.encode_event(event.into()).unwrap();
+            value.into_parts().1.endpoint
+        };
+
+        assert_eq!(
+            endpoint_of("topsql_cpu_time_ms"),
+            "http://localhost:8428/cpu/api/v1/import"
+        );
+        assert_eq!(
+            endpoint_of("topsql_read_keys"),
+            "http://localhost:8428/keys/api/v1/import"
+        );
+        // unrouted names fall back to the endpoint template
+        assert_eq!(
+            endpoint_of("topsql_write_keys"),
+            "http://localhost:8428/api/v1/import"
+        );
+    }
+
+    #[test]
+    fn user_agent() {
+        use bytes::Bytes;
+        use vector::event::Value;
+
+        let routine = |user_agent: Option<&str>, expected: String| {
+            let settings = EncoderSettings {
+                user_agent: user_agent.map(|user_agent| user_agent.try_into().unwrap()),
+                ..Default::default()
+            };
+            let mut encoder = VMImportSinkEventEncoder::new(
+                "http://localhost:8428/api/v1/import".try_into().unwrap(),
+                settings,
+            );
+
+            let mut event = Buf::default()
+                .label_name("topsql_cpu_time_ms")
+                .instance("db:10080")
+                .instance_type("tidb")
+                .points([(1661396787, 80.0)].into_iter())
+                .build_event()
+                .unwrap();
+            let labels = event.get_mut("labels").unwrap();
+            labels.insert("cluster_id", Value::Bytes(Bytes::from("10086")));
+
+            let (_, key) = encoder.encode_event(event.into()).unwrap().into_parts();
+            assert_eq!(key.user_agent, expected);
+        };
+
+        routine(None, default_user_agent());
+        routine(
+            Some("vector-vm-import/{{ labels.cluster_id }}"),
+            "vector-vm-import/10086".to_owned(),
+        );
+        routine(
+            Some("vector-vm-import/{{ labels.missing }}"),
+            default_user_agent(),
+        );
+    }
+
+    #[test]
+    fn over_max_labels() {
+        let event = || {
+            Buf::default()
+                .label_name("topsql_cpu_time_ms")
+                .instance("db:10080")
+                .instance_type("tidb")
+                .sql_digest("DEAD")
+                .plan_digest("BEEF")
+                .points([(1661396787, 80.0)].into_iter())
+                .build_event()
+                .unwrap()
+        };
+
+        let max_labels = |policy| Some(MaxLabels { limit: 3, policy });
+        let value = VMImportSinkEventEncoder::encode_log(
+            event().into(),
+            &FieldNames::default(),
+            None,
+            max_labels(MaxLabelsPolicy::Drop),
+            TimestampUnit::default(),
+            None,
+        );
+        assert!(value.is_none());
+
+        let value = VMImportSinkEventEncoder::encode_log(
+            event().into(),
+            &FieldNames::default(),
+            None,
+            max_labels(MaxLabelsPolicy::Trim),
+            TimestampUnit::default(),
+            None,
+        )
+        .unwrap();
+        let expected = serde_json::json!({
+            "__name__": "topsql_cpu_time_ms",
+            "instance": "db:10080",
+            "instance_type": "tidb",
+        });
+        assert_eq!(value["metric"], expected);
+
+        let value = VMImportSinkEventEncoder::encode_log(
+            event().into(),
+            &FieldNames::default(),
+            None,
+            Some(MaxLabels {
+                limit: 6,
+                policy: MaxLabelsPolicy::Drop,
+            }),
+            TimestampUnit::default(),
+            None,
+        );
+        assert!(value.is_some());
+
+        // the injected label counts towards the limit, and survives trimming
+        let value = VMImportSinkEventEncoder::encode_log(
+            event().into(),
+            &FieldNames::default(),
+            Some(("vm_cluster", "a")),
+            Some(MaxLabels {
+                limit: 6,
+                policy: MaxLabelsPolicy::Drop,
+            }),
+            TimestampUnit::default(),
+            None,
+        );
+        assert!(value.is_none());
+        let value = VMImportSinkEventEncoder::encode_log(
+            event().into(),
+            &FieldNames::default(),
+            Some(("vm_cluster", "a")),
+            max_labels(MaxLabelsPolicy::Trim),
+            TimestampUnit::default(),
+            None,
+        )
+        .unwrap();
+        let expected = serde_json::json!({
+            "__name__": "topsql_cpu_time_ms",
+            "instance": "db:10080",
+            "vm_cluster": "a",
+        });
+        assert_eq!(value["metric"], expected);
+    }
+
+    #[test]
+    fn over_max_labels_in_multiple_series() {
+        use std::collections::BTreeMap;
+
+        use ordered_float::NotNan;
+        use vector::event::{LogEvent, Value};
+
+        let series = |labels: &[&str]| {
+            let mut series = BTreeMap::new();
+            series.insert(
+                "labels".to_owned(),
+                Value::Object(
+                    labels
+                        .iter()
+                        .map(|name| (name.to_string(), Value::from("v")))
+                        .collect(),
+                ),
+            );
+            series.insert(
+                "timestamps".to_owned(),
+                Value::Array(vec![Value::Integer(1661396787000)]),
+            );
+            series.insert(
+                "values".to_owned(),
+                Value::Array(vec![Value::Float(NotNan::new(1.0).unwrap())]),
+            );
+            Value::Object(series)
+        };
+        let event = |series: Vec<Value>| {
+            let mut event = LogEvent::default();
+            event.insert("series", Value::Array(series));
+            event
+        };
+        let encode = |event: LogEvent| {
+            VMImportSinkEventEncoder::try_encode_log(
+                event.into(),
+                &FieldNames::default(),
+                None,
+                Some(MaxLabels {
+                    limit: 2,
+                    policy: MaxLabelsPolicy::Drop,
+                }),
+                TimestampUnit::default(),
+                None,
+            )
+        };
+
+        // only the series over the limit is dropped
+        let value = encode(event(vec![
+            series(&["__name__", "a"]),
+            series(&["__name__", "a", "b"]),
+            series(&["__name__"]),
+        ]))
+        .unwrap();
+        let metrics = value
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|series| series["metric"].as_object().unwrap().len())
+            .collect::<Vec<_>>();
+        assert_eq!(metrics, vec![2, 1]);
+
+        // the whole event once none is left
+        assert_eq!(
+            encode(event(vec![series(&["__name__", "a", "b"])])),
+            Err(DropReason::OverMaxLabels)
+        );
+    }
+
+    #[test]
+    fn malformed_event() {
+        use vector::event::{LogEvent, Value};
+
+        let event = || {
+            Buf::default()
+                .label_name("topsql_cpu_time_ms")
+                .instance("db:10080")
+                .instance_type("tidb")
+                .points([(1661396787, 80.0)].into_iter())
+                .build_event()
+                .unwrap()
+        };
+        let encode = |event: LogEvent| {
+            VMImportSinkEventEncoder::try_encode_log(
+                event.into(),
+                &FieldNames::default(),
+                None,
+                None,
+                TimestampUnit::default(),
+                None,
+            )
+            .map(|_| ())
+        };
+        let malformed = |reason| Err(DropReason::Malformed(reason));
+
+        assert_eq!(encode(event()), Ok(()));
+        for field in ["labels", "timestamps", "values"] {
+            let mut event = event();
+            event.remove(field);
+            assert_eq!(
+                encode(event),
+                malformed(match field {
+                    "labels" => "missing_labels",
+                    "timestamps" => "missing_timestamps",
+                    _ => "missing_values",
+                })
+            );
+        }
+
+        let mut labels_not_an_object = event();
+        labels_not_an_object.insert("labels", Value::from("instance=db:10080"));
+        assert_eq!(
+            encode(labels_not_an_object),
+            malformed("labels_not_an_object")
+        );
+
+        let mut invalid_label_value = event();
+        invalid_label_value.insert("labels.instance", Value::Integer(10080));
+        assert_eq!(
+            encode(invalid_label_value),
+            malformed("invalid_label_value")
+        );
+
+        let mut invalid_timestamps = event();
+        invalid_timestamps.insert("timestamps", Value::from("1661396787"));
+        assert_eq!(encode(invalid_timestamps), malformed("invalid_timestamps"));
+
+        let mut invalid_values = event();
+        invalid_values.insert("values", Value::Array(vec![Value::from("80.0")]));
+        assert_eq!(encode(invalid_values), malformed("invalid_values"));
+
+        let mut invalid_series = LogEvent::default();
+        invalid_series.insert("series", Value::Array(vec![Value::Integer(1)]));
+        assert_eq!(encode(invalid_series), malformed("invalid_series"));
+
+        let over_max_labels = VMImportSinkEventEncoder::try_encode_log(
+            event().into(),
+            &FieldNames::default(),
+            None,
+            Some(MaxLabels {
+                limit: 1,
+                policy: MaxLabelsPolicy::Drop,
+            }),
+            TimestampUnit::default(),
+            None,
+        );
+        assert_eq!(over_max_labels, Err(DropReason::OverMaxLabels));
+    }
+
+    #[test]
+    fn validate_events() {
+        use ordered_float::NotNan;
+        use vector::event::Value;
+
+        let mut event = Buf::default()
+            .label_name("topsql_cpu_time_ms")
+            .instance("db:10080")
+            .instance_type("tidb")
+            .points([(1661396787, 80.0), (1661396788, 443.0)].into_iter())
+            .build_event()
+            .unwrap();
+        let field_names = FieldNames::default();
+        assert_eq!(
+            VMImportSinkEventEncoder::validate(&event.clone().into(), &field_names),
+            Ok(())
+        );
+
+        event.insert(
+            "values",
+            Value::Array(vec![Value::Float(NotNan::new(80.0).unwrap())]),
+        );
+        assert_eq!(
+            VMImportSinkEventEncoder::validate(&event.clone().into(), &field_names),
+            Err("2 timestamps but 1 values".to_owned())
+        );
+
+        let dir =
+            std::env::temp_dir().join(format!("vm-import-invalid-events-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let settings = EncoderSettings {
+            validate_events: true,
+            dead_letter: Some(DeadLetter::new(dir.clone()).unwrap()),
+            ..Default::default()
+        };
+        let mut encoder = VMImportSinkEventEncoder::new(
+            "http://localhost:8428/api/v1/import".try_into().unwrap(),
+            settings,
+        );
+        assert!(encoder.encode_event(event.into()).is_none());
+
+        let invalid_events = std::fs::read_to_string(dir.join("invalid_events.ndjson")).unwrap();
+        let invalid_event: serde_json::Value = serde_json::from_str(&invalid_events).unwrap();
+        assert_eq!(invalid_event["error"], "2 timestamps but 1 values");
+        assert_eq!(
+            invalid_event["event"]["labels"]["__name__"],
+            "topsql_cpu_time_ms"
+        );
+    }
+
+    #[test]
+    fn timestamp_unit() {
+        use chrono::{TimeZone, Utc};
+        use ordered_float::NotNan;
+        use vector::event::Value;
+
+        let encode = |t: Value, unit| {
+            let timestamps = Value::Array(vec![t]);
+            VMImportSinkEventEncoder::encode_timestamps(timestamps, unit)
+        };
+        let seconds = || Value::Integer(1661396787);
+        let millis = || Value::Integer(1661396787123);
+        let float_seconds = || Value::Float(NotNan::new(1661396787.123).unwrap());
+        let timestamp = || Value::Timestamp(Utc.timestamp_millis(1661396787123));
+
+        let expected_seconds = Some(serde_json::json!([1661396787000i64]));
+        let expected_millis = Some(serde_json::json!([1661396787123i64]));
+
+        // auto
+        assert_eq!(encode(seconds(), TimestampUnit::Auto), expected_seconds);
+        assert_eq!(encode(millis(), TimestampUnit::Auto), expected_millis);
+        assert_eq!(
+            encode(float_seconds(), TimestampUnit::Auto),
+            expected_millis
+        );
+        assert_eq!(encode(timestamp(), TimestampUnit::Auto), expected_millis);
+
+        // seconds
+        assert_eq!(encode(seconds(), TimestampUnit::Seconds), expected_seconds);
+        assert_eq!(
+            encode(float_seconds(), TimestampUnit::Seconds),
+            expected_millis
+        );
+        assert_eq!(encode(timestamp(), TimestampUnit::Seconds), expected_millis);
+
+        // millis
+        assert_eq!(encode(millis(), TimestampUnit::Millis), expected_millis);
+        assert_eq!(
+            encode(seconds(), TimestampUnit::Millis),
+            Some(serde_json::json!([1661396787i64]))
+        );
+        assert_eq!(encode(timestamp(), TimestampUnit::Millis), expected_millis);
+
+        // timestamp
+        assert_eq!(
+            encode(timestamp(), TimestampUnit::Timestamp),
+            expected_millis
+        );
+        assert_eq!(encode(seconds(), TimestampUnit::Timestamp), None);
+        assert_eq!(encode(float_seconds(), TimestampUnit::Timestamp), None);
+
+        assert_eq!(
+            encode(Value::Bytes("1661396787".into()), TimestampUnit::Auto),
+            None
+        );
+    }
+
+    #[test]
+    fn value_precision() {
+        use ordered_float::NotNan;
+        use vector::event::Value;
+
+        let encode = |values: &[f64], precision| {
+            let values = values
+                .iter()
+                .map(|value| Value::Float(NotNan::new(*value).unwrap()))
+                .collect();
+            let json =
+                VMImportSinkEventEncoder::encode_values(Value::Array(values), Some(precision));
+            serde_json::to_string(&json.unwrap()).unwrap()
+        };
+
+        let values = [1.23456, -2.71828, 0.0, 12345.6789, 80.0];
+        assert_eq!(
+            encode(&values, ValuePrecision::Decimals(3)),
+            "[1.235,-2.718,0.0,12345.679,80.0]"
+        );
+        assert_eq!(
+            encode(&values, ValuePrecision::SignificantDigits(3)),
+            "[1.23,-2.72,0.0,12300.0,80.0]"
+        );
+
+        // too large to scale, left as is
+        assert_eq!(encode(&[1e307], ValuePrecision::Decimals(3)), "[1e307]");
+
+        // non-finite values are still rejected
+        let infinite = Value::Array(vec![Value::Float(NotNan::new(f64::INFINITY).unwrap())]);
+        assert_eq!(
+            VMImportSinkEventEncoder::encode_values(infinite, Some(ValuePrecision::Decimals(3))),
+            None
+        );
+    }
+
+    #[test]
+    fn renamed_fields() {
+        use std::collections::BTreeMap;
+
+        use ordered_float::NotNan;
+        use vector::event::{LogEvent, Value};
+
+        let field_names = toml::from_str::<FieldNames>(
+            r#"
+            labels_field = "tags"
+            values_field = "samples"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(field_names.timestamps_field, "timestamps");
+
+        let series = || {
+            let mut series = BTreeMap::new();
+            series.insert(
+                "tags".to_owned(),
+                Value::Object(
+                    [("__name__".to_owned(), Value::from("up"))]
+                        .into_iter()
+                        .collect(),
+                ),
+            );
+            series.insert(
+                "timestamps".to_owned(),
+                Value::Array(vec![Value::Integer(1661396787000)]),
+            );
+            series.insert(
+                "samples".to_owned(),
+                Value::Array(vec![Value::Float(NotNan::new(1.0).unwrap())]),
+            );
+            series
+        };
+        let expected = serde_json::json!({
+            "metric": { "__name__": "up" },
+            "timestamps": [1661396787000u64],
+            "values": [1.0],
+        });
+
+        let event = LogEvent::from(series());
+        let value = VMImportSinkEventEncoder::encode_log(
+            event.clone().into(),
+            &field_names,
+            None,
+            None,
+            TimestampUnit::Millis,
+            None,
+        )
+        .unwrap();
+        assert_eq!(value, expected);
+
+        let mut multiple = LogEvent::default();
+        multiple.insert("series", Value::Array(vec![Value::Object(series())]));
+        let value = VMImportSinkEventEncoder::encode_log(
+            multiple.into(),
+            &field_names,
+            None,
+            None,
+            TimestampUnit::Millis,
+            None,
+        )
+        .unwrap();
+        assert_eq!(value, serde_json::json!([expected]));
+
+        // the default names no longer match
+        assert_eq!(
+            VMImportSinkEventEncoder::try_encode_log(
+                event.into(),
+                &FieldNames::default(),
+                None,
+                None,
+                TimestampUnit::Millis,
+                None,
+            ),
+            Err(DropReason::Malformed("missing_labels"))
+        );
+    }
 }
@@ -0,0 +1,242 @@
+use std::pin::Pin;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures_util::future::BoxFuture;
+use futures_util::stream::BoxStream;
+use futures_util::{Sink, Stream, StreamExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use vector::event::Event;
+use vector::sinks::util::{Batch, PushResult};
+use vector_core::sink::StreamSink;
+
+pub type BoxedEventSink = Pin<Box<dyn Sink<Event, Error = ()> + Send>>;
+
+/// A batch cut on every multiple of `interval` since the Unix epoch, so
+/// batches hold the samples of one grid step, e.g. of the deduplication
+/// interval of VictoriaMetrics. The first event past a boundary overflows it,
+/// which has the partition sent as if full while the sink keeps running. A
+/// partition receiving no more events goes out on its batch timeout instead.
+/// Without an interval it behaves as the inner batch.
+pub struct AlignedBuffer<B> {
+    inner: B,
+    interval: Option<Duration>,
+    // the boundary following the first event
+    deadline: Option<Instant>,
+    clock: fn() -> SystemTime,
+}
+
+impl<B> AlignedBuffer<B> {
+    pub fn new(inner: B, interval: Option<Duration>) -> Self {
+        Self::with_clock(inner, interval, SystemTime::now)
+    }
+
+    fn with_clock(inner: B, interval: Option<Duration>, clock: fn() -> SystemTime) -> Self {
+        Self {
+            inner,
+            interval,
+            deadline: None,
+            clock,
+        }
+    }
+}
+
+impl<B: Batch> Batch for AlignedBuffer<B> {
+    type Input = B::Input;
+    type Output = B::Output;
+
+    fn push(&mut self, item: Self::Input) -> PushResult<Self::Input> {
+        if let Some(interval) = self.interval {
+            let now = Instant::now();
+            match self.deadline {
+                Some(deadline) if now >= deadline => return PushResult::Overflow(item),
+                Some(_) => {}
+                None => {
+                    self.deadline = Some(now + until_next_boundary((self.clock)(), interval));
+                }
+            }
+        }
+        self.inner.push(item)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    fn fresh(&self) -> Self {
+        Self::with_clock(self.inner.fresh(), self.interval, self.clock)
+    }
+
+    fn finish(self) -> Self::Output {
+        self.inner.finish()
+    }
+
+    fn num_items(&self) -> usize {
+        self.inner.num_items()
+    }
+}
+
+fn until_next_boundary(now: SystemTime, interval: Duration) -> Duration {
+    let since_epoch = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let interval = interval.as_nanos().max(1);
+    Duration::from_nanos((interval - since_epoch % interval) as u64)
+}
+
+/// Asks a running `FlushableSink` to send out its partial batches.
+#[derive(Clone)]
+pub struct FlushHandle {
+    tx: mpsc::UnboundedSender<oneshot::Sender<()>>,
+}
+
+impl FlushHandle {
+    /// Returns once every partial batch is sent and the requests in flight
+    /// are answered, or `false` right away if the sink is no longer running.
+    pub async fn flush(&self) -> bool {
+        let (done, waiter) = oneshot::channel();
+        if self.tx.send(done).is_err() {
+            return false;
+        }
+        waiter.await.is_ok()
+    }
+}
+
+/// Runs the sink built by `build` like `VectorSink::from_event_sink` would,
+/// except that a flush closes it the same as the end of the input does: every
+/// partition buffer is sent regardless of `batch.timeout_secs`, and the
+/// requests in flight are awaited. A new sink then takes over the events.
+pub struct FlushableSink {
+    build: Box<dyn FnMut() -> BoxedEventSink + Send>,
+    flushes: mpsc::UnboundedReceiver<oneshot::Sender<()>>,
+}
+
+impl FlushableSink {
+    pub fn new(build: Box<dyn FnMut() -> BoxedEventSink + Send>) -> (Self, FlushHandle) {
+        let (tx, flushes) = mpsc::unbounded_channel();
+        (Self { build, flushes }, FlushHandle { tx })
+    }
+}
+
+#[async_trait::async_trait]
+impl StreamSink<Event> for FlushableSink {
+    async fn run(mut self: Box<Self>, input: BoxStream<'_, Event>) -> Result<(), ()> {
+        run_flushable(input, &mut self.build, &mut self.flushes).await
+    }
+}
+
+async fn run_flushable<T, S, F>(
+    mut input: impl Stream<Item = T> + Unpin,
+    build: &mut F,
+    flushes: &mut mpsc::UnboundedReceiver<oneshot::Sender<()>>,
+) -> Result<(), ()>
+where
+    S: Sink<T, Error = ()> + Unpin,
+    F: FnMut() -> S,
+{
+    loop {
+        let flush: BoxFuture<'_, _> = Box::pin(async {
+            match flushes.recv().await {
+                Some(done) => done,
+                // nobody can ask for a flush anymore
+                None => futures_util::future::pending().await,
+            }
+        });
+        let mut events = (&mut input).take_until(flush);
+        // closes the sink once the input ends or a flush is asked for
+        (&mut events).map(Ok).forward(build()).await?;
+        match Pin::new(&mut events).take_result() {
+            Some(done) => {
+                let _ = done.send(());
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Flushes on every `SIGUSR1` until the sink stops, e.g. to drain the agent
+/// before a rolling upgrade.
+#[cfg(unix)]
+pub fn flush_on_sigusr1(handle: FlushHandle) -> std::io::Result<()> {
+    use futures_util::future::{select, Either};
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        loop {
+            let signaled = select(Box::pin(signals.recv()), Box::pin(handle.tx.closed())).await;
+            if !matches!(signaled, Either::Left((Some(()), _))) {
+                break;
+            }
+            info!(message = "Flushing on SIGUSR1.");
+            if !handle.flush().await {
+                break;
+            }
+            info!(message = "Flushed on SIGUSR1.");
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_boundary() {
+        let interval = Duration::from_secs(10);
+        let at = |secs: u64, millis: u64| {
+            UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis)
+        };
+        assert_eq!(
+            until_next_boundary(at(1661396781, 0), interval),
+            Duration::from_secs(9)
+        );
+        assert_eq!(
+            until_next_boundary(at(1661396789, 500), interval),
+            Duration::from_millis(500)
+        );
+        // on a boundary, the next one
+        assert_eq!(until_next_boundary(at(1661396790, 0), interval), interval);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cut_batches_on_grid_boundaries() {
+        use vector::sinks::util::{BatchConfig, JsonArrayBuffer};
+
+        use crate::config::VMImportDefaultBatchSettings;
+
+        let inner = || {
+            let size = BatchConfig::<VMImportDefaultBatchSettings>::default()
+                .into_batch_settings::<JsonArrayBuffer>()
+                .unwrap()
+                .size;
+            JsonArrayBuffer::new(size)
+        };
+        let item = || serde_json::json!({});
+        // started 4s into a 10s grid step
+        let clock = || UNIX_EPOCH + Duration::from_secs(1661396784);
+        let interval = Duration::from_secs(10);
+
+        let mut buffer = AlignedBuffer::with_clock(inner(), Some(interval), clock);
+        assert!(matches!(buffer.push(item()), PushResult::Ok(false)));
+        tokio::time::advance(Duration::from_millis(5999)).await;
+        assert!(matches!(buffer.push(item()), PushResult::Ok(false)));
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert!(matches!(buffer.push(item()), PushResult::Overflow(_)));
+        assert_eq!(buffer.num_items(), 2);
+
+        // a fresh batch waits for the next boundary
+        let mut buffer = buffer.fresh();
+        assert!(matches!(buffer.push(item()), PushResult::Ok(false)));
+        tokio::time::advance(Duration::from_millis(5999)).await;
+        assert!(matches!(buffer.push(item()), PushResult::Ok(false)));
+
+        // never cut without an interval
+        let mut buffer = AlignedBuffer::with_clock(inner(), None, clock);
+        assert!(matches!(buffer.push(item()), PushResult::Ok(false)));
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        assert!(matches!(buffer.push(item()), PushResult::Ok(false)));
+    }
+}
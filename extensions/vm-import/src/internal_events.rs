@@ -0,0 +1,176 @@
+use metrics::counter;
+use vector_core::internal_event::InternalEvent;
+
+/// An event dropped as it doesn't carry the expected series layout. `reason`
+/// tells which part is missing or malformed, e.g. `missing_labels` or
+/// `labels_not_an_object`.
+#[derive(Debug)]
+pub struct VMImportMalformedEvent {
+    pub reason: &'static str,
+}
+
+impl InternalEvent for VMImportMalformedEvent {
+    fn emit(self) {
+        warn!(
+            message = "Dropped malformed event.",
+            reason = %self.reason,
+            internal_log_rate_secs = 10,
+        );
+        counter!(
+            "vm_import_malformed_events_total", 1,
+            "reason" => self.reason,
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct VMImportInvalidEvent<'a> {
+    pub error: &'a str,
+}
+
+impl<'a> InternalEvent for VMImportInvalidEvent<'a> {
+    fn emit(self) {
+        warn!(
+            message = "Rejected event failing validation.",
+            error = %self.error,
+            internal_log_rate_secs = 10,
+        );
+        counter!("vm_import_invalid_events_total", 1);
+    }
+}
+
+#[derive(Debug)]
+pub struct VMImportSeriesOverMaxLabels {
+    pub labels: usize,
+    pub limit: usize,
+    pub action: &'static str,
+}
+
+impl InternalEvent for VMImportSeriesOverMaxLabels {
+    fn emit(self) {
+        warn!(
+            message = "Series has more labels than allowed.",
+            labels = %self.labels,
+            limit = %self.limit,
+            action = %self.action,
+            internal_log_rate_secs = 10,
+        );
+        counter!(
+            "vm_import_series_over_max_labels_total", 1,
+            "action" => self.action,
+        );
+    }
+}
+
+/// A batch written to the dead letter directory, tagged by the status it was
+/// last answered with, or `error` if it wasn't answered.
+#[derive(Debug)]
+pub struct VMImportDeadLettered<'a> {
+    pub endpoint: &'a str,
+    pub status: Option<http::StatusCode>,
+    pub path: &'a std::path::Path,
+}
+
+impl<'a> InternalEvent for VMImportDeadLettered<'a> {
+    fn emit(self) {
+        let status = self
+            .status
+            .as_ref()
+            .map_or("error", http::StatusCode::as_str)
+            .to_owned();
+        warn!(
+            message = "Batch failed for good, wrote it to the dead letter directory.",
+            endpoint = %self.endpoint,
+            status = %status,
+            path = ?self.path,
+        );
+        counter!(
+            "vm_import_dead_lettered_batches_total", 1,
+            "status" => status,
+        );
+    }
+}
+
+/// The size of a request body before and after compression, for monitoring the
+/// compression ratio. Not tagged by endpoint, which may be templated into an
+/// unbounded number of series.
+#[derive(Debug)]
+pub struct VMImportRequestBytes {
+    pub uncompressed: usize,
+    pub compressed: usize,
+}
+
+impl InternalEvent for VMImportRequestBytes {
+    fn emit(self) {
+        trace!(
+            message = "Built request.",
+            uncompressed = %self.uncompressed,
+            compressed = %self.compressed,
+        );
+        counter!(
+            "vm_import_uncompressed_bytes_total",
+            self.uncompressed as u64
+        );
+        counter!("vm_import_compressed_bytes_total", self.compressed as u64);
+    }
+}
+
+/// An event routed to `default_endpoint`, as it lacks a field the endpoint
+/// template refers to.
+#[derive(Debug)]
+pub struct VMImportDefaultEndpoint<'a> {
+    pub missing_keys: &'a [String],
+}
+
+impl<'a> InternalEvent for VMImportDefaultEndpoint<'a> {
+    fn emit(self) {
+        debug!(
+            message = "Routed event to the default endpoint.",
+            missing_keys = ?self.missing_keys,
+            internal_log_rate_secs = 10,
+        );
+        counter!("vm_import_default_endpoint_events_total", 1);
+    }
+}
+
+/// The adaptive batch limit of an endpoint changed. Only logged, as endpoints
+/// may be templated into an unbounded number of series.
+#[derive(Debug)]
+pub struct VMImportBatchLimit<'a> {
+    pub endpoint: &'a str,
+    pub limit: usize,
+}
+
+impl<'a> InternalEvent for VMImportBatchLimit<'a> {
+    fn emit(self) {
+        debug!(
+            message = "Adapted batch limit.",
+            endpoint = %self.endpoint,
+            limit = %self.limit,
+        );
+    }
+}
+
+/// The concurrency ramp raised or lowered the number of requests in flight.
+#[derive(Debug)]
+pub struct VMImportConcurrencyLimit {
+    pub limit: usize,
+}
+
+impl InternalEvent for VMImportConcurrencyLimit {
+    fn emit(self) {
+        debug!(message = "Adapted concurrency limit.", limit = %self.limit);
+    }
+}
+
+/// The `auto` gzip level was raised or lowered.
+#[derive(Debug)]
+pub struct VMImportCompressionLevel {
+    pub level: u32,
+}
+
+impl InternalEvent for VMImportCompressionLevel {
+    fn emit(self) {
+        debug!(message = "Adapted compression level.", level = %self.level);
+    }
+}
@@ -1,12 +1,26 @@
 #[macro_use]
 extern crate tracing;
 
+mod adaptive_batch;
+mod auth;
+mod compression;
+mod concurrency_ramp;
 mod config;
+mod dead_letter;
 mod encoder;
+mod flush;
+mod internal_events;
+#[cfg(test)]
+mod mock_vm;
+mod otlp;
 mod partition;
+mod series_limit;
 mod sink;
 
 pub use config::VMImportConfig;
+// Exposes the body encoding to the benchmarks under `benches`.
+#[cfg(feature = "bench")]
+pub use sink::bench;
 
 // #[cfg(test)]
 // mod tests {
@@ -0,0 +1,81 @@
+use std::future::Future;
+
+use http::{Request, Response};
+use hyper::service::{make_service_fn, service_fn};
+use serde_json::value::to_raw_value;
+use vector::http::HttpClient;
+use vector::sinks::util::{BoxedRawValue, PartitionInnerBuffer};
+use vector::tls::TlsSettings;
+use vector_core::config::proxy::ProxyConfig;
+
+use crate::compression::Gzip;
+use crate::encoder::EncoderSettings;
+use crate::partition::PartitionKey;
+use crate::sink::{VMImportBatch, VMImportSink};
+
+/// Serves `/api/v1/import` on a local port, answering every request with
+/// `respond`, and returns the endpoint.
+pub fn serve<F, Fut>(respond: F) -> String
+where
+    F: Fn(Request<hyper::Body>) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Response<hyper::Body>> + Send + 'static,
+{
+    let server =
+        hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(move |_| {
+            let respond = respond.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |request| {
+                    let response = respond(request);
+                    async move { Ok::<_, hyper::Error>(response.await) }
+                }))
+            }
+        }));
+    let endpoint = format!("http://{}/api/v1/import", server.local_addr());
+    tokio::spawn(server);
+    endpoint
+}
+
+/// Answers with an empty body and `status`.
+pub fn status(status: u16) -> Response<hyper::Body> {
+    Response::builder()
+        .status(status)
+        .body(hyper::Body::empty())
+        .unwrap()
+}
+
+pub fn client() -> HttpClient {
+    HttpClient::new(
+        TlsSettings::from_options(&None).unwrap(),
+        &ProxyConfig::default(),
+    )
+    .unwrap()
+}
+
+/// A sink with the default settings sending to `endpoint`.
+pub fn sink(endpoint: &str) -> VMImportSink {
+    VMImportSink::new(
+        endpoint.try_into().unwrap(),
+        EncoderSettings::default(),
+        None,
+        Gzip::default(),
+        None,
+    )
+}
+
+/// A series with a single sample.
+pub fn series(name: &str) -> BoxedRawValue {
+    to_raw_value(&serde_json::json!({
+        "metric": { "__name__": name },
+        "timestamps": [1661396787000u64],
+        "values": [1.0],
+    }))
+    .unwrap()
+}
+
+/// A batch of `events` sent to `endpoint`.
+pub fn batch(endpoint: &str, events: Vec<BoxedRawValue>) -> VMImportBatch {
+    VMImportBatch::new(PartitionInnerBuffer::new(
+        events,
+        PartitionKey::new(endpoint.to_owned(), "agent".to_owned()),
+    ))
+}
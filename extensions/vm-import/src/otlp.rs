@@ -0,0 +1,254 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use self::proto::{
+    any_value, metric, number_data_point, AggregationTemporality, AnyValue,
+    ExportMetricsServiceRequest, Gauge, InstrumentationScope, KeyValue, Metric, NumberDataPoint,
+    Resource, ResourceMetrics, ScopeMetrics, Sum,
+};
+
+pub mod proto {
+    include!(concat!(
+        env!("OUT_DIR"),
+        "/opentelemetry.proto.collector.metrics.v1.rs"
+    ));
+}
+
+pub const OTLP_CONTENT_TYPE: &str = "application/x-protobuf";
+
+/// How batches are sent.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// Newline delimited JSON for VictoriaMetrics' `/api/v1/import`.
+    VmImport,
+    /// An OTLP `ExportMetricsServiceRequest` in protobuf, for OTLP/HTTP
+    /// endpoints such as `/v1/metrics` of an OpenTelemetry collector.
+    OtlpHttp,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Self::VmImport
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OtlpConfig {
+    /// The metric names sent as monotonic delta sums, each sample counting
+    /// what happened since the previous sample of its series, e.g. the
+    /// per-second execution counts and CPU time of the topsql source. Every
+    /// other metric is sent as a gauge.
+    #[serde(default)]
+    pub sum_metrics: Vec<String>,
+}
+
+/// Translates the series of a batch, as encoded for VictoriaMetrics, into OTLP
+/// metrics: `__name__` becomes the metric name and the other labels the
+/// attributes of its data points.
+#[derive(Clone, Debug, Default)]
+pub struct OtlpEncoder {
+    sum_metrics: Arc<HashSet<String>>,
+}
+
+#[derive(Deserialize)]
+struct Series {
+    metric: BTreeMap<String, String>,
+    timestamps: Vec<i64>,
+    values: Vec<f64>,
+}
+
+impl OtlpEncoder {
+    pub fn new(config: &OtlpConfig) -> Self {
+        Self {
+            sum_metrics: Arc::new(config.sum_metrics.iter().cloned().collect()),
+        }
+    }
+
+    pub fn request(&self) -> OtlpRequestBuilder<'_> {
+        OtlpRequestBuilder {
+            sum_metrics: &self.sum_metrics,
+            metrics: BTreeMap::new(),
+        }
+    }
+}
+
+/// Collects the data points of a request by metric name.
+pub struct OtlpRequestBuilder<'a> {
+    sum_metrics: &'a HashSet<String>,
+    metrics: BTreeMap<String, Vec<NumberDataPoint>>,
+}
+
+impl<'a> OtlpRequestBuilder<'a> {
+    /// Adds a series in the JSON line format of `/api/v1/import`.
+    pub fn push(&mut self, series: &str) -> serde_json::Result<()> {
+        let Series {
+            mut metric,
+            timestamps,
+            values,
+        } = serde_json::from_str(series)?;
+        let name = metric.remove("__name__").unwrap_or_default();
+        let attributes = metric
+            .into_iter()
+            .map(|(key, value)| KeyValue {
+                key,
+                value: Some(AnyValue {
+                    value: Some(any_value::Value::StringValue(value)),
+                }),
+            })
+            .collect::<Vec<_>>();
+
+        let is_sum = self.sum_metrics.contains(&name);
+        let points = self.metrics.entry(name).or_default();
+        // a delta starts where the previous one of the series ended, the first
+        // one's start is unknown
+        let mut start_time_unix_nano = 0;
+        for (timestamp, value) in timestamps.into_iter().zip(values) {
+            let time_unix_nano = timestamp.max(0) as u64 * 1_000_000;
+            points.push(NumberDataPoint {
+                attributes: attributes.clone(),
+                start_time_unix_nano: if is_sum { start_time_unix_nano } else { 0 },
+                time_unix_nano,
+                value: Some(number_data_point::Value::AsDouble(value)),
+            });
+            start_time_unix_nano = time_unix_nano;
+        }
+        Ok(())
+    }
+
+    pub fn build(self) -> ExportMetricsServiceRequest {
+        let metrics = self
+            .metrics
+            .into_iter()
+            .map(|(name, data_points)| {
+                let data = if self.sum_metrics.contains(&name) {
+                    metric::Data::Sum(Sum {
+                        data_points,
+                        aggregation_temporality: AggregationTemporality::Delta as i32,
+                        is_monotonic: true,
+                    })
+                } else {
+                    metric::Data::Gauge(Gauge { data_points })
+                };
+                Metric {
+                    name,
+                    data: Some(data),
+                }
+            })
+            .collect();
+
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(Resource { attributes: vec![] }),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: Some(InstrumentationScope {
+                        name: "vector-vm-import".to_owned(),
+                        version: vector::get_version(),
+                    }),
+                    metrics,
+                }],
+            }],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::*;
+
+    #[test]
+    fn otlp_structure() {
+        let encoder = OtlpEncoder::new(&OtlpConfig {
+            sum_metrics: vec!["topsql_stmt_exec_count".to_owned()],
+        });
+        let mut request = encoder.request();
+        let series = |name: &str, instance: &str| {
+            serde_json::json!({
+                "metric": { "__name__": name, "instance": instance },
+                "timestamps": [1661396787000u64, 1661396788000u64],
+                "values": [1.0, 2.0],
+            })
+            .to_string()
+        };
+        request
+            .push(&series("topsql_cpu_time_ms", "tidb-0"))
+            .unwrap();
+        request
+            .push(&series("topsql_stmt_exec_count", "tidb-0"))
+            .unwrap();
+        request
+            .push(&series("topsql_stmt_exec_count", "tidb-1"))
+            .unwrap();
+        assert!(request.push("{}").is_err());
+
+        // survives the wire
+        let request =
+            ExportMetricsServiceRequest::decode(request.build().encode_to_vec().as_slice())
+                .unwrap();
+        assert_eq!(request.resource_metrics.len(), 1);
+        let scope_metrics = &request.resource_metrics[0].scope_metrics;
+        assert_eq!(scope_metrics.len(), 1);
+        assert_eq!(
+            scope_metrics[0].scope.as_ref().unwrap().name,
+            "vector-vm-import"
+        );
+
+        let metrics = &scope_metrics[0].metrics;
+        assert_eq!(metrics.len(), 2);
+        let attributes = |point: &NumberDataPoint| {
+            point
+                .attributes
+                .iter()
+                .map(|KeyValue { key, value }| match value {
+                    Some(AnyValue {
+                        value: Some(any_value::Value::StringValue(value)),
+                    }) => (key.clone(), value.clone()),
+                    _ => panic!("unexpected attribute value {:?}", value),
+                })
+                .collect::<Vec<_>>()
+        };
+        let instance = |instance: &str| vec![("instance".to_owned(), instance.to_owned())];
+
+        assert_eq!(metrics[0].name, "topsql_cpu_time_ms");
+        let gauge = match &metrics[0].data {
+            Some(metric::Data::Gauge(gauge)) => gauge,
+            data => panic!("expected a gauge, got {:?}", data),
+        };
+        assert_eq!(gauge.data_points.len(), 2);
+        assert_eq!(attributes(&gauge.data_points[0]), instance("tidb-0"));
+        assert_eq!(gauge.data_points[0].start_time_unix_nano, 0);
+        assert_eq!(
+            gauge.data_points[0].time_unix_nano,
+            1_661_396_787_000_000_000
+        );
+        assert_eq!(
+            gauge.data_points[1].value,
+            Some(number_data_point::Value::AsDouble(2.0))
+        );
+
+        assert_eq!(metrics[1].name, "topsql_stmt_exec_count");
+        let sum = match &metrics[1].data {
+            Some(metric::Data::Sum(sum)) => sum,
+            data => panic!("expected a sum, got {:?}", data),
+        };
+        assert_eq!(
+            sum.aggregation_temporality,
+            AggregationTemporality::Delta as i32
+        );
+        assert!(sum.is_monotonic);
+        assert_eq!(sum.data_points.len(), 4);
+        assert_eq!(attributes(&sum.data_points[0]), instance("tidb-0"));
+        assert_eq!(attributes(&sum.data_points[2]), instance("tidb-1"));
+        assert_eq!(sum.data_points[2].start_time_unix_nano, 0);
+        assert_eq!(
+            sum.data_points[3].start_time_unix_nano,
+            sum.data_points[2].time_unix_nano
+        );
+        assert_eq!(sum.data_points[3].time_unix_nano, 1_661_396_788_000_000_000);
+    }
+}
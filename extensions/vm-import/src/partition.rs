@@ -1,10 +1,47 @@
+use std::collections::BTreeMap;
+
 #[derive(Hash, Eq, PartialEq, Clone)]
 pub struct PartitionKey {
     pub endpoint: String,
+    pub user_agent: String,
+    /// Labels VictoriaMetrics adds to every series of the request, sent as
+    /// `extra_label` query parameters.
+    pub extra_labels: BTreeMap<String, String>,
 }
 
 impl PartitionKey {
-    pub fn new(endpoint: String) -> Self {
-        Self { endpoint }
+    pub fn new(endpoint: String, user_agent: String) -> Self {
+        Self {
+            endpoint,
+            user_agent,
+            extra_labels: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_extra_labels(mut self, extra_labels: BTreeMap<String, String>) -> Self {
+        self.extra_labels = extra_labels;
+        self
     }
+
+    /// The endpoint with an `extra_label=<name>=<value>` query parameter per
+    /// extra label appended.
+    pub fn uri(&self) -> String {
+        if self.extra_labels.is_empty() {
+            return self.endpoint.clone();
+        }
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        for (name, value) in &self.extra_labels {
+            query.append_pair("extra_label", &format!("{}={}", name, value));
+        }
+        let separator = if self.endpoint.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        format!("{}{}{}", self.endpoint, separator, query.finish())
+    }
+}
+
+pub fn default_user_agent() -> String {
+    format!("vector-vm-import/{}", vector::get_version())
 }
@@ -0,0 +1,256 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+
+use vector::sinks::util::{Batch, PartitionInnerBuffer, PushResult};
+
+use crate::adaptive_batch::AdaptiveBuffer;
+use crate::partition::PartitionKey;
+use crate::sink::VMImportBatch;
+
+type Input = PartitionInnerBuffer<serde_json::Value, PartitionKey>;
+
+/// An `AdaptiveBuffer` also full once it holds `max_series` distinct series,
+/// told apart by their `metric`, for stores capping the series per request.
+/// Events carrying more series than that are to be split by `split_series`
+/// beforehand, as a batch can't split them, and would make a batch of their
+/// own otherwise. Without a limit it behaves as the plain `AdaptiveBuffer`.
+/// Its batches are handed out as `VMImportBatch`es.
+pub struct SeriesLimitBuffer {
+    inner: AdaptiveBuffer,
+    max_series: Option<usize>,
+    series: HashSet<u64>,
+}
+
+impl SeriesLimitBuffer {
+    pub fn new(inner: AdaptiveBuffer, max_series: Option<usize>) -> Self {
+        Self {
+            inner,
+            max_series,
+            series: HashSet::new(),
+        }
+    }
+}
+
+impl Batch for SeriesLimitBuffer {
+    type Input = Input;
+    type Output = VMImportBatch;
+
+    fn push(&mut self, item: Self::Input) -> PushResult<Self::Input> {
+        let max_series = match self.max_series {
+            Some(max_series) => max_series,
+            None => return self.inner.push(item),
+        };
+        let (event, key) = item.into_parts();
+        let new_series = series_hashes(&event)
+            .into_iter()
+            .filter(|hash| !self.series.contains(hash))
+            .collect::<HashSet<_>>();
+        let item = PartitionInnerBuffer::new(event, key);
+        if !self.inner.is_empty() && self.series.len() + new_series.len() > max_series {
+            return PushResult::Overflow(item);
+        }
+        match self.inner.push(item) {
+            PushResult::Ok(full) => {
+                self.series.extend(new_series);
+                PushResult::Ok(full || self.series.len() >= max_series)
+            }
+            overflow => overflow,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    fn fresh(&self) -> Self {
+        Self::new(self.inner.fresh(), self.max_series)
+    }
+
+    fn finish(self) -> Self::Output {
+        VMImportBatch::new(self.inner.finish())
+    }
+
+    fn num_items(&self) -> usize {
+        self.inner.num_items()
+    }
+}
+
+/// Splits an event holding more than `max_series` distinct series into events
+/// holding at most that many each, in order. Other events are left whole.
+pub fn split_series(item: Input, max_series: Option<usize>) -> Vec<Input> {
+    let (event, key) = item.into_parts();
+    let (series, max_series) = match (event, max_series) {
+        (serde_json::Value::Array(series), Some(max_series))
+            if series.iter().map(series_hash).collect::<HashSet<_>>().len() > max_series =>
+        {
+            (series, max_series)
+        }
+        (event, _) => return vec![PartitionInnerBuffer::new(event, key)],
+    };
+
+    let mut parts = vec![];
+    let mut part = vec![];
+    let mut hashes = HashSet::new();
+    for series in series {
+        let hash = series_hash(&series);
+        if !hashes.contains(&hash) && hashes.len() == max_series {
+            parts.push(std::mem::take(&mut part));
+            hashes.clear();
+        }
+        hashes.insert(hash);
+        part.push(series);
+    }
+    parts.push(part);
+    parts
+        .into_iter()
+        .map(|part| PartitionInnerBuffer::new(serde_json::Value::Array(part), key.clone()))
+        .collect()
+}
+
+// the hashes of the `metric` of every series of `event`, which is either a
+// series or an array of them
+fn series_hashes(event: &serde_json::Value) -> Vec<u64> {
+    match event {
+        serde_json::Value::Array(series) => series.iter().map(series_hash).collect(),
+        series => vec![series_hash(series)],
+    }
+}
+
+fn series_hash(series: &serde_json::Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    series
+        .get("metric")
+        .map(ToString::to_string)
+        .hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use vector::sinks::util::{BatchConfig, JsonArrayBuffer, PartitionBuffer};
+
+    use super::*;
+    use crate::config::VMImportDefaultBatchSettings;
+
+    fn buffer(max_series: Option<usize>) -> SeriesLimitBuffer {
+        // up to 1000 events
+        let size = BatchConfig::<VMImportDefaultBatchSettings>::default()
+            .into_batch_settings::<JsonArrayBuffer>()
+            .unwrap()
+            .size;
+        SeriesLimitBuffer::new(
+            AdaptiveBuffer::new(PartitionBuffer::new(JsonArrayBuffer::new(size)), None),
+            max_series,
+        )
+    }
+
+    fn item(names: &[&str]) -> PartitionInnerBuffer<serde_json::Value, PartitionKey> {
+        let series = names
+            .iter()
+            .map(|name| {
+                serde_json::json!({
+                    "metric": { "__name__": name },
+                    "timestamps": [1661396787000u64],
+                    "values": [1.0],
+                })
+            })
+            .collect::<Vec<_>>();
+        PartitionInnerBuffer::new(
+            serde_json::Value::Array(series),
+            PartitionKey::new("http://localhost:8428".to_owned(), "agent".to_owned()),
+        )
+    }
+
+    #[test]
+    fn split_by_distinct_series() {
+        // batches events the way the sink does, returning the number of
+        // series pushed into every batch
+        let batches = |mut buffer: SeriesLimitBuffer, items: Vec<_>| {
+            let mut batches = vec![];
+            let mut series = 0;
+            for mut item in items {
+                loop {
+                    let (event, key) = item.into_parts();
+                    let pushed = series_hashes(&event).len();
+                    match buffer.push(PartitionInnerBuffer::new(event, key)) {
+                        PushResult::Ok(full) => {
+                            series += pushed;
+                            if full {
+                                batches.push(series);
+                                series = 0;
+                                buffer = buffer.fresh();
+                            }
+                            break;
+                        }
+                        PushResult::Overflow(overflow) => {
+                            batches.push(series);
+                            series = 0;
+                            buffer = buffer.fresh();
+                            item = overflow;
+                        }
+                    }
+                }
+            }
+            if !buffer.is_empty() {
+                batches.push(series);
+            }
+            batches
+        };
+        let names = (0..25).map(|i| format!("series_{}", i)).collect::<Vec<_>>();
+        let items = || {
+            names
+                .chunks(2)
+                .map(|names| item(&names.iter().map(String::as_str).collect::<Vec<_>>()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(batches(buffer(Some(10)), items()), vec![10, 10, 5]);
+        // events holding 2 series each don't fit batches of 9 exactly
+        assert_eq!(batches(buffer(Some(9)), items()), vec![8, 8, 8, 1]);
+        assert_eq!(batches(buffer(None), items()), vec![25]);
+
+        // points of the same series count once
+        let repeated = (0..25).map(|_| item(&["up"])).collect();
+        assert_eq!(batches(buffer(Some(10)), repeated), vec![25]);
+
+        // an event over the limit still makes a batch of its own
+        let mut buffer = buffer(Some(1));
+        assert!(matches!(
+            buffer.push(item(&["a", "b"])),
+            PushResult::Ok(true)
+        ));
+    }
+
+    #[test]
+    fn split_events_over_the_limit() {
+        // the names of the series of every part
+        let split = |names: &[&str], max_series| {
+            split_series(item(names), max_series)
+                .into_iter()
+                .map(|part| {
+                    let (event, _) = part.into_parts();
+                    event
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|series| series["metric"]["__name__"].as_str().unwrap().to_owned())
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // points of the same series stay together up to the limit
+        assert_eq!(
+            split(&["a", "b", "c", "a", "d"], Some(2)),
+            vec![vec!["a", "b"], vec!["c", "a"], vec!["d"]]
+        );
+        assert_eq!(split(&["a", "b", "a"], Some(2)), vec![vec!["a", "b", "a"]]);
+        assert_eq!(split(&["a", "b", "c"], None), vec![vec!["a", "b", "c"]]);
+
+        // every part fits a batch
+        for item in split_series(item(&["a", "b", "c"]), Some(1)) {
+            assert!(matches!(buffer(Some(1)).push(item), PushResult::Ok(true)));
+        }
+    }
+}
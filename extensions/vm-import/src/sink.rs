@@ -1,24 +1,60 @@
 use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
+use bytes::buf::Writer;
 use bytes::{BufMut, Bytes, BytesMut};
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use http::{Request, Uri};
+use futures_util::future::BoxFuture;
+use http::{Request, Response, Uri};
+use prost::Message;
+use tower::Service;
+use vector::emit;
+use vector::http::HttpClient;
 use vector::sinks::util::http::HttpSink;
 use vector::sinks::util::{BoxedRawValue, PartitionInnerBuffer};
 use vector::template::Template;
 
-use crate::encoder::VMImportSinkEventEncoder;
+use crate::adaptive_batch::AdaptiveBatchLimits;
+use crate::auth::Auth;
+use crate::compression::Gzip;
+use crate::concurrency_ramp::ConcurrencyRamp;
+use crate::encoder::{EncoderSettings, VMImportSinkEventEncoder};
+use crate::internal_events::VMImportRequestBytes;
+use crate::otlp::{OtlpEncoder, OtlpRequestBuilder, OTLP_CONTENT_TYPE};
 use crate::partition::PartitionKey;
 
+type Events = PartitionInnerBuffer<Vec<BoxedRawValue>, PartitionKey>;
+
+const TRANSFER_CHUNK_SIZE: usize = 64 * 1024;
+
 #[derive(Clone)]
 pub struct VMImportSink {
     endpoint_template: Template,
+    encoder_settings: EncoderSettings,
+    auth: Option<Auth>,
+    gzip: Gzip,
+    // sends OTLP rather than the JSON lines of `/api/v1/import` if set
+    otlp: Option<OtlpEncoder>,
 }
 
 impl VMImportSink {
-    pub const fn new(endpoint_template: Template) -> Self {
-        Self { endpoint_template }
+    pub const fn new(
+        endpoint_template: Template,
+        encoder_settings: EncoderSettings,
+        auth: Option<Auth>,
+        gzip: Gzip,
+        otlp: Option<OtlpEncoder>,
+    ) -> Self {
+        Self {
+            endpoint_template,
+            encoder_settings,
+            auth,
+            gzip,
+            otlp,
+        }
     }
 }
 
@@ -29,26 +65,808 @@ impl HttpSink for VMImportSink {
     type Encoder = VMImportSinkEventEncoder;
 
     fn build_encoder(&self) -> Self::Encoder {
-        VMImportSinkEventEncoder::new(self.endpoint_template.clone())
+        VMImportSinkEventEncoder::new(
+            self.endpoint_template.clone(),
+            self.encoder_settings.clone(),
+        )
     }
 
     async fn build_request(&self, output: Self::Output) -> vector::Result<Request<Bytes>> {
         let (events, key) = output.into_parts();
+        let body = self.compress(&events)?;
+        self.request(&key, body)
+    }
+}
 
-        let uri = key.endpoint.parse::<Uri>()?;
+impl VMImportSink {
+    /// Encodes and gzips `events` into a request body, at the level picked
+    /// for this body.
+    pub fn compress(&self, events: &[BoxedRawValue]) -> vector::Result<Bytes> {
+        self.compress_at(events, self.gzip.level())
+    }
 
-        let buffer = BytesMut::new();
-        let mut w = GzEncoder::new(buffer.writer(), Compression::default());
+    /// Like `compress`, at `level`, e.g. the one picked for the first attempt
+    /// of a batch, so that every attempt sends the very same bytes.
+    pub fn compress_at(
+        &self,
+        events: &[BoxedRawValue],
+        level: Compression,
+    ) -> vector::Result<Bytes> {
+        let mut encoder = BodyEncoder::new(self, level);
+        for event in events {
+            encoder.write(event.get())?;
+        }
+        encoder.finish()
+    }
 
+    /// Like `compress_at`, but hands the body to `sender` a chunk at a time
+    /// as it's compressed rather than buffering it whole. Compressing stops
+    /// once the request stops reading the body, e.g. once answered, and the
+    /// request is aborted on errors, so a truncated body is never taken as
+    /// whole.
+    pub async fn compress_into(
+        &self,
+        events: &[BoxedRawValue],
+        level: Compression,
+        mut sender: hyper::body::Sender,
+    ) -> vector::Result<()> {
+        let mut encoder = BodyEncoder::new(self, level);
         for event in events {
-            w.write_all(event.get().as_bytes())?;
-            w.write_all(b"\n")?;
+            if let Err(error) = encoder.write(event.get()) {
+                sender.abort();
+                return Err(error);
+            }
+            if let Some(chunk) = encoder.take_chunk() {
+                if sender.send_data(chunk).await.is_err() {
+                    // the request is done with the body, e.g. answered or failed
+                    return Ok(());
+                }
+            }
+        }
+        match encoder.finish() {
+            Ok(rest) => {
+                for chunk in chunks(rest) {
+                    if sender.send_data(chunk).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            Err(error) => {
+                sender.abort();
+                Err(error)
+            }
         }
-        let body = w.finish()?.into_inner().freeze();
+    }
+
+    /// Whether bodies can be sent before they're compressed whole, i.e. unless
+    /// requests are signed over them.
+    pub fn can_stream(&self) -> bool {
+        !self.auth.as_ref().map_or(false, Auth::signs_body)
+    }
 
-        let builder = Request::post(uri).header("Content-Encoding", "gzip");
-        let request = builder.body(body).unwrap();
+    /// Builds a request sending `body` to the partition `key`. Called on every
+    /// attempt, so that it's signed afresh.
+    pub fn request(&self, key: &PartitionKey, body: Bytes) -> vector::Result<Request<Bytes>> {
+        let uri = key.uri().parse::<Uri>()?;
+        let mut builder = Request::post(uri)
+            .header("Content-Encoding", "gzip")
+            .header("User-Agent", key.user_agent.as_str());
+        if self.otlp.is_some() {
+            builder = builder.header("Content-Type", OTLP_CONTENT_TYPE);
+        }
+        let mut request = builder.body(body).unwrap();
+        if let Some(auth) = &self.auth {
+            auth.apply(&mut request);
+        }
 
         Ok(request)
     }
 }
+
+/// Gzips the series of a batch as they're written, at the level of the body.
+/// OTLP requests are written once all series are in.
+struct BodyEncoder<'a> {
+    gzip: &'a Gzip,
+    w: GzEncoder<Writer<BytesMut>>,
+    otlp: Option<OtlpRequestBuilder<'a>>,
+    uncompressed: usize,
+    // the output taken so far
+    compressed: usize,
+    // the time spent compressing, leaving out waiting for the body to be read
+    busy: Duration,
+}
+
+impl<'a> BodyEncoder<'a> {
+    fn new(sink: &'a VMImportSink, level: Compression) -> Self {
+        Self {
+            gzip: &sink.gzip,
+            w: GzEncoder::new(BytesMut::new().writer(), level),
+            otlp: sink.otlp.as_ref().map(OtlpEncoder::request),
+            uncompressed: 0,
+            compressed: 0,
+            busy: Duration::ZERO,
+        }
+    }
+
+    /// Writes the series of `event`. Events carrying multiple series are
+    /// encoded as an array.
+    fn write(&mut self, event: &str) -> vector::Result<()> {
+        let start = Instant::now();
+        if event.starts_with('[') {
+            for series in serde_json::from_str::<Vec<BoxedRawValue>>(event)? {
+                self.write_series(series.get())?;
+            }
+        } else {
+            self.write_series(event)?;
+        }
+        self.busy += start.elapsed();
+        Ok(())
+    }
+
+    fn write_series(&mut self, series: &str) -> vector::Result<()> {
+        match &mut self.otlp {
+            None => {
+                self.w.write_all(series.as_bytes())?;
+                self.w.write_all(b"\n")?;
+                self.uncompressed += series.len() + 1;
+            }
+            Some(request) => request.push(series)?,
+        }
+        Ok(())
+    }
+
+    /// The output compressed since the last call, once there's a chunk of it.
+    fn take_chunk(&mut self) -> Option<Bytes> {
+        let output = self.w.get_mut().get_mut();
+        if output.len() < TRANSFER_CHUNK_SIZE {
+            return None;
+        }
+        let chunk = output.split().freeze();
+        self.compressed += chunk.len();
+        Some(chunk)
+    }
+
+    /// Finishes compressing, returning the output not taken yet.
+    fn finish(mut self) -> vector::Result<Bytes> {
+        let start = Instant::now();
+        if let Some(request) = self.otlp.take() {
+            let request = request.build().encode_to_vec();
+            self.w.write_all(&request)?;
+            self.uncompressed = request.len();
+        }
+        let rest = self.w.finish()?.into_inner().freeze();
+        self.gzip.observe(self.busy + start.elapsed());
+        emit!(VMImportRequestBytes {
+            uncompressed: self.uncompressed,
+            compressed: self.compressed + rest.len(),
+        });
+        Ok(rest)
+    }
+}
+
+/// A batch of events sent by `VMImportService`. Retries send clones of it,
+/// which share the same events: every attempt compresses them again, at the
+/// level picked by the first one, so that retries send the very same bytes
+/// rather than compressing at whatever level is current, without keeping the
+/// compressed body around between attempts.
+#[derive(Clone)]
+pub struct VMImportBatch {
+    key: PartitionKey,
+    body: Arc<Mutex<BatchBody>>,
+}
+
+enum BatchBody {
+    // not attempted yet
+    Events(Arc<Vec<BoxedRawValue>>),
+    // attempted at the level, and kept for the other attempts
+    Attempted(Arc<Vec<BoxedRawValue>>, Compression),
+    // attempted without keeping the events
+    Dropped,
+}
+
+impl VMImportBatch {
+    pub fn new(events: Events) -> Self {
+        let (events, key) = events.into_parts();
+        Self {
+            key,
+            body: Arc::new(Mutex::new(BatchBody::Events(Arc::new(events)))),
+        }
+    }
+
+    /// The endpoint the batch is sent to, with its extra labels.
+    pub fn endpoint(&self) -> String {
+        self.key.uri()
+    }
+
+    /// The body of the batch as its attempts sent it, compressed again by
+    /// `sink`, if it was attempted and kept.
+    pub fn compressed_body(&self, sink: &VMImportSink) -> Option<Bytes> {
+        let (events, level) = match &*self.body.lock().unwrap() {
+            BatchBody::Attempted(events, level) => (Arc::clone(events), *level),
+            BatchBody::Events(_) | BatchBody::Dropped => return None,
+        };
+        sink.compress_at(&events, level).ok()
+    }
+
+    /// The events of an attempt with the level to compress them at, picked by
+    /// `sink` on the first attempt. They're kept for the other attempts only
+    /// if `keep`.
+    fn attempt(
+        &self,
+        sink: &VMImportSink,
+        keep: bool,
+    ) -> vector::Result<(Arc<Vec<BoxedRawValue>>, Compression)> {
+        let mut body = self.body.lock().unwrap();
+        match std::mem::replace(&mut *body, BatchBody::Dropped) {
+            BatchBody::Events(events) => {
+                let level = sink.gzip.level();
+                if keep {
+                    *body = BatchBody::Attempted(Arc::clone(&events), level);
+                }
+                Ok((events, level))
+            }
+            BatchBody::Attempted(events, level) => {
+                *body = BatchBody::Attempted(Arc::clone(&events), level);
+                Ok((events, level))
+            }
+            BatchBody::Dropped => {
+                Err("The events of the batch were sent without keeping them.".into())
+            }
+        }
+    }
+}
+
+/// Sends the batches of `VMImportSink`, like `HttpBatchService` does, while
+/// keeping the events of every batch along with it for retries and the dead
+/// letter if `keep_bodies`, as neither can do without them.
+///
+/// With `chunked_transfer`, every attempt sends its body as it's compressed
+/// rather than once compressed whole, unless it's signed.
+#[derive(Clone)]
+pub struct VMImportService {
+    client: HttpClient,
+    sink: VMImportSink,
+    chunked_transfer: bool,
+    keep_bodies: bool,
+    batch_limits: Option<AdaptiveBatchLimits>,
+    concurrency_ramp: Option<ConcurrencyRamp>,
+}
+
+impl VMImportService {
+    pub const fn new(
+        client: HttpClient,
+        sink: VMImportSink,
+        chunked_transfer: bool,
+        keep_bodies: bool,
+        batch_limits: Option<AdaptiveBatchLimits>,
+        concurrency_ramp: Option<ConcurrencyRamp>,
+    ) -> Self {
+        Self {
+            client,
+            sink,
+            chunked_transfer,
+            keep_bodies,
+            batch_limits,
+            concurrency_ramp,
+        }
+    }
+}
+
+/// Reports the latency of a batch to the adaptive batch limits, or a failure
+/// if dropped before, e.g. when the request timed out.
+struct LatencyObserver {
+    limits: AdaptiveBatchLimits,
+    key: PartitionKey,
+    start: tokio::time::Instant,
+    observed: bool,
+}
+
+impl LatencyObserver {
+    fn observe(mut self, success: bool) {
+        let latency = success.then(|| self.start.elapsed());
+        self.limits.observe(&self.key, latency);
+        self.observed = true;
+    }
+}
+
+impl Drop for LatencyObserver {
+    fn drop(&mut self) {
+        if !self.observed {
+            self.limits.observe(&self.key, None);
+        }
+    }
+}
+
+/// `body` in chunks of `TRANSFER_CHUNK_SIZE`.
+fn chunks(body: Bytes) -> impl Iterator<Item = Bytes> {
+    (0..body.len())
+        .step_by(TRANSFER_CHUNK_SIZE)
+        .map(move |start| {
+            let end = (start + TRANSFER_CHUNK_SIZE).min(body.len());
+            body.slice(start..end)
+        })
+}
+
+/// A body of unknown length, sent with `Transfer-Encoding: chunked` over
+/// HTTP/1.1 instead of being framed by `Content-Length`.
+fn chunked_body(body: Bytes) -> hyper::Body {
+    let chunks = chunks(body)
+        .map(Ok::<_, std::io::Error>)
+        .collect::<Vec<_>>();
+    hyper::Body::wrap_stream(futures_util::stream::iter(chunks))
+}
+
+impl Service<VMImportBatch> for VMImportService {
+    type Response = Response<Bytes>;
+    type Error = vector::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, batch: VMImportBatch) -> Self::Future {
+        let client = self.client.clone();
+        let sink = self.sink.clone();
+        let chunked_transfer = self.chunked_transfer;
+        let keep_bodies = self.keep_bodies;
+        let observer = self.batch_limits.clone().map(|limits| LatencyObserver {
+            limits,
+            key: batch.key.clone(),
+            start: tokio::time::Instant::now(),
+            observed: false,
+        });
+        let concurrency_ramp = self.concurrency_ramp.clone();
+
+        Box::pin(async move {
+            let permit = match &concurrency_ramp {
+                Some(ramp) => Some(ramp.acquire().await),
+                None => None,
+            };
+
+            let (events, level) = batch.attempt(&sink, keep_bodies)?;
+            let response = if chunked_transfer && sink.can_stream() {
+                let (sender, body) = hyper::Body::channel();
+                let request = sink.request(&batch.key, Bytes::new())?.map(|_| body);
+                let (response, compressed) = futures_util::future::join(
+                    client.send(request),
+                    sink.compress_into(&events, level, sender),
+                )
+                .await;
+                compressed?;
+                response?
+            } else {
+                let request = sink.request(&batch.key, sink.compress_at(&events, level)?)?;
+                let request = if chunked_transfer {
+                    request.map(chunked_body)
+                } else {
+                    request.map(hyper::Body::from)
+                };
+                client.send(request).await?
+            };
+            let (parts, response_body) = response.into_parts();
+            let response_body = hyper::body::to_bytes(response_body).await?;
+
+            if let Some(observer) = observer {
+                observer.observe(parts.status.is_success());
+            }
+            if let Some(permit) = permit {
+                permit.release(parts.status.is_success());
+            }
+            Ok(Response::from_parts(parts, response_body))
+        })
+    }
+}
+
+#[cfg(feature = "bench")]
+pub mod bench {
+    pub use crate::compression::Gzip;
+    pub use crate::encoder::EncoderSettings;
+    pub use crate::sink::VMImportSink;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use serde_json::value::to_raw_value;
+
+    use super::*;
+    use crate::mock_vm;
+
+    #[tokio::test]
+    async fn body_is_bare_ndjson() {
+        let endpoint = "http://localhost:8428/api/v1/import";
+        let sink = VMImportSink::new(
+            endpoint.try_into().unwrap(),
+            EncoderSettings::default(),
+            None,
+            Gzip::default(),
+            None,
+        );
+
+        let series = |name: &str| {
+            serde_json::json!({
+                "metric": { "__name__": name },
+                "timestamps": [1661396787000u64],
+                "values": [1.0],
+            })
+        };
+        let events = vec![
+            to_raw_value(&series("a")).unwrap(),
+            to_raw_value(&serde_json::json!([series("b"), series("c")])).unwrap(),
+        ];
+        let output = PartitionInnerBuffer::new(
+            events,
+            PartitionKey::new(endpoint.to_owned(), "agent".to_owned()),
+        );
+
+        let request = sink.build_request(output).await.unwrap();
+        assert_eq!(request.headers()["User-Agent"], "agent");
+        let mut body = String::new();
+        GzDecoder::new(request.body().as_ref())
+            .read_to_string(&mut body)
+            .unwrap();
+
+        let line = |name: &str| {
+            format!(
+                r#"{{"metric":{{"__name__":"{}"}},"timestamps":[1661396787000],"values":[1.0]}}"#,
+                name
+            )
+        };
+        assert_eq!(
+            body,
+            format!("{}\n{}\n{}\n", line("a"), line("b"), line("c"))
+        );
+    }
+
+    #[tokio::test]
+    async fn extra_query_labels() {
+        let sink = VMImportSink::new(
+            "http://localhost:8428/api/v1/import".try_into().unwrap(),
+            EncoderSettings::default(),
+            None,
+            Gzip::default(),
+            None,
+        );
+        let uri = |endpoint: &str, extra_labels: &[(&str, &str)]| {
+            let key = PartitionKey::new(endpoint.to_owned(), "agent".to_owned()).with_extra_labels(
+                extra_labels
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+            );
+            let request = sink.build_request(PartitionInnerBuffer::new(vec![], key));
+            async move { request.await.unwrap().uri().to_string() }
+        };
+
+        assert_eq!(
+            uri("http://localhost:8428/api/v1/import", &[]).await,
+            "http://localhost:8428/api/v1/import"
+        );
+        assert_eq!(
+            uri(
+                "http://localhost:8428/api/v1/import",
+                &[("agent_region", "us-west-2"), ("team", "a&b c=d")]
+            )
+            .await,
+            "http://localhost:8428/api/v1/import\
+             ?extra_label=agent_region%3Dus-west-2&extra_label=team%3Da%26b+c%3Dd"
+        );
+        // appended to the query of the endpoint
+        assert_eq!(
+            uri(
+                "http://localhost:8428/api/v1/import?db=1",
+                &[("agent_region", "us-west-2")]
+            )
+            .await,
+            "http://localhost:8428/api/v1/import?db=1&extra_label=agent_region%3Dus-west-2"
+        );
+    }
+
+    #[tokio::test]
+    async fn otlp_body() {
+        let endpoint = "http://localhost:4318/v1/metrics";
+        let sink = VMImportSink::new(
+            endpoint.try_into().unwrap(),
+            EncoderSettings::default(),
+            None,
+            Gzip::default(),
+            Some(OtlpEncoder::default()),
+        );
+        let series = serde_json::json!([
+            { "metric": { "__name__": "a" }, "timestamps": [1661396787000u64], "values": [1.0] },
+            { "metric": { "__name__": "b" }, "timestamps": [1661396787000u64], "values": [2.0] },
+        ]);
+        let output = PartitionInnerBuffer::new(
+            vec![to_raw_value(&series).unwrap()],
+            PartitionKey::new(endpoint.to_owned(), "agent".to_owned()),
+        );
+
+        let request = sink.build_request(output).await.unwrap();
+        assert_eq!(request.headers()["Content-Type"], OTLP_CONTENT_TYPE);
+        assert_eq!(request.headers()["Content-Encoding"], "gzip");
+        let mut body = vec![];
+        GzDecoder::new(request.body().as_ref())
+            .read_to_end(&mut body)
+            .unwrap();
+        let request =
+            crate::otlp::proto::ExportMetricsServiceRequest::decode(body.as_slice()).unwrap();
+        let names = request.resource_metrics[0].scope_metrics[0]
+            .metrics
+            .iter()
+            .map(|metric| metric.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn retries_resend_the_same_body() {
+        use std::sync::Mutex;
+        use std::time::Duration;
+
+        use crate::compression::AutoGzipLevel;
+
+        let bodies = Arc::new(Mutex::new(vec![]));
+        let seen = Arc::clone(&bodies);
+        let endpoint = mock_vm::serve(move |request: Request<hyper::Body>| {
+            let seen = Arc::clone(&seen);
+            async move {
+                let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                seen.lock().unwrap().push(body);
+                mock_vm::status(503)
+            }
+        });
+
+        let gzip = Gzip::Auto(AutoGzipLevel::new(0.1));
+        let sink = VMImportSink::new(
+            endpoint.as_str().try_into().unwrap(),
+            EncoderSettings::default(),
+            None,
+            gzip.clone(),
+            None,
+        );
+        let mut service = VMImportService::new(mock_vm::client(), sink, false, true, None, None);
+
+        let series = (0..1000)
+            .map(|i| mock_vm::series(&format!("series_{}", i)))
+            .collect();
+        let batch = mock_vm::batch(&endpoint, series);
+        service.call(batch.clone()).await.unwrap();
+
+        // the level drops between the attempts
+        let level = gzip.level();
+        gzip.observe(Duration::from_secs(1));
+        gzip.observe(Duration::from_secs(1));
+        assert!(gzip.level().level() < level.level());
+
+        service.call(batch).await.unwrap();
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[0], bodies[1]);
+    }
+
+    #[tokio::test]
+    async fn chunked_transfer() {
+        use std::sync::{Arc, Mutex};
+
+        // (transfer-encoding, content-length, decompressed body) of each request
+        let requests = Arc::new(Mutex::new(vec![]));
+        let seen = Arc::clone(&requests);
+        let endpoint = mock_vm::serve(move |request: Request<hyper::Body>| {
+            let seen = Arc::clone(&seen);
+            async move {
+                let header = |name| {
+                    request
+                        .headers()
+                        .get(name)
+                        .map(|value: &http::HeaderValue| value.to_str().unwrap().to_owned())
+                };
+                let transfer_encoding = header("transfer-encoding");
+                let content_length = header("content-length");
+                let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                let mut decompressed = String::new();
+                GzDecoder::new(body.as_ref())
+                    .read_to_string(&mut decompressed)
+                    .unwrap();
+                seen.lock()
+                    .unwrap()
+                    .push((transfer_encoding, content_length, decompressed));
+                mock_vm::status(200)
+            }
+        });
+        let client = mock_vm::client();
+        let sink = mock_vm::sink(&endpoint);
+
+        // large enough to span several chunks even when compressed
+        let series = (0..20000)
+            .map(|i| {
+                let series = serde_json::json!({
+                    "metric": { "__name__": format!("series_{}", i) },
+                    "timestamps": [1661396787000u64 + i],
+                    "values": [i as f64],
+                });
+                to_raw_value(&series).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let expected = series
+            .iter()
+            .map(|series| format!("{}\n", series.get()))
+            .collect::<String>();
+
+        for chunked in [false, true] {
+            let mut service =
+                VMImportService::new(client.clone(), sink.clone(), chunked, true, None, None);
+            let response = service
+                .call(mock_vm::batch(&endpoint, series.clone()))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 200);
+        }
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let (transfer_encoding, content_length, body) = &requests[0];
+        assert_eq!(*transfer_encoding, None);
+        assert!(content_length.is_some());
+        assert_eq!(*body, expected);
+        let (transfer_encoding, content_length, body) = &requests[1];
+        assert_eq!(transfer_encoding.as_deref(), Some("chunked"));
+        assert_eq!(*content_length, None);
+        assert_eq!(*body, expected);
+    }
+
+    #[tokio::test]
+    async fn stream_every_attempt() {
+        // the body of every request, answering the first with a `503`
+        let bodies = Arc::new(Mutex::new(vec![]));
+        let seen = Arc::clone(&bodies);
+        let endpoint = mock_vm::serve(move |request: Request<hyper::Body>| {
+            let seen = Arc::clone(&seen);
+            async move {
+                let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                let mut seen = seen.lock().unwrap();
+                seen.push(body);
+                mock_vm::status(if seen.len() == 1 { 503 } else { 200 })
+            }
+        });
+        let sink = mock_vm::sink(&endpoint);
+        let series = (0..20000)
+            .map(|i| mock_vm::series(&format!("series_{}", i)))
+            .collect::<Vec<_>>();
+        let expected = series
+            .iter()
+            .map(|series| format!("{}\n", series.get()))
+            .collect::<String>();
+
+        // kept for the retry, which resends the very same bytes
+        let mut service =
+            VMImportService::new(mock_vm::client(), sink.clone(), true, true, None, None);
+        let batch = mock_vm::batch(&endpoint, series.clone());
+        assert_eq!(service.call(batch.clone()).await.unwrap().status(), 503);
+        assert_eq!(service.call(batch.clone()).await.unwrap().status(), 200);
+        {
+            let bodies = bodies.lock().unwrap();
+            assert_eq!(bodies.len(), 2);
+            assert_eq!(bodies[0], bodies[1]);
+            assert_eq!(batch.compressed_body(&sink).as_ref(), Some(&bodies[0]));
+            let mut body = String::new();
+            GzDecoder::new(bodies[0].as_ref())
+                .read_to_string(&mut body)
+                .unwrap();
+            assert_eq!(body, expected);
+        }
+
+        // not kept, so there's nothing left to retry
+        let mut service =
+            VMImportService::new(mock_vm::client(), sink.clone(), true, false, None, None);
+        let batch = mock_vm::batch(&endpoint, series);
+        assert_eq!(service.call(batch.clone()).await.unwrap().status(), 200);
+        assert_eq!(batch.compressed_body(&sink), None);
+        assert!(service.call(batch).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn adaptive_batch_latency() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        // an endpoint answering after `delay` milliseconds
+        let endpoint = |delay: Arc<AtomicU64>| {
+            mock_vm::serve(move |_| {
+                let delay = delay.load(Ordering::Relaxed);
+                async move {
+                    tokio::time::sleep(Duration::from_millis(delay)).await;
+                    mock_vm::status(200)
+                }
+            })
+        };
+        let fast = endpoint(Arc::new(AtomicU64::new(0)));
+        let slow_delay = Arc::new(AtomicU64::new(0));
+        let slow = endpoint(Arc::clone(&slow_delay));
+
+        let limits = AdaptiveBatchLimits::new(10, 1000, Duration::from_millis(100));
+        let mut service = VMImportService::new(
+            mock_vm::client(),
+            mock_vm::sink("http://localhost:8428/api/v1/import"),
+            false,
+            true,
+            Some(limits.clone()),
+            None,
+        );
+
+        let key = |endpoint: &str| PartitionKey::new(endpoint.to_owned(), "agent".to_owned());
+        let mut send =
+            |endpoint: &str| service.call(mock_vm::batch(endpoint, vec![mock_vm::series("up")]));
+
+        for _ in 0..10 {
+            send(&fast).await.unwrap();
+            send(&slow).await.unwrap();
+        }
+        let warmed_up = limits.limit(&key(&slow));
+        assert_eq!(limits.limit(&key(&fast)), warmed_up);
+        assert!(warmed_up > 10);
+
+        // the slow endpoint starts answering past the target latency, on the
+        // paused clock, which jumps over the delay
+        slow_delay.store(300, Ordering::Relaxed);
+        for _ in 0..2 {
+            send(&fast).await.unwrap();
+            send(&slow).await.unwrap();
+        }
+        assert!(limits.limit(&key(&slow)) < warmed_up);
+        assert!(limits.limit(&key(&fast)) > warmed_up);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrency_ramp() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        use futures_util::future::join_all;
+
+        // the number of requests in flight as each request arrives
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        let seen = (Arc::clone(&in_flight), Arc::clone(&arrivals));
+        let endpoint = mock_vm::serve(move |_| {
+            let (in_flight, arrivals) = seen.clone();
+            let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            arrivals.lock().unwrap().push(now);
+            async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                mock_vm::status(200)
+            }
+        });
+
+        let ramp = ConcurrencyRamp::new(1, 8);
+        let mut service = VMImportService::new(
+            mock_vm::client(),
+            mock_vm::sink(&endpoint),
+            false,
+            true,
+            None,
+            Some(ramp),
+        );
+
+        // a backlog sent all at once on a cold start
+        let requests = (0..40)
+            .map(|_| service.call(mock_vm::batch(&endpoint, vec![mock_vm::series("up")])))
+            .collect::<Vec<_>>();
+        for response in join_all(requests).await {
+            assert_eq!(response.unwrap().status(), 200);
+        }
+
+        let arrivals = arrivals.lock().unwrap();
+        assert_eq!(arrivals.len(), 40);
+        assert_eq!(arrivals[0], 1);
+        assert!(arrivals[..3].iter().all(|&in_flight| in_flight <= 2));
+        assert!(arrivals.iter().all(|&in_flight| in_flight <= 8));
+        assert_eq!(arrivals.iter().max(), Some(&8));
+    }
+}
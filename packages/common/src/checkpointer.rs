@@ -1,43 +1,138 @@
 use std::collections::{BTreeSet, HashMap};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::time::{Duration, SystemTime};
 use std::{fs, io};
 
 use chrono::{DateTime, Utc};
+use fs2::FileExt;
 use serde::{Deserialize, Serialize};
-use vector_core::event::Event;
+use vector_core::event::{Event, LogEvent};
 
 const TMP_FILE_NAME: &str = "checkpoints.new.json";
 const CHECKPOINT_FILE_NAME: &str = "checkpoints.json";
+const LOCK_FILE_NAME: &str = "checkpoints.lock";
+// How long locking the data dir is retried, e.g. while the sink replaced by a
+// config reload still holds it.
+const LOCK_RETRY_SECS: u64 = 10;
+const LOCK_RETRY_INTERVAL_MILLIS: u64 = 100;
+// How long expired checkpoints are remembered, to tell uploads only due to
+// the expiry apart from uploads of new or changed files.
+const EXPIRED_RETENTION_SECS: i64 = 24 * 60 * 60;
+// How long `FileMtime` checkpoints are kept at least after the upload, unless
+// `expire_after` is shorter, so unchanged files seen again by the next scans
+// aren't uploaded again.
+const MTIME_MIN_RETENTION_SECS: u64 = 24 * 60 * 60;
 
 pub struct Checkpointer {
     tmp_file_path: PathBuf,
     stable_file_path: PathBuf,
     checkpoints: CheckPointsView,
     last: State,
+    lock_file_path: PathBuf,
+    // Holds an exclusive lock on `checkpoints.lock` once taken by `lock`, so
+    // two components sharing a data_dir can't clobber each other's
+    // checkpoints. Closing the file releases the lock.
+    _lock: Option<fs::File>,
 }
 
 impl Checkpointer {
-    pub fn new(data_dir: PathBuf) -> Checkpointer {
+    pub fn new(data_dir: PathBuf, expire_policy: ExpirePolicy) -> io::Result<Checkpointer> {
+        let lock_file_path = data_dir.join(LOCK_FILE_NAME);
         let tmp_file_path = data_dir.join(TMP_FILE_NAME);
         let stable_file_path = data_dir.join(CHECKPOINT_FILE_NAME);
-        Checkpointer {
+        Ok(Checkpointer {
             tmp_file_path,
             stable_file_path,
-            checkpoints: CheckPointsView::default(),
-            last: State::V1 {
+            checkpoints: CheckPointsView::new(expire_policy),
+            last: State::V2 {
                 checkpoints: BTreeSet::default(),
+                sessions: BTreeSet::default(),
             },
+            lock_file_path,
+            _lock: None,
+        })
+    }
+
+    /// Locks the data dir for the lifetime of the checkpointer, to be called
+    /// by the running sink before reading the checkpoints. Taken there rather
+    /// than on build, as on a config reload the new sink is built while the
+    /// old one still holds the lock, and retried for a while as the old one
+    /// may still be finishing. Sinks of the same type sharing a data_dir still
+    /// fail to lock it, as the data dir isn't keyed by the component.
+    pub async fn lock(&mut self) -> io::Result<()> {
+        let lock = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&self.lock_file_path)?;
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(LOCK_RETRY_SECS);
+        loop {
+            match lock.try_lock_exclusive() {
+                Ok(()) => break,
+                Err(_) if tokio::time::Instant::now() < deadline => {
+                    tokio::time::sleep(Duration::from_millis(LOCK_RETRY_INTERVAL_MILLIS)).await;
+                }
+                Err(error) => {
+                    return Err(io::Error::new(
+                        error.kind(),
+                        format!(
+                            "Failed to lock {:?}, is another component using the same data_dir? {}",
+                            self.lock_file_path, error
+                        ),
+                    ))
+                }
+            }
         }
+        self._lock = Some(lock);
+        Ok(())
     }
 
     pub fn contains(&self, key: &UploadKey, upload_time_after: SystemTime) -> bool {
         self.checkpoints.contains(key, upload_time_after)
     }
 
-    pub fn update(&mut self, key: UploadKey, upload_time: SystemTime, expire_after: Duration) {
+    /// Whether `key` isn't checkpointed only because its checkpoint expired,
+    /// i.e. it was uploaded after `upload_time_after` before, and uploading it
+    /// again is redundant. Expired checkpoints are remembered for a day, and
+    /// not across restarts.
+    pub fn contains_expired(&self, key: &UploadKey, upload_time_after: SystemTime) -> bool {
+        !self.checkpoints.contains(key, upload_time_after)
+            && self.checkpoints.contains_expired(key, upload_time_after)
+    }
+
+    pub fn update(
+        &mut self,
+        key: UploadKey,
+        upload_time: SystemTime,
+        modified_time: SystemTime,
+        expire_after: Duration,
+    ) {
         self.checkpoints
-            .update(key, upload_time.into(), (upload_time + expire_after).into());
+            .update(key, upload_time, modified_time, expire_after);
+    }
+
+    /// Attaches `metadata` to the checkpoint of `key`, persisted along with it
+    /// until it's updated again or expires.
+    pub fn update_metadata(&mut self, key: &UploadKey, metadata: CheckpointMetadata) {
+        if self.checkpoints.upload_times.contains_key(key) {
+            self.checkpoints.metadata.insert(key.clone(), metadata);
+        }
+    }
+
+    /// The in-progress upload session of `key`, if any.
+    pub fn session(&self, key: &UploadKey) -> Option<&UploadSession> {
+        self.checkpoints.sessions.get(key)
+    }
+
+    pub fn update_session(&mut self, key: UploadKey, session: UploadSession) {
+        self.checkpoints.sessions.insert(key, session);
+    }
+
+    pub fn remove_session(&mut self, key: &UploadKey) {
+        self.checkpoints.sessions.remove(key);
+    }
+
+    pub fn sessions(&self) -> impl Iterator<Item = (&UploadKey, &UploadSession)> {
+        self.checkpoints.sessions.iter()
     }
 
     /// Read persisted checkpoints from disk, preferring the new JSON file format.
@@ -131,52 +226,295 @@ pub struct UploadKey {
 
 impl UploadKey {
     pub fn from_event(event: &Event, bucket: &str) -> Option<Self> {
+        Self::from_event_with_fields(event, bucket, None, None, None)
+    }
+
+    /// Like `from_event`, but the bucket is read from `bucket_field` of the
+    /// event if set there, falling back to `bucket`. The bucket read isn't
+    /// validated, a value that isn't a string is taken as its string form.
+    ///
+    /// With `base_dir`, the filename is taken relative to it, and events with
+    /// an absolute filename or one escaping `base_dir` through `..` yield
+    /// `None`. Symlinks aren't resolved, so ones inside `base_dir` may still
+    /// point outside of it.
+    ///
+    /// With `key_normalization`, the object key is normalized by it, and
+    /// events with a key it rejects yield `None`.
+    pub fn from_event_with_fields(
+        event: &Event,
+        bucket: &str,
+        bucket_field: Option<&str>,
+        base_dir: Option<&Path>,
+        key_normalization: Option<&KeyNormalization>,
+    ) -> Option<Self> {
         let log = event.maybe_as_log()?;
         let filename_val = log.get("message")?;
         let filename = String::from_utf8_lossy(filename_val.as_bytes()?);
+        let filename = match base_dir {
+            Some(base_dir) => Self::join_base_dir(base_dir, &filename)?,
+            None => filename.to_string(),
+        };
 
         let object_key_val = log.get("key")?;
         let object_key = String::from_utf8_lossy(object_key_val.as_bytes()?);
+        let object_key = match key_normalization {
+            Some(key_normalization) => key_normalization.normalize(&object_key)?,
+            None => object_key.into_owned(),
+        };
 
         Some(UploadKey {
-            bucket: bucket.to_owned(),
+            bucket: Self::bucket_from_log(log, bucket, bucket_field),
+            object_key,
+            filename,
+        })
+    }
+
+    fn join_base_dir(base_dir: &Path, filename: &str) -> Option<String> {
+        let mut path = base_dir.to_path_buf();
+        let mut depth = 0usize;
+        for component in Path::new(filename).components() {
+            match component {
+                Component::Normal(name) => {
+                    path.push(name);
+                    depth += 1;
+                }
+                Component::CurDir => {}
+                Component::ParentDir if depth > 0 => {
+                    path.pop();
+                    depth -= 1;
+                }
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+            }
+        }
+        if depth == 0 {
+            return None;
+        }
+        Some(path.to_string_lossy().into_owned())
+    }
+
+    /// The key of a zero-byte marker object, e.g. a directory marker, carried
+    /// by an event with a `key` but no file in `message`. Its `filename` is
+    /// left empty.
+    pub fn marker_from_event(
+        event: &Event,
+        bucket: &str,
+        bucket_field: Option<&str>,
+    ) -> Option<Self> {
+        let log = event.maybe_as_log()?;
+        let has_file = log
+            .get("message")
+            .and_then(|filename| filename.as_bytes())
+            .map_or(false, |filename| !filename.is_empty());
+        if has_file {
+            return None;
+        }
+
+        let object_key_val = log.get("key")?;
+        let object_key = String::from_utf8_lossy(object_key_val.as_bytes()?);
+
+        Some(UploadKey {
+            bucket: Self::bucket_from_log(log, bucket, bucket_field),
             object_key: object_key.to_string(),
-            filename: filename.to_string(),
+            filename: String::new(),
         })
     }
+
+    fn bucket_from_log(log: &LogEvent, bucket: &str, bucket_field: Option<&str>) -> String {
+        match bucket_field.and_then(|field| log.get(field)) {
+            Some(bucket) => bucket.to_string_lossy(),
+            None => bucket.to_owned(),
+        }
+    }
+}
+
+/// How object keys taken from events are rewritten before uploading, as keys
+/// derived from filenames may hold characters behaving surprisingly in S3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyNormalization {
+    /// Take keys as they are if `false`, ignoring the other options.
+    pub enabled: bool,
+    /// Collapse runs of `/`, e.g. `logs//tidb.log` into `logs/tidb.log`.
+    pub collapse_slashes: bool,
+    pub control_chars: ControlChars,
+    /// Lowercase the whole key.
+    pub lowercase: bool,
+}
+
+impl Default for KeyNormalization {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            collapse_slashes: true,
+            control_chars: ControlChars::Reject,
+            lowercase: false,
+        }
+    }
+}
+
+/// What key normalization does with control characters, e.g. newlines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlChars {
+    /// Reject keys holding any.
+    Reject,
+    /// Percent-encode each of their UTF-8 bytes, e.g. `\n` into `%0A`.
+    PercentEncode,
+    /// Keep them as they are.
+    Keep,
+}
+
+impl KeyNormalization {
+    /// The normalized `key`, `None` if rejected.
+    pub fn normalize(&self, key: &str) -> Option<String> {
+        if !self.enabled {
+            return Some(key.to_owned());
+        }
+
+        let lowercased;
+        let key = if self.lowercase {
+            lowercased = key.to_lowercase();
+            &lowercased
+        } else {
+            key
+        };
+        let mut normalized = String::with_capacity(key.len());
+        for c in key.chars() {
+            if self.collapse_slashes && c == '/' && normalized.ends_with('/') {
+                continue;
+            }
+            if c.is_control() {
+                match self.control_chars {
+                    ControlChars::Reject => return None,
+                    ControlChars::PercentEncode => {
+                        let mut buf = [0; 4];
+                        for byte in c.encode_utf8(&mut buf).bytes() {
+                            normalized.push_str(&format!("%{:02X}", byte));
+                        }
+                        continue;
+                    }
+                    ControlChars::Keep => {}
+                }
+            }
+            normalized.push(c);
+        }
+        Some(normalized)
+    }
+}
+
+/// The point in time that `expire_after` is counted from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpirePolicy {
+    /// Checkpoints expire `expire_after` since the file was uploaded.
+    UploadTime,
+    /// Checkpoints expire `expire_after` since the file was last modified, so
+    /// rotated files that no longer change are forgotten sooner, though not
+    /// before a day (or `expire_after` if shorter) since the upload.
+    FileMtime,
+}
+
+impl Default for ExpirePolicy {
+    fn default() -> Self {
+        Self::UploadTime
+    }
+}
+
+/// An in-progress resumable upload, persisted along with the checkpoints so an
+/// interrupted upload can continue from `committed_bytes` after a restart.
+///
+/// S3 multipart uploads are tracked the same way with their upload id as
+/// `session_uri`, though only to abort them once orphaned, never resumed.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "snake_case")]
+pub struct UploadSession {
+    pub session_uri: String,
+    pub committed_bytes: u64,
+    /// The modified time of the file when the session was created, a session
+    /// of a file modified since can't be resumed.
+    pub modified_at: DateTime<Utc>,
+    pub expire_at: DateTime<Utc>,
 }
 
 #[derive(Default)]
 struct CheckPointsView {
     upload_times: HashMap<UploadKey, DateTime<Utc>>,
     expire_times: HashMap<UploadKey, DateTime<Utc>>,
+    sessions: HashMap<UploadKey, UploadSession>,
+    metadata: HashMap<UploadKey, CheckpointMetadata>,
+    // the upload times of expired checkpoints, until they're forgotten
+    expired: HashMap<UploadKey, (DateTime<Utc>, DateTime<Utc>)>,
+    expire_policy: ExpirePolicy,
 }
 
 impl CheckPointsView {
+    pub fn new(expire_policy: ExpirePolicy) -> Self {
+        Self {
+            expire_policy,
+            ..Default::default()
+        }
+    }
+
     pub fn get_state(&self) -> State {
-        State::V1 {
+        State::V2 {
             checkpoints: self
                 .expire_times
                 .iter()
-                .map(|(key, time)| Checkpoint {
+                .map(|(key, time)| CheckpointV2 {
                     upload_key: key.clone(),
                     expire_at: *time,
                     upload_at: self.upload_times.get(key).copied().unwrap_or_else(Utc::now),
+                    metadata: self.metadata.get(key).cloned().unwrap_or_default(),
+                })
+                .collect(),
+            sessions: self
+                .sessions
+                .iter()
+                .map(|(key, session)| SessionCheckpoint {
+                    upload_key: key.clone(),
+                    session: session.clone(),
                 })
                 .collect(),
         }
     }
 
+    /// Loads `state`, upgrading V1 checkpoints into V2 ones without metadata,
+    /// so the next `get_state` is written in the V2 format.
     pub fn set_state(&mut self, state: &State) {
-        match state {
-            State::V1 { checkpoints } => {
+        let sessions = match state {
+            State::V1 {
+                checkpoints,
+                sessions,
+            } => {
                 for checkpoint in checkpoints {
-                    self.expire_times
-                        .insert(checkpoint.upload_key.clone(), checkpoint.expire_at);
-                    self.upload_times
-                        .insert(checkpoint.upload_key.clone(), checkpoint.upload_at);
+                    self.set_checkpoint(checkpoint.upgrade());
                 }
+                sessions
             }
+            State::V2 {
+                checkpoints,
+                sessions,
+            } => {
+                for checkpoint in checkpoints {
+                    self.set_checkpoint(checkpoint.clone());
+                }
+                sessions
+            }
+        };
+        for checkpoint in sessions {
+            self.sessions
+                .insert(checkpoint.upload_key.clone(), checkpoint.session.clone());
+        }
+    }
+
+    fn set_checkpoint(&mut self, checkpoint: CheckpointV2) {
+        let key = checkpoint.upload_key;
+        self.expire_times.insert(key.clone(), checkpoint.expire_at);
+        self.upload_times.insert(key.clone(), checkpoint.upload_at);
+        if checkpoint.metadata == CheckpointMetadata::default() {
+            self.metadata.remove(&key);
+        } else {
+            self.metadata.insert(key, checkpoint.metadata);
         }
     }
 
@@ -188,9 +526,32 @@ impl CheckPointsView {
             .unwrap_or_default()
     }
 
-    pub fn update(&mut self, key: UploadKey, upload_at: DateTime<Utc>, expire_at: DateTime<Utc>) {
-        self.upload_times.insert(key.clone(), upload_at);
-        self.expire_times.insert(key, expire_at);
+    pub fn contains_expired(&self, key: &UploadKey, upload_time_after: SystemTime) -> bool {
+        let upload_time_after = DateTime::<Utc>::from(upload_time_after);
+        self.expired
+            .get(key)
+            .map(|(time, _)| time >= &upload_time_after)
+            .unwrap_or_default()
+    }
+
+    pub fn update(
+        &mut self,
+        key: UploadKey,
+        upload_time: SystemTime,
+        modified_time: SystemTime,
+        expire_after: Duration,
+    ) {
+        let expire_at = match self.expire_policy {
+            ExpirePolicy::UploadTime => upload_time + expire_after,
+            ExpirePolicy::FileMtime => {
+                let retention = expire_after.min(Duration::from_secs(MTIME_MIN_RETENTION_SECS));
+                (modified_time + expire_after).max(upload_time + retention)
+            }
+        };
+        self.expired.remove(&key);
+        self.metadata.remove(&key);
+        self.upload_times.insert(key.clone(), upload_time.into());
+        self.expire_times.insert(key, expire_at.into());
     }
 
     pub fn remove_expired(&mut self) {
@@ -201,10 +562,16 @@ impl CheckPointsView {
                 expired.push(key.clone());
             }
         }
+        let forget_at = now + chrono::Duration::seconds(EXPIRED_RETENTION_SECS);
         for key in expired {
-            self.upload_times.remove(&key);
+            if let Some(upload_time) = self.upload_times.remove(&key) {
+                self.expired.insert(key.clone(), (upload_time, forget_at));
+            }
             self.expire_times.remove(&key);
+            self.metadata.remove(&key);
         }
+        self.expired.retain(|_, (_, forget_at)| *forget_at >= now);
+        self.sessions.retain(|_, session| session.expire_at >= now);
     }
 
     pub fn len(&self) -> usize {
@@ -216,7 +583,17 @@ impl CheckPointsView {
 #[serde(tag = "version", rename_all = "snake_case")]
 enum State {
     #[serde(rename = "1")]
-    V1 { checkpoints: BTreeSet<Checkpoint> },
+    V1 {
+        checkpoints: BTreeSet<Checkpoint>,
+        #[serde(default)]
+        sessions: BTreeSet<SessionCheckpoint>,
+    },
+    #[serde(rename = "2")]
+    V2 {
+        checkpoints: BTreeSet<CheckpointV2>,
+        #[serde(default)]
+        sessions: BTreeSet<SessionCheckpoint>,
+    },
 }
 
 /// A simple JSON-friendly struct of the fingerprint/position pair, since
@@ -228,3 +605,416 @@ struct Checkpoint {
     upload_at: DateTime<Utc>,
     expire_at: DateTime<Utc>,
 }
+
+impl Checkpoint {
+    fn upgrade(&self) -> CheckpointV2 {
+        CheckpointV2 {
+            upload_key: self.upload_key.clone(),
+            upload_at: self.upload_at,
+            expire_at: self.expire_at,
+            metadata: CheckpointMetadata::default(),
+        }
+    }
+}
+
+/// A `Checkpoint` along with what's known about the upload it records.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "snake_case")]
+struct CheckpointV2 {
+    upload_key: UploadKey,
+    upload_at: DateTime<Utc>,
+    expire_at: DateTime<Utc>,
+    #[serde(flatten)]
+    metadata: CheckpointMetadata,
+}
+
+/// Details of an upload kept in its checkpoint, unknown for checkpoints
+/// migrated from the V1 format.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "snake_case")]
+pub struct CheckpointMetadata {
+    /// The size of the file uploaded, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_size: Option<u64>,
+    /// How long the upload took, in milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_duration_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "snake_case")]
+struct SessionCheckpoint {
+    upload_key: UploadKey,
+    session: UploadSession,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload_key() -> UploadKey {
+        UploadKey {
+            filename: "/var/log/tidb/tidb.log".to_owned(),
+            bucket: "bucket".to_owned(),
+            object_key: "tidb.log".to_owned(),
+        }
+    }
+
+    #[test]
+    fn bucket_from_event() {
+        let mut log = LogEvent::from("/var/log/tidb/tidb.log");
+        log.insert("key", "tidb.log");
+        let event = Event::from(log.clone());
+        assert_eq!(UploadKey::from_event(&event, "bucket"), Some(upload_key()));
+        // falls back to the bucket given if the event has none
+        assert_eq!(
+            UploadKey::from_event_with_fields(&event, "bucket", Some("tenant.bucket"), None, None),
+            Some(upload_key())
+        );
+
+        log.insert("tenant.bucket", "tenant-bucket");
+        let event = Event::from(log);
+        assert_eq!(
+            UploadKey::from_event_with_fields(&event, "bucket", Some("tenant.bucket"), None, None),
+            Some(UploadKey {
+                bucket: "tenant-bucket".to_owned(),
+                ..upload_key()
+            })
+        );
+        assert_eq!(UploadKey::from_event(&event, "bucket"), Some(upload_key()));
+    }
+
+    #[test]
+    fn filename_relative_to_base_dir() {
+        let from_event = |filename: &str| {
+            let mut log = LogEvent::from(filename);
+            log.insert("key", "tidb.log");
+            UploadKey::from_event_with_fields(
+                &log.into(),
+                "bucket",
+                None,
+                Some(Path::new("/var/log/tenant-1")),
+                None,
+            )
+            .map(|upload_key| upload_key.filename)
+        };
+
+        assert_eq!(
+            from_event("tidb/tidb.log"),
+            Some("/var/log/tenant-1/tidb/tidb.log".to_owned())
+        );
+        assert_eq!(
+            from_event("./tidb/../tidb.log"),
+            Some("/var/log/tenant-1/tidb.log".to_owned())
+        );
+
+        assert_eq!(from_event("../tenant-2/tidb.log"), None);
+        assert_eq!(from_event("tidb/../../tenant-2/tidb.log"), None);
+        assert_eq!(from_event("/etc/passwd"), None);
+        // the base dir itself isn't a file
+        assert_eq!(from_event("tidb/.."), None);
+    }
+
+    #[test]
+    fn normalize_object_key() {
+        let from_event = |key: &str, key_normalization: &KeyNormalization| {
+            let mut log = LogEvent::from("/var/log/tidb/tidb.log");
+            log.insert("key", key);
+            UploadKey::from_event_with_fields(
+                &log.into(),
+                "bucket",
+                None,
+                None,
+                Some(key_normalization),
+            )
+            .map(|upload_key| upload_key.object_key)
+        };
+
+        let default = KeyNormalization::default();
+        assert_eq!(
+            from_event("logs//tidb///tidb.log", &default),
+            Some("logs/tidb/tidb.log".to_owned())
+        );
+        assert_eq!(from_event("logs/tidb\n.log", &default), None);
+
+        let encode = KeyNormalization {
+            control_chars: ControlChars::PercentEncode,
+            lowercase: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            from_event("Logs//TiDB\n.log", &encode),
+            Some("logs/tidb%0A.log".to_owned())
+        );
+
+        let disabled = KeyNormalization {
+            enabled: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            from_event("logs//tidb\n.log", &disabled),
+            Some("logs//tidb\n.log".to_owned())
+        );
+    }
+
+    #[test]
+    fn marker_from_event() {
+        let mut log = LogEvent::default();
+        log.insert("key", "logs/2022/");
+        assert_eq!(
+            UploadKey::marker_from_event(&log.clone().into(), "bucket", None),
+            Some(UploadKey {
+                filename: String::new(),
+                bucket: "bucket".to_owned(),
+                object_key: "logs/2022/".to_owned(),
+            })
+        );
+
+        log.insert("message", "");
+        assert!(UploadKey::marker_from_event(&log.clone().into(), "bucket", None).is_some());
+
+        // events with a file upload it instead
+        log.insert("message", "/var/log/tidb/tidb.log");
+        assert!(UploadKey::marker_from_event(&log.into(), "bucket", None).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lock_data_dir() {
+        let data_dir =
+            std::env::temp_dir().join(format!("checkpointer-lock-{}", std::process::id()));
+        fs::create_dir_all(&data_dir).unwrap();
+        let checkpointer = || Checkpointer::new(data_dir.clone(), ExpirePolicy::default()).unwrap();
+
+        // building doesn't lock, e.g. while the sink replaced by a reload runs
+        let mut old = checkpointer();
+        let mut new = checkpointer();
+        old.lock().await.unwrap();
+
+        // locking waits for the old one to go away
+        let release = async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            drop(old);
+        };
+        let (locked, ()) = tokio::join!(new.lock(), release);
+        locked.unwrap();
+
+        // and gives up if it doesn't
+        assert!(checkpointer().lock().await.is_err());
+
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn persist_upload_sessions() {
+        let data_dir =
+            std::env::temp_dir().join(format!("checkpointer-sessions-{}", std::process::id()));
+        fs::create_dir_all(&data_dir).unwrap();
+
+        let session = |expire_at| UploadSession {
+            session_uri: "https://storage.googleapis.com/upload?upload_id=1".to_owned(),
+            committed_bytes: 8 * 1024 * 1024,
+            modified_at: Utc::now(),
+            expire_at,
+        };
+        let expired_key = UploadKey {
+            object_key: "tidb-1.log".to_owned(),
+            ..upload_key()
+        };
+
+        let mut checkpointer =
+            Checkpointer::new(data_dir.clone(), ExpirePolicy::default()).unwrap();
+        let active = session(Utc::now() + chrono::Duration::days(7));
+        checkpointer.update_session(upload_key(), active.clone());
+        checkpointer.update_session(
+            expired_key.clone(),
+            session(Utc::now() - chrono::Duration::seconds(1)),
+        );
+        checkpointer.write_checkpoints().unwrap();
+        drop(checkpointer);
+
+        let mut checkpointer =
+            Checkpointer::new(data_dir.clone(), ExpirePolicy::default()).unwrap();
+        checkpointer.read_checkpoints();
+        assert_eq!(checkpointer.session(&upload_key()), Some(&active));
+        assert_eq!(checkpointer.session(&expired_key), None);
+
+        checkpointer.remove_session(&upload_key());
+        checkpointer.write_checkpoints().unwrap();
+        drop(checkpointer);
+
+        let mut checkpointer =
+            Checkpointer::new(data_dir.clone(), ExpirePolicy::default()).unwrap();
+        checkpointer.read_checkpoints();
+        assert_eq!(checkpointer.session(&upload_key()), None);
+        drop(checkpointer);
+
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn migrate_v1_checkpoints() {
+        let data_dir =
+            std::env::temp_dir().join(format!("checkpointer-migrate-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        fs::create_dir_all(&data_dir).unwrap();
+
+        let upload_at = Utc::now() - chrono::Duration::minutes(5);
+        let checkpoint = Checkpoint {
+            upload_key: upload_key(),
+            upload_at,
+            expire_at: Utc::now() + chrono::Duration::days(1),
+        };
+        let v1 = State::V1 {
+            checkpoints: [checkpoint].into_iter().collect(),
+            sessions: BTreeSet::default(),
+        };
+        let stable_file_path = data_dir.join(CHECKPOINT_FILE_NAME);
+        fs::write(&stable_file_path, serde_json::to_vec(&v1).unwrap()).unwrap();
+
+        let upload_time_after = SystemTime::from(upload_at - chrono::Duration::seconds(1));
+        let mut checkpointer =
+            Checkpointer::new(data_dir.clone(), ExpirePolicy::default()).unwrap();
+        checkpointer.read_checkpoints();
+        assert!(checkpointer.contains(&upload_key(), upload_time_after));
+        assert_eq!(checkpointer.write_checkpoints().unwrap(), 1);
+        drop(checkpointer);
+
+        let v2 = serde_json::from_slice::<State>(&fs::read(&stable_file_path).unwrap()).unwrap();
+        let expected = match &v1 {
+            State::V1 { checkpoints, .. } => State::V2 {
+                checkpoints: checkpoints.iter().map(Checkpoint::upgrade).collect(),
+                sessions: BTreeSet::default(),
+            },
+            State::V2 { .. } => unreachable!(),
+        };
+        assert_eq!(v2, expected);
+
+        // metadata survives a round trip through the V2 format
+        let mut checkpointer =
+            Checkpointer::new(data_dir.clone(), ExpirePolicy::default()).unwrap();
+        checkpointer.read_checkpoints();
+        assert!(checkpointer.contains(&upload_key(), upload_time_after));
+        let metadata = CheckpointMetadata {
+            file_size: Some(1024),
+            upload_duration_ms: Some(150),
+        };
+        checkpointer.update_metadata(&upload_key(), metadata.clone());
+        checkpointer.write_checkpoints().unwrap();
+        drop(checkpointer);
+
+        let mut checkpointer =
+            Checkpointer::new(data_dir.clone(), ExpirePolicy::default()).unwrap();
+        checkpointer.read_checkpoints();
+        assert!(checkpointer.contains(&upload_key(), upload_time_after));
+        assert_eq!(
+            checkpointer.checkpoints.metadata.get(&upload_key()),
+            Some(&metadata)
+        );
+        drop(checkpointer);
+
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn read_checkpoints_without_sessions() {
+        let state = serde_json::from_str::<State>(r#"{"version":"1","checkpoints":[]}"#).unwrap();
+        assert_eq!(
+            state,
+            State::V1 {
+                checkpoints: BTreeSet::default(),
+                sessions: BTreeSet::default(),
+            }
+        );
+    }
+
+    #[test]
+    fn expire_by_upload_time() {
+        let mut view = CheckPointsView::new(ExpirePolicy::UploadTime);
+        let now = SystemTime::now();
+        let modified_time = now - Duration::from_secs(3600);
+        view.update(upload_key(), now, modified_time, Duration::from_secs(1800));
+
+        view.remove_expired();
+        assert_eq!(view.len(), 1);
+        assert!(view.contains(&upload_key(), modified_time));
+    }
+
+    #[test]
+    fn expire_by_file_mtime() {
+        let mut view = CheckPointsView::new(ExpirePolicy::FileMtime);
+        let now = SystemTime::now();
+        let expire_after = Duration::from_secs(1800);
+
+        // modified since the upload, kept for `expire_after` since then
+        let modified_time = now + Duration::from_secs(60);
+        view.update(upload_key(), now, modified_time, expire_after);
+        view.remove_expired();
+        assert_eq!(view.len(), 1);
+        assert_eq!(
+            view.expire_times[&upload_key()],
+            DateTime::<Utc>::from(modified_time + expire_after)
+        );
+    }
+
+    #[test]
+    fn expire_rotated_file_by_file_mtime() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = SystemTime::now();
+        let upload_time = now - Duration::from_secs(600);
+        // rotated 10 days ago, and unchanged since
+        let modified_time = now - 10 * day;
+        let expire_after = 7 * day;
+
+        let mut by_upload_time = CheckPointsView::new(ExpirePolicy::UploadTime);
+        by_upload_time.update(upload_key(), upload_time, modified_time, expire_after);
+
+        let mut by_file_mtime = CheckPointsView::new(ExpirePolicy::FileMtime);
+        by_file_mtime.update(upload_key(), upload_time, modified_time, expire_after);
+        // forgotten a day after the upload rather than a week
+        assert_eq!(
+            by_file_mtime.expire_times[&upload_key()],
+            DateTime::<Utc>::from(upload_time + day)
+        );
+        assert!(
+            by_file_mtime.expire_times[&upload_key()] < by_upload_time.expire_times[&upload_key()]
+        );
+
+        // seen again by the next scan, and skipped as unchanged
+        by_file_mtime.remove_expired();
+        assert!(by_file_mtime.contains(&upload_key(), modified_time));
+
+        // kept no longer than `expire_after` after the upload, if shorter
+        let expire_after = Duration::from_secs(1800);
+        by_file_mtime.update(upload_key(), upload_time, modified_time, expire_after);
+        assert_eq!(
+            by_file_mtime.expire_times[&upload_key()],
+            DateTime::<Utc>::from(upload_time + expire_after)
+        );
+    }
+
+    #[test]
+    fn remember_expired_checkpoints() {
+        let mut view = CheckPointsView::new(ExpirePolicy::UploadTime);
+        let now = SystemTime::now();
+        let modified_time = now - Duration::from_secs(3600);
+        let upload_time = now - Duration::from_secs(1800);
+        view.update(
+            upload_key(),
+            upload_time,
+            modified_time,
+            Duration::from_secs(60),
+        );
+        assert!(!view.contains_expired(&upload_key(), modified_time));
+
+        view.remove_expired();
+        assert!(!view.contains(&upload_key(), modified_time));
+        // unchanged since the expired upload
+        assert!(view.contains_expired(&upload_key(), modified_time));
+        // changed since
+        assert!(!view.contains_expired(&upload_key(), now));
+
+        // uploaded again
+        view.update(upload_key(), now, modified_time, Duration::from_secs(60));
+        assert!(!view.contains_expired(&upload_key(), modified_time));
+    }
+}
@@ -0,0 +1,65 @@
+use metrics::counter;
+use vector_core::internal_event::InternalEvent;
+
+/// A file acknowledged without being uploaded. `reason` is one of
+/// `checkpoint_hit`, `pending`, `etag_match` or `object_exists`.
+#[derive(Debug)]
+pub struct UploadSkipped<'a> {
+    pub reason: &'static str,
+    pub filename: &'a str,
+}
+
+impl<'a> InternalEvent for UploadSkipped<'a> {
+    fn emit(self) {
+        debug!(
+            message = "Skipped uploading file.",
+            reason = %self.reason,
+            filename = %self.filename,
+        );
+        counter!(
+            "upload_skipped_total", 1,
+            "reason" => self.reason,
+        );
+    }
+}
+
+/// A file uploaded again only because its checkpoint expired, unchanged since
+/// it was last uploaded. Frequent ones suggest raising `expire_after_secs`.
+#[derive(Debug)]
+pub struct UploadCheckpointExpired<'a> {
+    pub filename: &'a str,
+}
+
+impl<'a> InternalEvent for UploadCheckpointExpired<'a> {
+    fn emit(self) {
+        debug!(
+            message = "Uploading file again as its checkpoint expired.",
+            filename = %self.filename,
+        );
+        counter!("upload_checkpoint_expired_total", 1);
+    }
+}
+
+/// A file uploaded to `url`, the object `key` of `bucket`.
+#[derive(Debug)]
+pub struct FileUploaded<'a> {
+    pub filename: &'a str,
+    pub bucket: &'a str,
+    pub key: &'a str,
+    pub url: &'a str,
+    pub size: usize,
+}
+
+impl<'a> InternalEvent for FileUploaded<'a> {
+    fn emit(self) {
+        info!(
+            message = "Uploaded file.",
+            filename = %self.filename,
+            bucket = %self.bucket,
+            key = %self.key,
+            url = %self.url,
+            size = %self.size,
+        );
+        counter!("files_uploaded_total", 1);
+    }
+}
@@ -2,3 +2,8 @@
 extern crate tracing;
 
 pub mod checkpointer;
+pub mod internal_events;
+#[cfg(feature = "mock-http")]
+pub mod mock_http;
+pub mod object_url;
+pub mod startup_guard;
@@ -0,0 +1,136 @@
+//! A mock HTTP server for the tests of the sinks, recording the requests it
+//! receives.
+
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A request received by the mock.
+#[derive(Clone, Debug)]
+pub struct Request {
+    /// As sent, e.g. `PUT /bucket/key HTTP/1.1`.
+    pub line: String,
+    /// The header lines, lowercased.
+    pub headers: String,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn method(&self) -> &str {
+        self.line.split(' ').next().unwrap_or_default()
+    }
+}
+
+/// A response of the mock, with a `Content-Length` framing its body.
+pub struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+}
+
+impl Response {
+    pub fn ok(body: impl Into<String>) -> Self {
+        Self {
+            status: 200,
+            headers: vec![],
+            body: body.into(),
+        }
+    }
+
+    pub fn status(status: u16) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: String::new(),
+        }
+    }
+
+    pub fn body(mut self, body: impl Into<String>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let reason = http::StatusCode::from_u16(self.status)
+            .ok()
+            .and_then(|status| status.canonical_reason())
+            .unwrap_or_default();
+        let mut response = format!("HTTP/1.1 {} {}\r\n", self.status, reason);
+        for (name, value) in &self.headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if self.status != 204 {
+            response.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        response.push_str("\r\n");
+        response.push_str(&self.body);
+        response.into_bytes()
+    }
+}
+
+/// Serves HTTP on a local port, answering every request with `respond`, and
+/// returns the endpoint along with the requests received so far.
+pub async fn serve<F>(respond: F) -> (String, Arc<Mutex<Vec<Request>>>)
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let requests = Arc::new(Mutex::new(vec![]));
+    let respond = Arc::new(respond);
+
+    let seen = Arc::clone(&requests);
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let seen = Arc::clone(&seen);
+            let respond = Arc::clone(&respond);
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                while let Some(request) = read_request(&mut stream).await {
+                    let response = respond(&request);
+                    seen.lock().unwrap().push(request);
+                    stream.write_all(&response.to_bytes()).await.unwrap();
+                }
+            });
+        }
+    });
+
+    (format!("http://{}", address), requests)
+}
+
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Option<Request> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+        return None;
+    }
+    let mut headers = String::new();
+    loop {
+        let mut header = String::new();
+        if stream.read_line(&mut header).await.unwrap_or(0) == 0 {
+            return None;
+        }
+        if header == "\r\n" {
+            break;
+        }
+        headers.push_str(&header.to_lowercase());
+    }
+    let length = headers
+        .lines()
+        .filter_map(|header| header.split_once(':'))
+        .find(|(name, _)| *name == "content-length")
+        .map_or(0, |(_, value)| value.trim().parse().unwrap());
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await.ok()?;
+    Some(Request {
+        line: line.trim_end().to_owned(),
+        headers,
+        body,
+    })
+}
@@ -0,0 +1,70 @@
+use url::Url;
+
+/// Builds the URL of uploaded objects, percent-encoding their keys, e.g. to
+/// log them for operators to open.
+#[derive(Clone, Debug)]
+pub struct ObjectUrl {
+    endpoint: Url,
+    path_style: bool,
+}
+
+impl ObjectUrl {
+    /// Objects served by `endpoint`, addressed with the bucket in the path if
+    /// `path_style`, as custom endpoints such as MinIO are, or in the host
+    /// otherwise.
+    pub fn new(endpoint: Url, path_style: bool) -> Self {
+        Self {
+            endpoint,
+            path_style,
+        }
+    }
+
+    pub fn of(&self, bucket: &str, key: &str) -> String {
+        let mut url = self.endpoint.clone();
+        // buckets with dots don't match the wildcard certificate of the
+        // virtual hosts
+        let mut path_style = self.path_style || bucket.contains('.');
+        if !path_style {
+            let host = url.host_str().map(|host| format!("{}.{}", bucket, host));
+            path_style = host.map_or(true, |host| url.set_host(Some(&host)).is_err());
+        }
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty();
+            if path_style {
+                segments.push(bucket);
+            }
+            segments.extend(key.split('/'));
+        }
+        url.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn object_url() {
+        let aws = ObjectUrl::new("https://s3.us-east-1.amazonaws.com".parse().unwrap(), false);
+        assert_eq!(
+            aws.of("logs", "tidb/tidb 1.log"),
+            "https://logs.s3.us-east-1.amazonaws.com/tidb/tidb%201.log"
+        );
+        assert_eq!(
+            aws.of("logs.example.com", "tidb.log"),
+            "https://s3.us-east-1.amazonaws.com/logs.example.com/tidb.log"
+        );
+
+        let minio = ObjectUrl::new("http://minio:9000/s3/".parse().unwrap(), true);
+        assert_eq!(
+            minio.of("logs", "tidb/tidb.log"),
+            "http://minio:9000/s3/logs/tidb/tidb.log"
+        );
+
+        let gcs = ObjectUrl::new("https://storage.googleapis.com/".parse().unwrap(), true);
+        assert_eq!(
+            gcs.of("logs", "tidb/tidb?1#%.log"),
+            "https://storage.googleapis.com/logs/tidb/tidb%3F1%23%25.log"
+        );
+    }
+}
@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+/// Holds back the start of a source by `delay`, logging once when it starts,
+/// so every source reports its startup the same way. A zero `delay` starts it
+/// right away.
+pub async fn startup_guard(source_type: &str, delay: Duration) {
+    info!(
+        message = "Starting source.",
+        %source_type,
+        delay_secs = delay.as_secs_f64(),
+    );
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Instant;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_for_delay() {
+        let start = Instant::now();
+        startup_guard("test", Duration::ZERO).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        startup_guard("test", Duration::from_secs(30)).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+    }
+}
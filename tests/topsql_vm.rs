@@ -4,12 +4,18 @@
 //
 // Please run `make test-integration` to set up environment and run the test cases.
 
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use hyper::client::HttpConnector;
-use hyper::Client;
+use hyper::{Body, Client, Method, Request};
 
 type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
 const VM_BASE_URL: &str = "http://127.0.0.1:8428/prometheus/api/v1";
+const VM_IMPORT_URL: &str = "http://127.0.0.1:8428/api/v1/import";
 
 #[tokio::test]
 async fn topsql_vm() {
@@ -60,6 +66,58 @@ async fn topsql_vm() {
     }
 }
 
+// `vm_import` with `chunked_transfer = true` sends gzip bodies without a
+// `Content-Length`, make sure VictoriaMetrics accepts that framing.
+#[tokio::test]
+async fn vm_import_chunked_gzip() {
+    let client = Client::new();
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    for i in 0..1000 {
+        writeln!(
+            encoder,
+            r#"{{"metric":{{"__name__":"vm_import_chunked_test","series":"{}"}},"timestamps":[{}],"values":[{}]}}"#,
+            i, now, i
+        )
+        .unwrap();
+    }
+    let body = encoder.finish().unwrap();
+
+    let (mut sender, chunked_body) = Body::channel();
+    tokio::spawn(async move {
+        for chunk in body.chunks(1024) {
+            sender.send_data(chunk.to_vec().into()).await.unwrap();
+        }
+    });
+    let import = Request::builder()
+        .method(Method::POST)
+        .uri(VM_IMPORT_URL)
+        .header("Content-Encoding", "gzip")
+        .body(chunked_body)
+        .unwrap();
+    assert!(import.headers().get("Content-Length").is_none());
+    let response = client.request(import).await.unwrap();
+    assert_eq!(response.status(), 204);
+
+    // newly imported samples become searchable after a short while
+    let url = build_url(
+        format!("{}/query", VM_BASE_URL),
+        &[("query", "count(vm_import_chunked_test)")],
+    );
+    for _ in 0..30 {
+        let body = request(&client, url.clone()).await.unwrap();
+        if body.contains(r#""1000"]"#) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    panic!("imported series not found");
+}
+
 async fn check_top(
     client: &Client<HttpConnector>,
     k: u32,